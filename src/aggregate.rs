@@ -0,0 +1,466 @@
+use crate::budget::MemoryBudget;
+use crate::collation::Collation;
+use crate::eval::{
+    apply_numeric_affinity, compare_collated, evaluate, matches, numeric_value,
+    total_order_collated, Row,
+};
+use crate::functions::{self, AggregateFactory};
+use crate::parser::Expr;
+use crate::record::Value;
+use crate::temp::row_size;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// An aggregate of the rows a query matches.
+pub struct Aggregate {
+    pub function: AggregateFunction,
+    /// Only rows this holds for are aggregated.
+    pub filter: Option<Expr>,
+}
+
+pub enum AggregateFunction {
+    /// `count(*)`
+    CountRows,
+    /// `count(column)`: the rows where the column, by index, isn't NULL.
+    Count(usize),
+    /// `sum(arg)`: INTEGER while every value is one, REAL otherwise, and
+    /// NULL if there are none.
+    Sum(Expr),
+    /// `avg(arg)`: the mean of the values that aren't NULL, as a REAL.
+    Avg(Expr),
+    /// `min(arg)`, comparing text by `collation`.
+    Min { arg: Expr, collation: Collation },
+    /// `max(arg)`, comparing text by `collation`.
+    Max { arg: Expr, collation: Collation },
+    /// An aggregate registered on the connection, stepped with its
+    /// arguments evaluated for each row.
+    User {
+        new: AggregateFactory,
+        args: Vec<Expr>,
+    },
+}
+
+/// The running state of one aggregate.
+enum Accumulator {
+    Count(i64),
+    Sum(Sum),
+    /// The smallest or largest value so far.
+    Extreme(Option<Value>),
+    User(Box<dyn functions::Aggregate>),
+}
+
+/// A running `sum()` or `avg()`, the way SQLite keeps it: an exact INTEGER
+/// total until a REAL (or an overflow) turns up, then a compensated REAL one.
+#[derive(Default)]
+struct Sum {
+    count: i64,
+    int: i64,
+    real: f64,
+    /// What rounding has lost from `real` so far.
+    error: f64,
+    approximate: bool,
+    overflowed: bool,
+}
+
+impl Sum {
+    fn add(&mut self, value: Value) {
+        if matches!(value, Value::Null) {
+            return;
+        }
+        self.count += 1;
+        match apply_numeric_affinity(value) {
+            Value::Int(i) if !self.approximate => match self.int.checked_add(i) {
+                Some(sum) => self.int = sum,
+                None => {
+                    self.overflowed = true;
+                    self.approximate = true;
+                    self.real = self.int as f64;
+                    self.add_real(i as f64);
+                }
+            },
+            Value::Int(i) => self.add_real(i as f64),
+            value => {
+                if !self.approximate {
+                    self.approximate = true;
+                    self.real = self.int as f64;
+                }
+                match numeric_value(value) {
+                    Some(Value::Int(i)) => self.add_real(i as f64),
+                    Some(Value::Float(f)) => self.add_real(f),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Kahan-Babuska-Neumaier summation, as SQLite does it.
+    fn add_real(&mut self, x: f64) {
+        let sum = self.real + x;
+        if self.real.abs() > x.abs() {
+            self.error += (self.real - sum) + x;
+        } else {
+            self.error += (x - sum) + self.real;
+        }
+        self.real = sum;
+    }
+
+    /// The real sum, corrected for rounding unless the correction itself
+    /// overflowed, as it does once the sum is infinite.
+    fn total(&self) -> f64 {
+        if self.error.is_finite() {
+            self.real + self.error
+        } else {
+            self.real
+        }
+    }
+
+    fn sum(&self) -> Result<Value> {
+        Ok(match self {
+            Sum { count: 0, .. } => Value::Null,
+            Sum {
+                overflowed: true, ..
+            } => bail!("Integer overflow in sum()"),
+            Sum {
+                approximate: true, ..
+            } => Value::Float(self.total()),
+            _ => Value::Int(self.int),
+        })
+    }
+
+    fn avg(&self) -> Value {
+        match self {
+            Sum { count: 0, .. } => Value::Null,
+            Sum {
+                approximate: true, ..
+            } => Value::Float(self.total() / self.count as f64),
+            _ => Value::Float(self.int as f64 / self.count as f64),
+        }
+    }
+}
+
+/// The running state of every aggregate of a query, for one group of rows.
+pub struct Accumulators {
+    accumulators: Vec<Accumulator>,
+    /// The one `min()` or `max()` of the query, if it has exactly one: the
+    /// columns outside an aggregate then come from the row it picked.
+    lone_extreme: Option<usize>,
+}
+
+impl Accumulators {
+    pub fn new(aggregates: &[Aggregate]) -> Self {
+        let mut extremes = aggregates.iter().enumerate().filter(|(_, aggregate)| {
+            matches!(
+                aggregate.function,
+                AggregateFunction::Min { .. } | AggregateFunction::Max { .. }
+            )
+        });
+        let lone_extreme = match (extremes.next(), extremes.next()) {
+            (Some((index, _)), None) => Some(index),
+            _ => None,
+        };
+        Accumulators {
+            accumulators: aggregates
+                .iter()
+                .map(|aggregate| match &aggregate.function {
+                    AggregateFunction::CountRows | AggregateFunction::Count(_) => {
+                        Accumulator::Count(0)
+                    }
+                    AggregateFunction::Sum(_) | AggregateFunction::Avg(_) => {
+                        Accumulator::Sum(Sum::default())
+                    }
+                    AggregateFunction::Min { .. } | AggregateFunction::Max { .. } => {
+                        Accumulator::Extreme(None)
+                    }
+                    AggregateFunction::User { new, .. } => Accumulator::User(new()),
+                })
+                .collect(),
+            lone_extreme,
+        }
+    }
+
+    /// Adds `row` to each of `aggregates` whose filter it passes. Returns
+    /// whether the query's lone `min()` or `max()` took its value from it,
+    /// so the group's other columns should too.
+    pub fn step(&mut self, aggregates: &[Aggregate], row: &Row) -> Result<bool> {
+        let mut picked = false;
+        for (index, (aggregate, accumulator)) in
+            aggregates.iter().zip(&mut self.accumulators).enumerate()
+        {
+            if let Some(filter) = &aggregate.filter {
+                if !matches(filter, row)? {
+                    continue;
+                }
+            }
+            match (&aggregate.function, accumulator) {
+                (AggregateFunction::CountRows, Accumulator::Count(count)) => {
+                    *count += 1;
+                }
+                (AggregateFunction::Count(index), Accumulator::Count(count)) => {
+                    if !matches!(row.values.get(*index), None | Some(Value::Null)) {
+                        *count += 1;
+                    }
+                }
+                (
+                    AggregateFunction::Sum(arg) | AggregateFunction::Avg(arg),
+                    Accumulator::Sum(sum),
+                ) => sum.add(evaluate(arg, row)?),
+                (
+                    AggregateFunction::Min { arg, collation }
+                    | AggregateFunction::Max { arg, collation },
+                    Accumulator::Extreme(extreme),
+                ) => {
+                    let value = evaluate(arg, row)?;
+                    let wanted = if matches!(aggregate.function, AggregateFunction::Min { .. }) {
+                        std::cmp::Ordering::Less
+                    } else {
+                        std::cmp::Ordering::Greater
+                    };
+                    // Ties keep the first value, and NULLs are skipped.
+                    let better = match extreme {
+                        _ if matches!(value, Value::Null) => false,
+                        None => true,
+                        Some(current) => {
+                            compare_collated(&value, current, collation) == Some(wanted)
+                        }
+                    };
+                    if better {
+                        *extreme = Some(value);
+                        picked |= self.lone_extreme == Some(index);
+                    }
+                }
+                (AggregateFunction::User { args, .. }, Accumulator::User(state)) => {
+                    let args = args
+                        .iter()
+                        .map(|arg| evaluate(arg, row))
+                        .collect::<Result<Vec<_>>>()?;
+                    state.step(&args)?;
+                }
+                _ => unreachable!("accumulators are made from their aggregates"),
+            }
+        }
+        Ok(picked)
+    }
+
+    /// The result of each of `aggregates` so far, leaving them to take more
+    /// rows; `None` if one is registered on the connection, whose result can
+    /// only be taken once.
+    pub fn current(&self, aggregates: &[Aggregate]) -> Option<Result<Vec<Value>>> {
+        self.accumulators
+            .iter()
+            .zip(aggregates)
+            .map(|(accumulator, aggregate)| match accumulator {
+                Accumulator::Count(count) => Some(Ok(Value::Int(*count))),
+                Accumulator::Sum(sum) => Some(match aggregate.function {
+                    AggregateFunction::Avg(_) => Ok(sum.avg()),
+                    _ => sum.sum(),
+                }),
+                Accumulator::Extreme(extreme) => Some(Ok(extreme.clone().unwrap_or(Value::Null))),
+                Accumulator::User(_) => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.into_iter().collect())
+    }
+
+    /// The result of each of `aggregates`.
+    pub fn finish(self, aggregates: &[Aggregate]) -> Result<Vec<Value>> {
+        self.accumulators
+            .into_iter()
+            .zip(aggregates)
+            .map(|(accumulator, aggregate)| match accumulator {
+                Accumulator::Count(count) => Ok(Value::Int(count)),
+                Accumulator::Sum(sum) => match aggregate.function {
+                    AggregateFunction::Avg(_) => Ok(sum.avg()),
+                    _ => sum.sum(),
+                },
+                Accumulator::Extreme(extreme) => Ok(extreme.unwrap_or(Value::Null)),
+                Accumulator::User(mut state) => state.finalize(),
+            })
+            .collect()
+    }
+}
+
+/// One group of a `GROUP BY`.
+pub struct Group {
+    /// The values of the GROUP BY expressions.
+    pub key: Vec<Value>,
+    /// The row columns outside an aggregate take their values from: the
+    /// first one added to the group, or the one a lone `min()` or `max()`
+    /// picked.
+    pub row: Vec<Value>,
+    pub accumulators: Accumulators,
+}
+
+/// The groups of a `GROUP BY`, built up one row at a time. Keys are told
+/// apart the way DISTINCT tells rows apart, by `ValueKey`.
+pub struct Groups {
+    /// How each GROUP BY expression compares text.
+    collations: Vec<Collation>,
+    index: HashMap<Vec<ValueKey>, usize>,
+    groups: Vec<Group>,
+}
+
+impl Groups {
+    pub fn new(collations: Vec<Collation>) -> Self {
+        Groups {
+            collations,
+            index: HashMap::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    /// The group `key` is the key of, started (and charged to `budget`) if
+    /// there is none yet.
+    pub fn group(
+        &mut self,
+        key: Vec<Value>,
+        aggregates: &[Aggregate],
+        budget: &mut MemoryBudget,
+    ) -> Result<&mut Group> {
+        let hashed = key
+            .iter()
+            .zip(&self.collations)
+            .map(|(value, collation)| ValueKey::new(value, collation))
+            .collect::<Vec<_>>();
+        let position = match self.index.get(&hashed) {
+            Some(&position) => position,
+            None => {
+                budget.charge(row_size(&key))?;
+                self.index.insert(hashed, self.groups.len());
+                self.groups.push(Group {
+                    key,
+                    row: Vec::new(),
+                    accumulators: Accumulators::new(aggregates),
+                });
+                self.groups.len() - 1
+            }
+        };
+        Ok(&mut self.groups[position])
+    }
+
+    /// The groups in the order of their keys, as SQLite returns them when
+    /// nothing else is asked for.
+    pub fn into_sorted(mut self) -> Vec<Group> {
+        let collations = self.collations;
+        self.groups.sort_by(|a, b| {
+            a.key
+                .iter()
+                .zip(&b.key)
+                .zip(&collations)
+                .map(|((a, b), collation)| total_order_collated(a, b, collation))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        self.groups
+    }
+}
+
+/// A value as DISTINCT and GROUP BY tell values apart: numbers by value,
+/// whether stored as INTEGER or REAL, NULLs all the same, and text as its
+/// collation compares it.
+#[derive(PartialEq, Eq, Hash)]
+pub enum ValueKey {
+    Null,
+    Int(i64),
+    /// The bits of a REAL that isn't a whole number an INTEGER could hold.
+    Real(u64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl ValueKey {
+    /// The key of `value`. `collation` has to be a built-in one: a
+    /// registered collation can't be hashed, and is taken as BINARY.
+    pub fn new(value: &Value, collation: &Collation) -> Self {
+        match value {
+            Value::Null => ValueKey::Null,
+            Value::Int(i) => ValueKey::Int(*i),
+            // Every integer up to 2^63 in size converts exactly; 2^63 itself
+            // doesn't fit.
+            Value::Float(f)
+                if f.fract() == 0.0
+                    && (-9.223372036854776e18..9.223372036854776e18).contains(f) =>
+            {
+                ValueKey::Int(*f as i64)
+            }
+            Value::Float(f) => ValueKey::Real(f.to_bits()),
+            Value::Text(text) => ValueKey::Text(match collation {
+                Collation::NoCase => text.to_ascii_lowercase(),
+                Collation::RTrim => text.trim_end_matches(' ').to_string(),
+                Collation::Binary | Collation::User(_) => text.to_string(),
+            }),
+            Value::Blob(bytes) => ValueKey::Blob(bytes.clone()),
+        }
+    }
+}
+
+/// Rebuilds `expr` with every call to an aggregate function (by
+/// `is_aggregate`, or `count`) replaced by what `replace` makes of it, in
+/// the order they are written. The arguments of an aggregate are left
+/// alone, and so are subqueries, which have aggregates of their own.
+pub fn replace_aggregate_calls(
+    expr: Expr,
+    is_aggregate: &dyn Fn(&str) -> bool,
+    replace: &mut dyn FnMut(String, Vec<Expr>) -> Result<Expr>,
+) -> Result<Expr> {
+    let mut map =
+        |expr: Box<Expr>| replace_aggregate_calls(*expr, is_aggregate, &mut *replace).map(Box::new);
+    Ok(match expr {
+        Expr::Function { name, args }
+            if name.eq_ignore_ascii_case("count") || is_aggregate(&name) =>
+        {
+            replace(name, args)?
+        }
+        Expr::Function { name, args } => Expr::Function {
+            name,
+            args: args
+                .into_iter()
+                .map(|arg| map(Box::new(arg)).map(|arg| *arg))
+                .collect::<Result<_>>()?,
+        },
+        Expr::Compare { left, op, right } => Expr::Compare {
+            left: map(left)?,
+            op,
+            right: map(right)?,
+        },
+        Expr::Like {
+            left,
+            pattern,
+            escape,
+        } => Expr::Like {
+            left: map(left)?,
+            pattern: map(pattern)?,
+            escape,
+        },
+        Expr::In { left, list } => Expr::In {
+            left: map(left)?,
+            list: list
+                .into_iter()
+                .map(|item| map(Box::new(item)).map(|item| *item))
+                .collect::<Result<_>>()?,
+        },
+        Expr::IsNull(operand) => Expr::IsNull(map(operand)?),
+        Expr::Collate { operand, collation } => Expr::Collate {
+            operand: map(operand)?,
+            collation,
+        },
+        Expr::Arithmetic { left, op, right } => Expr::Arithmetic {
+            left: map(left)?,
+            op,
+            right: map(right)?,
+        },
+        Expr::And(left, right) => Expr::And(map(left)?, map(right)?),
+        Expr::Or(left, right) => Expr::Or(map(left)?, map(right)?),
+        Expr::Not(inner) => Expr::Not(map(inner)?),
+        Expr::InSubquery { left, query } => Expr::InSubquery {
+            left: map(left)?,
+            query,
+        },
+        // A window's aggregate is worked out by the window.
+        expr @ (Expr::Column(_)
+        | Expr::Literal(_)
+        | Expr::OuterColumn { .. }
+        | Expr::Parameter(_)
+        | Expr::Exists(_)
+        | Expr::Window { .. }) => expr,
+    })
+}
@@ -0,0 +1,200 @@
+use crate::database::Database;
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// How far `Backup` got, as returned by `finish`.
+#[derive(Debug, Default)]
+pub struct BackupStats {
+    /// Pages in the copy.
+    pub pages: u32,
+    /// Pages written to the destination, counting rewrites.
+    pub pages_written: u64,
+    /// Times the source changed under the copy and the pages already copied
+    /// had to be checked again.
+    pub restarts: u32,
+}
+
+/// A page-by-page copy of a database into a standalone file, like
+/// `sqlite3_backup`. Each `step` copies a few pages under the latest
+/// committed state of the source. Other connections may keep writing in the
+/// meantime: when a step finds that the source has changed, every page
+/// already copied is read again and rewritten if it differs, and the backup
+/// only completes once a whole pass has gone by without a change, so the
+/// copy is one consistent state of the source.
+///
+/// A copy of a WAL-mode database is switched to rollback-journal mode, so it
+/// opens without a `-wal` file next to it.
+pub struct Backup<'a> {
+    source: &'a mut Database,
+    file: File,
+    page_size: usize,
+    page_count: u32,
+    /// Pages below this have been written once.
+    copied: u32,
+    /// Pages below this still have to be checked after a change; checking
+    /// starts at `verify_next`.
+    verify_upto: u32,
+    verify_next: u32,
+    stats: BackupStats,
+}
+
+impl<'a> Backup<'a> {
+    /// Starts a backup of `source` into `destination`, which is created or
+    /// overwritten. Nothing is copied until `step`.
+    pub fn new(source: &'a mut Database, destination: &str) -> Result<Self> {
+        source.check_writable(destination)?;
+        if source.is_database_file(destination) {
+            bail!("Can't back up '{}' onto itself", destination);
+        }
+        for suffix in ["-journal", "-wal"] {
+            let companion = format!("{}{}", destination, suffix);
+            if Path::new(&companion).exists() {
+                bail!(
+                    "'{}' exists: remove it first, or SQLite would apply it to the backup",
+                    companion
+                );
+            }
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(destination)
+            .with_context(|| format!("Failed to create '{}'", destination))?;
+
+        source.begin_statement()?;
+        let (page_size, page_count) = (source.page_size(), source.page_count());
+        Ok(Backup {
+            source,
+            file,
+            page_size,
+            page_count,
+            copied: 1,
+            verify_upto: 1,
+            verify_next: 1,
+            stats: BackupStats::default(),
+        })
+    }
+
+    /// Copies up to `pages` pages, or all that are left if negative. Returns
+    /// whether the backup is complete.
+    pub fn step(&mut self, pages: i64) -> Result<bool> {
+        if self.source.refresh()? {
+            self.restart()?;
+        }
+        let mut budget = if pages < 0 { u64::MAX } else { pages as u64 };
+        while budget > 0 {
+            let page_number = if self.verify_next < self.verify_upto {
+                self.verify_next += 1;
+                self.verify_next - 1
+            } else if self.copied <= self.page_count {
+                self.copied += 1;
+                self.copied - 1
+            } else {
+                break;
+            };
+            self.copy_page(page_number)?;
+            budget -= 1;
+        }
+        if self.remaining() > 0 {
+            return Ok(false);
+        }
+
+        // Pages copied before the last change were checked against it;
+        // anything since means another pass.
+        if self.source.refresh()? {
+            self.restart()?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Pages still to be copied or checked in the current pass.
+    pub fn remaining(&self) -> u32 {
+        (self.verify_upto - self.verify_next) + (self.page_count + 1 - self.copied)
+    }
+
+    /// Pages in the source, as of the last step.
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    /// Completes the backup, copying whatever is left, and flushes the copy
+    /// to disk.
+    pub fn finish(mut self) -> Result<BackupStats> {
+        while !self.step(-1)? {}
+        self.file
+            .set_len(self.page_count as u64 * self.page_size as u64)?;
+        self.file.sync_all()?;
+        self.stats.pages = self.page_count;
+        Ok(self.stats)
+    }
+
+    /// The source changed: every page copied so far has to be checked again,
+    /// against a page count and page size that may be different now.
+    fn restart(&mut self) -> Result<()> {
+        if self.source.page_size() != self.page_size {
+            // A VACUUM changed the page size: nothing copied is any use.
+            self.page_size = self.source.page_size();
+            self.file.set_len(0)?;
+            self.copied = 1;
+        }
+        self.page_count = self.source.page_count();
+        self.copied = self.copied.min(self.page_count + 1);
+        self.verify_upto = self.copied;
+        self.verify_next = 1;
+        self.stats.restarts += 1;
+        Ok(())
+    }
+
+    /// Writes page `page_number` of the source to the copy, unless the copy
+    /// already holds the same bytes.
+    fn copy_page(&mut self, page_number: u32) -> Result<()> {
+        let mut page = self.source.read_page(page_number)?;
+        if page_number == 1 {
+            standalone_header(&mut page, self.page_count);
+        }
+
+        let offset = (page_number as u64 - 1) * self.page_size as u64;
+        if page_number < self.verify_upto {
+            let mut copied = vec![0; self.page_size];
+            self.file.seek(SeekFrom::Start(offset))?;
+            if self.file.read_exact(&mut copied).is_ok() && copied == page {
+                return Ok(());
+            }
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&page)?;
+        self.stats.pages_written += 1;
+        Ok(())
+    }
+}
+
+/// Fixes up the header on page 1 for a file that stands alone: the page
+/// count is the one of the copied state (a WAL-mode header may lag behind
+/// the log), and a WAL-mode database becomes a rollback-journal one.
+fn standalone_header(page: &mut [u8], page_count: u32) {
+    // File format write and read versions: 2 is WAL, 1 rollback journal.
+    for version in &mut page[18..20] {
+        if *version == 2 {
+            *version = 1;
+        }
+    }
+    page[28..32].copy_from_slice(&page_count.to_be_bytes());
+    // The page count is only trusted while the change counter matches the
+    // "version valid for" number.
+    let change_counter = [page[24], page[25], page[26], page[27]];
+    page[92..96].copy_from_slice(&change_counter);
+}
+
+/// Copies all of `source` into `destination` in one go; see `Backup`.
+pub fn backup(source: &mut Database, destination: &str) -> Result<BackupStats> {
+    let result = Backup::new(source, destination)?.finish();
+    if result.is_err() {
+        let _ = std::fs::remove_file(destination);
+    }
+    result
+}
@@ -0,0 +1,107 @@
+use crate::database::Database;
+use crate::query::prepare;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheMode {
+    /// Every run starts with an empty page cache and no cached schema.
+    Cold,
+    /// Runs reuse whatever the previous one left in the page cache, after one
+    /// untimed warm-up run.
+    Warm,
+}
+
+/// One timed execution of the benchmarked statement.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchRun {
+    pub elapsed: Duration,
+    pub pages_read: u64,
+    pub pages_fetched: u64,
+    pub rows: u64,
+}
+
+#[derive(Debug)]
+pub struct BenchReport {
+    pub mode: CacheMode,
+    pub runs: Vec<BenchRun>,
+}
+
+/// Runs `sql` to completion `iterations` times, discarding the rows, and
+/// records the latency and work of each run. The operating system's file
+/// cache is left alone, so "cold" only means cold for sequel's own caches.
+pub fn bench(
+    db: &mut Database,
+    sql: &str,
+    iterations: usize,
+    mode: CacheMode,
+) -> Result<BenchReport> {
+    if iterations == 0 {
+        bail!("A benchmark needs at least one iteration");
+    }
+    if mode == CacheMode::Warm {
+        run_once(db, sql)?;
+    }
+
+    let mut runs = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        if mode == CacheMode::Cold {
+            db.clear_cache();
+        }
+        runs.push(run_once(db, sql)?);
+    }
+    Ok(BenchReport { mode, runs })
+}
+
+fn run_once(db: &mut Database, sql: &str) -> Result<BenchRun> {
+    let started = Instant::now();
+    db.begin_statement()?;
+    let prepared = prepare(db, sql)?;
+    let mut rows = 0;
+    prepared.run(db, |_| {
+        rows += 1;
+        Ok(())
+    })?;
+    Ok(BenchRun {
+        elapsed: started.elapsed(),
+        pages_read: db.pages_read(),
+        pages_fetched: db.pages_fetched(),
+        rows,
+    })
+}
+
+impl BenchReport {
+    /// The nearest-rank percentile of the run latencies, `fraction` in 0..=1.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let mut latencies: Vec<Duration> = self.runs.iter().map(|run| run.elapsed).collect();
+        latencies.sort();
+        let rank = (fraction * latencies.len() as f64).ceil() as usize;
+        latencies[rank.clamp(1, latencies.len()) - 1]
+    }
+
+    fn mean(&self, value: impl Fn(&BenchRun) -> u64) -> f64 {
+        self.runs.iter().map(value).sum::<u64>() as f64 / self.runs.len() as f64
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{:<4}  {} runs  min {:.3} ms  median {:.3} ms  p95 {:.3} ms  pages/run {:.1} ({:.1} fetched)  rows/run {:.1}",
+            match self.mode {
+                CacheMode::Cold => "cold",
+                CacheMode::Warm => "warm",
+            },
+            self.runs.len(),
+            millis(self.percentile(0.0)),
+            millis(self.percentile(0.5)),
+            millis(self.percentile(0.95)),
+            self.mean(|run| run.pages_read),
+            self.mean(|run| run.pages_fetched),
+            self.mean(|run| run.rows),
+        )
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::Result;
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Result too large: the statement needs more than {limit} bytes of memory (raise --max-memory)"
+)]
+pub struct ResultTooLarge {
+    pub limit: usize,
+}
+
+/// Tracks the bytes one statement step has buffered against the connection's
+/// memory limit. Only blocking steps, which can't emit anything until they have
+/// seen all of their input, need to charge it.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        MemoryBudget { limit, used: 0 }
+    }
+
+    pub fn charge(&mut self, bytes: usize) -> Result<()> {
+        self.used = self.used.saturating_add(bytes);
+        match self.limit {
+            Some(limit) if self.used > limit => Err(ResultTooLarge { limit }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Gives back bytes charged for rows that have since been dropped.
+    pub fn release(&mut self, bytes: usize) {
+        self.used = self.used.saturating_sub(bytes);
+    }
+}
@@ -0,0 +1,46 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+/// A collation registered from Rust, comparing two text values.
+pub type CollationFunction = Rc<dyn Fn(&str, &str) -> Ordering>;
+
+/// How two text values compare: SQLite's built-in BINARY, NOCASE and RTRIM,
+/// or one registered on the connection. Other types ignore collations.
+#[derive(Clone, Default)]
+pub enum Collation {
+    #[default]
+    Binary,
+    /// ASCII letters compare without case, like SQLite's NOCASE.
+    NoCase,
+    /// Trailing spaces are ignored.
+    RTrim,
+    User(CollationFunction),
+}
+
+impl Collation {
+    /// The built-in collation called `name` (any case).
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "BINARY" => Some(Collation::Binary),
+            "NOCASE" => Some(Collation::NoCase),
+            "RTRIM" => Some(Collation::RTrim),
+            _ => None,
+        }
+    }
+
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Binary => a.as_bytes().cmp(b.as_bytes()),
+            Collation::NoCase => a
+                .bytes()
+                .map(|byte| byte.to_ascii_lowercase())
+                .cmp(b.bytes().map(|byte| byte.to_ascii_lowercase())),
+            Collation::RTrim => a.trim_end_matches(' ').cmp(b.trim_end_matches(' ')),
+            Collation::User(compare) => compare(a, b),
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Collation::Binary)
+    }
+}
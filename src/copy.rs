@@ -0,0 +1,349 @@
+use crate::budget::MemoryBudget;
+use crate::collation::Collation;
+use crate::database::{Database, SchemaEntry};
+use crate::eval::{total_order, total_order_collated};
+use crate::query::{
+    find_table, index_columns, index_descending, index_key_collation, ColumnDefaults,
+};
+use crate::record::{encode_record, encode_record_in, TextEncoding, Value};
+use crate::schema::{parse_create_index, parse_create_table, ColumnDef, TableDef};
+use crate::temp::TempTable;
+use crate::writer::{DatabaseWriter, TableBuilder};
+use anyhow::{bail, Context, Result};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+use std::path::Path;
+
+/// What `copy_table` wrote.
+#[derive(Debug, Default)]
+pub struct CopyStats {
+    pub rows: u64,
+    pub indexes: Vec<String>,
+    /// Indexes left out because their keys aren't plain columns or they
+    /// only cover some rows.
+    pub skipped_indexes: Vec<String>,
+}
+
+/// Where an index key column comes from in a table row.
+enum KeySource {
+    Rowid,
+    Column(usize),
+}
+
+/// Collects the entries of one index of a table as its rows go by, then sorts
+/// them and writes the index B-tree.
+pub(crate) struct IndexBuilder {
+    entry: SchemaEntry,
+    keys: Vec<KeySource>,
+    /// How each key sorts: by its collation, and reversed if `DESC`.
+    order: Vec<(Collation, bool)>,
+    /// Whether two rows may not have the same key: for PRIMARY KEY and
+    /// UNIQUE constraints, and `CREATE UNIQUE INDEX`.
+    unique: bool,
+    defaults: ColumnDefaults,
+    entries: TempTable,
+}
+
+impl IndexBuilder {
+    /// Returns `None` for indexes whose entries can't be derived from the
+    /// columns alone: partial indexes and indexes on expressions. Keys can
+    /// only be collated by the built-in BINARY, NOCASE and RTRIM.
+    pub(crate) fn new(entry: &SchemaEntry, table_def: &TableDef) -> Result<Option<Self>> {
+        let Some(columns) = index_columns(entry, table_def) else {
+            return Ok(None);
+        };
+        let descending = index_descending(entry);
+        let order = columns
+            .iter()
+            .enumerate()
+            .map(|(position, column)| {
+                let name = index_key_collation(entry, table_def, position, column);
+                let Some(collation) = Collation::builtin(&name) else {
+                    bail!(
+                        "Can't build index '{}': collation {} isn't built in",
+                        entry.name,
+                        name
+                    );
+                };
+                Ok((
+                    collation,
+                    descending.get(position).copied().unwrap_or(false),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let unique = match &entry.sql {
+            Some(sql) => parse_create_index(sql)?.unique,
+            None => true,
+        };
+
+        let keys = columns
+            .iter()
+            .map(|column| {
+                if table_def
+                    .rowid_alias
+                    .as_ref()
+                    .is_some_and(|alias| alias.eq_ignore_ascii_case(column))
+                {
+                    return Some(KeySource::Rowid);
+                }
+                table_def
+                    .columns
+                    .iter()
+                    .position(|c| c.name.eq_ignore_ascii_case(column))
+                    .map(KeySource::Column)
+            })
+            .collect::<Option<Vec<_>>>();
+        Ok(keys.map(|keys| IndexBuilder {
+            entry: entry.clone(),
+            entries: TempTable::new(vec![ColumnDef::new("key", ""); keys.len() + 1]),
+            keys,
+            order,
+            unique,
+            defaults: ColumnDefaults::new(&table_def.columns),
+        }))
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.entry.name
+    }
+
+    /// Adds the entry for the row `rowid`, whose stored values are `record`.
+    pub(crate) fn add(
+        &mut self,
+        rowid: i64,
+        record: &[Value],
+        budget: &mut MemoryBudget,
+    ) -> Result<()> {
+        let mut key: Vec<Value> = self
+            .keys
+            .iter()
+            .map(|source| match source {
+                KeySource::Rowid => Value::Int(rowid),
+                KeySource::Column(i) => self.defaults.column(record, *i).clone(),
+            })
+            .collect();
+        key.push(Value::Int(rowid));
+        self.entries.push(key, budget)
+    }
+
+    /// Writes the index and returns its schema entry, pointing at the new root.
+    pub(crate) fn finish<W: Write + Seek>(
+        mut self,
+        writer: &mut DatabaseWriter<W>,
+        encoding: TextEncoding,
+    ) -> Result<SchemaEntry> {
+        // Entries sort by their keys, then by rowid.
+        let order = &self.order;
+        let compare_keys = |a: &[Value], b: &[Value]| {
+            a.iter()
+                .zip(b)
+                .zip(order)
+                .map(|((a, b), (collation, descending))| {
+                    let ordering = total_order_collated(a, b, collation);
+                    if *descending {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        };
+        self.entries.sort_by(|a, b| {
+            compare_keys(a, b).then_with(|| total_order(&a[a.len() - 1], &b[b.len() - 1]))
+        });
+        // NULLs are never equal, so any number of rows may have them.
+        if self.unique {
+            if let Some(pair) = self.entries.rows().windows(2).find(|pair| {
+                let key = &pair[0][..order.len()];
+                !key.iter().any(|value| matches!(value, Value::Null))
+                    && compare_keys(key, &pair[1]).is_eq()
+            }) {
+                bail!(
+                    "UNIQUE constraint failed: index '{}' has key {:?} twice",
+                    self.entry.name,
+                    &pair[0][..order.len()]
+                );
+            }
+        }
+        let records: Vec<Vec<u8>> = self
+            .entries
+            .rows()
+            .iter()
+            .map(|key| encode_record_in(key, encoding))
+            .collect();
+        Ok(SchemaEntry {
+            rootpage: writer.write_index(&records)?,
+            ..self.entry
+        })
+    }
+}
+
+/// Creates the database file `destination` with `table` from `source`: its
+/// `CREATE TABLE`, every row with its rowid, and the automatic indexes SQLite
+/// keeps for PRIMARY KEY and UNIQUE constraints. With `with_indexes` the
+/// table's `CREATE INDEX` indexes are rebuilt too. Rows are streamed; only
+/// index entries are held in memory, to be sorted, and they count against
+/// the memory limit.
+///
+/// Only new files are written: copying into an existing database needs a
+/// write path that can update a B-tree in place, which sequel doesn't have.
+pub fn copy_table(
+    source: &mut Database,
+    destination: &str,
+    table: &str,
+    with_indexes: bool,
+) -> Result<CopyStats> {
+    source.check_writable(destination)?;
+    if Path::new(destination)
+        .metadata()
+        .is_ok_and(|metadata| metadata.len() > 0)
+    {
+        bail!(
+            "'{}' already exists: copy only creates new databases, it can't add to an existing one yet",
+            destination
+        );
+    }
+    let file =
+        File::create(destination).with_context(|| format!("Failed to create '{}'", destination))?;
+
+    let result = write_copy(source, file, table, with_indexes);
+    if result.is_err() {
+        let _ = std::fs::remove_file(destination);
+    }
+    result
+}
+
+fn write_copy(
+    source: &mut Database,
+    file: File,
+    table: &str,
+    with_indexes: bool,
+) -> Result<CopyStats> {
+    let schema = source.read_schema()?;
+    let table_entry = find_table(&schema, table)?.clone();
+    let table_sql = table_entry
+        .sql
+        .as_ref()
+        .with_context(|| format!("No SQL definition found for table '{}'", table))?;
+    let table_def = parse_create_table(table_sql)?;
+    if table_def.without_rowid {
+        bail!("Copying WITHOUT ROWID tables is not supported");
+    }
+
+    let mut stats = CopyStats::default();
+    let mut indexes = Vec::new();
+    for entry in schema
+        .iter()
+        .filter(|e| e.typ == "index" && e.tbl_name == table_entry.tbl_name)
+    {
+        let automatic = entry.sql.is_none();
+        if !automatic && !with_indexes {
+            continue;
+        }
+        match IndexBuilder::new(entry, &table_def)? {
+            Some(index) => indexes.push(index),
+            None if automatic => bail!(
+                "Can't work out the columns of automatic index '{}'",
+                entry.name
+            ),
+            None => stats.skipped_indexes.push(entry.name.clone()),
+        }
+    }
+
+    let mut writer = DatabaseWriter::new(BufWriter::new(file), source.page_size())?;
+    let mut rows = TableBuilder::new();
+    let mut budget = source.memory_budget();
+    source.for_each_table_record(table_entry.rootpage, |mut record| {
+        let Value::Int(rowid) = record.remove(0) else {
+            bail!("Table record without a rowid");
+        };
+        rows.insert(&mut writer, rowid, &encode_record(&record))?;
+        stats.rows += 1;
+
+        for index in &mut indexes {
+            index.add(rowid, &record, &mut budget)?;
+        }
+        Ok(())
+    })?;
+
+    let mut new_schema = vec![SchemaEntry {
+        rootpage: rows.finish(&mut writer)?,
+        ..table_entry
+    }];
+    for index in indexes {
+        stats.indexes.push(index.name().to_string());
+        new_schema.push(index.finish(&mut writer, TextEncoding::Utf8)?);
+    }
+
+    writer.finish(&new_schema)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::Value;
+    use crate::testing::{int, query, text, TestDatabase};
+
+    #[test]
+    fn unique_keys_must_differ() {
+        let build = |table: &str, index: Option<&str>, rows: [[i64; 2]; 3]| {
+            let rows = rows.map(|row| row.map(int).to_vec());
+            let mut database = TestDatabase::new().table(table, rows);
+            if let Some(index) = index {
+                database = database.index(index);
+            }
+            database.build()
+        };
+        let twice = [[1, 5], [2, 6], [3, 5]];
+        assert!(build("CREATE TABLE t (a, b PRIMARY KEY)", None, twice).is_err());
+        assert!(build("CREATE TABLE t (a, b UNIQUE)", None, twice).is_err());
+        assert!(build(
+            "CREATE TABLE t (a, b)",
+            Some("CREATE UNIQUE INDEX t_b ON t (b)"),
+            twice
+        )
+        .is_err());
+        assert!(build(
+            "CREATE TABLE t (a, b)",
+            Some("CREATE INDEX t_b ON t (b)"),
+            twice
+        )
+        .is_ok());
+        assert!(build("CREATE TABLE t (a, b, UNIQUE (a, b))", None, twice).is_ok());
+        // NULLs are all different.
+        let image = TestDatabase::new()
+            .table(
+                "CREATE TABLE t (a UNIQUE)",
+                [vec![Value::Null], vec![Value::Null]],
+            )
+            .build();
+        assert!(image.is_ok());
+    }
+
+    #[test]
+    fn builds_descending_and_collated_indexes() {
+        let image = TestDatabase::new()
+            .page_size(512)
+            .table(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT COLLATE NOCASE, n)",
+                (1..=200).map(|i| {
+                    let name = if i % 2 == 0 { "Name" } else { "name" };
+                    vec![int(i), text(&format!("{} {}", name, i % 50)), int(i % 7)]
+                }),
+            )
+            .index("CREATE INDEX t_name ON t (name)")
+            .index("CREATE INDEX t_n ON t (n DESC, name COLLATE RTRIM)")
+            .build()
+            .unwrap();
+        let ids = |sql: &str| query(&image, sql).unwrap().len();
+        assert_eq!(ids("SELECT id FROM t WHERE name = 'NAME 7'"), 4);
+        assert_eq!(ids("SELECT id FROM t WHERE n = 3"), 29);
+        assert!(TestDatabase::new()
+            .table("CREATE TABLE t (a)", [vec![text("x")]])
+            .index("CREATE INDEX t_a ON t (a COLLATE mine)")
+            .build()
+            .is_err());
+    }
+}
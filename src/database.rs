@@ -1,11 +1,95 @@
-use crate::record::{parse_record, read_varint, Value};
+use crate::budget::MemoryBudget;
+use crate::error::corrupt;
+use crate::eval::total_order;
+use crate::functions::{Aggregate, AggregateFactory, UserFunctions};
+use crate::interrupt::{Aborted, Interrupt};
+use crate::query::find_table;
+use crate::record::{parse_record, read_varint, DecodeOptions, RecordDecoder, TextEncoding, Value};
+use crate::schema::{parse_create_table, TableDef};
+use crate::temp::TempTable;
+use crate::virtual_table::VirtualTable;
+use crate::wal::{wal_checksum, WalSnapshot};
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
 use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
     fs::File,
     io::{Read, Seek, SeekFrom},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::mpsc::{sync_channel, Receiver},
+    time::{Duration, Instant},
 };
 
+/// `path` with its directory made absolute and symlinks in it resolved, so
+/// two spellings of the same file compare equal even before it exists.
+fn resolve_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.to_path_buf();
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    match parent.canonicalize() {
+        Ok(parent) => parent.join(name),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Converts a payload size varint to a length, refusing sizes that cannot be
+/// addressed on this target instead of silently truncating them.
+fn payload_len(payload_size: u64) -> Result<usize> {
+    usize::try_from(payload_size).with_context(|| {
+        format!(
+            "Payload size {} is too large for this platform",
+            payload_size
+        )
+    })
+}
+
+/// The cell a cell pointer points at: the rest of its page. A corrupt
+/// pointer past the end of the page is reported rather than followed.
+fn cell_at(page_data: &[u8], cell_offset: usize) -> Result<&[u8]> {
+    match page_data.get(cell_offset..) {
+        Some(cell_data) if !cell_data.is_empty() => Ok(cell_data),
+        _ => corrupt!("Cell offset {} out of bounds", cell_offset),
+    }
+}
+
+/// The most payload bytes a table leaf cell keeps on its page.
+fn table_max_local(usable_size: usize) -> usize {
+    usable_size - 35
+}
+
+/// The most payload bytes an index cell, leaf or interior, keeps on its
+/// page: less than a table's, so at least four entries fit on every page.
+fn index_max_local(usable_size: usize) -> usize {
+    (usable_size - 12) * 64 / 255 - 23
+}
+
+/// How many bytes of a cell's `payload_len`-byte payload are kept in the
+/// cell itself, on pages with `usable_size` bytes for content. Up to
+/// `max_local` bytes fit; a larger payload keeps as much of its tail as
+/// fills whole overflow pages, but never less than the minimum, which is
+/// the same for every kind of cell.
+fn local_payload_len(payload_len: usize, usable_size: usize, max_local: usize) -> usize {
+    let min_local = (usable_size - 12) * 32 / 255 - 23;
+    if payload_len <= max_local {
+        return payload_len;
+    }
+    let local = min_local + (payload_len - min_local) % (usable_size - 4);
+    if local <= max_local {
+        local
+    } else {
+        min_local
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum BTreePageType {
     InteriorIndex,
@@ -21,7 +105,7 @@ impl BTreePageType {
             0x05 => Ok(BTreePageType::InteriorTable),
             0x0a => Ok(BTreePageType::LeafIndex),
             0x0d => Ok(BTreePageType::LeafTable),
-            _ => bail!("Invalid B-tree page type: {}", byte),
+            _ => corrupt!("Invalid B-tree page type: {}", byte),
         }
     }
 }
@@ -40,7 +124,7 @@ pub struct BTreePageHeader {
 impl BTreePageHeader {
     pub fn parse(data: &[u8], _is_page_one: bool) -> Result<Self> {
         if data.len() < 8 {
-            bail!("Page data too short to parse header");
+            corrupt!("Page data too short to parse header");
         }
 
         let page_type = BTreePageType::from_byte(data[0])?;
@@ -49,7 +133,7 @@ impl BTreePageHeader {
             BTreePageType::InteriorIndex | BTreePageType::InteriorTable
         );
         if is_interior && data.len() < 12 {
-            bail!("Interior page data too short to parse header");
+            corrupt!("Interior page data too short to parse header");
         }
 
         let first_freeblock = u16::from_be_bytes([data[1], data[2]]);
@@ -81,53 +165,66 @@ impl BTreePageHeader {
 #[derive(Debug)]
 pub struct TableBTreeLeafCell {
     pub payload_size: u64,
-    pub rowid: u64,
+    pub rowid: i64,
     pub payload: Bytes,
     pub overflow_page: Option<u32>,
 }
 
 impl TableBTreeLeafCell {
-    pub fn parse(data: &[u8]) -> Result<(Self, usize)> {
+    /// Parses a cell of a page with `usable_size` bytes for content. The
+    /// payload is only the part in the cell; see `TableLeafCellRef`.
+    pub fn parse(data: &[u8], usable_size: usize) -> Result<(Self, usize)> {
+        let (cell, size) = TableLeafCellRef::parse(data, usable_size)?;
+        Ok((
+            TableBTreeLeafCell {
+                payload_size: cell.payload_size,
+                rowid: cell.rowid,
+                payload: Bytes::copy_from_slice(cell.payload),
+                overflow_page: cell.overflow_page,
+            },
+            size,
+        ))
+    }
+}
+
+/// A table leaf cell read in place, its payload borrowed from the page, so a
+/// scan can decode the record without copying it out first.
+#[derive(Debug)]
+pub struct TableLeafCellRef<'a> {
+    /// The size of the whole payload.
+    pub payload_size: u64,
+    pub rowid: i64,
+    /// The part of the payload stored in the cell: all of it, unless
+    /// `overflow_page` is set.
+    pub payload: &'a [u8],
+    /// The first page of the chain holding the rest of the payload.
+    pub overflow_page: Option<u32>,
+}
+
+impl<'a> TableLeafCellRef<'a> {
+    /// Parses a cell of a page with `usable_size` bytes for content, which
+    /// decides how much of a large payload is in the cell.
+    pub fn parse(data: &'a [u8], usable_size: usize) -> Result<(Self, usize)> {
         let mut offset = 0;
 
         let (payload_size, rest, bytes_read) =
             read_varint(data).context("Failed to read payload size varint")?;
+        let payload_len = payload_len(payload_size)?;
         offset += bytes_read;
 
         let (rowid, rest, bytes_read) = read_varint(rest).context("Failed to read rowid varint")?;
+        // Rowids are signed 64-bit integers stored as two's-complement varints.
+        let rowid = rowid as i64;
         offset += bytes_read;
 
-        if rest.len() < payload_size as usize {
-            bail!(
-                "Not enough data for payload: expected {} bytes, got {}",
-                payload_size,
-                rest.len()
-            );
-        }
-        let payload = Bytes::from(rest[..payload_size as usize].to_vec());
-        offset += payload_size as usize;
-
-        let overflow_page = if rest.len() >= payload_size as usize + 4 {
-            let overflow_value = u32::from_be_bytes([
-                rest[payload_size as usize],
-                rest[payload_size as usize + 1],
-                rest[payload_size as usize + 2],
-                rest[payload_size as usize + 3],
-            ]);
-            if overflow_value != 0 {
-                Some(overflow_value)
-            } else {
-                None
-            }
-        } else {
-            None
-        };
-        if overflow_page.is_some() {
-            offset += 4;
-        }
+        let max_local = table_max_local(usable_size);
+        let (payload, overflow_page, cell_len) =
+            split_payload(rest, payload_len, usable_size, max_local)
+                .context("Not enough data for table leaf cell payload")?;
+        offset += cell_len;
 
         Ok((
-            TableBTreeLeafCell {
+            TableLeafCellRef {
                 payload_size,
                 rowid,
                 payload,
@@ -138,17 +235,48 @@ impl TableBTreeLeafCell {
     }
 }
 
+/// Splits the cell content `rest`, which starts with a payload of
+/// `payload_len` bytes, into the part of the payload in the cell and the
+/// first overflow page, which follows it if the rest spills. Also returns
+/// how many bytes of `rest` the two take.
+fn split_payload(
+    rest: &[u8],
+    payload_len: usize,
+    usable_size: usize,
+    max_local: usize,
+) -> Result<(&[u8], Option<u32>, usize)> {
+    let local_len = local_payload_len(payload_len, usable_size, max_local);
+    let spills = local_len < payload_len;
+    let cell_len = local_len + if spills { 4 } else { 0 };
+    if rest.len() < cell_len {
+        corrupt!(
+            "Expected {} bytes in the cell, got {}",
+            cell_len,
+            rest.len()
+        );
+    }
+    let overflow_page = spills.then(|| {
+        u32::from_be_bytes([
+            rest[local_len],
+            rest[local_len + 1],
+            rest[local_len + 2],
+            rest[local_len + 3],
+        ])
+    });
+    Ok((&rest[..local_len], overflow_page, cell_len))
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct TableBTreeInteriorCell {
     pub left_child_page: u32,
-    pub rowid: u64,
+    pub rowid: i64,
 }
 
 impl TableBTreeInteriorCell {
     pub fn parse(data: &[u8]) -> Result<(Self, usize)> {
         if data.len() < 4 {
-            bail!("Not enough data for interior cell left child pointer");
+            corrupt!("Not enough data for interior cell left child pointer");
         }
 
         let left_child_page = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
@@ -158,7 +286,7 @@ impl TableBTreeInteriorCell {
         Ok((
             TableBTreeInteriorCell {
                 left_child_page,
-                rowid,
+                rowid: rowid as i64,
             },
             4 + bytes_read,
         ))
@@ -168,32 +296,37 @@ impl TableBTreeInteriorCell {
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct IndexBTreeLeafCell {
+    /// The size of the whole payload.
     pub payload_size: u64,
+    /// The part of the payload stored in the cell: all of it, unless
+    /// `overflow_page` is set.
     pub payload: Bytes,
+    /// The first page of the chain holding the rest of the payload.
+    pub overflow_page: Option<u32>,
 }
 
 impl IndexBTreeLeafCell {
-    pub fn parse(data: &[u8]) -> Result<(Self, usize)> {
+    /// Parses a cell of a page with `usable_size` bytes for content, which
+    /// decides how much of a large payload is in the cell.
+    pub fn parse(data: &[u8], usable_size: usize) -> Result<(Self, usize)> {
         let mut offset = 0;
 
         let (payload_size, rest, bytes_read) =
             read_varint(data).context("Failed to read index leaf cell payload size varint")?;
+        let payload_len = payload_len(payload_size)?;
         offset += bytes_read;
 
-        if rest.len() < payload_size as usize {
-            bail!(
-                "Not enough data for index leaf cell payload: expected {} bytes, got {}",
-                payload_size,
-                rest.len()
-            );
-        }
-        let payload = Bytes::from(rest[..payload_size as usize].to_vec());
-        offset += payload_size as usize;
+        let max_local = index_max_local(usable_size);
+        let (payload, overflow_page, cell_len) =
+            split_payload(rest, payload_len, usable_size, max_local)
+                .context("Not enough data for index leaf cell payload")?;
+        offset += cell_len;
 
         Ok((
             IndexBTreeLeafCell {
                 payload_size,
-                payload,
+                payload: Bytes::copy_from_slice(payload),
+                overflow_page,
             },
             offset,
         ))
@@ -204,143 +337,1128 @@ impl IndexBTreeLeafCell {
 #[derive(Debug)]
 pub struct IndexBTreeInteriorCell {
     pub left_child_page: u32,
+    /// The size of the whole payload.
     pub payload_size: u64,
+    /// The part of the payload stored in the cell, as in `IndexBTreeLeafCell`.
     pub payload: Bytes,
+    pub overflow_page: Option<u32>,
 }
 
 impl IndexBTreeInteriorCell {
-    pub fn parse(data: &[u8]) -> Result<(Self, usize)> {
+    /// Parses a cell of a page with `usable_size` bytes for content.
+    pub fn parse(data: &[u8], usable_size: usize) -> Result<(Self, usize)> {
         let mut offset = 0;
 
         if data.len() < 4 {
-            bail!("Not enough data for index interior cell left child pointer");
+            corrupt!("Not enough data for index interior cell left child pointer");
         }
         let left_child_page = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
         offset += 4;
 
         let (payload_size, rest, bytes_read) = read_varint(&data[offset..])
             .context("Failed to read index interior cell payload size varint")?;
+        let payload_len = payload_len(payload_size)?;
         offset += bytes_read;
 
-        if rest.len() < payload_size as usize {
-            bail!(
-                "Not enough data for index interior cell payload: expected {} bytes, got {}",
-                payload_size,
-                rest.len()
-            );
-        }
-        let payload = Bytes::from(rest[..payload_size as usize].to_vec());
-        offset += payload_size as usize;
+        let max_local = index_max_local(usable_size);
+        let (payload, overflow_page, cell_len) =
+            split_payload(rest, payload_len, usable_size, max_local)
+                .context("Not enough data for index interior cell payload")?;
+        offset += cell_len;
 
         Ok((
             IndexBTreeInteriorCell {
                 left_child_page,
                 payload_size,
-                payload,
+                payload: Bytes::copy_from_slice(payload),
+                overflow_page,
             },
             offset,
         ))
     }
 }
 
+pub(crate) const DATABASE_HEADER_SIZE: usize = 100;
+pub(crate) const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+const DEFAULT_PAGE_SIZE: usize = 4096;
+/// Page numbers are 32-bit and 0xFFFFFFFF is never used.
+const MAX_PAGE_COUNT: u32 = 0xFFFF_FFFE;
+/// Databases written through SQLite's cksumvfs reserve this many bytes at the
+/// end of every page for a checksum of the rest of the page.
+const CKSUMVFS_RESERVED_BYTES: u8 = 8;
+
+#[derive(Debug, Clone)]
 pub struct SchemaEntry {
     pub typ: String,
+    pub name: String,
     pub tbl_name: String,
     pub rootpage: u32,
     pub sql: Option<String>,
 }
 
+/// The parts of the 100-byte database header the reader depends on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileHeader {
+    page_size: usize,
+    page_count: u32,
+    change_counter: u32,
+    schema_cookie: u32,
+    reserved_bytes: u8,
+    text_encoding: TextEncoding,
+}
+
+impl FileHeader {
+    /// SQLite treats a zero-length file as a valid database that simply has
+    /// no pages yet (e.g. one created by `sqlite3 new.db` and never written).
+    fn empty() -> Self {
+        FileHeader {
+            page_size: DEFAULT_PAGE_SIZE,
+            page_count: 0,
+            change_counter: 0,
+            schema_cookie: 0,
+            reserved_bytes: 0,
+            text_encoding: TextEncoding::Utf8,
+        }
+    }
+
+    fn read(storage: &mut Storage, path: &str) -> Result<Self> {
+        let file_size = storage.len()?;
+
+        if file_size == 0 {
+            return Ok(FileHeader::empty());
+        }
+
+        if file_size < DATABASE_HEADER_SIZE as u64 {
+            corrupt!(
+                "File is not a SQLite database: '{}' is only {} bytes, too small to hold the {}-byte header",
+                path,
+                file_size,
+                DATABASE_HEADER_SIZE
+            );
+        }
+
+        let mut header = [0; DATABASE_HEADER_SIZE];
+        storage
+            .read_exact_at(0, &mut header)
+            .context("Failed to read database header")?;
+
+        if &header[..SQLITE_MAGIC.len()] != SQLITE_MAGIC {
+            corrupt!(
+                "File is not a SQLite database: '{}' does not start with the \"SQLite format 3\" header",
+                path
+            );
+        }
+
+        let page_size = match u16::from_be_bytes([header[16], header[17]]) as usize {
+            1 => 65536,
+            size => size,
+        };
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            corrupt!(
+                "Database header is corrupt: invalid page size {} (must be a power of two between 512 and 65536)",
+                page_size
+            );
+        }
+        if file_size < page_size as u64 {
+            corrupt!(
+                "Database file is truncated: {} bytes is smaller than a single {}-byte page",
+                file_size,
+                page_size
+            );
+        }
+
+        let mut file_header = FileHeader {
+            page_size,
+            page_count: (file_size / page_size as u64).min(MAX_PAGE_COUNT as u64) as u32,
+            change_counter: 0,
+            schema_cookie: 0,
+            reserved_bytes: header[20],
+            text_encoding: TextEncoding::Utf8,
+        };
+        file_header.read_counters(&header);
+        Ok(file_header)
+    }
+
+    /// Reads the fields another connection can change while this one has the
+    /// file open: the counters, and the text encoding of a database that had
+    /// no pages yet.
+    fn read_counters(&mut self, header: &[u8]) {
+        self.change_counter = u32::from_be_bytes([header[24], header[25], header[26], header[27]]);
+        self.schema_cookie = u32::from_be_bytes([header[40], header[41], header[42], header[43]]);
+        let encoding = u32::from_be_bytes([header[56], header[57], header[58], header[59]]);
+        // SQLite refuses other values; reading them as UTF-8 at least shows
+        // the ASCII.
+        self.text_encoding = TextEncoding::from_header_value(encoding).unwrap_or_default();
+    }
+}
+
+/// Where the pages come from: the database file, or for `:memory:` an image
+/// of the database held by the connection itself.
+enum Storage {
+    File(File),
+    Memory(Vec<u8>),
+}
+
+impl Storage {
+    fn len(&self) -> Result<u64> {
+        match self {
+            Storage::File(file) => Ok(file
+                .metadata()
+                .context("Failed to read database file metadata")?
+                .len()),
+            Storage::Memory(image) => Ok(image.len() as u64),
+        }
+    }
+
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        match self {
+            Storage::File(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(buf)?;
+            }
+            Storage::Memory(image) => {
+                let page = usize::try_from(offset)
+                    .ok()
+                    .and_then(|start| image.get(start..start.checked_add(buf.len())?))
+                    .context("Read past the end of the in-memory database")?;
+                buf.copy_from_slice(page);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every field of the 100-byte database header, as shown by `.dbinfo`.
+/// See https://www.sqlite.org/fileformat2.html#the_database_header.
+#[derive(Debug, Clone)]
+pub struct DatabaseHeader {
+    pub page_size: usize,
+    pub write_format: u8,
+    pub read_format: u8,
+    pub reserved_bytes: u8,
+    pub change_counter: u32,
+    pub page_count: u32,
+    pub freelist_count: u32,
+    pub schema_cookie: u32,
+    pub schema_format: u32,
+    pub default_cache_size: u32,
+    pub autovacuum_top_root: u32,
+    pub text_encoding: u32,
+    pub user_version: u32,
+    pub incremental_vacuum: u32,
+    pub application_id: u32,
+    pub software_version: u32,
+}
+
+impl DatabaseHeader {
+    fn parse(header: &[u8], page_size: usize) -> Self {
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ])
+        };
+        DatabaseHeader {
+            page_size,
+            write_format: header[18],
+            read_format: header[19],
+            reserved_bytes: header[20],
+            change_counter: u32_at(24),
+            page_count: u32_at(28),
+            freelist_count: u32_at(36),
+            schema_cookie: u32_at(40),
+            schema_format: u32_at(44),
+            default_cache_size: u32_at(48),
+            autovacuum_top_root: u32_at(52),
+            text_encoding: u32_at(56),
+            user_version: u32_at(60),
+            incremental_vacuum: u32_at(64),
+            application_id: u32_at(68),
+            software_version: u32_at(96),
+        }
+    }
+}
+
+/// Recently read pages, so repeated walks over the same B-tree levels don't go
+/// back to the file. Evicts in insertion order; a capacity of 0 disables it.
+#[derive(Default)]
+struct PageCache {
+    capacity: usize,
+    pages: HashMap<u32, Vec<u8>>,
+    order: VecDeque<u32>,
+}
+
+impl PageCache {
+    fn get(&self, page_number: u32) -> Option<Vec<u8>> {
+        self.pages.get(&page_number).cloned()
+    }
+
+    fn insert(&mut self, page_number: u32, page: &[u8]) {
+        if self.capacity == 0 || self.pages.contains_key(&page_number) {
+            return;
+        }
+        while self.pages.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(evicted) => {
+                    self.pages.remove(&evicted);
+                }
+                None => break,
+            }
+        }
+        self.pages.insert(page_number, page.to_vec());
+        self.order.push_back(page_number);
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.clear();
+    }
+
+    fn clear(&mut self) {
+        self.pages.clear();
+        self.order.clear();
+    }
+}
+
+/// How far the running statement has got, as passed to a progress handler.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Pages read since the statement started, revisits included.
+    pub pages_read: u64,
+    /// Pages in the database, an upper bound for a single full scan.
+    pub page_count: u32,
+}
+
+/// An approximate row count, as returned by `Database::estimate_rows`.
+#[derive(Debug, Clone, Copy)]
+pub struct RowEstimate {
+    pub rows: u64,
+    pub source: EstimateSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EstimateSource {
+    /// The table fits on its root page (or is a temp table), so the count is
+    /// exact.
+    Exact,
+    /// Extrapolated from random root-to-leaf descents of the B-tree, which
+    /// read `pages` pages between them.
+    Sampled { pages: u64 },
+    /// The row count `ANALYZE` last stored in `sqlite_stat1`.
+    Stat1,
+}
+
+/// Root-to-leaf descents `estimate_rows` averages over.
+const ESTIMATE_DESCENTS: u32 = 16;
+/// Deeper than any real B-tree gets; a longer path means a page loop.
+const MAX_TREE_DEPTH: u32 = 64;
+
+/// Scans of at least this many leaf pages read ahead on a background thread.
+const PREFETCH_MIN_PAGES: usize = 64;
+/// Pages the read-ahead thread reads per batch. One batch waits while the
+/// scan decodes the one before it, and the thread reads the one after.
+const PREFETCH_BATCH_PAGES: usize = 32;
+
+/// Reads the leaf pages of a table scan ahead of it on a background thread,
+/// so decoding one batch of records overlaps with reading the next. Only the
+/// bytes in the database file come from here: `load_page` still prefers the
+/// WAL and the page cache, and checks every page as usual. The thread stops
+/// at its next batch once this is dropped, and at the first read error,
+/// leaving the rest of the pages to be read (and the error reported) the
+/// normal way.
+struct Prefetch {
+    batches: Receiver<Vec<Vec<u8>>>,
+    batch: std::vec::IntoIter<Vec<u8>>,
+}
+
+impl Prefetch {
+    /// Starts reading `pages` of the database file at `path`, in order, or
+    /// returns `None` if the file can't be opened a second time.
+    fn start(path: &str, page_size: usize, pages: &[u32]) -> Option<Self> {
+        let mut file = File::open(path).ok()?;
+        let pages = pages.to_vec();
+        let (sender, batches) = sync_channel(1);
+        std::thread::spawn(move || {
+            for chunk in pages.chunks(PREFETCH_BATCH_PAGES) {
+                let mut batch = Vec::with_capacity(chunk.len());
+                for &page_number in chunk {
+                    let mut page = vec![0; page_size];
+                    let offset = (page_number as u64 - 1) * page_size as u64;
+                    if file.seek(SeekFrom::Start(offset)).is_err()
+                        || file.read_exact(&mut page).is_err()
+                    {
+                        break;
+                    }
+                    batch.push(page);
+                }
+                let complete = batch.len() == chunk.len();
+                if sender.send(batch).is_err() || !complete {
+                    return;
+                }
+            }
+        });
+        Some(Prefetch {
+            batches,
+            batch: Vec::new().into_iter(),
+        })
+    }
+
+    /// The next page in the order given to `start`, or `None` once the
+    /// thread has given up.
+    fn next_page(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if let Some(page) = self.batch.next() {
+                return Some(page);
+            }
+            self.batch = self.batches.recv().ok()?.into_iter();
+        }
+    }
+}
+
+/// Called every few pages; returning `true` interrupts the statement.
+pub type ProgressHandler = Box<dyn FnMut(&Progress) -> bool>;
+
+/// The path that opens a private, empty database held in memory.
+pub const MEMORY_PATH: &str = ":memory:";
+
 pub struct Database {
-    file: File,
+    storage: Storage,
+    path: String,
     page_size: usize,
+    page_count: u32,
+    header: FileHeader,
+    wal: Option<WalSnapshot>,
+    schema: Option<Vec<SchemaEntry>>,
+    decode_options: DecodeOptions,
+    interrupt: Interrupt,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    memory_limit: Option<usize>,
+    progress_handler: Option<(u64, ProgressHandler)>,
+    pages_read: u64,
+    /// Pages of the running statement that missed the cache.
+    pages_fetched: u64,
+    cache: PageCache,
+    /// `CREATE TEMP TABLE` tables by lowercased name; gone when the database
+    /// is closed.
+    temp_tables: HashMap<String, TempTable>,
+    /// The tables of the running statement's WITH clause, innermost last;
+    /// they shadow every other table of the same name.
+    common_tables: Vec<(String, TempTable)>,
+    /// Tables registered with `create_virtual_table`, by lowercased name,
+    /// with the columns their schema declares.
+    virtual_tables: HashMap<String, (TableDef, Rc<dyn VirtualTable>)>,
+    /// Shared with running statements, which take a copy of the handle.
+    functions: Rc<UserFunctions>,
+    /// The values bound to the running statement's parameters, `?1` first.
+    parameters: Rc<[Value]>,
+    /// Opened with `open_read_only`: nothing is written through it.
+    read_only: bool,
 }
 
 impl Database {
+    /// Opens the database file at `path`, or an empty in-memory database if
+    /// `path` is `:memory:`.
     pub fn open(path: &str) -> Result<Self> {
-        let mut file = File::open(path).context("Failed to open database file")?;
+        if path == MEMORY_PATH {
+            return Self::from_bytes(Vec::new());
+        }
+        let file = File::open(path).context("Failed to open database file")?;
+        Self::with_storage(Storage::File(file), path)
+    }
 
-        let mut header = [0; 100];
-        file.read_exact(&mut header)
-            .context("Failed to read database header")?;
+    /// Opens `path` like `open`, with a promise that nothing done through
+    /// this connection writes any file, so neither the database nor its
+    /// `-journal`, `-wal` and `-shm` files are created or changed, whatever
+    /// write support sequel grows: every write path calls `check_writable`
+    /// first. For forensics and production snapshots.
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        let mut db = Self::open(path)?;
+        db.read_only = true;
+        Ok(db)
+    }
 
-        let page_size = u16::from_be_bytes([header[16], header[17]]) as usize;
+    /// Whether the connection was opened with `open_read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fails if the connection is read-only. To be called before writing
+    /// `path`, or any file.
+    pub fn check_writable(&self, path: &str) -> Result<()> {
+        if self.read_only {
+            bail!(
+                "Refusing to write '{}': the database '{}' was opened read-only",
+                path,
+                self.path
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether `path` is the database file or one of its `-journal`, `-wal`
+    /// and `-shm` files, however it is spelled.
+    pub fn is_database_file(&self, path: &str) -> bool {
+        if self.path == MEMORY_PATH {
+            return false;
+        }
+        let target = resolve_path(path);
+        ["", "-journal", "-wal", "-shm"]
+            .iter()
+            .any(|suffix| resolve_path(&format!("{}{}", self.path, suffix)) == target)
+    }
+
+    /// Opens a database held entirely in memory: `image` is the content a
+    /// database file would have, or empty for a new database.
+    pub fn from_bytes(image: Vec<u8>) -> Result<Self> {
+        Self::with_storage(Storage::Memory(image), MEMORY_PATH)
+    }
+
+    fn with_storage(mut storage: Storage, path: &str) -> Result<Self> {
+        let (header, wal) = Self::read_state(&mut storage, path)?;
+        let mut functions = UserFunctions::default();
+        functions.set_text_encoding(header.text_encoding);
 
         Ok(Self {
-            file,
-            page_size: if page_size == 1 { 65536 } else { page_size },
+            storage,
+            path: path.to_string(),
+            page_size: header.page_size,
+            page_count: header.page_count,
+            header,
+            wal,
+            schema: None,
+            decode_options: DecodeOptions {
+                text_encoding: header.text_encoding,
+                ..DecodeOptions::default()
+            },
+            interrupt: Interrupt::default(),
+            timeout: None,
+            deadline: None,
+            memory_limit: None,
+            progress_handler: None,
+            pages_read: 0,
+            pages_fetched: 0,
+            cache: PageCache::default(),
+            temp_tables: HashMap::new(),
+            common_tables: Vec::new(),
+            virtual_tables: HashMap::new(),
+            functions: Rc::new(functions),
+            parameters: Rc::new([]),
+            read_only: false,
         })
     }
 
+    /// Reads the header and pins a WAL snapshot, if the database has one. In
+    /// WAL mode the newest committed page 1 (and with it the page size, page
+    /// count and schema cookie) may only exist in the log.
+    fn read_state(storage: &mut Storage, path: &str) -> Result<(FileHeader, Option<WalSnapshot>)> {
+        let mut header = FileHeader::read(storage, path)?;
+        let mut wal = match storage {
+            Storage::File(_) => WalSnapshot::load(path)?,
+            Storage::Memory(_) => None,
+        };
+        if let Some(wal) = &mut wal {
+            header.page_size = wal.page_size();
+            header.page_count = wal.db_size();
+            if let Some(page_one) = wal.read_page(1)? {
+                header.read_counters(&page_one);
+            }
+        }
+        Ok((header, wal))
+    }
+
+    /// Prepares for the next statement: clears any pending interrupt and the
+    /// bound parameters, starts the timeout clock and refreshes the read
+    /// snapshot (see `refresh`).
+    pub fn begin_statement(&mut self) -> Result<()> {
+        self.interrupt.clear();
+        self.parameters = Rc::new([]);
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.pages_read = 0;
+        self.pages_fetched = 0;
+        self.refresh()?;
+        Ok(())
+    }
+
+    /// Starts a new read: re-reads the database header and re-pins the WAL
+    /// snapshot, so every page of the upcoming statement comes from the latest
+    /// committed state and no later one. If another connection has modified
+    /// the file since we last looked, the new page size and page count are
+    /// picked up, and the cached schema is dropped when the schema cookie
+    /// changed, so column layouts are never served stale. Returns whether
+    /// anything changed.
+    pub fn refresh(&mut self) -> Result<bool> {
+        let (header, wal) = Self::read_state(&mut self.storage, &self.path)?;
+        let wal_changed =
+            self.wal.as_ref().map(WalSnapshot::mark) != wal.as_ref().map(WalSnapshot::mark);
+        self.wal = wal;
+        if header == self.header && !wal_changed {
+            return Ok(false);
+        }
+
+        self.cache.clear();
+
+        if header.schema_cookie != self.header.schema_cookie
+            || header.page_size != self.header.page_size
+            || header.page_count == 0
+        {
+            self.schema = None;
+        }
+        self.page_size = header.page_size;
+        self.page_count = header.page_count;
+        if header.text_encoding != self.header.text_encoding {
+            Rc::make_mut(&mut self.functions).set_text_encoding(header.text_encoding);
+        }
+        self.decode_options.text_encoding = header.text_encoding;
+        self.header = header;
+        Ok(true)
+    }
+
     pub fn page_size(&self) -> usize {
         self.page_size
     }
 
+    /// How the database stores TEXT values.
+    pub fn text_encoding(&self) -> TextEncoding {
+        self.header.text_encoding
+    }
+
+    /// Pages in the database as of the current read snapshot.
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    /// Returns a handle that stops the running statement from another thread
+    /// or a signal handler.
+    pub fn interrupt_handle(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    /// Keeps up to `pages` recently read pages in memory. Cached pages are
+    /// dropped whenever `refresh` sees the file or WAL change.
+    pub fn set_cache_size(&mut self, pages: usize) {
+        self.cache.set_capacity(pages);
+    }
+
+    /// Drops every cached page and the cached schema, so the next statement
+    /// starts cold.
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.schema = None;
+    }
+
+    /// Pages the running (or last) statement has read, revisits included.
+    pub fn pages_read(&self) -> u64 {
+        self.pages_read
+    }
+
+    /// Pages the running (or last) statement had to get from the file or
+    /// WAL because they weren't in the page cache.
+    pub fn pages_fetched(&self) -> u64 {
+        self.pages_fetched
+    }
+
+    /// Limits how long a statement may run; a statement still running when
+    /// it expires fails with `Aborted::Timeout` at its next page or row.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Caps the bytes a statement may hold in memory at once. Plain scans
+    /// stream and never touch the cap; anything that has to buffer rows or
+    /// rowids fails with `ResultTooLarge` once it has used more.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    /// A fresh allowance for one buffering step of the running statement.
+    pub fn memory_budget(&self) -> MemoryBudget {
+        MemoryBudget::new(self.memory_limit)
+    }
+
+    /// Installs `handler` to be called after every `every_n_pages` page reads
+    /// of a statement, like `sqlite3_progress_handler`. It can drive a
+    /// progress bar, and returning `true` from it interrupts the statement.
+    /// `every_n_pages` of 0 removes the handler.
+    pub fn set_progress_handler(
+        &mut self,
+        every_n_pages: u64,
+        handler: impl FnMut(&Progress) -> bool + 'static,
+    ) {
+        self.progress_handler = (every_n_pages > 0).then(|| {
+            let handler: ProgressHandler = Box::new(handler);
+            (every_n_pages, handler)
+        });
+    }
+
+    /// Fails if the running statement was interrupted or ran out of time.
+    pub fn check_interrupt(&self) -> Result<()> {
+        self.interrupt.check()?;
+        if let (Some(deadline), Some(timeout)) = (self.deadline, self.timeout) {
+            if Instant::now() >= deadline {
+                return Err(Aborted::Timeout(timeout).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// When enabled, TEXT values containing invalid UTF-8 are decoded with
+    /// replacement characters instead of aborting the whole query.
+    pub fn set_lossy_text(&mut self, lossy_text: bool) {
+        self.decode_options.lossy_text = lossy_text;
+    }
+
+    /// When enabled, table scans keep a small table of the short TEXT values
+    /// seen so far and hand out shared copies of repeats, so low-cardinality
+    /// columns cost one allocation per distinct value rather than per row.
+    pub fn set_intern_text(&mut self, intern_text: bool) {
+        self.decode_options.intern_text = intern_text;
+    }
+
+    /// Registers a scalar function callable from SQL as `name`, taking
+    /// `n_args` arguments or any number if negative, like
+    /// `sqlite3_create_function`. It's found before a built-in of the same
+    /// name, and an error it returns fails the statement.
+    pub fn create_scalar_function(
+        &mut self,
+        name: &str,
+        n_args: i32,
+        function: impl Fn(&[Value]) -> Result<Value> + 'static,
+    ) {
+        Rc::make_mut(&mut self.functions).add_scalar(name, n_args, Rc::new(function));
+    }
+
+    /// Registers an aggregate callable from SQL as `name`, taking `n_args`
+    /// arguments or any number if negative. `new` makes the state for each
+    /// query that uses it.
+    pub fn create_aggregate_function<A: Aggregate + 'static>(
+        &mut self,
+        name: &str,
+        n_args: i32,
+        new: impl Fn() -> A + 'static,
+    ) {
+        let new: AggregateFactory = Rc::new(move || Box::new(new()));
+        Rc::make_mut(&mut self.functions).add_aggregate(name, n_args, new);
+    }
+
+    /// Registers a collation for text columns declared `COLLATE name`, like
+    /// `sqlite3_create_collation`. It replaces a built-in of the same name.
+    pub fn create_collation(
+        &mut self,
+        name: &str,
+        compare: impl Fn(&str, &str) -> std::cmp::Ordering + 'static,
+    ) {
+        Rc::make_mut(&mut self.functions).add_collation(name, Rc::new(compare));
+    }
+
+    /// The functions and collations registered with
+    /// [`Database::create_scalar_function`] and friends.
+    pub fn functions(&self) -> Rc<UserFunctions> {
+        Rc::clone(&self.functions)
+    }
+
+    /// Binds `values` to the parameters of the statement about to be
+    /// prepared, `values[0]` to `?1`; parameters past the end are NULL.
+    /// `begin_statement` clears them.
+    pub fn set_parameters(&mut self, values: Vec<Value>) {
+        self.parameters = values.into();
+    }
+
+    /// The values bound with `set_parameters`.
+    pub fn parameters(&self) -> Rc<[Value]> {
+        Rc::clone(&self.parameters)
+    }
+
+    /// Reads the full database header from page 1 of the current snapshot.
+    pub fn read_header(&mut self) -> Result<DatabaseHeader> {
+        if self.page_count == 0 {
+            bail!("Database is empty: it has no header yet");
+        }
+        let page_one = self.read_page(1)?;
+        Ok(DatabaseHeader::parse(
+            &page_one[..DATABASE_HEADER_SIZE],
+            self.page_size,
+        ))
+    }
+
+    /// Looks up a temporary table, or a table of the running statement's
+    /// WITH clause. Like SQLite, temporary tables shadow tables of the same
+    /// name in the database file.
+    pub fn temp_table(&self, name: &str) -> Option<&TempTable> {
+        let common = self.common_tables.iter().rev();
+        match common
+            .into_iter()
+            .find(|(common, _)| common.eq_ignore_ascii_case(name))
+        {
+            Some((_, table)) => Some(table),
+            None => self.temp_tables.get(&name.to_lowercase()),
+        }
+    }
+
+    /// Makes `table` readable as `name` until `pop_common_table`, for a
+    /// table of a WITH clause.
+    pub fn push_common_table(&mut self, name: &str, table: TempTable) {
+        self.common_tables.push((name.to_string(), table));
+    }
+
+    /// Undoes the last `push_common_table`.
+    pub fn pop_common_table(&mut self) {
+        self.common_tables.pop();
+    }
+
+    /// Names of the temporary tables, sorted.
+    pub fn temp_table_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.temp_tables.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn create_temp_table(&mut self, name: &str, table: TempTable) -> Result<()> {
+        let key = name.to_lowercase();
+        if self.temp_tables.contains_key(&key) || self.virtual_tables.contains_key(&key) {
+            bail!("Table '{}' already exists", name);
+        }
+        self.temp_tables.insert(key, table);
+        Ok(())
+    }
+
+    /// Returns whether a temporary table of that name existed.
+    pub fn drop_temp_table(&mut self, name: &str) -> bool {
+        self.temp_tables.remove(&name.to_lowercase()).is_some()
+    }
+
+    /// Registers `table` under `name`, to be queried like any other table
+    /// for the rest of the session. Like a temporary table, it shadows a
+    /// table of the same name in the database file.
+    pub fn create_virtual_table(
+        &mut self,
+        name: &str,
+        table: impl VirtualTable + 'static,
+    ) -> Result<()> {
+        let key = name.to_lowercase();
+        if self.temp_tables.contains_key(&key) || self.virtual_tables.contains_key(&key) {
+            bail!("Table '{}' already exists", name);
+        }
+        let def = parse_create_table(&table.schema())
+            .with_context(|| format!("Bad schema for virtual table '{}'", name))?;
+        self.virtual_tables.insert(key, (def, Rc::new(table)));
+        Ok(())
+    }
+
+    /// Looks up a virtual table: its columns and the table itself.
+    pub fn virtual_table(&self, name: &str) -> Option<(TableDef, Rc<dyn VirtualTable>)> {
+        self.virtual_tables
+            .get(&name.to_lowercase())
+            .map(|(def, table)| (def.clone(), Rc::clone(table)))
+    }
+
+    /// Names of the virtual tables, sorted.
+    pub fn virtual_table_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.virtual_tables.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the rows of `sqlite_schema`, cached until `refresh` notices the
+    /// schema cookie change.
     pub fn read_schema(&mut self) -> Result<Vec<SchemaEntry>> {
-        let mut page_data = vec![0; self.page_size];
-        self.file.seek(SeekFrom::Start(0))?;
-        self.file.read_exact(&mut page_data)?;
+        if let Some(schema) = &self.schema {
+            return Ok(schema.clone());
+        }
+        let schema = self.load_schema()?;
+        self.schema = Some(schema.clone());
+        Ok(schema)
+    }
 
-        let header_offset = 100;
-        let cell_count =
-            u16::from_be_bytes([page_data[header_offset + 3], page_data[header_offset + 4]]);
+    fn load_schema(&mut self) -> Result<Vec<SchemaEntry>> {
+        if self.page_count == 0 {
+            return Ok(Vec::new());
+        }
 
-        let offsets_array_start = header_offset + 8;
+        // A schema too big for page 1 makes it the root of a deeper tree.
         let mut schema_entries = Vec::new();
+        self.for_each_table_record(1, |mut record| {
+            // Skip the rowid.
+            record.remove(0);
+            if record.len() < 5 {
+                return Ok(());
+            }
+            let (Value::Text(typ), Value::Text(name), Value::Text(tbl_name)) =
+                (&record[0], &record[1], &record[2])
+            else {
+                return Ok(());
+            };
+            let rootpage = match record[3] {
+                Value::Int(r) => u32::try_from(r)
+                    .with_context(|| format!("Invalid root page {} for '{}'", r, name))?,
+                _ => return Ok(()),
+            };
+            let sql = if let Value::Text(s) = &record[4] {
+                Some(s.to_string())
+            } else {
+                None
+            };
+
+            schema_entries.push(SchemaEntry {
+                typ: typ.to_string(),
+                name: name.to_string(),
+                tbl_name: tbl_name.to_string(),
+                rootpage,
+                sql,
+            });
+            Ok(())
+        })?;
 
-        for i in 0..cell_count {
-            let offset_pos = offsets_array_start + (i as usize * 2);
-            let cell_offset =
-                u16::from_be_bytes([page_data[offset_pos], page_data[offset_pos + 1]]) as usize;
+        Ok(schema_entries)
+    }
 
-            let cell_data = &page_data[cell_offset..];
-            let (_, rest, _) = read_varint(cell_data)?;
-            let (_, rest, _) = read_varint(rest)?;
+    pub fn read_page(&mut self, page_number: u32) -> Result<Vec<u8>> {
+        self.load_page(page_number, None)
+    }
 
-            let record = parse_record(rest)?;
+    /// Bytes of each page that B-tree cells can use: the page less the
+    /// reserved space at its end.
+    fn usable_size(&self) -> usize {
+        self.page_size - self.header.reserved_bytes as usize
+    }
 
-            if record.len() >= 5 {
-                let typ = if let Value::Text(t) = &record[0] {
-                    t.clone()
-                } else {
-                    continue;
-                };
-                let tbl_name = if let Value::Text(t) = &record[2] {
-                    t.clone()
-                } else {
-                    continue;
-                };
-                let rootpage = if let Value::Int(r) = record[3] {
-                    r as u32
-                } else {
-                    continue;
-                };
+    /// The whole `payload_size`-byte payload of a cell that keeps `local` of
+    /// it in the page: borrowed if that's all of it, else followed by the
+    /// rest from its chain of overflow pages, starting at `overflow_page`.
+    /// Each overflow page starts with the number of the next, then holds as
+    /// much of the payload as fits.
+    fn full_payload<'a>(
+        &mut self,
+        local: &'a [u8],
+        payload_size: u64,
+        overflow_page: Option<u32>,
+    ) -> Result<Cow<'a, [u8]>> {
+        let Some(mut page_number) = overflow_page else {
+            return Ok(Cow::Borrowed(local));
+        };
+        let payload_len = payload_len(payload_size)?;
+        let per_page = self.usable_size() - 4;
+        // Checked before allocating: a corrupt size could be anything.
+        let available = local.len() as u64 + self.page_count as u64 * per_page as u64;
+        if payload_size > available {
+            corrupt!(
+                "Payload of {} bytes can't fit in a database of {} pages",
+                payload_size,
+                self.page_count
+            );
+        }
+        let mut payload = Vec::with_capacity(payload_len);
+        payload.extend_from_slice(local);
+        // Every page adds to the payload, so even a looping chain ends.
+        while payload.len() < payload_len {
+            if page_number == 0 {
+                corrupt!(
+                    "Overflow chain ends after {} of {} payload bytes",
+                    payload.len(),
+                    payload_len
+                );
+            }
+            let page = self.read_page(page_number)?;
+            let take = (payload_len - payload.len()).min(per_page);
+            payload.extend_from_slice(&page[4..4 + take]);
+            page_number = u32::from_be_bytes([page[0], page[1], page[2], page[3]]);
+        }
+        Ok(Cow::Owned(payload))
+    }
 
-                let sql = if let Value::Text(s) = &record[4] {
-                    Some(s.clone())
-                } else {
-                    None
+    /// `read_page`, taking the bytes of the page in the database file from
+    /// `prefetched` if given rather than reading them. The WAL and the page
+    /// cache still come first, and the checks are the same.
+    fn load_page(&mut self, page_number: u32, prefetched: Option<Vec<u8>>) -> Result<Vec<u8>> {
+        self.check_interrupt()?;
+        self.pages_read += 1;
+        if let Some((every_n_pages, handler)) = &mut self.progress_handler {
+            if self.pages_read % *every_n_pages == 0 {
+                let progress = Progress {
+                    pages_read: self.pages_read,
+                    page_count: self.page_count,
                 };
+                if handler(&progress) {
+                    return Err(Aborted::Interrupted.into());
+                }
+            }
+        }
+        if page_number == 0 || page_number > self.page_count {
+            corrupt!(
+                "Page {} is out of range: database has {} pages",
+                page_number,
+                self.page_count
+            );
+        }
+
+        if let Some(page_data) = self.cache.get(page_number) {
+            return Ok(page_data);
+        }
+        self.pages_fetched += 1;
 
-                schema_entries.push(SchemaEntry {
-                    typ,
-                    tbl_name,
-                    rootpage,
-                    sql,
-                });
+        if let Some(wal) = &mut self.wal {
+            if let Some(page_data) = wal.read_page(page_number)? {
+                self.verify_checksum(page_number, &page_data)?;
+                self.cache.insert(page_number, &page_data);
+                return Ok(page_data);
             }
+            wal.verify_database_file()?;
         }
 
-        Ok(schema_entries)
+        let page_data = match prefetched {
+            Some(page_data) if page_data.len() == self.page_size => page_data,
+            _ => {
+                let mut page_data = vec![0; self.page_size];
+                // Done in u64: a 4-billion-page file at 64 KiB pages is far
+                // past usize on 32-bit targets.
+                let offset = (page_number as u64 - 1) * self.page_size as u64;
+                self.storage.read_exact_at(offset, &mut page_data)?;
+                page_data
+            }
+        };
+        self.verify_checksum(page_number, &page_data)?;
+
+        self.cache.insert(page_number, &page_data);
+        Ok(page_data)
     }
 
-    pub fn read_page(&mut self, page_number: usize) -> Result<Vec<u8>> {
-        let mut page_data = vec![0; self.page_size];
-        let offset = (page_number - 1) * self.page_size;
+    /// Checks the checksum cksumvfs keeps in the reserved bytes of each page:
+    /// the WAL checksum over the rest of the page, summed as little-endian
+    /// words and stored little-endian. Files with any other amount of reserved
+    /// space aren't checksummed.
+    fn verify_checksum(&self, page_number: u32, page_data: &[u8]) -> Result<()> {
+        if self.header.reserved_bytes != CKSUMVFS_RESERVED_BYTES {
+            return Ok(());
+        }
+        let (content, stored) = page_data.split_at(page_data.len() - 8);
+        let [s1, s2] = wal_checksum(false, [0, 0], content);
+        let computed = [s1.to_le_bytes(), s2.to_le_bytes()].concat();
+        if stored != computed {
+            let hex = |bytes: &[u8]| {
+                bytes
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            };
+            corrupt!(
+                "Checksum mismatch on page {}: stored {}, computed {}",
+                page_number,
+                hex(stored),
+                hex(&computed)
+            );
+        }
+        Ok(())
+    }
 
-        self.file.seek(SeekFrom::Start(offset as u64))?;
-        self.file.read_exact(&mut page_data)?;
+    /// Estimates the rows in `table` without visiting every page: from the
+    /// `sqlite_stat1` table if `ANALYZE` has filled it in, otherwise by
+    /// sampling a few paths from the root to a leaf and multiplying out the
+    /// fan-out of the pages on the way (Knuth's tree-size estimator). Each
+    /// descent costs one page per level, so even huge tables take a few
+    /// dozen page reads.
+    pub fn estimate_rows(&mut self, table: &str) -> Result<RowEstimate> {
+        if let Some(temp_table) = self.temp_table(table) {
+            return Ok(RowEstimate {
+                rows: temp_table.len() as u64,
+                source: EstimateSource::Exact,
+            });
+        }
+        let schema = self.read_schema()?;
+        let root_page = find_table(&schema, table)?.rootpage;
+        if let Some(rows) = self.stat1_row_count(&schema, table)? {
+            return Ok(RowEstimate {
+                rows,
+                source: EstimateSource::Stat1,
+            });
+        }
 
-        Ok(page_data)
+        let pages_before = self.pages_read;
+        let mut random = 0x9E37_79B9_7F4A_7C15_u64;
+        let mut total = 0.0;
+        for _ in 0..ESTIMATE_DESCENTS {
+            let mut page_number = root_page;
+            let mut weight = 1.0;
+            for depth in 0.. {
+                if depth == MAX_TREE_DEPTH {
+                    corrupt!("B-tree of '{}' is deeper than {} levels", table, depth);
+                }
+                let page_data = self.read_page(page_number)?;
+                let header_offset = if page_number == 1 { 100 } else { 0 };
+                let header = BTreePageHeader::parse(&page_data[header_offset..], page_number == 1)?;
+                let cell_count = header.cell_count as u64;
+                let Some(right_most) = header.right_most_pointer else {
+                    if depth == 0 {
+                        return Ok(RowEstimate {
+                            rows: cell_count,
+                            source: EstimateSource::Exact,
+                        });
+                    }
+                    total += weight * cell_count as f64;
+                    break;
+                };
+                // Interior index cells hold entries too; table ones only keys.
+                if header.page_type == BTreePageType::InteriorIndex {
+                    total += weight * cell_count as f64;
+                }
+
+                // xorshift64: deterministic, so estimates are repeatable.
+                random ^= random << 13;
+                random ^= random >> 7;
+                random ^= random << 17;
+                let child = random % (cell_count + 1);
+                page_number = if child == cell_count {
+                    right_most
+                } else {
+                    let pointer = header_offset + 12 + child as usize * 2;
+                    let cell_offset = u16::from_be_bytes([
+                        *page_data
+                            .get(pointer)
+                            .context("Cell pointer out of bounds")?,
+                        *page_data
+                            .get(pointer + 1)
+                            .context("Cell pointer out of bounds")?,
+                    ]) as usize;
+                    let left_child = page_data
+                        .get(cell_offset..cell_offset + 4)
+                        .context("Cell offset out of bounds")?;
+                    u32::from_be_bytes([left_child[0], left_child[1], left_child[2], left_child[3]])
+                };
+                weight *= (cell_count + 1) as f64;
+            }
+        }
+
+        Ok(RowEstimate {
+            rows: (total / ESTIMATE_DESCENTS as f64).round() as u64,
+            source: EstimateSource::Sampled {
+                pages: self.pages_read - pages_before,
+            },
+        })
+    }
+
+    /// The row count of `table` from `sqlite_stat1`, whose `stat` column
+    /// starts with the number of rows in the table for every row about it.
+    fn stat1_row_count(&mut self, schema: &[SchemaEntry], table: &str) -> Result<Option<u64>> {
+        let Some(stat1) = schema
+            .iter()
+            .find(|entry| entry.typ == "table" && entry.name.eq_ignore_ascii_case("sqlite_stat1"))
+        else {
+            return Ok(None);
+        };
+        let mut rows = None;
+        self.for_each_table_record(stat1.rootpage, |record| {
+            // [rowid, tbl, idx, stat]
+            if let (Some(Value::Text(tbl)), Some(Value::Text(stat))) =
+                (record.get(1), record.get(3))
+            {
+                if rows.is_none() && tbl.eq_ignore_ascii_case(table) {
+                    rows = stat.split_whitespace().next().and_then(|n| n.parse().ok());
+                }
+            }
+            Ok(())
+        })?;
+        Ok(rows)
     }
 
     pub fn collect_leaf_pages(&mut self, root_page: u32) -> Result<Vec<u32>> {
@@ -348,7 +1466,7 @@ impl Database {
         let mut stack = vec![root_page];
 
         while let Some(page_number) = stack.pop() {
-            let page_data = self.read_page(page_number as usize)?;
+            let page_data = self.read_page(page_number)?;
             let is_page_one = page_number == 1;
             let header_offset = if is_page_one { 100 } else { 0 };
             let header_data = &page_data[header_offset..];
@@ -371,13 +1489,13 @@ impl Database {
                     for i in 0..cell_count {
                         let pointer_offset = cell_pointers_start + i * 2;
                         if pointer_offset + 2 > page_data.len() {
-                            bail!("Cell pointer offset out of bounds");
+                            corrupt!("Cell pointer offset out of bounds");
                         }
                         let cell_offset = u16::from_be_bytes([
                             page_data[pointer_offset],
                             page_data[pointer_offset + 1],
                         ]) as usize;
-                        let cell_data = &page_data[cell_offset..];
+                        let cell_data = cell_at(&page_data, cell_offset)?;
                         let (cell, _) = TableBTreeInteriorCell::parse(cell_data)?;
                         child_pages.push(cell.left_child_page);
                     }
@@ -390,7 +1508,7 @@ impl Database {
                         stack.push(child_page);
                     }
                 }
-                _ => bail!(
+                _ => corrupt!(
                     "Unexpected page type for table B-tree: {:?}",
                     header.page_type
                 ),
@@ -400,19 +1518,46 @@ impl Database {
         Ok(leaf_pages)
     }
 
-    pub fn read_table_records(&mut self, root_page: u32) -> Result<Vec<Vec<Value>>> {
+    /// Streams every record of a table B-tree, in rowid order, to `on_record`
+    /// without holding more than one page in memory.
+    pub fn for_each_table_record(
+        &mut self,
+        root_page: u32,
+        mut on_record: impl FnMut(Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
+        self.for_each_table_record_with_db(root_page, |_, record| on_record(std::mem::take(record)))
+    }
+
+    /// Like `for_each_table_record`, but hands the database back to
+    /// `on_record` so it can read other tables in the middle of the scan.
+    /// Every record is decoded into the same buffer, which `on_record` may
+    /// take if it keeps the row.
+    pub fn for_each_table_record_with_db(
+        &mut self,
+        root_page: u32,
+        mut on_record: impl FnMut(&mut Database, &mut Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
         let leaf_pages = self.collect_leaf_pages(root_page)?;
-        let mut all_records = Vec::new();
+        let mut prefetch = match self.storage {
+            Storage::File(_) if leaf_pages.len() >= PREFETCH_MIN_PAGES => {
+                Prefetch::start(&self.path, self.page_size, &leaf_pages)
+            }
+            _ => None,
+        };
 
+        let mut decoder = RecordDecoder::new(self.decode_options);
+        let mut record = Vec::new();
+        let usable_size = self.usable_size();
         for page_number in leaf_pages {
-            let page_data = self.read_page(page_number as usize)?;
+            let prefetched = prefetch.as_mut().and_then(Prefetch::next_page);
+            let page_data = self.load_page(page_number, prefetched)?;
             let is_page_one = page_number == 1;
             let header_offset = if is_page_one { 100 } else { 0 };
             let header_data = &page_data[header_offset..];
             let header = BTreePageHeader::parse(header_data, is_page_one)?;
 
             if header.page_type != BTreePageType::LeafTable {
-                bail!("Expected leaf table page, got {:?}", header.page_type);
+                corrupt!("Expected leaf table page, got {:?}", header.page_type);
             }
 
             let cell_pointers_start = header_offset + 8;
@@ -421,34 +1566,140 @@ impl Database {
             for i in 0..cell_count {
                 let pointer_offset = cell_pointers_start + i * 2;
                 if pointer_offset + 2 > page_data.len() {
-                    bail!("Cell pointer offset out of bounds");
+                    corrupt!("Cell pointer offset out of bounds");
                 }
                 let cell_offset =
                     u16::from_be_bytes([page_data[pointer_offset], page_data[pointer_offset + 1]])
                         as usize;
-                let cell_data = &page_data[cell_offset..];
-                let (cell, _) = TableBTreeLeafCell::parse(cell_data)?;
+                let cell_data = cell_at(&page_data, cell_offset)?;
+                let (cell, _) = TableLeafCellRef::parse(cell_data, usable_size)?;
+                let payload =
+                    self.full_payload(cell.payload, cell.payload_size, cell.overflow_page)?;
+                decoder.decode_row(cell.rowid, &payload, &mut record)?;
+
+                self.check_interrupt()?;
+                on_record(self, &mut record)?;
+            }
+        }
 
-                let mut record = parse_record(&cell.payload)?;
-                record.insert(0, Value::Int(cell.rowid as i64));
+        Ok(())
+    }
 
-                all_records.push(record);
+    /// Streams every entry of an index B-tree, in key order, to `on_record`.
+    /// The rows of a WITHOUT ROWID table are the entries of one.
+    pub fn for_each_index_record(
+        &mut self,
+        root_page: u32,
+        mut on_record: impl FnMut(Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
+        /// What's left to visit, in reverse: a subtree, or an entry of an
+        /// interior page, which comes after its left child.
+        enum Pending {
+            Page(u32),
+            Entry(Vec<Value>),
+        }
+        let mut stack = vec![Pending::Page(root_page)];
+        let usable_size = self.usable_size();
+
+        while let Some(pending) = stack.pop() {
+            let page_number = match pending {
+                Pending::Page(page_number) => page_number,
+                Pending::Entry(record) => {
+                    on_record(record)?;
+                    continue;
+                }
+            };
+            self.check_interrupt()?;
+            let page_data = self.read_page(page_number)?;
+            let is_page_one = page_number == 1;
+            let header_offset = if is_page_one { 100 } else { 0 };
+            let header = BTreePageHeader::parse(&page_data[header_offset..], is_page_one)?;
+            let interior = match header.page_type {
+                BTreePageType::LeafIndex => false,
+                BTreePageType::InteriorIndex => true,
+                _ => corrupt!(
+                    "Unexpected page type for index B-tree: {:?}",
+                    header.page_type
+                ),
+            };
+            let cell_pointers_start = header_offset + if interior { 12 } else { 8 };
+            let mut pending = Vec::with_capacity(header.cell_count as usize * 2 + 1);
+            for i in 0..header.cell_count as usize {
+                let pointer_offset = cell_pointers_start + i * 2;
+                if pointer_offset + 2 > page_data.len() {
+                    corrupt!("Index cell pointer offset out of bounds");
+                }
+                let cell_offset =
+                    u16::from_be_bytes([page_data[pointer_offset], page_data[pointer_offset + 1]])
+                        as usize;
+                let cell_data = cell_at(&page_data, cell_offset)?;
+                let record = if interior {
+                    let (cell, _) = IndexBTreeInteriorCell::parse(cell_data, usable_size)?;
+                    pending.push(Pending::Page(cell.left_child_page));
+                    let payload =
+                        self.full_payload(&cell.payload, cell.payload_size, cell.overflow_page)?;
+                    parse_record(&payload, self.decode_options)?
+                } else {
+                    let (cell, _) = IndexBTreeLeafCell::parse(cell_data, usable_size)?;
+                    let payload =
+                        self.full_payload(&cell.payload, cell.payload_size, cell.overflow_page)?;
+                    parse_record(&payload, self.decode_options)?
+                };
+                pending.push(Pending::Entry(record));
+            }
+            if let Some(right_most) = header.right_most_pointer.filter(|_| interior) {
+                pending.push(Pending::Page(right_most));
             }
+            stack.extend(pending.into_iter().rev());
         }
-
-        Ok(all_records)
+        Ok(())
     }
 
+    /// Collects, sorted, the rowids of the index entries whose leading keys
+    /// equal `key`, compared column by column in the index's order: NULLs
+    /// first, then numbers by value (so `3` finds `3.0`), then text by its
+    /// bytes, then blobs, all reversed for the columns `descending` marks.
+    /// The index has to compare text as BINARY.
     pub fn collect_index_rowids(
         &mut self,
         index_root_page: u32,
-        target_country: &str,
-    ) -> Result<Vec<u64>> {
+        key: &[Value],
+        descending: &[bool],
+    ) -> Result<Vec<i64>> {
+        self.collect_index_rowids_in_range(index_root_page, |found| {
+            found
+                .iter()
+                .zip(key)
+                .zip(descending.iter().chain(std::iter::repeat(&false)))
+                .map(|((found, key), descending)| {
+                    if *descending {
+                        total_order(found, key).reverse()
+                    } else {
+                        total_order(found, key)
+                    }
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+    }
+
+    /// Collects, sorted, the rowids of the index entries that fall in a
+    /// contiguous range of the index order. `locate` places an entry's keys,
+    /// without its rowid, relative to that range: `Less` before it, `Equal`
+    /// in it, `Greater` after it. Only the subtrees that can overlap the
+    /// range are read.
+    pub fn collect_index_rowids_in_range(
+        &mut self,
+        index_root_page: u32,
+        locate: impl Fn(&[Value]) -> std::cmp::Ordering,
+    ) -> Result<Vec<i64>> {
         let mut rowids = Vec::new();
+        let mut budget = self.memory_budget();
         let mut stack = vec![index_root_page];
+        let usable_size = self.usable_size();
 
         while let Some(page_number) = stack.pop() {
-            let page_data = self.read_page(page_number as usize)?;
+            let page_data = self.read_page(page_number)?;
             let is_page_one = page_number == 1;
             let header_offset = if is_page_one { 100 } else { 0 };
             let header_data = &page_data[header_offset..];
@@ -462,22 +1713,24 @@ impl Database {
                     for i in 0..cell_count {
                         let pointer_offset = cell_pointers_start + i * 2;
                         if pointer_offset + 2 > page_data.len() {
-                            bail!("Index leaf cell pointer offset out of bounds");
+                            corrupt!("Index leaf cell pointer offset out of bounds");
                         }
                         let cell_offset = u16::from_be_bytes([
                             page_data[pointer_offset],
                             page_data[pointer_offset + 1],
                         ]) as usize;
-                        let cell_data = &page_data[cell_offset..];
-                        let (cell, _) = IndexBTreeLeafCell::parse(cell_data)?;
-                        let record = parse_record(&cell.payload)?;
-                        if record.len() >= 2 {
-                            if let (Value::Text(country), Value::Int(rowid)) =
-                                (&record[0], &record[1])
-                            {
-                                if country == target_country {
-                                    rowids.push(*rowid as u64);
-                                }
+                        let cell_data = cell_at(&page_data, cell_offset)?;
+                        let (cell, _) = IndexBTreeLeafCell::parse(cell_data, usable_size)?;
+                        let payload = self.full_payload(
+                            &cell.payload,
+                            cell.payload_size,
+                            cell.overflow_page,
+                        )?;
+                        let record = parse_record(&payload, self.decode_options)?;
+                        if let [keys @ .., Value::Int(rowid)] = record.as_slice() {
+                            if !keys.is_empty() && locate(keys).is_eq() {
+                                budget.charge(std::mem::size_of::<i64>())?;
+                                rowids.push(*rowid);
                             }
                         }
                     }
@@ -486,37 +1739,59 @@ impl Database {
                     let cell_pointers_start = header_offset + 12;
                     let cell_count = header.cell_count as usize;
                     let mut child_pages = Vec::new();
+                    let mut reached_end = false;
 
                     for i in 0..cell_count {
                         let pointer_offset = cell_pointers_start + i * 2;
                         if pointer_offset + 2 > page_data.len() {
-                            bail!("Index interior cell pointer offset out of bounds");
+                            corrupt!("Index interior cell pointer offset out of bounds");
                         }
                         let cell_offset = u16::from_be_bytes([
                             page_data[pointer_offset],
                             page_data[pointer_offset + 1],
                         ]) as usize;
-                        let cell_data = &page_data[cell_offset..];
-                        let (cell, _) = IndexBTreeInteriorCell::parse(cell_data)?;
-                        let record = parse_record(&cell.payload)?;
-                        if record.len() >= 1 {
-                            if let Value::Text(country) = &record[0] {
-                                if target_country <= country.as_str() {
-                                    child_pages.push(cell.left_child_page);
-                                }
-                            }
+                        let cell_data = cell_at(&page_data, cell_offset)?;
+                        let (cell, _) = IndexBTreeInteriorCell::parse(cell_data, usable_size)?;
+                        let payload = self.full_payload(
+                            &cell.payload,
+                            cell.payload_size,
+                            cell.overflow_page,
+                        )?;
+                        let record = parse_record(&payload, self.decode_options)?;
+                        let keys = match record.as_slice() {
+                            [keys @ .., _] if !keys.is_empty() => keys,
+                            _ => corrupt!("Empty index entry on page {}", page_number),
+                        };
+                        let ordering = locate(keys);
+                        // Everything left of a key before the range is too.
+                        if ordering.is_lt() {
+                            continue;
+                        }
+
+                        child_pages.push(cell.left_child_page);
+                        if ordering.is_gt() {
+                            reached_end = true;
+                            break;
+                        }
+                        // Interior cells of an index B-tree are entries in their
+                        // own right and never repeat in the leaves.
+                        if let Some(Value::Int(rowid)) = record.last() {
+                            budget.charge(std::mem::size_of::<i64>())?;
+                            rowids.push(*rowid);
                         }
                     }
 
-                    if let Some(right_most) = header.right_most_pointer {
-                        child_pages.push(right_most);
+                    if !reached_end {
+                        if let Some(right_most) = header.right_most_pointer {
+                            child_pages.push(right_most);
+                        }
                     }
 
                     for &child_page in child_pages.iter().rev() {
                         stack.push(child_page);
                     }
                 }
-                _ => bail!(
+                _ => corrupt!(
                     "Unexpected page type for index B-tree: {:?}",
                     header.page_type
                 ),
@@ -527,21 +1802,79 @@ impl Database {
         Ok(rowids)
     }
 
-    pub fn read_table_records_by_rowids(
+    /// Streams the records with the given rowids to `on_record`, in rowid
+    /// order, visiting only the subtrees that can hold them.
+    pub fn for_each_table_record_by_rowids(
+        &mut self,
+        table_root_page: u32,
+        target_rowids: &[i64],
+        mut on_record: impl FnMut(Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
+        self.for_each_table_record_by_rowids_with_db(table_root_page, target_rowids, |_, record| {
+            on_record(std::mem::take(record))
+        })
+    }
+
+    /// Like `for_each_table_record_by_rowids`, but hands the database back to
+    /// `on_record` so it can read other tables in the middle of the scan, in
+    /// a reused buffer as `for_each_table_record_with_db` does.
+    pub fn for_each_table_record_by_rowids_with_db(
         &mut self,
         table_root_page: u32,
-        target_rowids: &[u64],
-    ) -> Result<Vec<Vec<Value>>> {
+        target_rowids: &[i64],
+        on_record: impl FnMut(&mut Database, &mut Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
         if target_rowids.is_empty() {
-            return Ok(Vec::new());
+            return Ok(());
         }
+        let mut sorted_targets = target_rowids.to_vec();
+        sorted_targets.sort_unstable();
+        sorted_targets.dedup();
+        self.for_each_table_record_where(
+            table_root_page,
+            |rowid| sorted_targets.binary_search(&rowid).is_ok(),
+            |after, up_to| any_target_in(&sorted_targets, after, up_to),
+            on_record,
+        )
+    }
+
+    /// Streams the records whose rowids lie in `rowids` to `on_record`, in
+    /// rowid order, visiting only the subtrees that can hold them. Records
+    /// come in a reused buffer, as for `for_each_table_record_with_db`.
+    pub fn for_each_table_record_in_rowid_range_with_db(
+        &mut self,
+        table_root_page: u32,
+        rowids: RangeInclusive<i64>,
+        on_record: impl FnMut(&mut Database, &mut Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
+        self.for_each_table_record_where(
+            table_root_page,
+            |rowid| rowids.contains(&rowid),
+            |after, up_to| {
+                after.map_or(true, |after| after < *rowids.end())
+                    && up_to.map_or(true, |up_to| up_to >= *rowids.start())
+            },
+            on_record,
+        )
+    }
 
-        let mut records = Vec::new();
+    /// Streams the records whose rowid is `wanted` to `on_record`, in rowid
+    /// order. `in_subtree` says whether any wanted rowid falls in
+    /// `(after, up_to]`, the rowids of a subtree, which is skipped if not.
+    fn for_each_table_record_where(
+        &mut self,
+        table_root_page: u32,
+        wanted: impl Fn(i64) -> bool,
+        in_subtree: impl Fn(Option<i64>, Option<i64>) -> bool,
+        mut on_record: impl FnMut(&mut Database, &mut Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
         let mut stack = vec![table_root_page];
-        let rowid_set: std::collections::HashSet<u64> = target_rowids.iter().copied().collect();
+        let mut decoder = RecordDecoder::new(self.decode_options);
+        let mut record = Vec::new();
+        let usable_size = self.usable_size();
 
         while let Some(page_number) = stack.pop() {
-            let page_data = self.read_page(page_number as usize)?;
+            let page_data = self.read_page(page_number)?;
             let is_page_one = page_number == 1;
             let header_offset = if is_page_one { 100 } else { 0 };
             let header_data = &page_data[header_offset..];
@@ -555,19 +1888,24 @@ impl Database {
                     for i in 0..cell_count {
                         let pointer_offset = cell_pointers_start + i * 2;
                         if pointer_offset + 2 > page_data.len() {
-                            bail!("Table leaf cell pointer offset out of bounds");
+                            corrupt!("Table leaf cell pointer offset out of bounds");
                         }
                         let cell_offset = u16::from_be_bytes([
                             page_data[pointer_offset],
                             page_data[pointer_offset + 1],
                         ]) as usize;
-                        let cell_data = &page_data[cell_offset..];
-                        let (cell, _) = TableBTreeLeafCell::parse(cell_data)?;
-
-                        if rowid_set.contains(&cell.rowid) {
-                            let mut record = parse_record(&cell.payload)?;
-                            record.insert(0, Value::Int(cell.rowid as i64));
-                            records.push(record);
+                        let cell_data = cell_at(&page_data, cell_offset)?;
+                        let (cell, _) = TableLeafCellRef::parse(cell_data, usable_size)?;
+
+                        if wanted(cell.rowid) {
+                            let payload = self.full_payload(
+                                cell.payload,
+                                cell.payload_size,
+                                cell.overflow_page,
+                            )?;
+                            decoder.decode_row(cell.rowid, &payload, &mut record)?;
+                            self.check_interrupt()?;
+                            on_record(self, &mut record)?;
                         }
                     }
                 }
@@ -575,41 +1913,162 @@ impl Database {
                     let cell_pointers_start = header_offset + 12;
                     let cell_count = header.cell_count as usize;
                     let mut child_pages = Vec::new();
-
-                    let min_target = *target_rowids.iter().min().unwrap_or(&0);
+                    // Each left child holds the rowids in (previous key, key].
+                    let mut previous_key = None;
 
                     for i in 0..cell_count {
                         let pointer_offset = cell_pointers_start + i * 2;
                         if pointer_offset + 2 > page_data.len() {
-                            bail!("Table interior cell pointer offset out of bounds");
+                            corrupt!("Table interior cell pointer offset out of bounds");
                         }
                         let cell_offset = u16::from_be_bytes([
                             page_data[pointer_offset],
                             page_data[pointer_offset + 1],
                         ]) as usize;
-                        let cell_data = &page_data[cell_offset..];
+                        let cell_data = cell_at(&page_data, cell_offset)?;
                         let (cell, _) = TableBTreeInteriorCell::parse(cell_data)?;
 
-                        if cell.rowid >= min_target {
+                        if in_subtree(previous_key, Some(cell.rowid)) {
                             child_pages.push(cell.left_child_page);
                         }
+                        previous_key = Some(cell.rowid);
                     }
 
                     if let Some(right_most) = header.right_most_pointer {
-                        child_pages.push(right_most);
+                        if in_subtree(previous_key, None) {
+                            child_pages.push(right_most);
+                        }
                     }
 
                     for &child_page in child_pages.iter().rev() {
                         stack.push(child_page);
                     }
                 }
-                _ => bail!(
+                _ => corrupt!(
                     "Unexpected page type for table B-tree: {:?}",
                     header.page_type
                 ),
             }
         }
 
-        Ok(records)
+        Ok(())
+    }
+}
+
+/// Whether any of the sorted rowids falls in `(after, up_to]`, where `None`
+/// leaves that side of the range unbounded.
+fn any_target_in(sorted_targets: &[i64], after: Option<i64>, up_to: Option<i64>) -> bool {
+    let first = match after {
+        Some(after) => sorted_targets.partition_point(|&rowid| rowid <= after),
+        None => 0,
+    };
+    match (sorted_targets.get(first), up_to) {
+        (Some(&rowid), Some(up_to)) => rowid <= up_to,
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{index_max_local, local_payload_len, table_max_local, Database};
+    use crate::record::Value;
+    use crate::testing::{int, one_table, query, TestDatabase};
+    use crate::{backup, copy};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn payload_thresholds_match_sqlite() {
+        assert_eq!(table_max_local(4096), 4061);
+        assert_eq!(index_max_local(4096), 1002);
+        assert_eq!(table_max_local(512), 477);
+        assert_eq!(index_max_local(512), 102);
+
+        let table = |len| local_payload_len(len, 4096, table_max_local(4096));
+        assert_eq!(table(4061), 4061);
+        // The tail past the minimum of 489 would fill no page, so only the
+        // minimum stays.
+        assert_eq!(table(4062), 489);
+        assert_eq!(table(10_000), 489 + (10_000 - 489) % 4092);
+
+        let index = |len| local_payload_len(len, 4096, index_max_local(4096));
+        assert_eq!(index(1002), 1002);
+        assert_eq!(index(1003), 489);
+        assert_eq!(index(5000), 489 + (5000 - 489) % 4092);
+        // A reserved region at the end of each page shrinks them all.
+        assert_eq!(local_payload_len(1000, 4076, index_max_local(4076)), 486);
+    }
+
+    #[test]
+    fn reads_values_around_the_overflow_thresholds() {
+        // Around where a table cell and an index cell first spill, and
+        // where the spill first takes a second overflow page.
+        let lengths: Vec<usize> = [90..=110, 465..=485, 540..=550, 975..=1000]
+            .into_iter()
+            .flatten()
+            .collect();
+        let text = |i: usize, len: usize| format!("{:04}{}", i, "x".repeat(len - 4));
+        let image = TestDatabase::new()
+            .page_size(512)
+            .table(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT, b BLOB)",
+                lengths.iter().enumerate().map(|(i, &len)| {
+                    vec![
+                        Value::Int(i as i64),
+                        Value::Text(text(i, len).into()),
+                        Value::Blob(vec![i as u8; len]),
+                    ]
+                }),
+            )
+            .index("CREATE INDEX t_v ON t (v)")
+            .build()
+            .unwrap();
+
+        let rows = query(&image, "SELECT id, v, b FROM t").unwrap();
+        assert_eq!(rows.len(), lengths.len());
+        for (i, &len) in lengths.iter().enumerate() {
+            assert_eq!(
+                rows[i],
+                vec![
+                    Value::Int(i as i64),
+                    Value::Text(text(i, len).into()),
+                    Value::Blob(vec![i as u8; len]),
+                ]
+            );
+            // Found by comparing against keys that overflow the index page.
+            let sql = format!("SELECT id FROM t WHERE v = '{}'", text(i, len));
+            assert_eq!(
+                query(&image, &sql).unwrap(),
+                vec![vec![Value::Int(i as i64)]]
+            );
+        }
+    }
+
+    #[test]
+    fn read_only_connections_write_nothing() {
+        let dir = std::env::temp_dir();
+        let name = |suffix: &str| {
+            dir.join(format!("sequel-readonly-{}-{}", std::process::id(), suffix))
+                .to_string_lossy()
+                .into_owned()
+        };
+        let path = name("source.db");
+        fs::write(&path, one_table("CREATE TABLE t (a)", [vec![int(1)]])).unwrap();
+
+        let mut db = Database::open_read_only(&path).unwrap();
+        assert!(db.is_read_only());
+        let backup_path = name("backup.db");
+        assert!(backup::backup(&mut db, &backup_path).is_err());
+        let copy_path = name("copy.db");
+        assert!(copy::copy_table(&mut db, &copy_path, "t", false).is_err());
+        assert!(!Path::new(&backup_path).exists() && !Path::new(&copy_path).exists());
+
+        // Opened normally, the same file backs up.
+        let mut db = Database::open(&path).unwrap();
+        backup::backup(&mut db, &backup_path).unwrap();
+        for path in [path, backup_path] {
+            fs::remove_file(path).unwrap();
+        }
     }
 }
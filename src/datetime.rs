@@ -0,0 +1,738 @@
+use crate::printf;
+use crate::record::Value;
+use anyhow::{bail, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds from the start of the julian day count (noon, 24 November
+/// 4714 BC) to 1970-01-01 00:00:00.
+const UNIX_EPOCH_MS: i64 = 210_866_760_000_000;
+/// The last millisecond of 9999-12-31, the latest time SQLite handles.
+const MAX_MS: i64 = 464_269_060_799_999;
+const DAY_MS: i64 = 86_400_000;
+
+/// A point in time as SQLite's date functions work it out: a julian day
+/// number in milliseconds, or the calendar date and time of day it is
+/// computed from, whichever is known. A plain number stays "raw" until the
+/// first modifier says whether it counts days or seconds.
+#[derive(Debug, Default, Clone)]
+pub struct DateTime {
+    ms: i64,
+    year: i32,
+    month: i32,
+    day: i32,
+    hour: i32,
+    minute: i32,
+    second: f64,
+    /// Offset from UTC in minutes, from a `+HH:MM` suffix.
+    tz: i32,
+    /// Days the last month or year shift rolled past the end of a month,
+    /// which `floor` takes back.
+    overflow_days: i32,
+    raw: Option<f64>,
+    valid_ms: bool,
+    valid_date: bool,
+    valid_time: bool,
+    /// Set when a computation leaves the range SQLite handles, which makes
+    /// the whole result NULL however later modifiers move it.
+    is_error: bool,
+    /// Whether `subsec` asked for milliseconds in the output.
+    pub subsec: bool,
+}
+
+impl DateTime {
+    /// The time described by a date function's arguments: a time value, then
+    /// modifiers applied left to right. No arguments means `'now'`. `None`
+    /// if any argument is NULL or can't be understood, which the functions
+    /// turn into NULL as SQLite does.
+    pub fn from_args(args: &[Value]) -> Result<Option<Self>> {
+        let Some((time, modifiers)) = args.split_first() else {
+            return Ok(Some(DateTime::now()));
+        };
+        let mut date = match time {
+            Value::Null | Value::Blob(_) => return Ok(None),
+            Value::Int(i) => DateTime::from_number(*i as f64),
+            Value::Float(f) => DateTime::from_number(*f),
+            Value::Text(text) => match DateTime::parse(text) {
+                Some(date) => date,
+                None => return Ok(None),
+            },
+        };
+        for (i, modifier) in modifiers.iter().enumerate() {
+            let Value::Text(modifier) = modifier else {
+                return Ok(None);
+            };
+            if !date.apply(modifier, i == 0)? {
+                return Ok(None);
+            }
+        }
+        date.compute_ms();
+        if modifiers.is_empty() && date.day > 28 {
+            // A day past the end of its month, as in 2023-02-31, rolls over
+            // into the next.
+            date.valid_date = false;
+        }
+        Ok((!date.is_error && date.valid_ms && (0..=MAX_MS).contains(&date.ms)).then_some(date))
+    }
+
+    fn now() -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        DateTime::from_ms(UNIX_EPOCH_MS + since_epoch)
+    }
+
+    fn from_ms(ms: i64) -> Self {
+        DateTime {
+            ms,
+            valid_ms: true,
+            ..DateTime::default()
+        }
+    }
+
+    /// A number is a julian day if it can be one; modifiers may still
+    /// reinterpret it as unix seconds.
+    fn from_number(number: f64) -> Self {
+        let mut date = DateTime {
+            raw: Some(number),
+            ..DateTime::default()
+        };
+        if (0.0..5_373_484.5).contains(&number) {
+            date.ms = (number * DAY_MS as f64 + 0.5) as i64;
+            date.valid_ms = true;
+        }
+        date
+    }
+
+    /// Parses `YYYY-MM-DD`, `YYYY-MM-DD HH:MM[:SS[.SSS]]` (with `T` allowed
+    /// between them and an optional `Z` or `±HH:MM` zone), `HH:MM[:SS]`,
+    /// `now`, or a julian day number written as text.
+    fn parse(text: &str) -> Option<Self> {
+        let mut date = DateTime::default();
+        if date.parse_date(text) || date.parse_time(text) {
+            return Some(date);
+        }
+        if text.eq_ignore_ascii_case("now") {
+            return Some(DateTime::now());
+        }
+        if text.eq_ignore_ascii_case("subsec") || text.eq_ignore_ascii_case("subsecond") {
+            return Some(DateTime {
+                subsec: true,
+                ..DateTime::now()
+            });
+        }
+        text.trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|number| number.is_finite())
+            .map(DateTime::from_number)
+    }
+
+    fn parse_date(&mut self, text: &str) -> bool {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let bytes = text.as_bytes();
+        if bytes.len() < 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+            return false;
+        }
+        let (Some(year), Some(month), Some(day), Some(rest)) = (
+            digits(text, 0..4, 0, 9999),
+            digits(text, 5..7, 1, 12),
+            digits(text, 8..10, 1, 31),
+            text.get(10..),
+        ) else {
+            return false;
+        };
+        let rest = rest.trim_start_matches([' ', '\t', 'T']);
+        if !rest.is_empty() && !self.parse_time(rest) {
+            return false;
+        }
+        self.year = if negative { -year } else { year };
+        self.month = month;
+        self.day = day;
+        self.valid_date = true;
+        self.valid_ms = false;
+        true
+    }
+
+    fn parse_time(&mut self, text: &str) -> bool {
+        let bytes = text.as_bytes();
+        if bytes.len() < 5 || bytes[2] != b':' {
+            return false;
+        }
+        let (Some(hour), Some(minute), Some(mut rest)) = (
+            digits(text, 0..2, 0, 24),
+            digits(text, 3..5, 0, 59),
+            text.get(5..),
+        ) else {
+            return false;
+        };
+        let mut second = 0.0;
+        if let Some(after) = rest.strip_prefix(':') {
+            let (Some(whole), Some(after)) = (digits(after, 0..2, 0, 59), after.get(2..)) else {
+                return false;
+            };
+            second = whole as f64;
+            rest = after;
+            if let Some(fraction) = rest.strip_prefix('.') {
+                let length = fraction.bytes().take_while(u8::is_ascii_digit).count();
+                if length > 0 {
+                    // Like SQLite, which keeps milliseconds only and
+                    // won't let them round up into the next second.
+                    second += format!("0.{}", &fraction[..length])
+                        .parse::<f64>()
+                        .unwrap_or(0.0)
+                        .min(0.999);
+                    rest = &fraction[length..];
+                }
+            }
+        }
+        let Some(tz) = parse_timezone(rest) else {
+            return false;
+        };
+        self.hour = hour;
+        self.minute = minute;
+        self.second = second;
+        self.tz = tz;
+        self.valid_time = true;
+        self.valid_ms = false;
+        true
+    }
+
+    /// Applies one modifier. `first` says whether it directly follows the
+    /// time value, the only place `unixepoch`, `julianday` and `auto` may
+    /// go. Returns `false` for a modifier SQLite would reject, making the
+    /// result NULL.
+    fn apply(&mut self, modifier: &str, first: bool) -> Result<bool> {
+        let lower = modifier.to_ascii_lowercase();
+        match lower.as_str() {
+            "unixepoch" => {
+                let Some(seconds) = self.raw.filter(|_| first) else {
+                    return Ok(false);
+                };
+                let ms = seconds * 1000.0 + UNIX_EPOCH_MS as f64;
+                if !(0.0..(MAX_MS + 1) as f64).contains(&ms) {
+                    return Ok(false);
+                }
+                *self = DateTime {
+                    subsec: self.subsec,
+                    ..DateTime::from_ms((ms + 0.5) as i64)
+                };
+                return Ok(true);
+            }
+            "julianday" => {
+                if !first || self.raw.is_none() || !self.valid_ms {
+                    return Ok(false);
+                }
+                self.raw = None;
+                return Ok(true);
+            }
+            "auto" => {
+                if !first {
+                    return Ok(false);
+                }
+                // A number that isn't a julian day is unix seconds, as long
+                // as those land between 4713 BC and 9999.
+                match self.raw {
+                    Some(seconds) if !self.valid_ms => {
+                        if (-210_866_760_000.0..=253_402_300_799.0).contains(&seconds) {
+                            let ms = seconds * 1000.0 + UNIX_EPOCH_MS as f64;
+                            *self = DateTime {
+                                subsec: self.subsec,
+                                ..DateTime::from_ms((ms + 0.5) as i64)
+                            };
+                        }
+                    }
+                    _ => self.raw = None,
+                }
+                return Ok(true);
+            }
+            "subsec" | "subsecond" => {
+                self.subsec = true;
+                return Ok(true);
+            }
+            "ceiling" => {
+                self.overflow_days = 0;
+                return Ok(true);
+            }
+            "floor" => {
+                if self.overflow_days > 0 {
+                    self.compute_ms();
+                    self.ms -= self.overflow_days as i64 * DAY_MS;
+                    self.clear_date_and_time();
+                }
+                self.overflow_days = 0;
+                return Ok(true);
+            }
+            "localtime" | "utc" => bail!(
+                "The '{}' modifier is not supported: sequel has no time zone database, so all times are UTC",
+                modifier
+            ),
+            _ => {}
+        }
+        // A number taken as a julian day is settled; one that can't be
+        // stays raw, so shifting it is an error rather than a shift of
+        // 2000-01-01.
+        if self.valid_ms {
+            self.raw = None;
+        }
+
+        if let Some(unit) = lower.strip_prefix("start of ") {
+            if !self.valid_ms && !self.valid_date && !self.valid_time {
+                return Ok(false);
+            }
+            self.compute_date_and_time();
+            match unit {
+                "month" => self.day = 1,
+                "year" => {
+                    self.month = 1;
+                    self.day = 1;
+                }
+                "day" => {}
+                _ => return Ok(false),
+            }
+            self.hour = 0;
+            self.minute = 0;
+            self.second = 0.0;
+            self.tz = 0;
+            self.valid_time = true;
+            self.valid_ms = false;
+            return Ok(true);
+        }
+
+        if let Some(weekday) = lower.strip_prefix("weekday ") {
+            let Some(weekday) = weekday
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|n| (0.0..7.0).contains(n) && n.fract() == 0.0)
+            else {
+                return Ok(false);
+            };
+            self.compute_date_and_time();
+            self.tz = 0;
+            self.valid_ms = false;
+            self.compute_ms();
+            let mut today = ((self.ms + 129_600_000) / DAY_MS) % 7;
+            if today > weekday as i64 {
+                today -= 7;
+            }
+            self.ms += (weekday as i64 - today) * DAY_MS;
+            self.clear_date_and_time();
+            return Ok(true);
+        }
+
+        Ok(self.shift(modifier))
+    }
+
+    /// Applies `±HH:MM[:SS[.SSS]]` or `±N units` (days, hours, minutes,
+    /// seconds, months or years, with or without the `s`).
+    fn shift(&mut self, modifier: &str) -> bool {
+        let number_length = modifier
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| c == ':' || c.is_whitespace())
+            .map_or(modifier.len(), |(i, _)| i);
+        let (number, rest) = modifier.split_at(number_length);
+
+        if rest.starts_with(':') {
+            let (negative, time) = match modifier.as_bytes()[0] {
+                b'-' => (true, &modifier[1..]),
+                b'+' => (false, &modifier[1..]),
+                _ => (false, modifier),
+            };
+            let mut shift = DateTime::default();
+            if !shift.parse_time(time) {
+                return false;
+            }
+            let ms = (shift.hour as i64 * 3600 + shift.minute as i64 * 60) * 1000
+                + (shift.second * 1000.0 + 0.5) as i64;
+            self.compute_ms();
+            self.clear_date_and_time();
+            self.ms += if negative { -ms } else { ms };
+            return true;
+        }
+
+        let Ok(mut amount) = number.parse::<f64>() else {
+            return false;
+        };
+        let unit = rest.trim_start();
+        let unit = unit
+            .strip_suffix(['s', 'S'])
+            .unwrap_or(unit)
+            .to_ascii_lowercase();
+        let (limit, unit_seconds) = match unit.as_str() {
+            "second" => (4.6427e14, 1.0),
+            "minute" => (7.7379e12, 60.0),
+            "hour" => (1.2897e11, 3600.0),
+            "day" => (5_373_485.0, 86_400.0),
+            "month" => (176_546.0, 2_592_000.0),
+            "year" => (14_713.0, 31_536_000.0),
+            _ => return false,
+        };
+        if !(-limit < amount && amount < limit) {
+            return false;
+        }
+        self.overflow_days = 0;
+        self.compute_ms();
+        // Whole months and years move the calendar date, which may land
+        // past the end of a month and roll over; anything left over counts
+        // as 30-day months and 365-day years.
+        if unit == "month" || unit == "year" {
+            self.compute_date_and_time();
+            let whole = amount.trunc() as i32;
+            if unit == "month" {
+                self.month += whole;
+                let years = if self.month > 0 {
+                    (self.month - 1) / 12
+                } else {
+                    (self.month - 12) / 12
+                };
+                self.year += years;
+                self.month -= years * 12;
+                self.overflow_days = (self.day - days_in_month(self.year, self.month)).max(0);
+            } else {
+                self.year += whole;
+                self.overflow_days = (self.day - days_in_month(self.year, self.month)).max(0);
+            }
+            self.valid_ms = false;
+            amount -= amount.trunc();
+            self.compute_ms();
+        }
+        let rounder = if amount < 0.0 { -0.5 } else { 0.5 };
+        self.ms += (amount * 1000.0 * unit_seconds + rounder) as i64;
+        self.clear_date_and_time();
+        true
+    }
+
+    /// Gives up on the computation, as SQLite's `datetimeError` does.
+    fn set_error(&mut self) {
+        *self = DateTime {
+            is_error: true,
+            ..DateTime::default()
+        };
+    }
+
+    fn clear_date_and_time(&mut self) {
+        self.valid_date = false;
+        self.valid_time = false;
+        self.tz = 0;
+    }
+
+    /// Works out the julian day from the calendar date and time of day, which
+    /// default to 2000-01-01 and midnight.
+    fn compute_ms(&mut self) {
+        if self.valid_ms {
+            return;
+        }
+        let (mut year, mut month, day) = if self.valid_date {
+            (self.year, self.month, self.day)
+        } else {
+            (2000, 1, 1)
+        };
+        // A number that is neither a julian day nor taken as unix seconds
+        // has no date to shift.
+        if !(-4713..=9999).contains(&year) || self.raw.is_some() {
+            self.set_error();
+            return;
+        }
+        if month <= 2 {
+            year -= 1;
+            month += 12;
+        }
+        let a = year / 100;
+        let b = 2 - a + a / 4;
+        let x1 = 36525 * (year + 4716) / 100;
+        let x2 = 306001 * (month + 1) / 10000;
+        self.ms = (((x1 + x2 + day + b) as f64 - 1524.5) * DAY_MS as f64) as i64;
+        self.valid_ms = true;
+        if self.valid_time {
+            self.ms += self.hour as i64 * 3_600_000
+                + self.minute as i64 * 60_000
+                + (self.second * 1000.0 + 0.5) as i64;
+            if self.tz != 0 {
+                self.ms -= self.tz as i64 * 60_000;
+                self.clear_date_and_time();
+            }
+        }
+    }
+
+    /// Works out the calendar date and time of day from the julian day.
+    fn compute_date_and_time(&mut self) {
+        self.compute_ms();
+        if !self.valid_date {
+            if self.valid_ms {
+                if !(0..=MAX_MS).contains(&self.ms) {
+                    self.set_error();
+                    return;
+                }
+                let z = ((self.ms + DAY_MS / 2) / DAY_MS) as i32;
+                let alpha = ((z as f64 + 32044.75) / 36524.25) as i32 - 52;
+                let a = z + 1 + alpha - ((alpha + 100) / 4) + 25;
+                let b = a + 1524;
+                let c = ((b as f64 - 122.1) / 365.25) as i32;
+                let d = (36525 * (c & 32767)) / 100;
+                let e = ((b - d) as f64 / 30.6001) as i32;
+                let x1 = (30.6001 * e as f64) as i32;
+                self.day = b - d - x1;
+                self.month = if e < 14 { e - 1 } else { e - 13 };
+                self.year = if self.month > 2 { c - 4716 } else { c - 4715 };
+            } else {
+                (self.year, self.month, self.day) = (2000, 1, 1);
+            }
+            self.valid_date = true;
+        }
+        if !self.valid_time {
+            let day_ms = (self.ms + DAY_MS / 2) % DAY_MS;
+            self.second = (day_ms % 60_000) as f64 / 1000.0;
+            let day_minutes = (day_ms / 60_000) as i32;
+            self.minute = day_minutes % 60;
+            self.hour = day_minutes / 60;
+            self.valid_time = true;
+        }
+    }
+
+    /// The julian day number, with a fractional part for the time of day.
+    pub fn julian_day(&self) -> f64 {
+        self.ms as f64 / DAY_MS as f64
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 UTC, with milliseconds if `subsec`
+    /// was given.
+    pub fn unix_epoch(&self) -> Value {
+        let ms = self.ms - UNIX_EPOCH_MS;
+        if self.subsec {
+            Value::Float(ms as f64 / 1000.0)
+        } else {
+            Value::Int(ms.div_euclid(1000))
+        }
+    }
+
+    /// `YYYY-MM-DD HH:MM:SS`, with `.SSS` if `subsec` was given.
+    pub fn format_datetime(&self) -> String {
+        format!("{} {}", self.format_date(), self.format_time())
+    }
+
+    /// `YYYY-MM-DD`.
+    pub fn format_date(&self) -> String {
+        let mut date = self.clone();
+        date.compute_date_and_time();
+        let year = if date.year < 0 {
+            format!("-{:04}", -date.year)
+        } else {
+            format!("{:04}", date.year)
+        };
+        format!("{}-{:02}-{:02}", year, date.month, date.day)
+    }
+
+    /// `HH:MM:SS`, with `.SSS` if `subsec` was given.
+    pub fn format_time(&self) -> String {
+        let mut date = self.clone();
+        date.compute_date_and_time();
+        let second = if self.subsec {
+            format!("{:06.3}", date.second)
+        } else {
+            format!("{:02}", date.second as i32)
+        };
+        format!("{:02}:{:02}:{}", date.hour, date.minute, second)
+    }
+
+    /// `format` with SQLite's `strftime` conversions filled in, or `None` if
+    /// it uses one SQLite doesn't have.
+    pub fn strftime(&self, format: &str) -> Option<String> {
+        let mut date = self.clone();
+        date.compute_date_and_time();
+        // The Thursday of this ISO week, whose year the week belongs to.
+        let thursday = || {
+            let mut thursday = DateTime::from_ms(date.ms + (3 - date.days_after_monday()) * DAY_MS);
+            thursday.compute_date_and_time();
+            thursday
+        };
+        let twelve_hour = match date.hour % 12 {
+            0 => 12,
+            hour => hour,
+        };
+        let mut out = String::with_capacity(format.len());
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            let text = match chars.next()? {
+                'd' => format!("{:02}", date.day),
+                'e' => format!("{:2}", date.day),
+                'f' => format!("{:06.3}", date.second.min(59.999)),
+                'F' => format!("{:04}-{:02}-{:02}", date.year, date.month, date.day),
+                'G' => format!("{:04}", thursday().year),
+                'g' => format!("{:02}", thursday().year % 100),
+                'H' => format!("{:02}", date.hour),
+                'k' => format!("{:2}", date.hour),
+                'I' => format!("{:02}", twelve_hour),
+                'l' => format!("{:2}", twelve_hour),
+                'j' => format!("{:03}", date.days_after_jan01() + 1),
+                'J' => printf::format("%.16g", &[Value::Float(date.julian_day())]).ok()?,
+                'm' => format!("{:02}", date.month),
+                'M' => format!("{:02}", date.minute),
+                'p' => if date.hour >= 12 { "PM" } else { "AM" }.to_string(),
+                'P' => if date.hour >= 12 { "pm" } else { "am" }.to_string(),
+                'R' => format!("{:02}:{:02}", date.hour, date.minute),
+                's' if date.subsec => format!("{:.3}", (date.ms - UNIX_EPOCH_MS) as f64 / 1000.0),
+                's' => (date.ms / 1000 - UNIX_EPOCH_MS / 1000).to_string(),
+                'S' => format!("{:02}", date.second as i32),
+                'T' => format!(
+                    "{:02}:{:02}:{:02}",
+                    date.hour, date.minute, date.second as i32
+                ),
+                'u' => match date.days_after_sunday() {
+                    0 => "7".to_string(),
+                    day => day.to_string(),
+                },
+                'w' => date.days_after_sunday().to_string(),
+                'U' => format!(
+                    "{:02}",
+                    (date.days_after_jan01() - date.days_after_sunday() + 7) / 7
+                ),
+                'V' => format!("{:02}", thursday().days_after_jan01() / 7 + 1),
+                'W' => format!(
+                    "{:02}",
+                    (date.days_after_jan01() - date.days_after_monday() + 7) / 7
+                ),
+                'Y' => format!("{:04}", date.year),
+                '%' => "%".to_string(),
+                _ => return None,
+            };
+            out.push_str(&text);
+        }
+        Some(out)
+    }
+
+    /// Days since 1 January of the year, which is day 0.
+    fn days_after_jan01(&self) -> i64 {
+        let mut jan01 = DateTime {
+            month: 1,
+            day: 1,
+            raw: None,
+            valid_ms: false,
+            ..self.clone()
+        };
+        jan01.compute_ms();
+        (self.ms - jan01.ms + DAY_MS / 2) / DAY_MS
+    }
+
+    /// The day of the week, counting Monday as 0.
+    fn days_after_monday(&self) -> i64 {
+        ((self.ms + DAY_MS / 2) / DAY_MS) % 7
+    }
+
+    /// The day of the week, counting Sunday as 0.
+    fn days_after_sunday(&self) -> i64 {
+        ((self.ms + DAY_MS * 3 / 2) / DAY_MS) % 7
+    }
+}
+
+fn days_in_month(year: i32, month: i32) -> i32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parses the digits at `range` of `text`, checking they are within
+/// `min..=max`.
+fn digits(text: &str, range: std::ops::Range<usize>, min: i32, max: i32) -> Option<i32> {
+    let digits = text.get(range)?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok().filter(|n| (min..=max).contains(n))
+}
+
+/// Parses what may follow a time: nothing, `Z`, or `±HH:MM`, in minutes
+/// east of UTC.
+fn parse_timezone(text: &str) -> Option<i32> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Some(0);
+    }
+    if text.eq_ignore_ascii_case("z") {
+        return Some(0);
+    }
+    let sign = match text.as_bytes()[0] {
+        b'-' => -1,
+        b'+' => 1,
+        _ => return None,
+    };
+    let rest = &text[1..];
+    if rest.len() != 5 || rest.as_bytes()[2] != b':' {
+        return None;
+    }
+    let hours = digits(rest, 0..2, 0, 14)?;
+    let minutes = digits(rest, 3..5, 0, 59)?;
+    Some(sign * (hours * 60 + minutes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::Value;
+    use crate::testing::{int, real, select, text};
+
+    #[test]
+    fn formats_dates_and_times() {
+        assert_eq!(select("time('12:34:56.789')"), text("12:34:56"));
+        assert_eq!(select("date(2460000.5)"), text("2023-02-25"));
+        assert_eq!(
+            select("datetime('2024-06-01T10:00:00+02:00')"),
+            text("2024-06-01 08:00:00")
+        );
+        assert_eq!(
+            select("strftime('%Y-%j %H:%M:%f %w %s', '2024-12-31 23:59:59.5')"),
+            text("2024-366 23:59:59.500 2 1735689599")
+        );
+        assert_eq!(select("julianday('2000-01-01 12:00')"), real(2451545.0));
+        assert_eq!(select("unixepoch('1970-01-02')"), int(86400));
+    }
+
+    #[test]
+    fn applies_modifiers() {
+        assert_eq!(select("date('2024-02-29', '+1 year')"), text("2025-03-01"));
+        assert_eq!(
+            select("datetime('2024-01-31 12:00', '+1 month')"),
+            text("2024-03-02 12:00:00")
+        );
+        assert_eq!(
+            select("date('2024-03-15', 'start of month', '-1 day')"),
+            text("2024-02-29")
+        );
+        assert_eq!(
+            select("date('2024-05-31', '-1 month', 'start of year')"),
+            text("2024-01-01")
+        );
+        assert_eq!(
+            select("date('2024-01-01', 'weekday 0')"),
+            text("2024-01-07")
+        );
+        assert_eq!(select("time('10:00', '-30 minutes')"), text("09:30:00"));
+        assert_eq!(
+            select("datetime('2024-01-01', '+1.5 days')"),
+            text("2024-01-02 12:00:00")
+        );
+        assert_eq!(
+            select("datetime(1700000000, 'unixepoch')"),
+            text("2023-11-14 22:13:20")
+        );
+    }
+
+    #[test]
+    fn out_of_range_is_null() {
+        // An impossible day rolls over, but an impossible month doesn't.
+        assert_eq!(select("date('2024-02-30')"), text("2024-03-01"));
+        assert_eq!(select("date('2024-13-01')"), Value::Null);
+        assert_eq!(select("date('9999-12-31', '+1 day')"), Value::Null);
+        assert_eq!(select("date(-1, '+1.5 hours')"), Value::Null);
+        assert_eq!(select("julianday(1e10, '+10 years')"), Value::Null);
+    }
+}
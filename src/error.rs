@@ -0,0 +1,61 @@
+use crate::budget::ResultTooLarge;
+use crate::interrupt::Aborted;
+
+/// Error categories callers may want to tell apart. Each one maps to its own
+/// process exit code, so scripts can react without parsing messages.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Bad command-line arguments or dot-command usage.
+    #[error("{0}")]
+    Usage(String),
+    /// SQL that doesn't parse or isn't supported.
+    #[error("{0}")]
+    Parse(String),
+    /// A table or column the statement refers to doesn't exist.
+    #[error("{0}")]
+    NotFound(String),
+    /// The file isn't a SQLite database, or its contents break the format.
+    #[error("{0}")]
+    Corrupt(String),
+}
+
+/// Like `bail!`, for file contents that don't follow the SQLite format.
+macro_rules! corrupt {
+    ($($arg:tt)*) => {
+        return Err($crate::error::Error::Corrupt(format!($($arg)*)).into())
+    };
+}
+pub(crate) use corrupt;
+
+pub const EXIT_SUCCESS: i32 = 0;
+/// Anything not covered below.
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_PARSE: i32 = 3;
+pub const EXIT_NOT_FOUND: i32 = 4;
+pub const EXIT_CORRUPT: i32 = 5;
+pub const EXIT_IO: i32 = 6;
+/// Interrupted, timed out, or over the memory limit.
+pub const EXIT_ABORTED: i32 = 7;
+
+/// The exit code for an error, decided by the first categorized error in its
+/// chain, outermost first.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<Error>() {
+            return match error {
+                Error::Usage(_) => EXIT_USAGE,
+                Error::Parse(_) => EXIT_PARSE,
+                Error::NotFound(_) => EXIT_NOT_FOUND,
+                Error::Corrupt(_) => EXIT_CORRUPT,
+            };
+        }
+        if cause.is::<Aborted>() || cause.is::<ResultTooLarge>() {
+            return EXIT_ABORTED;
+        }
+        if cause.is::<std::io::Error>() {
+            return EXIT_IO;
+        }
+    }
+    EXIT_ERROR
+}
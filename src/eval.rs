@@ -0,0 +1,583 @@
+use crate::collation::Collation;
+use crate::functions::UserFunctions;
+use crate::parser::{ArithmeticOp, CompareOp, Expr};
+use crate::record::{format_real, Value};
+use crate::schema::{Affinity, ColumnDef};
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+
+/// A record together with the column definitions used to resolve `Expr::Column`,
+/// the functions registered on the connection, and the values bound to the
+/// statement's parameters.
+pub struct Row<'a> {
+    pub columns: &'a [ColumnDef],
+    pub values: &'a [Value],
+    pub functions: &'a UserFunctions,
+    pub parameters: &'a [Value],
+}
+
+impl Row<'_> {
+    pub(crate) fn position(&self, name: &str) -> Result<usize> {
+        match self.columns.iter().position(|column| column.is_named(name)) {
+            Some(index) => Ok(index),
+            None => bail!("Column '{}' not found", name),
+        }
+    }
+
+    fn get(&self, name: &str) -> Result<Value> {
+        let index = self.position(name)?;
+        Ok(self.values.get(index).cloned().unwrap_or(Value::Null))
+    }
+}
+
+/// Evaluates an expression against a row. Boolean results follow SQLite and are
+/// represented as `Int(1)`/`Int(0)`, with `Null` standing for "unknown".
+pub fn evaluate(expr: &Expr, row: &Row) -> Result<Value> {
+    match expr {
+        Expr::Column(name) => row.get(name),
+        Expr::Literal(value) | Expr::OuterColumn { value, .. } => Ok(value.clone()),
+        Expr::Parameter(number) => Ok(row
+            .parameters
+            .get(number - 1)
+            .cloned()
+            .unwrap_or(Value::Null)),
+        Expr::Compare { left, op, right } => {
+            let left_affinity = affinity_of(left, row);
+            let right_affinity = affinity_of(right, row);
+            let collation = comparison_collation(left, right, row)?;
+            let left = evaluate(left, row)?;
+            let right = evaluate(right, row)?;
+            let (left, right) =
+                apply_comparison_affinity(left, left_affinity, right, right_affinity);
+            Ok(from_truth(
+                compare_collated(&left, &right, &collation).map(|ordering| op.holds(ordering)),
+            ))
+        }
+        Expr::Like {
+            left,
+            pattern,
+            escape,
+        } => {
+            let (Some(text), Some(pattern)) = (
+                like_operand(evaluate(left, row)?),
+                like_operand(evaluate(pattern, row)?),
+            ) else {
+                return Ok(Value::Null);
+            };
+            Ok(from_truth(Some(like(&pattern, &text, *escape))))
+        }
+        Expr::In { left, list } => {
+            let left_affinity = affinity_of(left, row);
+            let collation = row.functions.collation(
+                explicit_collation(left).or_else(|| column_collation(left, row).flatten()),
+            )?;
+            let left = evaluate(left, row)?;
+            // Like a chain of `=` joined by OR: a match wins, and failing
+            // that any NULL makes the answer unknown. The items are compared
+            // as `left = +item`, without an affinity of their own.
+            let mut result = Some(false);
+            for item in list {
+                let (left, item) = apply_comparison_affinity(
+                    left.clone(),
+                    left_affinity,
+                    evaluate(item, row)?,
+                    None,
+                );
+                result = or(
+                    result,
+                    compare_collated(&left, &item, &collation).map(Ordering::is_eq),
+                );
+                if result == Some(true) {
+                    break;
+                }
+            }
+            Ok(from_truth(result))
+        }
+        Expr::Arithmetic { left, op, right } => {
+            Ok(arithmetic(*op, evaluate(left, row)?, evaluate(right, row)?))
+        }
+        Expr::And(left, right) => {
+            let left = truth(&evaluate(left, row)?);
+            if left == Some(false) {
+                return Ok(from_truth(Some(false)));
+            }
+            let right = truth(&evaluate(right, row)?);
+            Ok(from_truth(and(left, right)))
+        }
+        Expr::Or(left, right) => {
+            let left = truth(&evaluate(left, row)?);
+            if left == Some(true) {
+                return Ok(from_truth(Some(true)));
+            }
+            let right = truth(&evaluate(right, row)?);
+            Ok(from_truth(or(left, right)))
+        }
+        Expr::Not(inner) => Ok(from_truth(truth(&evaluate(inner, row)?).map(|b| !b))),
+        Expr::IsNull(operand) => Ok(from_truth(Some(matches!(
+            evaluate(operand, row)?,
+            Value::Null
+        )))),
+        // Running a subquery takes the database; the query around it replaces
+        // it with its result before evaluating.
+        Expr::Exists(_) => bail!("EXISTS subquery was not run"),
+        Expr::InSubquery { .. } => bail!("IN subquery was not run"),
+        Expr::Function { name, args } => {
+            let args = args
+                .iter()
+                .map(|arg| evaluate(arg, row))
+                .collect::<Result<Vec<_>>>()?;
+            row.functions.call(name, args)
+        }
+        Expr::Collate { operand, .. } => evaluate(operand, row),
+        // Worked out over all the rows by the query, which replaces it with
+        // its value for the row first; anywhere else, it can't be.
+        Expr::Window { name, .. } => bail!("Misuse of window function {}()", name),
+    }
+}
+
+/// Evaluates a WHERE predicate. Only a definite true keeps the row; false and
+/// unknown (NULL) both exclude it.
+pub fn matches(expr: &Expr, row: &Row) -> Result<bool> {
+    Ok(truth(&evaluate(expr, row)?) == Some(true))
+}
+
+/// Checks that every column referenced by the expression exists, so typos are
+/// reported even when the table has no rows.
+pub fn check_columns(expr: &Expr, columns: &[ColumnDef]) -> Result<()> {
+    match expr {
+        Expr::Column(name) => {
+            if !columns.iter().any(|column| column.is_named(name)) {
+                bail!("Column '{}' not found", name);
+            }
+            Ok(())
+        }
+        Expr::Literal(_) | Expr::OuterColumn { .. } | Expr::Parameter(_) => Ok(()),
+        Expr::Compare { left, right, .. }
+        | Expr::Arithmetic { left, right, .. }
+        | Expr::And(left, right)
+        | Expr::Or(left, right) => {
+            check_columns(left, columns)?;
+            check_columns(right, columns)
+        }
+        Expr::Like { left, pattern, .. } => {
+            check_columns(left, columns)?;
+            check_columns(pattern, columns)
+        }
+        Expr::In { left, list } => {
+            check_columns(left, columns)?;
+            list.iter()
+                .try_for_each(|item| check_columns(item, columns))
+        }
+        Expr::Not(inner) | Expr::IsNull(inner) => check_columns(inner, columns),
+        // Checked against both its own table and this one when prepared.
+        Expr::Exists(_) => Ok(()),
+        Expr::InSubquery { left, .. } => check_columns(left, columns),
+        Expr::Function { args, .. } => args.iter().try_for_each(|arg| check_columns(arg, columns)),
+        Expr::Collate { operand, .. } => check_columns(operand, columns),
+        Expr::Window {
+            args,
+            partition_by,
+            order_by,
+            ..
+        } => args
+            .iter()
+            .chain(partition_by)
+            .chain(order_by.iter().map(|term| &term.expr))
+            .try_for_each(|expr| check_columns(expr, columns)),
+    }
+}
+
+/// `left op right` the way SQLite computes it: NULL if either side is NULL,
+/// integer arithmetic when both sides are integers (falling back to REAL on
+/// overflow), REAL otherwise. Dividing by zero gives NULL, and `%` works on
+/// the integer parts of its operands.
+fn arithmetic(op: ArithmeticOp, left: Value, right: Value) -> Value {
+    let (left, right) = match (numeric_value(left), numeric_value(right)) {
+        (Some(left), Some(right)) => (left, right),
+        _ => return Value::Null,
+    };
+    if let (Value::Int(a), Value::Int(b)) = (&left, &right) {
+        let (a, b) = (*a, *b);
+        let exact = match op {
+            ArithmeticOp::Add => a.checked_add(b),
+            ArithmeticOp::Subtract => a.checked_sub(b),
+            ArithmeticOp::Multiply => a.checked_mul(b),
+            ArithmeticOp::Divide | ArithmeticOp::Remainder if b == 0 => return Value::Null,
+            ArithmeticOp::Divide => a.checked_div(b),
+            ArithmeticOp::Remainder => Some(a.wrapping_rem(b)),
+        };
+        if let Some(result) = exact {
+            return Value::Int(result);
+        }
+    }
+    let as_float = |value: &Value| match value {
+        Value::Int(i) => *i as f64,
+        Value::Float(f) => *f,
+        _ => 0.0,
+    };
+    let (a, b) = (as_float(&left), as_float(&right));
+    let result = match op {
+        ArithmeticOp::Add => a + b,
+        ArithmeticOp::Subtract => a - b,
+        ArithmeticOp::Multiply => a * b,
+        ArithmeticOp::Divide if b == 0.0 => return Value::Null,
+        ArithmeticOp::Divide => a / b,
+        ArithmeticOp::Remainder => {
+            let (a, b) = (a as i64, b as i64);
+            if b == 0 {
+                return Value::Null;
+            }
+            a.wrapping_rem(b) as f64
+        }
+    };
+    if result.is_nan() {
+        Value::Null
+    } else {
+        Value::Float(result)
+    }
+}
+
+/// The number an arithmetic operand stands for: text and blobs count as
+/// their longest numeric prefix (`'12abc'` is 12, `'abc'` is 0). `None` for
+/// NULL.
+pub(crate) fn numeric_value(value: Value) -> Option<Value> {
+    let text = match value {
+        Value::Null => return None,
+        Value::Int(_) | Value::Float(_) => return Some(value),
+        Value::Text(text) => text.to_string(),
+        Value::Blob(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+    let text = text.trim_start();
+    let bytes = text.as_bytes();
+    let digits_from = |start: usize| {
+        start
+            + bytes[start..]
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .count()
+    };
+    let sign = usize::from(matches!(bytes.first(), Some(b'+' | b'-')));
+    let mut end = digits_from(sign);
+    let mut has_digits = end > sign;
+    let mut is_real = false;
+    if bytes.get(end) == Some(&b'.') {
+        let fraction_end = digits_from(end + 1);
+        if has_digits || fraction_end > end + 1 {
+            has_digits = true;
+            is_real = true;
+            end = fraction_end;
+        }
+    }
+    if !has_digits {
+        return Some(Value::Int(0));
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let exponent_sign = usize::from(matches!(bytes.get(end + 1), Some(b'+' | b'-')));
+        let exponent_end = digits_from(end + 1 + exponent_sign);
+        if exponent_end > end + 1 + exponent_sign {
+            is_real = true;
+            end = exponent_end;
+        }
+    }
+    let number = &text[..end];
+    if !is_real {
+        if let Ok(i) = number.parse::<i64>() {
+            return Some(Value::Int(i));
+        }
+    }
+    Some(Value::Float(number.parse().unwrap_or(0.0)))
+}
+
+/// LIKE compares the text form of its operands; NULL makes it unknown.
+fn like_operand(value: Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Int(i) => Some(i.to_string()),
+        Value::Float(f) => Some(format_real(f)),
+        Value::Text(text) => Some(text.to_string()),
+        Value::Blob(bytes) => Some(String::from_utf8_lossy(&bytes).into_owned()),
+    }
+}
+
+impl CompareOp {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering == Ordering::Equal,
+            CompareOp::NotEq => ordering != Ordering::Equal,
+            CompareOp::Lt => ordering == Ordering::Less,
+            CompareOp::LtEq => ordering != Ordering::Greater,
+            CompareOp::Gt => ordering == Ordering::Greater,
+            CompareOp::GtEq => ordering != Ordering::Less,
+        }
+    }
+}
+
+fn truth(value: &Value) -> Option<bool> {
+    match value {
+        Value::Null => None,
+        Value::Int(i) => Some(*i != 0),
+        Value::Float(f) => Some(*f != 0.0),
+        Value::Text(s) => Some(s.trim().parse::<f64>().is_ok_and(|f| f != 0.0)),
+        Value::Blob(_) => Some(false),
+    }
+}
+
+fn from_truth(truth: Option<bool>) -> Value {
+    match truth {
+        Some(b) => Value::Int(b as i64),
+        None => Value::Null,
+    }
+}
+
+fn and(left: Option<bool>, right: Option<bool>) -> Option<bool> {
+    match (left, right) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+fn or(left: Option<bool>, right: Option<bool>) -> Option<bool> {
+    match (left, right) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+/// Only column references carry an affinity; literals have none. COLLATE
+/// keeps its operand's.
+fn affinity_of(expr: &Expr, row: &Row) -> Option<Affinity> {
+    match expr {
+        Expr::Column(name) => row
+            .position(name)
+            .ok()
+            .map(|index| row.columns[index].affinity),
+        Expr::OuterColumn { affinity, .. } => Some(*affinity),
+        Expr::Collate { operand, .. } => affinity_of(operand, row),
+        _ => None,
+    }
+}
+
+/// The collation a comparison uses: one named by COLLATE on the left operand,
+/// else on the right; failing that, the one declared for the left operand if
+/// it is a column, else for the right one if that is, else BINARY.
+fn comparison_collation(left: &Expr, right: &Expr, row: &Row) -> Result<Collation> {
+    let name = explicit_collation(left)
+        .or_else(|| explicit_collation(right))
+        .or_else(|| {
+            column_collation(left, row)
+                .or_else(|| column_collation(right, row))
+                .flatten()
+        });
+    row.functions.collation(name)
+}
+
+/// The collation named by the outermost COLLATE of `expr`, if it has one.
+pub fn explicit_collation(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::Collate { collation, .. } => Some(collation),
+        _ => None,
+    }
+}
+
+/// For a column reference, the collation it was declared with, if any.
+fn column_collation<'a>(expr: &'a Expr, row: &Row<'a>) -> Option<Option<&'a str>> {
+    match expr {
+        Expr::Column(name) => row
+            .position(name)
+            .ok()
+            .map(|index| row.columns[index].collation.as_deref()),
+        Expr::OuterColumn { collation, .. } => Some(collation.as_deref()),
+        _ => None,
+    }
+}
+
+/// Before comparing, SQLite converts operands according to their affinities
+/// (https://www.sqlite.org/datatype3.html#type_conversions_prior_to_comparison):
+/// a numeric operand pulls a TEXT/BLOB/affinity-less operand towards a number,
+/// and a TEXT operand pulls an affinity-less operand towards text.
+fn apply_comparison_affinity(
+    left: Value,
+    left_affinity: Option<Affinity>,
+    right: Value,
+    right_affinity: Option<Affinity>,
+) -> (Value, Value) {
+    let is_numeric = |affinity: Option<Affinity>| affinity.is_some_and(Affinity::is_numeric);
+    let is_text_or_none = |affinity: Option<Affinity>| {
+        matches!(affinity, None | Some(Affinity::Text) | Some(Affinity::Blob))
+    };
+
+    if is_numeric(left_affinity) && is_text_or_none(right_affinity) {
+        (left, apply_numeric_affinity(right))
+    } else if is_numeric(right_affinity) && is_text_or_none(left_affinity) {
+        (apply_numeric_affinity(left), right)
+    } else if left_affinity == Some(Affinity::Text) && right_affinity.is_none() {
+        (left, apply_text_affinity(right))
+    } else if right_affinity == Some(Affinity::Text) && left_affinity.is_none() {
+        (apply_text_affinity(left), right)
+    } else {
+        (left, right)
+    }
+}
+
+/// Converts text that looks like a number into an INTEGER or REAL; anything
+/// else is left untouched.
+pub fn apply_numeric_affinity(value: Value) -> Value {
+    if let Value::Text(text) = &value {
+        if let Some(number) = parse_numeric_text(text) {
+            return number;
+        }
+    }
+    value
+}
+
+/// Converts a number into the text SQLite stores it as in a TEXT column;
+/// anything else is left untouched.
+pub fn apply_text_affinity(value: Value) -> Value {
+    match value {
+        Value::Int(i) => Value::Text(i.to_string().into()),
+        Value::Float(f) => Value::Text(format_real(f).into()),
+        other => other,
+    }
+}
+
+fn parse_numeric_text(text: &str) -> Option<Value> {
+    let trimmed = text.trim();
+    if trimmed.is_empty()
+        || !trimmed.chars().any(|c| c.is_ascii_digit())
+        || !trimmed
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '+' | '-' | '.' | 'e' | 'E'))
+    {
+        return None;
+    }
+    if let Ok(i) = trimmed.parse::<i64>() {
+        return Some(Value::Int(i));
+    }
+    trimmed.parse::<f64>().ok().map(Value::Float)
+}
+
+/// SQL `LIKE`: `%` matches any run of characters and `_` exactly one, and
+/// ASCII letters match regardless of case, as in SQLite's built-in LIKE. A
+/// character after `escape` is taken literally.
+pub fn like(pattern: &str, text: &str, escape: Option<char>) -> bool {
+    enum Token {
+        AnyRun,
+        AnyOne,
+        Literal(char),
+    }
+
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            c if Some(c) == escape => match chars.next() {
+                Some(escaped) => Token::Literal(escaped),
+                None => return false,
+            },
+            '%' => Token::AnyRun,
+            '_' => Token::AnyOne,
+            c => Token::Literal(c),
+        });
+    }
+
+    let text: Vec<char> = text.chars().collect();
+    let (mut t, mut p) = (0, 0);
+    // Where to resume after the most recent `%` if the current attempt fails.
+    let mut backtrack = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(Token::AnyRun) => {
+                p += 1;
+                backtrack = Some((p, t));
+                continue;
+            }
+            Some(Token::AnyOne) => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(Token::Literal(c)) if c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((resume_p, resume_t)) => {
+                p = resume_p;
+                t = resume_t + 1;
+                backtrack = Some((resume_p, resume_t + 1));
+            }
+            None => return false,
+        }
+    }
+    tokens[p..]
+        .iter()
+        .all(|token| matches!(token, Token::AnyRun))
+}
+
+/// Orders two values the way SQLite does: NULL < INTEGER/REAL < TEXT < BLOB,
+/// with integers and reals compared numerically. Returns `None` when either side
+/// is NULL, since such comparisons are unknown.
+pub fn compare_values(left: &Value, right: &Value) -> Option<Ordering> {
+    compare_collated(left, right, &Collation::Binary)
+}
+
+/// `compare_values`, with two text values compared by `collation`.
+pub fn compare_collated(left: &Value, right: &Value, collation: &Collation) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Null, _) | (_, Value::Null) => None,
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::Float(a), Value::Float(b)) => Some(a.partial_cmp(b).unwrap_or(Ordering::Equal)),
+        (Value::Int(a), Value::Float(b)) => Some(compare_int_float(*a, *b)),
+        (Value::Float(a), Value::Int(b)) => Some(compare_int_float(*b, *a).reverse()),
+        (Value::Text(a), Value::Text(b)) => Some(collation.compare(a, b)),
+        (Value::Blob(a), Value::Blob(b)) => Some(a.cmp(b)),
+        _ => Some(storage_class_rank(left).cmp(&storage_class_rank(right))),
+    }
+}
+
+/// Index and ORDER BY order: `compare_values`, with NULL sorting first
+/// instead of being incomparable.
+pub fn total_order(a: &Value, b: &Value) -> Ordering {
+    total_order_collated(a, b, &Collation::Binary)
+}
+
+/// `total_order`, with two text values compared by `collation`.
+pub fn total_order_collated(a: &Value, b: &Value, collation: &Collation) -> Ordering {
+    compare_collated(a, b, collation)
+        .unwrap_or_else(|| matches!(b, Value::Null).cmp(&matches!(a, Value::Null)))
+}
+
+fn storage_class_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Int(_) | Value::Float(_) => 1,
+        Value::Text(_) => 2,
+        Value::Blob(_) => 3,
+    }
+}
+
+/// Compares without converting the integer to f64, which would lose precision
+/// above 2^53.
+fn compare_int_float(int: i64, float: f64) -> Ordering {
+    if float.is_nan() {
+        return Ordering::Greater;
+    }
+    if float < -9.223_372_036_854_776e18 {
+        return Ordering::Greater;
+    }
+    if float >= 9.223_372_036_854_776e18 {
+        return Ordering::Less;
+    }
+    let truncated = float.trunc();
+    match int.cmp(&(truncated as i64)) {
+        Ordering::Equal => 0.0
+            .partial_cmp(&(float - truncated))
+            .unwrap_or(Ordering::Equal),
+        other => other,
+    }
+}
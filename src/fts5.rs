@@ -0,0 +1,854 @@
+use crate::database::{Database, SchemaEntry};
+use crate::error::{corrupt, Error};
+use crate::query::{find_table, ROWID_NAMES};
+use crate::record::{read_varint, Value};
+use crate::schema::{parse_create_table, unquote, ColumnDef, TableDef};
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+
+/// Rowid of the `_data` record that lists the index's segments.
+const STRUCTURE_ROWID: i64 = 10;
+
+/// An FTS5 virtual table. SQLite keeps its rows in shadow tables: each
+/// document in `<name>_content` under its rowid, and the full-text index as
+/// blocks in `<name>_data`.
+#[derive(Debug, Clone)]
+pub struct FullTextTable {
+    /// The rows of `<name>_content` as a query sees them: the rowid, then
+    /// its own columns, with `c0, c1, ...` renamed to the table's and its
+    /// `id` nameless.
+    pub table_def: TableDef,
+    pub content_root: u32,
+    /// Root page of `<name>_data`.
+    pub data_root: u32,
+    /// Whether the index was built by the `ascii` tokenizer rather than
+    /// `unicode61`.
+    ascii: bool,
+}
+
+impl FullTextTable {
+    /// Parses the right-hand side of `MATCH`: terms (`term*` for a prefix),
+    /// joined by `AND` (or nothing), `OR` and `NOT`, with parentheses. The
+    /// terms go through the table's tokenizer, so they match the same way
+    /// the indexed text does.
+    pub fn parse_query(&self, text: &str) -> Result<MatchQuery> {
+        let tokens = query_tokens(text)?;
+        let mut parser = QueryParser {
+            tokens,
+            pos: 0,
+            ascii: self.ascii,
+        };
+        let query = parser.parse_or()?;
+        if parser.pos < parser.tokens.len() {
+            bail!("fts5: syntax error near '{}'", parser.tokens[parser.pos]);
+        }
+        Ok(query)
+    }
+}
+
+/// The FTS5 table called `name`, or `None` if `name` is an ordinary table.
+/// Other virtual tables, and FTS5 options that change where the text lives
+/// or how it is split into terms, are reported as unsupported.
+pub fn full_text_table(schema: &[SchemaEntry], name: &str) -> Result<Option<FullTextTable>> {
+    let entry = find_table(schema, name)?;
+    let Some(sql) = entry.sql.as_deref() else {
+        return Ok(None);
+    };
+    let words: Vec<&str> = sql.split_whitespace().take(3).collect();
+    if !(words.len() == 3
+        && words[0].eq_ignore_ascii_case("create")
+        && words[1].eq_ignore_ascii_case("virtual")
+        && words[2].eq_ignore_ascii_case("table"))
+    {
+        return Ok(None);
+    }
+
+    let unsupported = |what: String| Error::Parse(format!("Table '{}': {}", name, what));
+    let lowercase = sql.to_ascii_lowercase();
+    let using = lowercase
+        .find(" using ")
+        .ok_or_else(|| unsupported("can't find the module of this virtual table".to_string()))?;
+    let rest = &sql[using + " using ".len()..];
+    let (module, args) = match (rest.find('('), rest.rfind(')')) {
+        (Some(open), Some(close)) if open < close => (&rest[..open], &rest[open + 1..close]),
+        _ => (rest, ""),
+    };
+    let module = module.trim();
+    if !module.eq_ignore_ascii_case("fts5") {
+        bail!(unsupported(format!(
+            "virtual tables using '{}' are not supported, only fts5",
+            module
+        )));
+    }
+
+    let mut columns = Vec::new();
+    let mut ascii = false;
+    for arg in split_args(args) {
+        if let Some((key, value)) = split_option(arg) {
+            let value = unquote(value.trim());
+            match key.trim().to_ascii_lowercase().as_str() {
+                "prefix" | "columnsize" => {}
+                "detail" if !value.eq_ignore_ascii_case("none") => {}
+                "tokenize" => match value.to_ascii_lowercase().as_str() {
+                    "unicode61" => ascii = false,
+                    "ascii" => ascii = true,
+                    _ => bail!(unsupported(format!(
+                        "the tokenizer '{}' is not supported, only plain unicode61 and ascii",
+                        value
+                    ))),
+                },
+                key => bail!(unsupported(format!(
+                    "the FTS5 option {}={} is not supported",
+                    key, value
+                ))),
+            }
+            continue;
+        }
+        let mut words = arg.split_whitespace();
+        let column = words.next().map(unquote).unwrap_or_default();
+        match words.next() {
+            None => {}
+            Some(word) if word.eq_ignore_ascii_case("unindexed") && words.next().is_none() => {}
+            Some(_) => bail!(unsupported(format!(
+                "can't read the column '{}'",
+                arg.trim()
+            ))),
+        }
+        columns.push(column);
+    }
+    if columns.is_empty() {
+        bail!(unsupported(
+            "an FTS5 table needs at least one column".to_string()
+        ));
+    }
+
+    // The docid is the table's rowid, under whichever of SQLite's names for
+    // it no column has taken. `<name>_content` keeps it in an INTEGER
+    // PRIMARY KEY `id`, which the FTS5 table itself doesn't have.
+    let mut names = ROWID_NAMES
+        .iter()
+        .filter(|rowid| {
+            !columns
+                .iter()
+                .any(|column| column.eq_ignore_ascii_case(rowid))
+        })
+        .map(|rowid| rowid.to_string());
+    let rowid = ColumnDef::new(&names.next().unwrap_or_default(), "INTEGER");
+    let rowid = ColumnDef {
+        aliases: names.collect(),
+        ..rowid
+    };
+    let content = find_table(schema, &format!("{}_content", name))?;
+    let mut content_def = TableDef::with_columns(vec![rowid]);
+    if let Some(sql) = &content.sql {
+        content_def
+            .columns
+            .extend(parse_create_table(sql)?.columns.into_iter().map(|column| {
+                if column.name.eq_ignore_ascii_case("id") {
+                    ColumnDef::new("", "INTEGER")
+                } else {
+                    column
+                }
+            }));
+    }
+    for (i, column) in columns.into_iter().enumerate() {
+        let stored = format!("c{}", i);
+        match content_def.columns.iter_mut().find(|c| c.name == stored) {
+            Some(def) => def.name = column,
+            None => corrupt!("'{}_content' has no column {}", name, stored),
+        }
+    }
+
+    Ok(Some(FullTextTable {
+        table_def: content_def,
+        content_root: content.rootpage,
+        data_root: find_table(schema, &format!("{}_data", name))?.rootpage,
+        ascii,
+    }))
+}
+
+/// Splits the arguments of `fts5(...)` at the commas between them.
+fn split_args(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    for (i, c) in args.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, ',') => {
+                parts.push(&args[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&args[start..]);
+    parts.retain(|part| !part.trim().is_empty());
+    parts
+}
+
+/// Splits `key = value` at an `=` outside quotes.
+fn split_option(arg: &str) -> Option<(&str, &str)> {
+    let mut quote = None;
+    for (i, c) in arg.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '=') => return Some((&arg[..i], &arg[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A full-text query, the right-hand side of `MATCH`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MatchQuery {
+    /// Documents containing `term`, or with `prefix` any term starting with
+    /// it. Terms are lowercase, as the tokenizer indexes them.
+    Term {
+        term: String,
+        prefix: bool,
+    },
+    And(Box<MatchQuery>, Box<MatchQuery>),
+    Or(Box<MatchQuery>, Box<MatchQuery>),
+    /// Documents matching the left side but not the right.
+    Not(Box<MatchQuery>, Box<MatchQuery>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum QueryToken {
+    /// A bareword, or a `"quoted string"` when `quoted`.
+    Word {
+        text: String,
+        quoted: bool,
+    },
+    Star,
+    LeftParen,
+    RightParen,
+    /// Syntax that is valid FTS5 but not supported, like `:` or `^`.
+    Other(char),
+}
+
+impl std::fmt::Display for QueryToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryToken::Word { text, .. } => write!(f, "{}", text),
+            QueryToken::Star => write!(f, "*"),
+            QueryToken::LeftParen => write!(f, "("),
+            QueryToken::RightParen => write!(f, ")"),
+            QueryToken::Other(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+/// Characters FTS5 allows in a bareword.
+fn is_bareword_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '\x1a' || !c.is_ascii()
+}
+
+fn query_tokens(text: &str) -> Result<Vec<QueryToken>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '*' => tokens.push(QueryToken::Star),
+            '(' => tokens.push(QueryToken::LeftParen),
+            ')' => tokens.push(QueryToken::RightParen),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            text.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => text.push(c),
+                        None => bail!("fts5: unterminated string in query"),
+                    }
+                }
+                tokens.push(QueryToken::Word { text, quoted: true });
+            }
+            c if is_bareword_char(c) => {
+                let mut text = c.to_string();
+                while let Some(&c) = chars.peek().filter(|&&c| is_bareword_char(c)) {
+                    text.push(c);
+                    chars.next();
+                }
+                tokens.push(QueryToken::Word {
+                    text,
+                    quoted: false,
+                });
+            }
+            c => tokens.push(QueryToken::Other(c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over a query's tokens. `NOT` binds tightest, then
+/// `AND`, then `OR`, as in FTS5.
+struct QueryParser {
+    tokens: Vec<QueryToken>,
+    pos: usize,
+    ascii: bool,
+}
+
+impl QueryParser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        // Only uppercase barewords are operators; `and` is a search term.
+        matches!(
+            self.tokens.get(self.pos),
+            Some(QueryToken::Word { text, quoted: false }) if text == keyword
+        )
+    }
+
+    fn parse_or(&mut self) -> Result<MatchQuery> {
+        let mut query = self.parse_and()?;
+        while self.peek_keyword("OR") {
+            self.pos += 1;
+            query = MatchQuery::Or(Box::new(query), Box::new(self.parse_and()?));
+        }
+        Ok(query)
+    }
+
+    fn parse_and(&mut self) -> Result<MatchQuery> {
+        let mut query = self.parse_not()?;
+        loop {
+            if self.peek_keyword("AND") {
+                self.pos += 1;
+            } else if !matches!(
+                self.tokens.get(self.pos),
+                Some(QueryToken::Word { .. } | QueryToken::LeftParen)
+            ) || self.peek_keyword("OR")
+                || self.peek_keyword("NOT")
+            {
+                return Ok(query);
+            }
+            query = MatchQuery::And(Box::new(query), Box::new(self.parse_not()?));
+        }
+    }
+
+    fn parse_not(&mut self) -> Result<MatchQuery> {
+        let mut query = self.parse_primary()?;
+        while self.peek_keyword("NOT") {
+            self.pos += 1;
+            query = MatchQuery::Not(Box::new(query), Box::new(self.parse_primary()?));
+        }
+        Ok(query)
+    }
+
+    fn parse_primary(&mut self) -> Result<MatchQuery> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(QueryToken::LeftParen) => {
+                let query = self.parse_or()?;
+                if self.tokens.get(self.pos) != Some(&QueryToken::RightParen) {
+                    bail!("fts5: expected ')' in query");
+                }
+                self.pos += 1;
+                Ok(query)
+            }
+            Some(QueryToken::Word { text, quoted }) => {
+                if !quoted && ["AND", "OR", "NOT"].contains(&text.as_str()) {
+                    bail!("fts5: syntax error near '{}'", text);
+                }
+                if !quoted && text == "NEAR" {
+                    bail!("fts5: NEAR queries are not supported");
+                }
+                let prefix = self.tokens.get(self.pos) == Some(&QueryToken::Star);
+                if prefix {
+                    self.pos += 1;
+                }
+                let mut terms = self.terms(&text)?;
+                if terms.len() > 1 {
+                    bail!(
+                        "fts5: '{}' is {} terms; phrase queries are not supported, only single terms",
+                        text,
+                        terms.len()
+                    );
+                }
+                // An empty phrase, like `""`, matches nothing.
+                let term = terms.pop().unwrap_or_default();
+                Ok(MatchQuery::Term {
+                    prefix: prefix && !term.is_empty(),
+                    term,
+                })
+            }
+            Some(QueryToken::Other(c)) => {
+                bail!("fts5: '{}' in a query is not supported", c)
+            }
+            Some(token) => bail!("fts5: syntax error near '{}'", token),
+            None => bail!("fts5: syntax error at the end of the query"),
+        }
+    }
+
+    /// Splits `text` into terms as the table's tokenizer does: runs of
+    /// letters and digits, lowercased. Outside ASCII, `unicode61` also folds
+    /// case and strips diacritics, which isn't done here.
+    fn terms(&self, text: &str) -> Result<Vec<String>> {
+        if !self.ascii && !text.is_ascii() {
+            bail!(
+                "fts5: '{}' has non-ASCII characters, which the unicode61 tokenizer folds in ways that are not supported",
+                text
+            );
+        }
+        Ok(text
+            .split(|c: char| !c.is_ascii_alphanumeric() && c.is_ascii())
+            .filter(|term| !term.is_empty())
+            .map(str::to_ascii_lowercase)
+            .collect())
+    }
+}
+
+/// One segment of the index: a run of leaf blocks holding a sorted list of
+/// terms, each followed by the rowids of the documents containing it.
+struct Segment {
+    id: u64,
+    first_page: u64,
+    last_page: u64,
+}
+
+/// A leaf block of a segment.
+struct Leaf {
+    /// The block up to its footer: a 4-byte header, then terms and doclists.
+    data: Vec<u8>,
+    /// Where each term on the page starts.
+    term_offsets: Vec<usize>,
+}
+
+/// Where a doclist left off at the end of a leaf.
+#[derive(Default)]
+struct DoclistState {
+    rowid: i64,
+    /// Bytes of the last position list still to come on the next leaf.
+    pending: usize,
+}
+
+/// The rowids of the documents that `query` matches, in order, from the
+/// index whose `_data` table has the root page `data_root`.
+pub fn matching_rowids(db: &mut Database, data_root: u32, query: &MatchQuery) -> Result<Vec<i64>> {
+    let segments = read_structure(db, data_root)?;
+    Ok(query_rowids(db, data_root, &segments, query)?
+        .into_iter()
+        .collect())
+}
+
+fn query_rowids(
+    db: &mut Database,
+    data_root: u32,
+    segments: &[Segment],
+    query: &MatchQuery,
+) -> Result<BTreeSet<i64>> {
+    let mut both = |left: &MatchQuery, right: &MatchQuery| -> Result<_> {
+        Ok((
+            query_rowids(db, data_root, segments, left)?,
+            query_rowids(db, data_root, segments, right)?,
+        ))
+    };
+    Ok(match query {
+        MatchQuery::Term { term, prefix } => {
+            // Terms of the main index start with '0'; prefix indexes, which
+            // aren't used here, have other leading characters.
+            let key = [b"0", term.as_bytes()].concat();
+            term_rowids(db, data_root, segments, &key, *prefix)?
+        }
+        MatchQuery::And(left, right) => {
+            let (left, right) = both(left, right)?;
+            left.intersection(&right).copied().collect()
+        }
+        MatchQuery::Or(left, right) => {
+            let (mut left, right) = both(left, right)?;
+            left.extend(right);
+            left
+        }
+        MatchQuery::Not(left, right) => {
+            let (left, right) = both(left, right)?;
+            left.difference(&right).copied().collect()
+        }
+    })
+}
+
+/// The rowids of the documents containing `key`, or with `prefix` any term
+/// starting with it. Segments are read newest first, and the newest entry
+/// for a term and rowid wins: a later delete hides an earlier insert.
+fn term_rowids(
+    db: &mut Database,
+    data_root: u32,
+    segments: &[Segment],
+    key: &[u8],
+    prefix: bool,
+) -> Result<BTreeSet<i64>> {
+    let mut newest: HashMap<Vec<u8>, HashMap<i64, bool>> = HashMap::new();
+    for segment in segments {
+        for_each_entry(
+            db,
+            data_root,
+            segment,
+            key,
+            prefix,
+            |term, rowid, deleted| {
+                if !newest.contains_key(term) {
+                    newest.insert(term.to_vec(), HashMap::new());
+                }
+                if let Some(rowids) = newest.get_mut(term) {
+                    rowids.entry(rowid).or_insert(!deleted);
+                }
+            },
+        )?;
+    }
+    Ok(newest
+        .into_values()
+        .flatten()
+        .filter_map(|(rowid, present)| present.then_some(rowid))
+        .collect())
+}
+
+/// Reads the list of segments, newest first: level 0 holds the newest, and
+/// within a level later segments are newer.
+fn read_structure(db: &mut Database, data_root: u32) -> Result<Vec<Segment>> {
+    let block = read_block(db, data_root, STRUCTURE_ROWID)?;
+    if block.len() < 4 {
+        corrupt!("FTS5 structure record is too short");
+    }
+    let mut rest = &block[4..];
+    if rest.starts_with(b"FLV2") {
+        bail!("FTS5 indexes in the version 2 structure format are not supported");
+    }
+    let mut next = || -> Result<u64> {
+        let (value, remaining, _) = read_varint(rest)?;
+        rest = remaining;
+        Ok(value)
+    };
+    let levels = next()?;
+    let _segment_count = next()?;
+    let _write_counter = next()?;
+    let mut by_level = Vec::new();
+    for _ in 0..levels {
+        let _merging = next()?;
+        let count = next()?;
+        let mut level = Vec::new();
+        for _ in 0..count {
+            level.push(Segment {
+                id: next()?,
+                first_page: next()?,
+                last_page: next()?,
+            });
+        }
+        by_level.push(level);
+    }
+    Ok(by_level
+        .into_iter()
+        .flat_map(|level| level.into_iter().rev())
+        .filter(|segment| segment.first_page > 0 && segment.first_page <= segment.last_page)
+        .collect())
+}
+
+fn read_block(db: &mut Database, data_root: u32, rowid: i64) -> Result<Vec<u8>> {
+    let mut block = None;
+    db.for_each_table_record_by_rowids(data_root, &[rowid], |mut record| {
+        block = record.pop();
+        Ok(())
+    })?;
+    match block {
+        Some(Value::Blob(bytes)) => Ok(bytes),
+        Some(_) => corrupt!("FTS5 index block {} is not a blob", rowid),
+        None => corrupt!("FTS5 index block {} is missing", rowid),
+    }
+}
+
+fn read_leaf(db: &mut Database, data_root: u32, segment: &Segment, page: u64) -> Result<Leaf> {
+    let rowid = ((segment.id << 37) + page) as i64;
+    let mut data = read_block(db, data_root, rowid)?;
+    if data.len() < 4 {
+        corrupt!("FTS5 leaf {} of segment {} is too short", page, segment.id);
+    }
+    let size = u16::from_be_bytes([data[2], data[3]]) as usize;
+    if size < 4 || size > data.len() {
+        corrupt!(
+            "FTS5 leaf {} of segment {} has a bad size",
+            page,
+            segment.id
+        );
+    }
+    let mut term_offsets = Vec::new();
+    let mut footer = &data[size..];
+    let mut offset = 0;
+    while !footer.is_empty() {
+        let (delta, rest, _) = read_varint(footer)?;
+        offset += delta as usize;
+        if offset < 4 || offset >= size {
+            corrupt!(
+                "FTS5 leaf {} of segment {} has a bad term offset",
+                page,
+                segment.id
+            );
+        }
+        term_offsets.push(offset);
+        footer = rest;
+    }
+    data.truncate(size);
+    Ok(Leaf { data, term_offsets })
+}
+
+/// The first term on a leaf, which is stored whole.
+fn first_term(leaf: &Leaf) -> Result<Option<Vec<u8>>> {
+    let Some(&offset) = leaf.term_offsets.first() else {
+        return Ok(None);
+    };
+    let (length, rest, _) = read_varint(&leaf.data[offset..])?;
+    match rest.get(..length as usize) {
+        Some(term) => Ok(Some(term.to_vec())),
+        None => corrupt!("FTS5 term runs past the end of its leaf"),
+    }
+}
+
+/// The leaf to start looking for `key` on: the last one whose first term is
+/// at most `key`, found by binary search. Leaves with no term of their own
+/// continue an earlier doclist and are skipped over.
+fn start_page(db: &mut Database, data_root: u32, segment: &Segment, key: &[u8]) -> Result<u64> {
+    let (mut low, mut high) = (segment.first_page, segment.last_page);
+    while low < high {
+        let middle = low + (high - low).div_ceil(2);
+        let mut found = None;
+        for page in middle..=high {
+            db.check_interrupt()?;
+            if let Some(term) = first_term(&read_leaf(db, data_root, segment, page)?)? {
+                found = Some((page, term));
+                break;
+            }
+        }
+        match found {
+            Some((page, term)) if term.as_slice() <= key => low = page,
+            _ => high = middle - 1,
+        }
+    }
+    Ok(low)
+}
+
+/// Calls `on_entry` with the term, rowid and whether it is a delete marker
+/// for every doclist entry of `segment` whose term is `key` (or with
+/// `prefix`, starts with it).
+fn for_each_entry(
+    db: &mut Database,
+    data_root: u32,
+    segment: &Segment,
+    key: &[u8],
+    prefix: bool,
+    mut on_entry: impl FnMut(&[u8], i64, bool),
+) -> Result<()> {
+    let start = start_page(db, data_root, segment, key)?;
+    let mut term = Vec::new();
+    let mut collecting = false;
+    let mut state = DoclistState::default();
+    for page in start..=segment.last_page {
+        db.check_interrupt()?;
+        let leaf = read_leaf(db, data_root, segment, page)?;
+        let data = &leaf.data;
+        // Before the first term is the rest of a doclist from earlier leaves.
+        let first_term = leaf.term_offsets.first().copied().unwrap_or(data.len());
+        if collecting && page != start {
+            read_doclist(&data[4..first_term], &term, &mut state, &mut on_entry)?;
+        }
+        for (i, &offset) in leaf.term_offsets.iter().enumerate() {
+            let end = leaf.term_offsets.get(i + 1).copied().unwrap_or(data.len());
+            if end < offset {
+                corrupt!(
+                    "FTS5 leaf {} of segment {} has a bad term offset",
+                    page,
+                    segment.id
+                );
+            }
+            let mut rest = &data[offset..end];
+            if i > 0 {
+                let (shared, remaining, _) = read_varint(rest)?;
+                if shared as usize > term.len() {
+                    corrupt!("FTS5 term shares more than the term before it");
+                }
+                term.truncate(shared as usize);
+                rest = remaining;
+            } else {
+                term.clear();
+            }
+            let (length, remaining, _) = read_varint(rest)?;
+            let Some(suffix) = remaining.get(..length as usize) else {
+                corrupt!("FTS5 term runs past the end of its leaf");
+            };
+            term.extend_from_slice(suffix);
+            rest = &remaining[length as usize..];
+
+            // Terms are sorted, so past `key` nothing more can match.
+            if term.as_slice() > key && !(prefix && term.starts_with(key)) {
+                return Ok(());
+            }
+            collecting = if prefix {
+                term.starts_with(key)
+            } else {
+                term == key
+            };
+            state = DoclistState::default();
+            if collecting {
+                read_doclist(rest, &term, &mut state, &mut on_entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads the part of a doclist that is on one leaf. Its first rowid is
+/// stored whole and the rest as deltas; each is followed by the size of its
+/// position list, doubled, plus one for a delete marker. Position lists
+/// themselves are skipped, and may carry on to the next leaf.
+fn read_doclist(
+    mut bytes: &[u8],
+    term: &[u8],
+    state: &mut DoclistState,
+    on_entry: &mut impl FnMut(&[u8], i64, bool),
+) -> Result<()> {
+    let skip = state.pending.min(bytes.len());
+    bytes = &bytes[skip..];
+    state.pending -= skip;
+    let mut first = true;
+    while !bytes.is_empty() {
+        let (rowid, rest, _) = read_varint(bytes)?;
+        state.rowid = if first {
+            rowid as i64
+        } else {
+            state.rowid.wrapping_add(rowid as i64)
+        };
+        first = false;
+        let (header, rest, _) = read_varint(rest)?;
+        let size = (header >> 1) as usize;
+        on_entry(term, state.rowid, header & 1 == 1 && size == 0);
+        let skip = size.min(rest.len());
+        bytes = &rest[skip..];
+        state.pending = size - skip;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{full_text_table, FullTextTable, MatchQuery};
+    use crate::database::SchemaEntry;
+
+    fn table_entry(name: &str, rootpage: u32, sql: &str) -> SchemaEntry {
+        SchemaEntry {
+            typ: "table".to_string(),
+            name: name.to_string(),
+            tbl_name: name.to_string(),
+            rootpage,
+            sql: Some(sql.to_string()),
+        }
+    }
+
+    /// The schema SQLite writes for `CREATE VIRTUAL TABLE docs USING <using>`
+    /// over two columns, leaving out the shadow tables that aren't read.
+    fn schema(using: &str) -> Vec<SchemaEntry> {
+        vec![
+            table_entry(
+                "docs",
+                0,
+                &format!("CREATE VIRTUAL TABLE docs USING {}", using),
+            ),
+            table_entry(
+                "docs_data",
+                2,
+                "CREATE TABLE 'docs_data'(id INTEGER PRIMARY KEY, block BLOB)",
+            ),
+            table_entry(
+                "docs_content",
+                5,
+                "CREATE TABLE 'docs_content'(id INTEGER PRIMARY KEY, c0, c1)",
+            ),
+        ]
+    }
+
+    fn docs(using: &str) -> FullTextTable {
+        full_text_table(&schema(using), "docs").unwrap().unwrap()
+    }
+
+    fn term(term: &str, prefix: bool) -> Box<MatchQuery> {
+        Box::new(MatchQuery::Term {
+            term: term.to_string(),
+            prefix,
+        })
+    }
+
+    #[test]
+    fn reads_columns_from_the_content_table() {
+        let table = docs("fts5(title, body UNINDEXED, tokenize = 'ascii')");
+        assert_eq!((table.content_root, table.data_root), (5, 2));
+        let names: Vec<&str> = table
+            .table_def
+            .columns
+            .iter()
+            .map(|column| column.name.as_str())
+            .collect();
+        assert_eq!(names, ["rowid", "", "title", "body"]);
+        // A column named like the rowid pushes it to another of its names.
+        let table = docs("fts5(rowid, oid)");
+        assert_eq!(table.table_def.columns[0].name, "_rowid_");
+        assert!(table.table_def.columns[0].aliases.is_empty());
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_read() {
+        let error = |using: &str| {
+            full_text_table(&schema(using), "docs")
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error("fts5(body, tokenize = 'porter')").contains("tokenizer 'porter'"));
+        assert!(error("fts5(body, content = '')").contains("content="));
+        assert!(error("fts4(body)").contains("only fts5"));
+        assert!(
+            full_text_table(&[table_entry("t", 2, "CREATE TABLE t (a)")], "t")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn parses_queries() {
+        let table = docs("fts5(body)");
+        let parse = |text: &str| table.parse_query(text).unwrap();
+        assert_eq!(*term("apple", true), parse("Apple*"));
+        // NOT binds tightest, then AND (or nothing), then OR.
+        assert_eq!(
+            parse("a b OR c NOT d"),
+            MatchQuery::Or(
+                Box::new(MatchQuery::And(term("a", false), term("b", false))),
+                Box::new(MatchQuery::Not(term("c", false), term("d", false))),
+            )
+        );
+        assert_eq!(
+            parse("(a OR b) AND \"c\""),
+            MatchQuery::And(
+                Box::new(MatchQuery::Or(term("a", false), term("b", false))),
+                term("c", false),
+            )
+        );
+        // Only uppercase operators are operators.
+        assert_eq!(
+            parse("x and y"),
+            MatchQuery::And(
+                Box::new(MatchQuery::And(term("x", false), term("and", false))),
+                term("y", false),
+            )
+        );
+        let error = |text: &str| table.parse_query(text).unwrap_err().to_string();
+        assert!(error("NEAR(a b)").contains("NEAR"));
+        assert!(error("\"two words\"").contains("phrase queries"));
+        assert!(error("a OR").contains("end of the query"));
+        assert!(error("body:a").contains("':'"));
+    }
+}
@@ -0,0 +1,644 @@
+use crate::collation::{Collation, CollationFunction};
+use crate::datetime::DateTime;
+use crate::error::Error;
+use crate::eval::compare_collated;
+use crate::json::{json_argument, Json};
+use crate::parser::Expr;
+use crate::printf;
+use crate::record::{format_real, TextEncoding, Value};
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+/// A scalar function registered from Rust: gets the argument values, returns
+/// the result.
+pub type ScalarFunction = Rc<dyn Fn(&[Value]) -> Result<Value>>;
+
+/// An aggregate function implemented in Rust. A fresh one is made for every
+/// query that uses it, gets each row's arguments through `step`, and gives
+/// the result from `finalize`, which is called even if no row matched.
+pub trait Aggregate {
+    fn step(&mut self, args: &[Value]) -> Result<()>;
+    fn finalize(&mut self) -> Result<Value>;
+}
+
+/// The aggregates every connection has, besides `count`.
+const BUILTIN_AGGREGATES: [&str; 4] = ["sum", "avg", "min", "max"];
+
+/// Makes the state of a registered aggregate for one query.
+pub type AggregateFactory = Rc<dyn Fn() -> Box<dyn Aggregate>>;
+
+/// Functions registered on a connection, by lowercased name, each with its
+/// number of arguments (negative takes any number). Scalar ones are looked
+/// up before the built-ins, so they can replace one. Collations registered
+/// alongside them work the same way.
+#[derive(Clone, Default)]
+pub struct UserFunctions {
+    scalar: HashMap<String, Vec<(i32, ScalarFunction)>>,
+    aggregate: HashMap<String, Vec<(i32, AggregateFactory)>>,
+    collations: HashMap<String, CollationFunction>,
+    /// How the connection's database stores text, which built-ins that see
+    /// text as bytes, like `hex()`, go by.
+    text_encoding: TextEncoding,
+}
+
+impl UserFunctions {
+    pub(crate) fn set_text_encoding(&mut self, text_encoding: TextEncoding) {
+        self.text_encoding = text_encoding;
+    }
+
+    /// Registers `function` as `name` taking `n_args` arguments, or any
+    /// number if negative. Registering the same name and count again
+    /// replaces the earlier one; other counts are kept as overloads.
+    pub fn add_scalar(&mut self, name: &str, n_args: i32, function: ScalarFunction) {
+        add_overload(&mut self.scalar, name, n_args, function);
+    }
+
+    /// Registers an aggregate, like [`UserFunctions::add_scalar`].
+    pub fn add_aggregate(&mut self, name: &str, n_args: i32, new: AggregateFactory) {
+        add_overload(&mut self.aggregate, name, n_args, new);
+    }
+
+    /// Registers a collation, replacing any earlier one (or built-in) of the
+    /// same name.
+    pub fn add_collation(&mut self, name: &str, compare: CollationFunction) {
+        self.collations.insert(name.to_lowercase(), compare);
+    }
+
+    /// The collation called `name`, or BINARY for `None`.
+    pub fn collation(&self, name: Option<&str>) -> Result<Collation> {
+        let Some(name) = name else {
+            return Ok(Collation::Binary);
+        };
+        match self.collations.get(&name.to_lowercase()) {
+            Some(compare) => Ok(Collation::User(Rc::clone(compare))),
+            None => match Collation::builtin(name) {
+                Some(collation) => Ok(collation),
+                None => bail!(Error::NotFound(format!(
+                    "No such collation sequence: {}",
+                    name
+                ))),
+            },
+        }
+    }
+
+    /// Whether an aggregate of this name is built in or registered, with any
+    /// number of arguments.
+    pub fn is_aggregate(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        BUILTIN_AGGREGATES.contains(&name.as_str()) || self.aggregate.contains_key(&name)
+    }
+
+    /// The aggregate `name` taking `n_args` arguments.
+    pub fn aggregate(&self, name: &str, n_args: usize) -> Result<&AggregateFactory> {
+        match self.aggregate.get(&name.to_lowercase()) {
+            Some(overloads) => match find_overload(overloads, n_args) {
+                Some(new) => Ok(new),
+                None => bail!("Wrong number of arguments to {}(): got {}", name, n_args),
+            },
+            None => bail!("No such aggregate function: {}", name),
+        }
+    }
+
+    /// Calls `name` with `args`: a registered function taking exactly that
+    /// many arguments, else one taking any number, else the built-in.
+    pub fn call(&self, name: &str, args: Vec<Value>) -> Result<Value> {
+        if let Some(function) = self
+            .scalar
+            .get(&name.to_lowercase())
+            .and_then(|overloads| find_overload(overloads, args.len()))
+        {
+            return function(&args);
+        }
+        if self.is_aggregate(name) {
+            bail!("Misuse of aggregate function {}()", name);
+        }
+        call(name, args, self.text_encoding)
+    }
+}
+
+fn add_overload<T>(map: &mut HashMap<String, Vec<(i32, T)>>, name: &str, n_args: i32, item: T) {
+    let overloads = map.entry(name.to_lowercase()).or_default();
+    overloads.retain(|(count, _)| *count != n_args);
+    overloads.push((n_args, item));
+}
+
+/// The overload taking exactly `n_args` arguments, else one taking any number.
+fn find_overload<T>(overloads: &[(i32, T)], n_args: usize) -> Option<&T> {
+    let exact = overloads
+        .iter()
+        .find(|(count, _)| usize::try_from(*count) == Ok(n_args));
+    let variadic = || overloads.iter().find(|(count, _)| *count < 0);
+    exact.or_else(variadic).map(|(_, item)| item)
+}
+
+/// Calls the built-in scalar function `name` (any case) with `args`, in a
+/// database storing text in `text_encoding`.
+pub fn call(name: &str, args: Vec<Value>, text_encoding: TextEncoding) -> Result<Value> {
+    match name.to_lowercase().as_str() {
+        "length" => {
+            let [value] = exact_args(name, args)?;
+            Ok(match value {
+                Value::Null => Value::Null,
+                Value::Blob(bytes) => Value::Int(bytes.len() as i64),
+                value => Value::Int(text_of(value).chars().count() as i64),
+            })
+        }
+        "lower" => {
+            let [value] = exact_args(name, args)?;
+            Ok(map_text(value, |text| text.to_ascii_lowercase()))
+        }
+        "upper" => {
+            let [value] = exact_args(name, args)?;
+            Ok(map_text(value, |text| text.to_ascii_uppercase()))
+        }
+        "replace" => {
+            let [text, pattern, replacement] = exact_args(name, args)?;
+            Ok(match (text, pattern, replacement) {
+                (Value::Null, _, _) | (_, Value::Null, _) | (_, _, Value::Null) => Value::Null,
+                // Replacing the empty string leaves the value as it was.
+                (text, pattern, _) if text_of(pattern.clone()).is_empty() => text,
+                (text, pattern, replacement) => Value::Text(
+                    text_of(text)
+                        .replace(&text_of(pattern), &text_of(replacement))
+                        .into(),
+                ),
+            })
+        }
+        "trim" | "ltrim" | "rtrim" => {
+            if !(1..=2).contains(&args.len()) {
+                bail!(
+                    "Wrong number of arguments to {}(): expected 1 or 2, got {}",
+                    name,
+                    args.len()
+                );
+            }
+            let mut args = args.into_iter();
+            let text = args.next().unwrap_or(Value::Null);
+            let characters = match args.next() {
+                None => " ".to_string(),
+                Some(Value::Null) => return Ok(Value::Null),
+                Some(characters) => text_of(characters),
+            };
+            let lower = name.to_lowercase();
+            Ok(map_text(text, |text| {
+                let trimmed = match lower.as_str() {
+                    "ltrim" => text.trim_start_matches(|c| characters.contains(c)),
+                    "rtrim" => text.trim_end_matches(|c| characters.contains(c)),
+                    _ => text.trim_matches(|c| characters.contains(c)),
+                };
+                trimmed.to_string()
+            }))
+        }
+        "substr" | "substring" => {
+            if !(2..=3).contains(&args.len()) {
+                bail!(
+                    "Wrong number of arguments to {}(): expected 2 or 3, got {}",
+                    name,
+                    args.len()
+                );
+            }
+            if args.iter().any(|arg| matches!(arg, Value::Null)) {
+                return Ok(Value::Null);
+            }
+            let mut args = args.into_iter();
+            let value = args.next().unwrap_or(Value::Null);
+            let start = printf::int_arg(args.next().unwrap_or(Value::Null));
+            let length = args.next().map(printf::int_arg);
+            // A blob is cut by bytes, anything else by characters.
+            Ok(match value {
+                Value::Blob(bytes) => {
+                    let range = substr_range(bytes.len(), start, length);
+                    Value::Blob(bytes[range].to_vec())
+                }
+                value => {
+                    let text = text_of(value);
+                    let range = substr_range(text.chars().count(), start, length);
+                    Value::Text(
+                        text.chars()
+                            .skip(range.start)
+                            .take(range.len())
+                            .collect::<String>()
+                            .into(),
+                    )
+                }
+            })
+        }
+        "instr" => {
+            let [haystack, needle] = exact_args(name, args)?;
+            Ok(match (haystack, needle) {
+                (Value::Null, _) | (_, Value::Null) => Value::Null,
+                // Two blobs are searched byte by byte, anything else as text.
+                (Value::Blob(haystack), Value::Blob(needle)) => Value::Int(
+                    haystack
+                        .windows(needle.len().max(1))
+                        .position(|window| needle.is_empty() || window == needle)
+                        .map_or(0, |i| i as i64 + 1),
+                ),
+                (haystack, needle) => {
+                    let haystack = text_of(haystack);
+                    Value::Int(haystack.find(&text_of(needle)).map_or(0, |byte| {
+                        haystack[..byte].chars().count() as i64 + 1
+                    }))
+                }
+            })
+        }
+        "printf" | "format" => {
+            let Some((format, args)) = args.split_first() else {
+                return Ok(Value::Null);
+            };
+            Ok(match format {
+                Value::Null => Value::Null,
+                format => Value::Text(printf::format(&text_of(format.clone()), args)?.into()),
+            })
+        }
+        "abs" => {
+            let [value] = exact_args(name, args)?;
+            Ok(match value {
+                Value::Null => Value::Null,
+                Value::Int(i) => match i.checked_abs() {
+                    Some(i) => Value::Int(i),
+                    None => bail!("Integer overflow in abs()"),
+                },
+                // Anything else, text that reads as an integer included,
+                // comes back REAL.
+                value => Value::Float(printf::real_arg(value).abs()),
+            })
+        }
+        "round" => {
+            if !(1..=2).contains(&args.len()) {
+                bail!(
+                    "Wrong number of arguments to {}(): expected 1 or 2, got {}",
+                    name,
+                    args.len()
+                );
+            }
+            if args.iter().any(|arg| matches!(arg, Value::Null)) {
+                return Ok(Value::Null);
+            }
+            let mut args = args.into_iter();
+            let value = printf::real_arg(args.next().unwrap_or(Value::Null));
+            let places = args.next().map_or(0, printf::int_arg).clamp(0, 30);
+            // Past 2^52 a REAL has no fraction left to round.
+            Ok(Value::Float(if value.abs() > 4503599627370496.0 {
+                value
+            } else if places == 0 {
+                (value + 0.5f64.copysign(value)) as i64 as f64
+            } else {
+                // Through decimal text, as SQLite does, so 2.675 rounds to
+                // 2.68 although its binary value is a little below.
+                let text = printf::format("%!.*f", &[Value::Int(places), Value::Float(value)])?;
+                text.parse().unwrap_or(value)
+            }))
+        }
+        "coalesce" | "ifnull" => {
+            let (fits, expected) = if name.eq_ignore_ascii_case("ifnull") {
+                (args.len() == 2, "2")
+            } else {
+                (args.len() >= 2, "at least 2")
+            };
+            if !fits {
+                bail!(
+                    "Wrong number of arguments to {}(): expected {}, got {}",
+                    name,
+                    expected,
+                    args.len()
+                );
+            }
+            Ok(args
+                .into_iter()
+                .find(|arg| !matches!(arg, Value::Null))
+                .unwrap_or(Value::Null))
+        }
+        "nullif" => {
+            let [value, other] = exact_args(name, args)?;
+            let equal = compare_collated(&value, &other, &Collation::Binary)
+                .is_some_and(Ordering::is_eq);
+            Ok(if equal { Value::Null } else { value })
+        }
+        "hex" => {
+            let [value] = exact_args(name, args)?;
+            // Text is shown as the database stores it; numbers as UTF-8.
+            let bytes = match value {
+                Value::Blob(bytes) => bytes,
+                Value::Text(text) => text_encoding.encode(&text),
+                value => text_of(value).into_bytes(),
+            };
+            Ok(Value::Text(
+                bytes
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect::<String>()
+                    .into(),
+            ))
+        }
+        "typeof" => {
+            let [value] = exact_args(name, args)?;
+            let type_name = match value {
+                Value::Null => "null",
+                Value::Int(_) => "integer",
+                Value::Float(_) => "real",
+                Value::Text(_) => "text",
+                Value::Blob(_) => "blob",
+            };
+            Ok(Value::Text(type_name.into()))
+        }
+        "quote" => {
+            let [value] = exact_args(name, args)?;
+            let literal = match value {
+                Value::Float(f) => quote_real(f)?,
+                value => Expr::Literal(value).to_string(),
+            };
+            Ok(Value::Text(literal.into()))
+        }
+        "unhex" => {
+            if !(1..=2).contains(&args.len()) {
+                bail!(
+                    "Wrong number of arguments to {}(): expected 1 or 2, got {}",
+                    name,
+                    args.len()
+                );
+            }
+            let mut args = args.into_iter();
+            let (hex, ignored) = match (args.next(), args.next()) {
+                (Some(Value::Null), _) | (_, Some(Value::Null)) => return Ok(Value::Null),
+                (hex, ignored) => (
+                    text_of(hex.unwrap_or(Value::Null)),
+                    ignored.map(text_of).unwrap_or_default(),
+                ),
+            };
+            Ok(unhex(&hex, &ignored).map_or(Value::Null, Value::Blob))
+        }
+        "zeroblob" | "randomblob" => {
+            let [size] = exact_args(name, args)?;
+            let size = printf::int_arg(size);
+            if size > MAX_LENGTH {
+                bail!("{}({}) is too big: blobs are capped at {} bytes", name, size, MAX_LENGTH);
+            }
+            Ok(Value::Blob(if name.eq_ignore_ascii_case("zeroblob") {
+                vec![0; size.max(0) as usize]
+            } else {
+                random_bytes(size.max(1) as usize)
+            }))
+        }
+        "json_extract" => {
+            let Some((json, paths)) = args.split_first() else {
+                bail!(
+                    "Wrong number of arguments to {}(): expected at least 2, got 0",
+                    name
+                );
+            };
+            if paths.is_empty() {
+                bail!(
+                    "Wrong number of arguments to {}(): expected at least 2, got 1",
+                    name
+                );
+            }
+            let Some(json) = json_argument(json)? else {
+                return Ok(Value::Null);
+            };
+            // One path gives the value itself, several a JSON array of them.
+            if let [path] = paths {
+                return Ok(match json_path(&json, path)? {
+                    Some(Some(node)) => node.to_value(),
+                    _ => Value::Null,
+                });
+            }
+            let mut found = Vec::with_capacity(paths.len());
+            for path in paths {
+                match json_path(&json, path)? {
+                    Some(node) => found.push(node.cloned().unwrap_or(Json::Null)),
+                    None => return Ok(Value::Null),
+                }
+            }
+            Ok(Value::Text(Json::Array(found).to_string().into()))
+        }
+        "json_array_length" | "json_type" => {
+            if !(1..=2).contains(&args.len()) {
+                bail!(
+                    "Wrong number of arguments to {}(): expected 1 or 2, got {}",
+                    name,
+                    args.len()
+                );
+            }
+            let Some(json) = json_argument(&args[0])? else {
+                return Ok(Value::Null);
+            };
+            let node = match args.get(1) {
+                Some(path) => json_path(&json, path)?.flatten(),
+                None => Some(&json),
+            };
+            Ok(match node {
+                None => Value::Null,
+                Some(node) if name.eq_ignore_ascii_case("json_type") => {
+                    Value::Text(node.type_name().into())
+                }
+                Some(Json::Array(items)) => Value::Int(items.len() as i64),
+                Some(_) => Value::Int(0),
+            })
+        }
+        "julianday" | "unixepoch" | "datetime" | "date" | "time" => {
+            let Some(time) = DateTime::from_args(&args)? else {
+                return Ok(Value::Null);
+            };
+            Ok(match name.to_lowercase().as_str() {
+                "julianday" => Value::Float(time.julian_day()),
+                "unixepoch" => time.unix_epoch(),
+                "date" => Value::Text(time.format_date().into()),
+                "time" => Value::Text(time.format_time().into()),
+                _ => Value::Text(time.format_datetime().into()),
+            })
+        }
+        "strftime" => {
+            let Some((format, args)) = args.split_first() else {
+                bail!(
+                    "Wrong number of arguments to {}(): expected at least 1, got 0",
+                    name
+                );
+            };
+            let (false, Some(time)) = (format == &Value::Null, DateTime::from_args(args)?) else {
+                return Ok(Value::Null);
+            };
+            Ok(time
+                .strftime(&text_of(format.clone()))
+                .map_or(Value::Null, |text| Value::Text(text.into())))
+        }
+        // `table MATCH query` is planned as a full-text index scan; anywhere
+        // else there is nothing to answer it.
+        "match" => bail!(
+            "MATCH can only be used as `table MATCH 'query'` on an FTS5 table, ANDed with the rest of the WHERE clause"
+        ),
+        _ => bail!("No such function: {}", name),
+    }
+}
+
+/// Looks up the path `path` in `json`: `None` if the path is NULL, `Some(None)`
+/// if nothing is there.
+fn json_path<'a>(json: &'a Json, path: &Value) -> Result<Option<Option<&'a Json>>> {
+    match path {
+        Value::Null => Ok(None),
+        path => json.at_path(&text_of(path.clone())).map(Some),
+    }
+}
+
+/// Which of `len` characters (or bytes) `substr(x, start, length)` keeps.
+/// `start` counts from 1, or back from the end if negative; a negative
+/// `length` takes the characters before `start` instead of after it, and
+/// without one the rest of the value is kept.
+fn substr_range(len: usize, start: i64, length: Option<i64>) -> Range<usize> {
+    let len = len as i64;
+    let mut count = length.map_or(i64::MAX, i64::saturating_abs);
+    let mut first = match start {
+        start if start < 0 => start.saturating_add(len),
+        start if start > 0 => start - 1,
+        // Position 0 is just before the first character, and counts
+        // against the length.
+        _ => {
+            count = count.saturating_sub(1).max(0);
+            0
+        }
+    };
+    if first < 0 {
+        count = count.saturating_add(first).max(0);
+        first = 0;
+    }
+    if length.is_some_and(|length| length < 0) {
+        first -= count;
+        if first < 0 {
+            count += first;
+            first = 0;
+        }
+    }
+    let first = first.min(len);
+    first as usize..first.saturating_add(count).min(len) as usize
+}
+
+/// A REAL as `quote()` writes it: with 15 significant digits if they read
+/// back as the same value, else as `%!.20e` does.
+fn quote_real(value: f64) -> Result<String> {
+    if value.is_infinite() {
+        return Ok(format!("{}9.0e+999", if value < 0.0 { "-" } else { "" }));
+    }
+    let short = format_real(value);
+    if short.parse::<f64>().ok() == Some(value) {
+        return Ok(short);
+    }
+    printf::format("%!.20e", &[Value::Float(value)])
+}
+
+/// SQLite's default SQLITE_MAX_LENGTH, the most bytes a string or blob can
+/// have.
+pub(crate) const MAX_LENGTH: i64 = 1_000_000_000;
+
+/// Decodes pairs of hex digits. Characters in `ignored` may sit between
+/// pairs but not inside one; anything else makes it `None`.
+pub(crate) fn unhex(hex: &str, ignored: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    let mut chars = hex.chars();
+    while let Some(c) = chars.next() {
+        match c.to_digit(16) {
+            Some(high) => {
+                let low = chars.next()?.to_digit(16)?;
+                bytes.push((high * 16 + low) as u8);
+            }
+            None if ignored.contains(c) => {}
+            None => return None,
+        }
+    }
+    Some(bytes)
+}
+
+/// `count` pseudo-random bytes: xorshift64 seeded from std's per-process
+/// hash keys and the clock, which is plenty for test data and not meant for
+/// anything secret.
+fn random_bytes(count: usize) -> Vec<u8> {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let mut random = RandomState::new().hash_one(nanos) | 1;
+    let mut bytes = Vec::with_capacity(count);
+    while bytes.len() < count {
+        random ^= random << 13;
+        random ^= random >> 7;
+        random ^= random << 17;
+        let take = (count - bytes.len()).min(8);
+        bytes.extend_from_slice(&random.to_le_bytes()[..take]);
+    }
+    bytes
+}
+
+fn exact_args<const N: usize>(name: &str, args: Vec<Value>) -> Result<[Value; N]> {
+    let count = args.len();
+    match args.try_into() {
+        Ok(args) => Ok(args),
+        Err(_) => bail!(
+            "Wrong number of arguments to {}(): expected {}, got {}",
+            name,
+            N,
+            count
+        ),
+    }
+}
+
+/// The text form of a value, as SQLite's text functions see it.
+pub(crate) fn text_of(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => format_real(f),
+        Value::Text(text) => text.to_string(),
+        Value::Blob(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+    }
+}
+
+/// Applies `f` to the text form of `value`; NULL stays NULL. Like SQLite
+/// built without ICU, case mapping only touches ASCII letters.
+fn map_text(value: Value, f: impl Fn(&str) -> String) -> Value {
+    match value {
+        Value::Null => Value::Null,
+        value => Value::Text(f(&text_of(value)).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::TextEncoding;
+    use crate::testing::{query, text, TestDatabase};
+
+    #[test]
+    fn hex_shows_text_in_the_database_encoding() {
+        let hex = |text_encoding: TextEncoding| {
+            let image = TestDatabase::new()
+                .text_encoding(text_encoding)
+                .table("CREATE TABLE t (a TEXT)", [vec![text("aé")]])
+                .build()
+                .unwrap();
+            query(
+                &image,
+                "SELECT hex(a), hex('b'), hex(x'00ff'), hex(-1.5) FROM t",
+            )
+            .unwrap()
+            .remove(0)
+        };
+        // Numbers are always hexed as their UTF-8 digits.
+        let (blob, number) = (text("00FF"), text("2D312E35"));
+        assert_eq!(
+            hex(TextEncoding::Utf8),
+            [text("61C3A9"), text("62"), blob.clone(), number.clone()]
+        );
+        assert_eq!(
+            hex(TextEncoding::Utf16le),
+            [text("6100E900"), text("6200"), blob.clone(), number.clone()]
+        );
+        assert_eq!(
+            hex(TextEncoding::Utf16be),
+            [text("006100E9"), text("0062"), blob, number]
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use crate::database::Database;
+use crate::eval::like;
+use crate::query::{find_table, ColumnDefaults};
+use crate::record::Value;
+use crate::schema::{parse_create_table, ColumnDef};
+use anyhow::{Context, Result};
+
+/// Hashes of the data in a database, as computed by `content_hashes`.
+#[derive(Debug)]
+pub struct ContentHashes {
+    /// Every hashed table with its hash, sorted by name.
+    pub tables: Vec<(String, [u8; 32])>,
+    /// One hash over all of `tables`.
+    pub total: [u8; 32],
+}
+
+/// The SHA3-256 hash of the rows of `table`, in rowid order, or PRIMARY KEY
+/// order for a WITHOUT ROWID table. Only the values
+/// count, not how they are stored: page size, text encoding, free space and
+/// the integer-or-real storage of REAL values all leave it unchanged. Rowids
+/// are hashed only through an INTEGER PRIMARY KEY column, since VACUUM may
+/// renumber the others.
+///
+/// Each value is hashed the way sqlite3's `.sha3sum` hashes it: `N` for NULL,
+/// `I` or `F` and the 8 big-endian bytes of an integer or a real's bits, and
+/// `T` or `B`, the length in bytes, `:` and the bytes of text (as UTF-8) or
+/// a blob.
+pub fn table_hash(db: &mut Database, table: &str) -> Result<[u8; 32]> {
+    let schema = db.read_schema()?;
+    let entry = find_table(&schema, table)?;
+    let sql = entry
+        .sql
+        .as_ref()
+        .with_context(|| format!("No SQL definition found for table '{}'", table))?;
+    let table_def = parse_create_table(sql)?;
+    let mut hasher = Sha3_256::new();
+    // Its rows are the entries of an index B-tree, PRIMARY KEY columns first.
+    if table_def.without_rowid {
+        let order = table_def.storage_order();
+        let stored: Vec<ColumnDef> = order
+            .iter()
+            .map(|&i| table_def.columns[i].clone())
+            .collect();
+        let defaults = ColumnDefaults::new(&stored);
+        db.for_each_index_record(entry.rootpage, |mut record| {
+            defaults.fill(&mut record);
+            let mut row = vec![Value::Null; table_def.columns.len()];
+            for (value, &i) in record.into_iter().zip(&order) {
+                row[i] = value;
+            }
+            table_def.present(&mut row);
+            for value in &row {
+                hash_value(&mut hasher, value);
+            }
+            Ok(())
+        })?;
+        return Ok(hasher.finish());
+    }
+    let alias = table_def.rowid_alias.as_ref().and_then(|alias| {
+        table_def
+            .columns
+            .iter()
+            .position(|column| column.name.eq_ignore_ascii_case(alias))
+    });
+
+    let defaults = ColumnDefaults::new(&table_def.columns);
+    db.for_each_table_record(entry.rootpage, |mut record| {
+        let rowid = record.remove(0);
+        defaults.fill(&mut record);
+        if let Some(alias) = alias {
+            record[alias] = rowid;
+        }
+        table_def.present(&mut record);
+        for value in &record {
+            hash_value(&mut hasher, value);
+        }
+        Ok(())
+    })?;
+    Ok(hasher.finish())
+}
+
+/// Hashes every table matching the LIKE `pattern` (all of them without one),
+/// skipping SQLite's own `sqlite_` tables and virtual tables, whose rows live
+/// in their shadow tables. The total hashes each table's name and hash in
+/// name order, so two databases with the same tables holding the same rows
+/// get the same total.
+pub fn content_hashes(db: &mut Database, pattern: Option<&str>) -> Result<ContentHashes> {
+    let mut names: Vec<String> = db
+        .read_schema()?
+        .into_iter()
+        .filter(|entry| {
+            entry.typ == "table" && entry.rootpage != 0 && !entry.name.starts_with("sqlite_")
+        })
+        .map(|entry| entry.name)
+        .filter(|name| pattern.map_or(true, |pattern| like(pattern, name, None)))
+        .collect();
+    names.sort();
+
+    let mut tables = Vec::with_capacity(names.len());
+    let mut total = Sha3_256::new();
+    for name in names {
+        let hash = table_hash(db, &name)?;
+        hash_value(&mut total, &Value::Text(name.as_str().into()));
+        total.update(&hash);
+        tables.push((name, hash));
+    }
+    Ok(ContentHashes {
+        tables,
+        total: total.finish(),
+    })
+}
+
+pub fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_value(hasher: &mut Sha3_256, value: &Value) {
+    match value {
+        Value::Null => hasher.update(b"N"),
+        Value::Int(i) => {
+            hasher.update(b"I");
+            hasher.update(&i.to_be_bytes());
+        }
+        Value::Float(f) => {
+            hasher.update(b"F");
+            hasher.update(&f.to_bits().to_be_bytes());
+        }
+        Value::Text(text) => {
+            hasher.update(format!("T{}:", text.len()).as_bytes());
+            hasher.update(text.as_bytes());
+        }
+        Value::Blob(bytes) => {
+            hasher.update(format!("B{}:", bytes.len()).as_bytes());
+            hasher.update(bytes);
+        }
+    }
+}
+
+/// Bytes absorbed per Keccak permutation for a 256-bit output.
+const SHA3_256_RATE: usize = 136;
+
+const ROUND_CONSTANTS: [u64; 24] = [
+    0x0000_0000_0000_0001,
+    0x0000_0000_0000_8082,
+    0x8000_0000_0000_808a,
+    0x8000_0000_8000_8000,
+    0x0000_0000_0000_808b,
+    0x0000_0000_8000_0001,
+    0x8000_0000_8000_8081,
+    0x8000_0000_0000_8009,
+    0x0000_0000_0000_008a,
+    0x0000_0000_0000_0088,
+    0x0000_0000_8000_8009,
+    0x0000_0000_8000_000a,
+    0x0000_0000_8000_808b,
+    0x8000_0000_0000_008b,
+    0x8000_0000_0000_8089,
+    0x8000_0000_0000_8003,
+    0x8000_0000_0000_8002,
+    0x8000_0000_0000_0080,
+    0x0000_0000_0000_800a,
+    0x8000_0000_8000_000a,
+    0x8000_0000_8000_8081,
+    0x8000_0000_0000_8080,
+    0x0000_0000_8000_0001,
+    0x8000_0000_8000_8008,
+];
+const ROTATIONS: [u32; 24] = [
+    1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+];
+const PI_LANES: [usize; 24] = [
+    10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+];
+
+/// SHA3-256 (FIPS 202), absorbing one byte at a time into the Keccak state.
+struct Sha3_256 {
+    state: [u64; 25],
+    position: usize,
+}
+
+impl Sha3_256 {
+    fn new() -> Self {
+        Sha3_256 {
+            state: [0; 25],
+            position: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.xor_byte(self.position, byte);
+            self.position += 1;
+            if self.position == SHA3_256_RATE {
+                keccak_f(&mut self.state);
+                self.position = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        self.xor_byte(self.position, 0x06);
+        self.xor_byte(SHA3_256_RATE - 1, 0x80);
+        keccak_f(&mut self.state);
+        let mut hash = [0; 32];
+        for (chunk, lane) in hash.chunks_exact_mut(8).zip(self.state) {
+            chunk.copy_from_slice(&lane.to_le_bytes());
+        }
+        hash
+    }
+
+    fn xor_byte(&mut self, position: usize, byte: u8) {
+        self.state[position / 8] ^= (byte as u64) << (8 * (position % 8));
+    }
+}
+
+fn keccak_f(state: &mut [u64; 25]) {
+    for round_constant in ROUND_CONSTANTS {
+        // θ
+        let mut parity = [0u64; 5];
+        for (x, column) in parity.iter_mut().enumerate() {
+            *column = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+        }
+        for x in 0..5 {
+            let d = parity[(x + 4) % 5] ^ parity[(x + 1) % 5].rotate_left(1);
+            for y in (0..25).step_by(5) {
+                state[y + x] ^= d;
+            }
+        }
+        // ρ and π
+        let mut carried = state[1];
+        for (&lane, &rotation) in PI_LANES.iter().zip(&ROTATIONS) {
+            let next = state[lane];
+            state[lane] = carried.rotate_left(rotation);
+            carried = next;
+        }
+        // χ
+        for y in (0..25).step_by(5) {
+            let row = [
+                state[y],
+                state[y + 1],
+                state[y + 2],
+                state[y + 3],
+                state[y + 4],
+            ];
+            for x in 0..5 {
+                state[y + x] = row[x] ^ (!row[(x + 1) % 5] & row[(x + 2) % 5]);
+            }
+        }
+        // ι
+        state[0] ^= round_constant;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::table_hash;
+    use crate::testing::{int, real, text, TestDatabase};
+
+    #[test]
+    fn without_rowid_tables_hash_in_key_order() {
+        // More rows than fit on one 512-byte page, so the key order spans
+        // interior pages.
+        let rows = |order: &mut dyn Iterator<Item = i64>| {
+            order
+                .map(|i| vec![text(&format!("row {:04}", i)), int(i % 7), real(i as f64)])
+                .collect::<Vec<_>>()
+        };
+        let mut keyed = (0..300).collect::<Vec<i64>>();
+        keyed.sort_by_key(|&i| (i % 7, i));
+        let mut db = TestDatabase::new()
+            .page_size(512)
+            .table(
+                "CREATE TABLE w (a TEXT, b INT, c REAL, PRIMARY KEY (b, a)) WITHOUT ROWID",
+                rows(&mut (0..300).rev()),
+            )
+            .table(
+                "CREATE TABLE r (a TEXT, b INT, c REAL)",
+                rows(&mut keyed.into_iter()),
+            )
+            .table(
+                "CREATE TABLE s (a TEXT, b INT, c REAL)",
+                rows(&mut (0..300)),
+            )
+            .open()
+            .unwrap();
+
+        let without_rowid = table_hash(&mut db, "w").unwrap();
+        assert_eq!(without_rowid, table_hash(&mut db, "r").unwrap());
+        assert_ne!(without_rowid, table_hash(&mut db, "s").unwrap());
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use std::os::raw::c_int;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Once,
+};
+use std::time::Duration;
+
+/// Why a statement stopped before finishing.
+#[derive(Debug, thiserror::Error)]
+pub enum Aborted {
+    #[error("Interrupted")]
+    Interrupted,
+    #[error("Statement timed out after {} ms", .0.as_millis())]
+    Timeout(Duration),
+}
+
+/// A cloneable flag asking the running statement to stop. B-tree traversal and
+/// the row loops check it, so a long scan ends with an `Aborted::Interrupted`
+/// error at the next page or row instead of running to completion.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<Flags>);
+
+#[derive(Debug, Default)]
+struct Flags {
+    interrupted: AtomicBool,
+    /// Set by `interrupt_on_sigint`: a SIGINT since `sigints_seen` counts as
+    /// an interrupt too.
+    on_sigint: AtomicBool,
+    sigints_seen: AtomicUsize,
+}
+
+impl Interrupt {
+    pub fn interrupt(&self) {
+        self.0.interrupted.store(true, Ordering::SeqCst);
+    }
+
+    /// Resets the flag; called at the start of every statement so an earlier
+    /// Ctrl-C doesn't abort the next one.
+    pub fn clear(&self) {
+        self.0.interrupted.store(false, Ordering::SeqCst);
+        self.0
+            .sigints_seen
+            .store(SIGINTS.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.interrupted.load(Ordering::Relaxed)
+            || (self.0.on_sigint.load(Ordering::Relaxed)
+                && SIGINTS.load(Ordering::Relaxed) != self.0.sigints_seen.load(Ordering::Relaxed))
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.is_interrupted() {
+            return Err(Aborted::Interrupted.into());
+        }
+        Ok(())
+    }
+}
+
+/// How many SIGINTs have arrived. A signal handler can only reach statics,
+/// and an atomic add is async-signal-safe.
+static SIGINTS: AtomicUsize = AtomicUsize::new(0);
+
+static INSTALL_HANDLER: Once = Once::new();
+
+/// Makes Ctrl-C interrupt `interrupt` instead of killing the process. Any
+/// number of interrupts may follow it, one per open database; the handler is
+/// installed by the first call. Where there's no handler to install, Ctrl-C
+/// still kills the process.
+pub fn interrupt_on_sigint(interrupt: &Interrupt) -> Result<()> {
+    let mut installed = Ok(());
+    INSTALL_HANDLER.call_once(|| installed = install_sigint_handler());
+    installed.context("Failed to install the Ctrl-C handler")?;
+    interrupt.clear();
+    interrupt.0.on_sigint.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+extern "C" fn on_sigint(_: c_int) {
+    SIGINTS.fetch_add(1, Ordering::SeqCst);
+}
+
+const SIGINT: c_int = 2;
+
+/// `struct sigaction` as glibc and musl lay it out.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SigAction {
+    handler: extern "C" fn(c_int),
+    mask: [u64; 16],
+    flags: c_int,
+    restorer: usize,
+}
+
+#[cfg(target_os = "linux")]
+const SA_RESTART: c_int = 0x1000_0000;
+
+#[cfg(target_os = "macos")]
+#[repr(C)]
+struct SigAction {
+    handler: extern "C" fn(c_int),
+    mask: u32,
+    flags: c_int,
+}
+
+#[cfg(target_os = "macos")]
+const SA_RESTART: c_int = 0x2;
+
+/// Installs `on_sigint` with an empty mask. Reads and writes it cuts short
+/// are restarted, so a Ctrl-C at the prompt doesn't end the input.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn install_sigint_handler() -> std::io::Result<()> {
+    extern "C" {
+        fn sigaction(signum: c_int, action: *const SigAction, old: *mut SigAction) -> c_int;
+    }
+
+    #[cfg(target_os = "linux")]
+    let action = SigAction {
+        handler: on_sigint,
+        mask: [0; 16],
+        flags: SA_RESTART,
+        restorer: 0,
+    };
+    #[cfg(target_os = "macos")]
+    let action = SigAction {
+        handler: on_sigint,
+        mask: 0,
+        flags: SA_RESTART,
+    };
+    // SAFETY: `action` is a valid `struct sigaction` for this platform, and
+    // `on_sigint` only touches an atomic.
+    if unsafe { sigaction(SIGINT, &action, std::ptr::null_mut()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn install_sigint_handler() -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn sigint_interrupts_what_follows_it() {
+        extern "C" {
+            fn raise(signum: c_int) -> c_int;
+        }
+
+        let (following, other) = (Interrupt::default(), Interrupt::default());
+        interrupt_on_sigint(&following).unwrap();
+        assert!(!following.is_interrupted());
+
+        assert_eq!(unsafe { raise(SIGINT) }, 0);
+        assert!(following.is_interrupted());
+        assert!(!other.is_interrupted());
+
+        following.clear();
+        assert!(!following.is_interrupted());
+    }
+}
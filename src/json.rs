@@ -0,0 +1,519 @@
+use crate::record::{format_real, Value};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
+
+/// A parsed JSON document. Objects keep their keys in document order and
+/// numbers keep the text they were written with, as SQLite's JSON functions
+/// do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Real(f64),
+    /// A number from a parsed document that an `Int` wouldn't write back the
+    /// same, such as `1.5e2`, `1e400` or `9223372036854775808`.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Looks up a key of an object; the first occurrence wins.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Looks up a JSON path as SQLite's JSON functions take it: `$` followed
+    /// by `.key`, `."key"`, `[N]` or `[#-N]` steps. `None` when nothing is
+    /// there; an error when `path` isn't a path.
+    pub fn at_path(&self, path: &str) -> Result<Option<&Json>> {
+        let bad_path = || anyhow!("Bad JSON path: '{}'", path);
+        let mut rest = path.strip_prefix('$').ok_or_else(bad_path)?;
+        let mut node = self;
+        while !rest.is_empty() {
+            let child = if let Some(after) = rest.strip_prefix('.') {
+                let (key, tail) = match after.strip_prefix('"') {
+                    Some(quoted) => {
+                        let end = quoted.find('"').ok_or_else(bad_path)?;
+                        (&quoted[..end], &quoted[end + 1..])
+                    }
+                    None => after.split_at(after.find(['.', '[']).unwrap_or(after.len())),
+                };
+                if key.is_empty() && !after.starts_with('"') {
+                    return Err(bad_path());
+                }
+                rest = tail;
+                node.get(key)
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(bad_path)?;
+                let index = after[..end].trim();
+                rest = &after[end + 1..];
+                let Json::Array(items) = node else {
+                    return Ok(None);
+                };
+                let position = match index.strip_prefix('#') {
+                    Some("") => Some(items.len()),
+                    Some(from_end) => {
+                        let back = from_end
+                            .trim_start()
+                            .strip_prefix('-')
+                            .and_then(|n| n.trim().parse::<usize>().ok())
+                            .ok_or_else(bad_path)?;
+                        items.len().checked_sub(back)
+                    }
+                    None => Some(index.parse::<usize>().map_err(|_| bad_path())?),
+                };
+                position.and_then(|i| items.get(i))
+            } else {
+                return Err(bad_path());
+            };
+            match child {
+                Some(child) => node = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(node))
+    }
+
+    /// The type name `json_type()` gives this value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(true) => "true",
+            Json::Bool(false) => "false",
+            Json::Int(_) => "integer",
+            Json::Real(_) => "real",
+            Json::Number(text) if is_integer_literal(text) => "integer",
+            Json::Number(_) => "real",
+            Json::String(_) => "text",
+            Json::Array(_) => "array",
+            Json::Object(_) => "object",
+        }
+    }
+
+    /// The SQL value `json_extract()` turns this into: booleans become 1 and
+    /// 0, strings lose their quotes, and arrays and objects stay JSON text.
+    pub fn to_value(&self) -> Value {
+        match self {
+            Json::Null => Value::Null,
+            Json::Bool(b) => Value::Int(*b as i64),
+            Json::Int(i) => Value::Int(*i),
+            Json::Real(r) => Value::Float(*r),
+            // Integers too big for an i64 come out as reals, as in SQLite.
+            Json::Number(text) => match text.parse::<i64>() {
+                Ok(i) if is_integer_literal(text) => Value::Int(i),
+                _ => Value::Float(text.parse().unwrap_or(0.0)),
+            },
+            Json::String(s) => Value::Text(s.as_str().into()),
+            Json::Array(_) | Json::Object(_) => Value::Text(self.to_string().into()),
+        }
+    }
+}
+
+fn is_integer_literal(text: &str) -> bool {
+    !text.contains(['.', 'e', 'E'])
+}
+
+/// Parses a SQL value holding JSON text, as the JSON functions take their
+/// first argument. `None` for NULL.
+pub fn json_argument(value: &Value) -> Result<Option<Json>> {
+    let text = match value {
+        Value::Null => return Ok(None),
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => format_real(*f),
+        Value::Text(text) => text.to_string(),
+        Value::Blob(_) => bail!("JSONB blobs are not supported"),
+    };
+    parse_json(&text).map(Some)
+}
+
+/// The path step that selects `key` in an object: `.key`, or `."key"` unless
+/// it is an ASCII letter followed by ASCII letters and digits, the same
+/// test SQLite makes.
+pub fn key_path_step(key: &str) -> String {
+    let plain = key.bytes().next().is_some_and(|b| b.is_ascii_alphabetic())
+        && key.bytes().all(|b| b.is_ascii_alphanumeric());
+    if plain {
+        format!(".{}", key)
+    } else {
+        format!(".{}", Json::String(key.to_string()))
+    }
+}
+
+/// Converts a column value for JSON output. Blobs have no JSON form and are
+/// written as lowercase hex strings; NaN becomes null.
+impl From<&Value> for Json {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => Json::Null,
+            Value::Int(i) => Json::Int(*i),
+            Value::Float(f) if f.is_nan() => Json::Null,
+            Value::Float(f) => Json::Real(*f),
+            Value::Text(s) => Json::String(s.to_string()),
+            Value::Blob(bytes) => {
+                Json::String(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(i) => write!(f, "{}", i),
+            // Infinities are written too large to be anything else, as
+            // SQLite writes them.
+            Json::Real(r) if r.is_infinite() => {
+                write!(f, "{}9.0e+999", if *r < 0.0 { "-" } else { "" })
+            }
+            // `{:?}` keeps a ".0" on integral values, so they read back as reals.
+            Json::Real(r) => write!(f, "{:?}", r),
+            Json::Number(text) => write!(f, "{}", text),
+            Json::String(s) => write_quoted(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_quoted(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_quoted(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Parses a complete JSON document; trailing non-whitespace is an error.
+pub fn parse_json(text: &str) -> Result<Json> {
+    let mut parser = JsonParser {
+        bytes: text.as_bytes(),
+        text,
+        pos: 0,
+    };
+    let value = parser.parse_value(0)?;
+    parser.skip_whitespace();
+    if parser.pos != parser.bytes.len() {
+        bail!("Malformed JSON: unexpected data at offset {}", parser.pos);
+    }
+    Ok(value)
+}
+
+/// Deeper documents are rejected rather than risking a stack overflow.
+const MAX_JSON_DEPTH: usize = 1000;
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    text: &'a str,
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(|b| matches!(b, b' ' | b'\t' | b'\n' | b'\r'))
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        self.skip_whitespace();
+        if self.bytes.get(self.pos) != Some(&byte) {
+            bail!(
+                "Malformed JSON: expected '{}' at offset {}",
+                byte as char,
+                self.pos
+            );
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Json> {
+        if depth > MAX_JSON_DEPTH {
+            bail!("Malformed JSON: nesting is too deep");
+        }
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    // A trailing comma is allowed, as in JSON5.
+                    if !members.is_empty() && self.bytes.get(self.pos) == Some(&b'}') {
+                        self.pos += 1;
+                        return Ok(Json::Object(members));
+                    }
+                    let key = self.parse_string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.parse_value(depth + 1)?));
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(members));
+                        }
+                        _ => bail!(
+                            "Malformed JSON: expected ',' or '}}' at offset {}",
+                            self.pos
+                        ),
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if !items.is_empty() && self.bytes.get(self.pos) == Some(&b']') {
+                        self.pos += 1;
+                        return Ok(Json::Array(items));
+                    }
+                    items.push(self.parse_value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => bail!("Malformed JSON: expected ',' or ']' at offset {}", self.pos),
+                    }
+                }
+            }
+            Some(b'"') => Ok(Json::String(self.parse_string()?)),
+            Some(b't') => self.parse_keyword("true", Json::Bool(true)),
+            Some(b'f') => self.parse_keyword("false", Json::Bool(false)),
+            Some(b'n') => self.parse_keyword("null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            _ => bail!(
+                "Malformed JSON: unexpected character at offset {}",
+                self.pos
+            ),
+        }
+    }
+
+    fn parse_keyword(&mut self, keyword: &str, value: Json) -> Result<Json> {
+        if !self.text[self.pos..].starts_with(keyword) {
+            bail!(
+                "Malformed JSON: unexpected character at offset {}",
+                self.pos
+            );
+        }
+        self.pos += keyword.len();
+        Ok(value)
+    }
+
+    fn parse_number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        let mut is_real = false;
+        if self.bytes.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        while let Some(&b) = self.bytes.get(self.pos) {
+            match b {
+                b'0'..=b'9' => {}
+                b'.' | b'e' | b'E' => is_real = true,
+                b'+' | b'-' if is_real => {}
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let literal = &self.text[start..self.pos];
+        if !is_real {
+            if let Some(i) = literal
+                .parse::<i64>()
+                .ok()
+                .filter(|i| i.to_string() == literal)
+            {
+                return Ok(Json::Int(i));
+            }
+        }
+        literal
+            .parse::<f64>()
+            .with_context(|| format!("Malformed JSON: invalid number '{}'", literal))?;
+        Ok(Json::Number(literal.to_string()))
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        if self.bytes.get(self.pos) != Some(&b'"') {
+            bail!("Malformed JSON: expected a string at offset {}", self.pos);
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let Some(c) = self.text[self.pos..].chars().next() else {
+                bail!("Malformed JSON: unterminated string");
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let Some(&escape) = self.bytes.get(self.pos) else {
+                        bail!("Malformed JSON: unterminated string");
+                    };
+                    self.pos += 1;
+                    match escape {
+                        b'"' => out.push('"'),
+                        b'\\' => out.push('\\'),
+                        b'/' => out.push('/'),
+                        b'b' => out.push('\u{8}'),
+                        b'f' => out.push('\u{c}'),
+                        b'n' => out.push('\n'),
+                        b'r' => out.push('\r'),
+                        b't' => out.push('\t'),
+                        b'u' => {
+                            let high = self.parse_hex4()?;
+                            let code = if (0xD800..0xDC00).contains(&high)
+                                && self.text[self.pos..].starts_with("\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.parse_hex4()?;
+                                0x10000
+                                    + ((high - 0xD800) << 10)
+                                    + (low.wrapping_sub(0xDC00) & 0x3FF)
+                            } else {
+                                high
+                            };
+                            out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        _ => bail!("Malformed JSON: invalid escape at offset {}", self.pos - 1),
+                    }
+                }
+                c if (c as u32) < 0x20 => {
+                    bail!("Malformed JSON: control character in string")
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .context("Malformed JSON: truncated \\u escape")?;
+        let code = u32::from_str_radix(digits, 16)
+            .with_context(|| format!("Malformed JSON: invalid \\u escape '{}'", digits))?;
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_path_step, parse_json};
+    use crate::record::Value;
+    use crate::testing::{int, query, real, select, text, TestDatabase};
+
+    #[test]
+    fn keeps_numbers_as_written() {
+        let json = r#"[1,2.50,1e400,123456789012345678901,-0]"#;
+        assert_eq!(parse_json(json).unwrap().to_string(), json);
+        assert_eq!(select(r#"json_type('{"a":1.5e2}', '$.a')"#), text("real"));
+        assert_eq!(select("json_type('[9e999]', '$[0]')"), text("real"));
+        assert_eq!(
+            select("json_extract('[1e400]', '$[0]')"),
+            real(f64::INFINITY)
+        );
+        assert_eq!(
+            select("json_extract('[12345678901234567890]', '$[0]')"),
+            real(12345678901234567890.0)
+        );
+    }
+
+    #[test]
+    fn accepts_trailing_commas() {
+        assert_eq!(parse_json(r#"[1,2,]"#).unwrap().to_string(), "[1,2]");
+        assert_eq!(parse_json(r#"{"a":1,}"#).unwrap().to_string(), r#"{"a":1}"#);
+        assert!(parse_json("[1,,]").is_err());
+        assert!(parse_json("[,]").is_err());
+    }
+
+    #[test]
+    fn extracts_values() {
+        let doc = r#"'{"a":[1,[2,3],{}],"n":null}'"#;
+        assert_eq!(
+            select(&format!("json_extract({}, '$.a[1][0]')", doc)),
+            int(2)
+        );
+        assert_eq!(
+            select(&format!("json_extract({}, '$.n')", doc)),
+            Value::Null
+        );
+        assert_eq!(select(&format!("json_type({}, '$.n')", doc)), text("null"));
+        assert_eq!(
+            select(&format!("json_extract({}, '$.missing')", doc)),
+            Value::Null
+        );
+        assert_eq!(
+            select(&format!("json_array_length({}, '$.a')", doc)),
+            int(3)
+        );
+    }
+
+    #[test]
+    fn quotes_keys_that_are_not_plain_names() {
+        assert_eq!(key_path_step("ok2"), ".ok2");
+        assert_eq!(key_path_step("x_y"), r#"."x_y""#);
+        assert_eq!(key_path_step("b c"), r#"."b c""#);
+        assert_eq!(key_path_step("2a"), r#"."2a""#);
+        let image = TestDatabase::new().build().unwrap();
+        let rows = query(
+            &image,
+            r#"SELECT fullkey FROM json_each('{"b c":1,"x_y":[1],"ok":"s"}')"#,
+        )
+        .unwrap();
+        let keys = [r#"$."b c""#, r#"$."x_y""#, "$.ok"];
+        assert_eq!(rows, keys.map(|key| vec![text(key)]).to_vec());
+    }
+}
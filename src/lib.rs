@@ -0,0 +1,300 @@
+//! A read-only SQLite file reader. The `sequel` binary is a thin CLI over
+//! this library; embed it directly through [`Connection`].
+
+pub mod aggregate;
+pub mod backup;
+pub mod bench;
+pub mod budget;
+pub mod collation;
+pub mod copy;
+pub mod database;
+pub mod datetime;
+pub mod error;
+pub mod eval;
+pub mod fts5;
+pub mod functions;
+pub mod hash;
+pub mod interrupt;
+pub mod json;
+pub mod parser;
+pub mod pipe;
+pub mod plan;
+pub mod printf;
+pub mod query;
+pub mod record;
+pub mod schema;
+pub mod server;
+pub mod shell;
+pub mod table_function;
+pub mod temp;
+pub mod testing;
+pub mod virtual_table;
+mod wal;
+pub mod window;
+pub mod writer;
+
+use anyhow::{bail, Result};
+use database::{Database, Progress};
+use error::Error;
+use interrupt::Interrupt;
+use record::Value;
+use std::time::Duration;
+
+/// An open database file, ready to run statements.
+pub struct Connection {
+    db: Database,
+}
+
+impl Connection {
+    pub fn open(path: &str) -> Result<Self> {
+        Ok(Connection {
+            db: Database::open(path)?,
+        })
+    }
+
+    /// Opens a database that must never be written; see
+    /// [`Database::open_read_only`].
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        Ok(Connection {
+            db: Database::open_read_only(path)?,
+        })
+    }
+
+    /// Opens a database image held in memory, e.g. one made with
+    /// [`testing::TestDatabase`].
+    pub fn from_bytes(image: Vec<u8>) -> Result<Self> {
+        Ok(Connection {
+            db: Database::from_bytes(image)?,
+        })
+    }
+
+    /// Runs `sql` and calls `on_row` with each result row as soon as it is
+    /// produced, so huge tables are processed in constant memory. An error
+    /// returned by `on_row` stops the statement and is passed through.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// let mut conn = sequel::Connection::open("companies.db")?;
+    /// let mut names = 0;
+    /// conn.query_each("SELECT name FROM companies", |_row| {
+    ///     names += 1;
+    ///     Ok(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn query_each(
+        &mut self,
+        sql: &str,
+        on_row: impl FnMut(Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
+        self.db.begin_statement()?;
+        let prepared = query::prepare(&mut self.db, sql)?;
+        prepared.run(&mut self.db, on_row)
+    }
+
+    /// A statement whose `?`, `?N`, `:name`, `@name` and `$name` parameters
+    /// take the values bound to them when it runs, rather than values
+    /// pasted into the SQL.
+    ///
+    /// ```
+    /// # fn main() -> anyhow::Result<()> {
+    /// use sequel::record::Value;
+    ///
+    /// let mut conn = sequel::Connection::open(":memory:")?;
+    /// let mut statement = conn.prepare("SELECT upper(:name), ?2 * 2")?;
+    /// statement.bind_named(":name", Value::Text("o'brien".into()))?;
+    /// statement.bind(2, Value::Int(21))?;
+    /// let mut rows = Vec::new();
+    /// statement.query_each(|row| {
+    ///     rows.push(row);
+    ///     Ok(())
+    /// })?;
+    /// assert_eq!(rows, vec![vec![Value::Text("O'BRIEN".into()), Value::Int(42)]]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn prepare(&mut self, sql: &str) -> Result<Statement<'_>> {
+        let parameters = parser::parameters(sql).map_err(|e| Error::Parse(format!("{:#}", e)))?;
+        Ok(Statement {
+            values: vec![Value::Null; parameters.len()],
+            parameters,
+            sql: sql.to_string(),
+            conn: self,
+        })
+    }
+
+    /// Names of the columns `sql` would return, without running it.
+    pub fn column_names(&mut self, sql: &str) -> Result<Vec<String>> {
+        self.db.begin_statement()?;
+        Ok(query::prepare(&mut self.db, sql)?.columns().to_vec())
+    }
+
+    /// See [`Database::set_timeout`].
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.db.set_timeout(timeout);
+    }
+
+    /// See [`Database::set_memory_limit`].
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.db.set_memory_limit(limit);
+    }
+
+    /// See [`Database::set_progress_handler`].
+    pub fn set_progress_handler(
+        &mut self,
+        every_n_pages: u64,
+        handler: impl FnMut(&Progress) -> bool + 'static,
+    ) {
+        self.db.set_progress_handler(every_n_pages, handler);
+    }
+
+    /// See [`Database::set_lossy_text`].
+    pub fn set_lossy_text(&mut self, lossy_text: bool) {
+        self.db.set_lossy_text(lossy_text);
+    }
+
+    /// See [`Database::set_intern_text`].
+    pub fn set_intern_text(&mut self, intern_text: bool) {
+        self.db.set_intern_text(intern_text);
+    }
+
+    /// Registers a scalar function callable from SQL; see
+    /// [`Database::create_scalar_function`].
+    ///
+    /// ```
+    /// # fn main() -> anyhow::Result<()> {
+    /// use sequel::record::Value;
+    ///
+    /// let mut conn = sequel::Connection::open(":memory:")?;
+    /// conn.create_scalar_function("half", 1, |args| {
+    ///     Ok(match &args[0] {
+    ///         Value::Int(i) => Value::Float(*i as f64 / 2.0),
+    ///         _ => Value::Null,
+    ///     })
+    /// });
+    /// let mut rows = Vec::new();
+    /// conn.query_each("SELECT half(7)", |row| {
+    ///     rows.push(row);
+    ///     Ok(())
+    /// })?;
+    /// assert_eq!(rows, vec![vec![Value::Float(3.5)]]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn create_scalar_function(
+        &mut self,
+        name: &str,
+        n_args: i32,
+        function: impl Fn(&[Value]) -> Result<Value> + 'static,
+    ) {
+        self.db.create_scalar_function(name, n_args, function);
+    }
+
+    /// Registers an aggregate callable from SQL; see
+    /// [`Database::create_aggregate_function`].
+    pub fn create_aggregate_function<A: functions::Aggregate + 'static>(
+        &mut self,
+        name: &str,
+        n_args: i32,
+        new: impl Fn() -> A + 'static,
+    ) {
+        self.db.create_aggregate_function(name, n_args, new);
+    }
+
+    /// Registers a collation; see [`Database::create_collation`].
+    pub fn create_collation(
+        &mut self,
+        name: &str,
+        compare: impl Fn(&str, &str) -> std::cmp::Ordering + 'static,
+    ) {
+        self.db.create_collation(name, compare);
+    }
+
+    /// Registers a table backed by Rust; see [`Database::create_virtual_table`].
+    pub fn create_virtual_table(
+        &mut self,
+        name: &str,
+        table: impl virtual_table::VirtualTable + 'static,
+    ) -> Result<()> {
+        self.db.create_virtual_table(name, table)
+    }
+
+    /// Copies the whole database into a standalone file at `destination`,
+    /// page by page; see [`backup::Backup`] to copy a few pages at a time.
+    pub fn backup(&mut self, destination: &str) -> Result<backup::BackupStats> {
+        backup::backup(&mut self.db, destination)
+    }
+
+    /// A handle that interrupts the running statement from another thread.
+    pub fn interrupt_handle(&self) -> Interrupt {
+        self.db.interrupt_handle()
+    }
+
+    /// The underlying page-level reader.
+    pub fn database(&mut self) -> &mut Database {
+        &mut self.db
+    }
+}
+
+/// A statement with parameters, from [`Connection::prepare`]. Parameters
+/// nothing is bound to are NULL.
+pub struct Statement<'c> {
+    conn: &'c mut Connection,
+    sql: String,
+    parameters: Vec<Option<String>>,
+    values: Vec<Value>,
+}
+
+impl Statement<'_> {
+    /// How many parameters the statement has: the highest parameter number.
+    pub fn parameter_count(&self) -> usize {
+        self.parameters.len()
+    }
+
+    /// The name of parameter `index`, counting from 1, as written with its
+    /// `:`, `@`, `$` or `?`; `None` for a bare `?`.
+    pub fn parameter_name(&self, index: usize) -> Option<&str> {
+        self.parameters.get(index.checked_sub(1)?)?.as_deref()
+    }
+
+    /// Binds `value` to parameter `index`, counting from 1 like SQLite.
+    pub fn bind(&mut self, index: usize, value: Value) -> Result<()> {
+        match index.checked_sub(1).and_then(|i| self.values.get_mut(i)) {
+            Some(slot) => *slot = value,
+            None => bail!(Error::NotFound(format!(
+                "Parameter index {} is out of range: the statement has {}",
+                index,
+                self.parameters.len()
+            ))),
+        }
+        Ok(())
+    }
+
+    /// Binds `value` to the parameter called `name`, e.g. `:country`.
+    pub fn bind_named(&mut self, name: &str, value: Value) -> Result<()> {
+        match self
+            .parameters
+            .iter()
+            .position(|other| other.as_deref() == Some(name))
+        {
+            Some(i) => self.bind(i + 1, value),
+            None => bail!(Error::NotFound(format!("No parameter named {}", name))),
+        }
+    }
+
+    /// Runs the statement with the values bound so far; see
+    /// [`Connection::query_each`].
+    pub fn query_each(&mut self, on_row: impl FnMut(Vec<Value>) -> Result<()>) -> Result<()> {
+        let db = &mut self.conn.db;
+        db.begin_statement()?;
+        db.set_parameters(self.values.clone());
+        let prepared = query::prepare(db, &self.sql)?;
+        prepared.run(db, on_row)
+    }
+
+    /// Names of the columns the statement returns, without running it.
+    pub fn column_names(&mut self) -> Result<Vec<String>> {
+        self.conn.column_names(&self.sql)
+    }
+}
@@ -1,223 +1,390 @@
-mod database;
-mod parser;
-mod record;
+use anyhow::Result;
+use sequel::bench::{self, CacheMode};
+use sequel::database::{Database, EstimateSource};
+use sequel::error::{exit_code, Error, EXIT_SUCCESS};
+use sequel::interrupt::{self, Interrupt};
+use sequel::parser::{parse_where_expression, Expr};
+use sequel::plan::PlanFormat;
+use sequel::record::Value;
+use sequel::shell::{self, Shell};
+use sequel::{backup, copy, pipe, server};
+use std::io::Write;
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Context, Result};
-use database::Database;
-use parser::{parse_query, QueryType, WhereCondition};
-use record::Value;
+/// Connection settings shared by every mode.
+#[derive(Default, Clone)]
+struct Settings {
+    lossy_text: bool,
+    intern_text: bool,
+    read_only: bool,
+    timeout: Option<Duration>,
+    memory_limit: Option<usize>,
+    progress_every: u64,
+}
 
-fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
+impl Settings {
+    /// Opens the database at `db_path`; Ctrl-C interrupts its statements.
+    fn open(&self, db_path: &str) -> Result<Database> {
+        let mut db = if self.read_only {
+            Database::open_read_only(db_path)?
+        } else {
+            Database::open(db_path)?
+        };
+        db.set_lossy_text(self.lossy_text);
+        db.set_intern_text(self.intern_text);
+        db.set_timeout(self.timeout);
+        db.set_memory_limit(self.memory_limit);
+        interrupt::interrupt_on_sigint(&db.interrupt_handle())?;
+        db.set_progress_handler(self.progress_every, |progress| {
+            eprintln!(
+                "progress: {} pages read ({} in database)",
+                progress.pages_read, progress.page_count
+            );
+            false
+        });
+        Ok(db)
+    }
+}
 
-    if args.len() < 3 {
-        bail!("Usage: {} <database path> <command>", args[0]);
+fn main() {
+    match run() {
+        Ok(status) => std::process::exit(status),
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(exit_code(&e));
+        }
     }
+}
 
-    let db_path = &args[1];
-    let command = &args[2];
+/// Runs the CLI and returns the process exit status; see `error` for the codes.
+fn run() -> Result<i32> {
+    let mut raw_args = std::env::args();
+    let program = raw_args.next().unwrap_or_else(|| "sequel".to_string());
 
-    if command.starts_with('.') {
-        match command.as_str() {
-            ".dbinfo" => handle_dbinfo(db_path),
-            ".tables" => handle_tables(db_path),
-            _ => bail!("Unsupported command: {}", command),
-        }
-    } else {
-        match parse_query(command)? {
-            QueryType::Select {
-                columns,
-                table,
-                where_clause,
-            } => handle_select(db_path, &columns, &table, where_clause),
-            QueryType::SelectCount { table } => handle_count(db_path, &table),
-            QueryType::Unknown => bail!("Unknown or unsupported SQL command: {}", command),
+    let mut settings = Settings::default();
+    let mut pipe = false;
+    let mut bail = false;
+    let mut quiet = false;
+    let mut plan_format = PlanFormat::Rows;
+    let mut parameters = Vec::new();
+    let mut named_parameters = Vec::new();
+    let mut args = Vec::new();
+    while let Some(arg) = raw_args.next() {
+        match arg.as_str() {
+            "--lossy-text" => settings.lossy_text = true,
+            "--intern-text" => settings.intern_text = true,
+            "--readonly" => settings.read_only = true,
+            "--pipe" => pipe = true,
+            "--bail" => bail = true,
+            "--quiet" => quiet = true,
+            "--timeout" => {
+                let millis: u64 = raw_args
+                    .next()
+                    .and_then(|millis| millis.parse().ok())
+                    .ok_or_else(|| usage("--timeout needs a value in milliseconds"))?;
+                // 0 means no limit.
+                settings.timeout = (millis > 0).then(|| Duration::from_millis(millis));
+            }
+            "--max-memory" => {
+                let bytes = raw_args
+                    .next()
+                    .ok_or_else(|| usage("--max-memory needs a size, e.g. 64M"))?;
+                settings.memory_limit = Some(parse_size(&bytes)?);
+            }
+            "--plan-format" => {
+                let format = raw_args
+                    .next()
+                    .ok_or_else(|| usage("--plan-format needs rows, dot or json"))?;
+                plan_format = PlanFormat::parse(&format)?;
+            }
+            "--progress" => {
+                settings.progress_every = raw_args
+                    .next()
+                    .and_then(|pages| pages.parse().ok())
+                    .ok_or_else(|| usage("--progress needs a page interval"))?;
+            }
+            "--param" => {
+                let param = raw_args
+                    .next()
+                    .ok_or_else(|| usage("--param needs a value, or :name=value"))?;
+                match param.split_once('=') {
+                    Some((name, value)) if name.starts_with([':', '@', '$']) => {
+                        named_parameters.push((name.to_string(), parameter_value(value)))
+                    }
+                    _ => parameters.push(parameter_value(&param)),
+                }
+            }
+            _ => args.push(arg),
         }
     }
+
+    if args.first().map(String::as_str) == Some("serve") {
+        run_server(&program, &args[1..], &settings, quiet)?;
+        return Ok(EXIT_SUCCESS);
+    }
+    if args.first().map(String::as_str) == Some("bench") {
+        run_bench(&program, &args[1..], &settings)?;
+        return Ok(EXIT_SUCCESS);
+    }
+    if args.first().map(String::as_str) == Some("estimate") {
+        run_estimate(&program, &args[1..], &settings)?;
+        return Ok(EXIT_SUCCESS);
+    }
+    if args.first().map(String::as_str) == Some("backup") {
+        run_backup(&program, &args[1..], &settings, quiet)?;
+        return Ok(EXIT_SUCCESS);
+    }
+    if args.first().map(String::as_str) == Some("copy") {
+        run_copy(&program, &args[1..], &settings, quiet)?;
+        return Ok(EXIT_SUCCESS);
+    }
+
+    if args.is_empty() {
+        return Err(usage(&format!(
+            "Usage: {} [--lossy-text] [--intern-text] [--readonly] [--timeout <ms>] [--max-memory <size>] [--progress <pages>] [--plan-format rows|dot|json] [--param <value>|:name=<value>]... [--pipe] [--bail] [--quiet] <database path> [command]\n       {} [options] serve <database path> [--listen <address>]\n       {} [options] backup <database path> <backup path>\n       {} [options] copy --from <database path> --to <new database path> --table <name> [--indexes]\n       {} [options] bench <database path> <sql> [--iterations <n>] [--cache warm|cold|both] [--cache-pages <pages>]\n       {} [options] estimate <database path> <table>",
+            program, program, program, program, program, program
+        )));
+    }
+
+    let mut db = settings.open(&args[0])?;
+    if pipe {
+        pipe::run_pipe(&mut db)?;
+        return Ok(EXIT_SUCCESS);
+    }
+
+    let mut shell = Shell::new(db);
+    shell.set_bail(bail);
+    shell.set_quiet(quiet);
+    shell.set_plan_format(plan_format);
+    shell.set_parameters(parameters, named_parameters);
+    if let Some(config) = shell::config_file() {
+        shell.run_script(&config)?;
+    }
+    match args.get(1) {
+        Some(command) => shell.run_command(command)?,
+        None => shell.run_repl()?,
+    }
+    Ok(shell.exit_status())
 }
 
-fn get_table_column_names(sql_create_table: &str) -> Result<Vec<String>> {
-    let start_idx = sql_create_table
-        .find('(')
-        .context("Invalid CREATE TABLE syntax: missing '('")?;
-    let end_idx = sql_create_table
-        .rfind(')')
-        .context("Invalid CREATE TABLE syntax: missing ')'")?;
-
-    if start_idx >= end_idx {
-        bail!("Invalid CREATE TABLE syntax: '(' not before ')'");
-    }
-
-    let columns_str = &sql_create_table[start_idx + 1..end_idx];
-    let mut columns = vec!["id".to_string()];
-    columns.extend(
-        columns_str
-            .split(',')
-            .map(|col_def| {
-                col_def
-                    .trim()
-                    .split_whitespace()
-                    .next()
-                    .unwrap_or("")
-                    .to_string()
-            })
-            .filter(|s| !s.is_empty()),
-    );
-    Ok(columns)
+fn usage(message: &str) -> anyhow::Error {
+    Error::Usage(message.to_string()).into()
 }
 
-fn handle_select(
-    db_path: &str,
-    requested_column_names: &[String],
-    table_name: &str,
-    where_clause: Option<WhereCondition>,
-) -> Result<()> {
-    let mut db = Database::open(db_path)?;
-    let schema_entries = db.read_schema()?;
-
-    let table_entry = schema_entries
-        .iter()
-        .find(|e| e.typ == "table" && e.tbl_name == table_name)
-        .context(format!("Table '{}' not found", table_name))?;
-
-    let table_sql = table_entry.sql.as_ref().context(format!(
-        "No SQL definition found for table '{}'",
-        table_name
-    ))?;
-
-    let all_table_column_names = get_table_column_names(table_sql)?;
-
-    let output_column_indices = requested_column_names
-        .iter()
-        .map(|req_col_name| {
-            all_table_column_names
-                .iter()
-                .position(|name| name.eq_ignore_ascii_case(req_col_name))
-                .context(format!(
-                    "Column '{}' not found in table '{}'",
-                    req_col_name, table_name
-                ))
-        })
-        .collect::<Result<Vec<usize>>>()?;
+/// The value `--param` binds: a literal like `42`, `-1.5`, `'42'`, `NULL` or
+/// `X'00FF'` if it is one, else the text as it is.
+fn parameter_value(text: &str) -> Value {
+    match parse_where_expression(text) {
+        Ok(Expr::Literal(value)) => value,
+        _ => Value::Text(text.into()),
+    }
+}
 
-    let records = if let Some(condition) = &where_clause {
-        if condition.column.eq_ignore_ascii_case("country") && condition.operator == "=" {
-            let index_entry = schema_entries
-                .iter()
-                .find(|e| e.typ == "index" && e.tbl_name == table_name)
-                .context("No index found for table")?;
+/// Parses a byte count with an optional K, M or G (binary) suffix.
+fn parse_size(size: &str) -> Result<usize> {
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| {
+            usage(&format!(
+                "Invalid size '{}': expected bytes, or e.g. 512K, 64M, 1G",
+                size
+            ))
+        })
+}
 
-            let rowids = db.collect_index_rowids(index_entry.rootpage, &condition.value)?;
-            db.read_table_records_by_rowids(table_entry.rootpage, &rowids)?
-        } else {
-            let all_records = db.read_table_records(table_entry.rootpage)?;
-            let condition_column_index = all_table_column_names
-                .iter()
-                .position(|name| name.eq_ignore_ascii_case(&condition.column))
-                .context(format!(
-                    "WHERE clause column '{}' not found in table '{}'",
-                    condition.column, table_name
-                ))?;
-
-            all_records
-                .into_iter()
-                .filter(|record| {
-                    if condition_column_index < record.len() {
-                        match &record[condition_column_index] {
-                            Value::Text(val) => {
-                                if condition.operator == "=" {
-                                    val == &condition.value
-                                } else {
-                                    false
-                                }
-                            }
-                            Value::Int(val_int) => {
-                                if condition.operator == "=" {
-                                    if let Ok(cond_val_int) = condition.value.parse::<i64>() {
-                                        *val_int == cond_val_int
-                                    } else {
-                                        false
-                                    }
-                                } else {
-                                    false
-                                }
-                            }
-                            _ => false,
-                        }
-                    } else {
-                        false
-                    }
-                })
-                .collect()
+/// `serve <database path> [--listen <address>]`; listens on 127.0.0.1:8080 by
+/// default. Ctrl-C interrupts the queries running and stops the server.
+fn run_server(program: &str, args: &[String], settings: &Settings, quiet: bool) -> Result<()> {
+    let mut db_path = None;
+    let mut listen = "127.0.0.1:8080".to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => match args.next() {
+                Some(address) => listen = address.clone(),
+                None => return Err(usage("--listen needs an address, e.g. 127.0.0.1:8080")),
+            },
+            _ if db_path.is_none() => db_path = Some(arg),
+            _ => return Err(usage(&format!("Unexpected argument: {}", arg))),
         }
-    } else {
-        db.read_table_records(table_entry.rootpage)?
+    }
+    let Some(db_path) = db_path else {
+        return Err(usage(&format!(
+            "Usage: {} serve <database path> [--listen <address>]",
+            program
+        )));
     };
 
-    for record in records {
-        let mut values_to_print = Vec::new();
-        for &index in &output_column_indices {
-            if index < record.len() {
-                match &record[index] {
-                    Value::Text(value) => values_to_print.push(value.clone()),
-                    Value::Int(value) => values_to_print.push(value.to_string()),
-                    Value::Float(value) => values_to_print.push(value.to_string()),
-                    Value::Blob(_) => values_to_print.push("[BLOB]".to_string()),
-                    Value::Null => values_to_print.push("NULL".to_string()),
-                }
-            } else {
-                values_to_print.push("".to_string());
+    let (settings, db_path) = (settings.clone(), db_path.clone());
+    let stop = Interrupt::default();
+    interrupt::interrupt_on_sigint(&stop)?;
+    server::serve(move || settings.open(&db_path), &listen, &stop, quiet)
+}
+
+/// `copy --from <path> --to <path> --table <name> [--indexes]`.
+fn run_copy(program: &str, args: &[String], settings: &Settings, quiet: bool) -> Result<()> {
+    let (mut from, mut to, mut table) = (None, None, None);
+    let mut with_indexes = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "--from" => &mut from,
+            "--to" => &mut to,
+            "--table" => &mut table,
+            "--indexes" => {
+                with_indexes = true;
+                continue;
             }
-        }
-        println!("{}", values_to_print.join("|"));
+            _ => return Err(usage(&format!("Unexpected argument: {}", arg))),
+        };
+        *value = Some(
+            args.next()
+                .ok_or_else(|| usage(&format!("{} needs a value", arg)))?,
+        );
     }
+    let (Some(from), Some(to), Some(table)) = (from, to, table) else {
+        return Err(usage(&format!(
+            "Usage: {} copy --from <database path> --to <new database path> --table <name> [--indexes]",
+            program
+        )));
+    };
 
+    let mut db = settings.open(from)?;
+    let stats = copy::copy_table(&mut db, to, table, with_indexes)?;
+    if !quiet {
+        eprintln!(
+            "Copied {} rows of '{}' to {}, with {} indexes",
+            stats.rows,
+            table,
+            to,
+            stats.indexes.len()
+        );
+        for index in &stats.skipped_indexes {
+            eprintln!(
+                "Skipped index '{}': only indexes on plain columns covering every row are copied",
+                index
+            );
+        }
+    }
     Ok(())
 }
 
-fn handle_dbinfo(db_path: &str) -> Result<()> {
-    let mut db = Database::open(db_path)?;
-    println!("database page size: {}", db.page_size());
-
-    let mut num_tables = 0;
-    let schema = db.read_schema()?;
+/// `backup <database path> <backup path>`.
+fn run_backup(program: &str, args: &[String], settings: &Settings, quiet: bool) -> Result<()> {
+    let [from, to] = args else {
+        return Err(usage(&format!(
+            "Usage: {} backup <database path> <backup path>",
+            program
+        )));
+    };
 
-    for entry in schema {
-        if entry.typ == "table" && !entry.tbl_name.starts_with("sqlite_") {
-            num_tables += 1;
-        }
+    let mut db = settings.open(from)?;
+    let stats = backup::backup(&mut db, to)?;
+    if !quiet {
+        eprintln!(
+            "Backed up {} pages to {} ({} restarts after concurrent writes)",
+            stats.pages, to, stats.restarts
+        );
     }
-
-    println!("number of tables: {}", num_tables);
     Ok(())
 }
 
-fn handle_tables(db_path: &str) -> Result<()> {
-    let mut db = Database::open(db_path)?;
-    let schema = db.read_schema()?;
-
-    let mut table_names = Vec::new();
-    for entry in schema {
-        if entry.typ == "table" && !entry.tbl_name.starts_with("sqlite_") {
-            table_names.push(entry.tbl_name);
+/// `bench <database path> <sql> [--iterations <n>] [--cache warm|cold|both]
+/// [--cache-pages <pages>]`; prints one line of timings per cache mode.
+fn run_bench(program: &str, args: &[String], settings: &Settings) -> Result<()> {
+    let mut positional = Vec::new();
+    let mut iterations = 10;
+    let mut modes = vec![CacheMode::Cold, CacheMode::Warm];
+    let mut cache_pages = 2000;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--iterations" => {
+                iterations = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| usage("--iterations needs a positive number"))?;
+            }
+            "--cache" => {
+                modes = match args.next().map(String::as_str) {
+                    Some("cold") => vec![CacheMode::Cold],
+                    Some("warm") => vec![CacheMode::Warm],
+                    Some("both") => vec![CacheMode::Cold, CacheMode::Warm],
+                    _ => return Err(usage("--cache needs warm, cold or both")),
+                };
+            }
+            "--cache-pages" => {
+                cache_pages = args
+                    .next()
+                    .and_then(|pages| pages.parse().ok())
+                    .ok_or_else(|| usage("--cache-pages needs a number of pages"))?;
+            }
+            _ => positional.push(arg),
         }
     }
+    let [db_path, sql] = positional.as_slice() else {
+        return Err(usage(&format!(
+            "Usage: {} bench <database path> <sql> [--iterations <n>] [--cache warm|cold|both] [--cache-pages <pages>]",
+            program
+        )));
+    };
 
-    println!("{}", table_names.join(" "));
+    let mut db = settings.open(db_path)?;
+    db.set_cache_size(cache_pages);
+    for mode in modes {
+        writeln!(
+            std::io::stdout(),
+            "{}",
+            bench::bench(&mut db, sql, iterations, mode)?
+        )?;
+    }
     Ok(())
 }
 
-fn handle_count(db_path: &str, table_name: &str) -> Result<()> {
-    let mut db = Database::open(db_path)?;
-    let schema = db.read_schema()?;
-
-    let entry = schema
-        .iter()
-        .find(|e| e.typ == "table" && e.tbl_name == table_name)
-        .context(format!("Table '{}' not found", table_name))?;
-
-    let records = db.read_table_records(entry.rootpage)?;
-    println!("{}", records.len());
+/// `estimate <database path> <table>`; prints the approximate row count and
+/// where it came from.
+fn run_estimate(program: &str, args: &[String], settings: &Settings) -> Result<()> {
+    let [db_path, table] = args else {
+        return Err(usage(&format!(
+            "Usage: {} estimate <database path> <table>",
+            program
+        )));
+    };
 
+    let mut db = settings.open(db_path)?;
+    let started = Instant::now();
+    db.begin_statement()?;
+    let estimate = db.estimate_rows(table)?;
+    let millis = started.elapsed().as_secs_f64() * 1000.0;
+    match estimate.source {
+        EstimateSource::Exact => writeln!(
+            std::io::stdout(),
+            "{} rows (exact, {:.3} ms)",
+            estimate.rows,
+            millis
+        ),
+        EstimateSource::Sampled { pages } => writeln!(
+            std::io::stdout(),
+            "~{} rows (sampled {} pages, {:.3} ms)",
+            estimate.rows,
+            pages,
+            millis
+        ),
+        EstimateSource::Stat1 => writeln!(
+            std::io::stdout(),
+            "~{} rows (from sqlite_stat1, {:.3} ms)",
+            estimate.rows,
+            millis
+        ),
+    }?;
     Ok(())
 }
@@ -1,128 +1,2095 @@
-use anyhow::{bail, Result};
+use crate::functions::unhex;
+use crate::record::{format_real, Value};
+use crate::schema::Affinity;
+use anyhow::{bail, Context, Result};
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
 
-#[derive(Debug, Clone)]
-pub struct WhereCondition {
-    pub column: String,
-    pub operator: String,
-    pub value: String,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithmeticOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Column(String),
+    Literal(Value),
+    /// A column of an outer query, bound to its value for the outer row
+    /// before a correlated subquery runs. Unlike a literal it keeps the
+    /// column's affinity and collation, so it compares as the column would.
+    OuterColumn {
+        value: Value,
+        affinity: Affinity,
+        collation: Option<String>,
+    },
+    /// Parameter `?N`, counting from 1: the value bound to it when the
+    /// statement runs, NULL if none is.
+    Parameter(usize),
+    Compare {
+        left: Box<Expr>,
+        op: CompareOp,
+        right: Box<Expr>,
+    },
+    /// `left LIKE pattern [ESCAPE 'c']`; `NOT LIKE` is a `Not` around it.
+    Like {
+        left: Box<Expr>,
+        pattern: Box<Expr>,
+        escape: Option<char>,
+    },
+    /// `left IN (list)`; `NOT IN` is a `Not` around it.
+    In {
+        left: Box<Expr>,
+        list: Vec<Expr>,
+    },
+    /// `operand IS NULL`; `IS NOT NULL` is a `Not` around it.
+    IsNull(Box<Expr>),
+    Arithmetic {
+        left: Box<Expr>,
+        op: ArithmeticOp,
+        right: Box<Expr>,
+    },
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// `EXISTS (SELECT ...)`. The subquery may refer to the columns of the
+    /// query around it.
+    Exists(Box<QueryType>),
+    /// `left IN (SELECT ...)`, with the values of the subquery's one column
+    /// for the list; `NOT IN` is a `Not` around it.
+    InSubquery {
+        left: Box<Expr>,
+        query: Box<QueryType>,
+    },
+    /// A call to a scalar function, `name(args...)`.
+    Function {
+        name: String,
+        args: Vec<Expr>,
+    },
+    /// A window function, `name(args) OVER (PARTITION BY ... ORDER BY ...)`:
+    /// worked out over the rows that share the row's `partition_by` values,
+    /// up to and including the last of them sorting equal to it by
+    /// `order_by` (or all of them, without ORDER BY).
+    Window {
+        name: String,
+        args: Vec<Expr>,
+        partition_by: Vec<Expr>,
+        order_by: Vec<OrderingTerm>,
+    },
+    /// `operand COLLATE name`: the operand's value, compared as text by the
+    /// named collation instead of its column's.
+    Collate {
+        operand: Box<Expr>,
+        collation: String,
+    },
+}
+
+impl fmt::Display for Expr {
+    /// Writes the expression back as SQL, with parentheses only where the
+    /// operators' precedence needs them, so it parses back to the same tree.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Column(name) => write!(f, "{}", quote_name(name)),
+            Expr::Literal(value) | Expr::OuterColumn { value, .. } => write_literal(f, value),
+            Expr::Parameter(number) => write!(f, "?{}", number),
+            Expr::Compare { left, op, right } => {
+                let op = match op {
+                    CompareOp::Eq => "=",
+                    CompareOp::NotEq => "!=",
+                    CompareOp::Lt => "<",
+                    CompareOp::LtEq => "<=",
+                    CompareOp::Gt => ">",
+                    CompareOp::GtEq => ">=",
+                };
+                write!(f, "{} {} {}", Operand(left, 5), op, Operand(right, 5))
+            }
+            Expr::Like {
+                left,
+                pattern,
+                escape,
+            } => {
+                write!(f, "{} LIKE {}", Operand(left, 5), Operand(pattern, 5))?;
+                match escape {
+                    Some(escape) => {
+                        write!(f, " ESCAPE ")?;
+                        write_literal(f, &Value::Text(escape.to_string().into()))
+                    }
+                    None => Ok(()),
+                }
+            }
+            Expr::In { left, list } => {
+                write!(f, "{} IN (", Operand(left, 5))?;
+                for (i, item) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", Operand(item, 5))?;
+                }
+                write!(f, ")")
+            }
+            Expr::IsNull(operand) => write!(f, "{} IS NULL", Operand(operand, 5)),
+            Expr::Arithmetic { left, op, right } => {
+                let op = match op {
+                    ArithmeticOp::Add => "+",
+                    ArithmeticOp::Subtract => "-",
+                    ArithmeticOp::Multiply => "*",
+                    ArithmeticOp::Divide => "/",
+                    ArithmeticOp::Remainder => "%",
+                };
+                let level = precedence(self);
+                write!(
+                    f,
+                    "{} {} {}",
+                    Operand(left, level),
+                    op,
+                    Operand(right, level + 1)
+                )
+            }
+            Expr::And(left, right) => write!(f, "{} AND {}", Operand(left, 2), Operand(right, 3)),
+            Expr::Or(left, right) => write!(f, "{} OR {}", Operand(left, 1), Operand(right, 2)),
+            Expr::Not(inner) => write!(f, "NOT {}", Operand(inner, 3)),
+            Expr::Exists(_) => write!(f, "EXISTS (SELECT ...)"),
+            Expr::InSubquery { left, .. } => write!(f, "{} IN (SELECT ...)", Operand(left, 5)),
+            Expr::Function { name, args }
+                if args.is_empty() && name.eq_ignore_ascii_case("count") =>
+            {
+                write!(f, "{}(*)", name)
+            }
+            Expr::Function { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Collate { operand, collation } => {
+                write!(
+                    f,
+                    "{} COLLATE {}",
+                    Operand(operand, 7),
+                    quote_name(collation)
+                )
+            }
+            Expr::Window {
+                name,
+                args,
+                partition_by,
+                order_by,
+            } => {
+                let call = Expr::Function {
+                    name: name.clone(),
+                    args: args.clone(),
+                };
+                write!(f, "{} OVER (", call)?;
+                for (i, key) in partition_by.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "PARTITION BY " } else { ", " }, key)?;
+                }
+                for (i, term) in order_by.iter().enumerate() {
+                    match i {
+                        0 if partition_by.is_empty() => write!(f, "ORDER BY ")?,
+                        0 => write!(f, " ORDER BY ")?,
+                        _ => write!(f, ", ")?,
+                    }
+                    write!(f, "{}", term.expr)?;
+                    if term.descending {
+                        write!(f, " DESC")?;
+                    }
+                    match term.nulls_first {
+                        Some(true) => write!(f, " NULLS FIRST")?,
+                        Some(false) => write!(f, " NULLS LAST")?,
+                        None => {}
+                    }
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// How tightly `expr` binds, following the levels of the parser: OR, AND,
+/// NOT, comparisons, `+` and `-`, then `*`, `/` and `%`. Everything else is
+/// a single operand.
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Or(..) => 1,
+        Expr::And(..) => 2,
+        Expr::Not(_) => 3,
+        Expr::Compare { .. }
+        | Expr::Like { .. }
+        | Expr::In { .. }
+        | Expr::InSubquery { .. }
+        | Expr::IsNull(_) => 4,
+        Expr::Arithmetic {
+            op: ArithmeticOp::Add | ArithmeticOp::Subtract,
+            ..
+        } => 5,
+        Expr::Arithmetic { .. } => 6,
+        _ => 7,
+    }
+}
+
+/// An operand of an operator, in parentheses if it binds less tightly than
+/// the given level. Operators group to the left, so a right operand at the
+/// operator's own level needs them too: `a - (b - c)`.
+struct Operand<'a>(&'a Expr, u8);
+
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if precedence(self.0) < self.1 {
+            write!(f, "({})", self.0)
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// Writes `value` as a SQL literal.
+fn write_literal(f: &mut fmt::Formatter<'_>, value: &Value) -> fmt::Result {
+    match value {
+        Value::Null => write!(f, "NULL"),
+        Value::Int(i) => write!(f, "{}", i),
+        Value::Float(x) => write!(f, "{}", format_real(*x)),
+        Value::Text(text) => write!(f, "'{}'", text.replace('\'', "''")),
+        Value::Blob(bytes) => {
+            write!(f, "X'")?;
+            for byte in bytes {
+                write!(f, "{:02X}", byte)?;
+            }
+            write!(f, "'")
+        }
+    }
+}
+
+/// One term of an ORDER BY clause.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderingTerm {
+    /// What to sort by. An integer literal is a position in the select list.
+    pub expr: Expr,
+    pub descending: bool,
+    /// `NULLS FIRST` (`Some(true)`) or `NULLS LAST` (`Some(false)`). Without
+    /// either, NULLs come first in ascending order and last in descending.
+    pub nulls_first: Option<bool>,
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum QueryType {
     Select {
-        columns: Vec<String>,
-        table: String,
-        where_clause: Option<WhereCondition>,
+        /// `SELECT DISTINCT`: duplicate result rows are left out.
+        distinct: bool,
+        columns: Vec<ResultColumn>,
+        /// The tables in FROM: at least one, and more for a cross join.
+        from: Vec<TableRef>,
+        where_clause: Option<Expr>,
+        /// `GROUP BY` expressions; an integer literal is a position in the
+        /// select list.
+        group_by: Vec<Expr>,
+        /// `HAVING`: which groups to keep, by their aggregates. Boxed, like
+        /// `offset`, to keep this variant from outgrowing the others.
+        having: Option<Box<Expr>>,
+        order_by: Vec<OrderingTerm>,
+        /// `LIMIT` and `OFFSET`, constant expressions.
+        limit: Option<Expr>,
+        offset: Option<Box<Expr>>,
+    },
+    /// A SELECT without FROM, whose select list is worked out once.
+    SelectExpressions {
+        columns: Vec<ResultColumn>,
+        where_clause: Option<Expr>,
+        order_by: Vec<OrderingTerm>,
+        limit: Option<Expr>,
+        offset: Option<Box<Expr>>,
     },
     SelectCount {
         table: String,
+        alias: Option<String>,
+        where_clause: Option<Expr>,
+    },
+    CreateTempTable {
+        table: String,
+        if_not_exists: bool,
+        source: TempTableSource,
+    },
+    DropTable {
+        table: String,
+        if_exists: bool,
+    },
+    /// `WITH name AS (SELECT ...) SELECT ...`: each of `tables` is
+    /// worked out in turn, then `query`, which can read them by name.
+    With {
+        tables: Vec<CommonTable>,
+        query: Box<QueryType>,
     },
+    /// `VALUES (expr, ...), ...`: one row per parenthesized list, all of
+    /// the same length, in columns called `column1`, `column2` and so on.
+    Values {
+        rows: Vec<Vec<Expr>>,
+    },
+    /// `PRAGMA name`, `PRAGMA name(value)` or `PRAGMA name = value`.
+    Pragma {
+        /// The pragma's name, lowercased.
+        name: String,
+        value: Option<String>,
+    },
+    /// `EXPLAIN SELECT ...`: runs the statement and reports its operators.
+    Explain(Box<QueryType>),
+    /// `EXPLAIN QUERY PLAN SELECT ...`: reports the operators the statement
+    /// would run, without running it.
+    ExplainQueryPlan(Box<QueryType>),
     Unknown,
 }
 
-pub fn parse_query(query: &str) -> Result<QueryType> {
-    let query_lower = query.trim().to_lowercase();
-    let original_query_trimmed = query.trim();
+/// An aggregate function call in a select list, like `count(*)` or
+/// `count(name) FILTER (WHERE country = 'Chad')`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateCall {
+    /// The function name, lowercased.
+    pub function: String,
+    /// The arguments passed to the function; none for `count(*)`.
+    pub args: Vec<Expr>,
+    /// Only rows this holds for are aggregated.
+    pub filter: Option<Expr>,
+}
 
-    if query_lower.starts_with("select") {
-        let parts: Vec<&str> = query_lower.split_whitespace().collect();
+/// Where the rows and columns of a new temporary table come from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TempTableSource {
+    /// `CREATE TEMP TABLE t AS SELECT ...`
+    Select(Box<QueryType>),
+    /// `CREATE TEMP TABLE t (...)`; holds the whole statement, to be parsed
+    /// like any other `CREATE TABLE`.
+    Columns(String),
+}
 
-        if parts.len() >= 4
-            && parts[0] == "select"
-            && (parts[1] == "count(*)"
-                || (parts[1] == "count" && parts[2] == "(*)" && parts[3] == "from"))
-        {
-            let table_index = if parts[1] == "count(*)" { 3 } else { 4 };
-            if parts.len() <= table_index {
-                bail!("Missing table name in SELECT COUNT query");
+/// One table of a WITH clause: `name [(column, ...)] AS (SELECT ...)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommonTable {
+    pub name: String,
+    /// Names for the columns of `query`, in place of its own; empty to
+    /// keep those.
+    pub columns: Vec<String>,
+    /// A SELECT or VALUES.
+    pub query: Box<QueryType>,
+}
+
+/// A statement as written, before `QueryType` works out how it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Select(Box<SelectStmt>),
+    /// A SELECT after a WITH clause.
+    With {
+        tables: Vec<CommonTable>,
+        select: Box<SelectStmt>,
+    },
+    /// `CREATE TEMP TABLE name (AS SELECT ... | (columns))`.
+    CreateTempTable {
+        table: String,
+        if_not_exists: bool,
+        /// The SELECT after AS. Without one the columns are declared in
+        /// `sql`, the whole statement.
+        select: Option<Box<SelectStmt>>,
+        sql: String,
+    },
+    /// `DROP TABLE [IF EXISTS] name`.
+    DropTable {
+        table: String,
+        if_exists: bool,
+    },
+    Values(Vec<Vec<Expr>>),
+    /// `PRAGMA [schema.]name [= value | (value)]`.
+    Pragma {
+        name: String,
+        value: Option<String>,
+    },
+    Explain(Box<Statement>),
+    ExplainQueryPlan(Box<Statement>),
+}
+
+/// `SELECT columns [FROM table, ...] [WHERE ...] [GROUP BY ... [HAVING ...]]
+/// [ORDER BY ...] [LIMIT ...]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectStmt {
+    pub distinct: bool,
+    pub columns: Vec<ResultColumn>,
+    /// The tables in FROM, none without it. With more than one, the query
+    /// reads every combination of their rows: `FROM a, b` or
+    /// `FROM a CROSS JOIN b`.
+    pub from: Vec<TableRef>,
+    pub where_clause: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderingTerm>,
+    pub limit: Option<Expr>,
+    pub offset: Option<Expr>,
+}
+
+/// One item of a select list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultColumn {
+    pub item: SelectItem,
+    /// The item as written, without its alias, which names the result
+    /// column when it's neither a plain name nor aliased.
+    pub text: String,
+    /// The name given after it (`count(*) AS n`), which the result column
+    /// is then called, and the rest of the query can refer to it by.
+    pub alias: Option<String>,
+}
+
+/// What a select list item is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectItem {
+    /// `*`, or `t.*` with the name of `t`: every column of the query's
+    /// tables, or of one.
+    All(Option<String>),
+    /// `(SELECT ...)`: the first column of the subquery's first row.
+    Subquery(Box<QueryType>),
+    /// An expression, which aggregate calls such as `count(*)` are too.
+    Expr(Expr),
+    /// An aggregate call with a FILTER clause, which only a whole item can
+    /// have. Whether the function is an aggregate is known once the query
+    /// is prepared.
+    Filtered(AggregateCall),
+}
+
+impl ResultColumn {
+    /// The column `name`, without an alias.
+    pub fn column(name: &str) -> Self {
+        ResultColumn {
+            item: SelectItem::Expr(Expr::Column(name.to_string())),
+            text: quote_name(name),
+            alias: None,
+        }
+    }
+
+    /// `*`, without an alias.
+    pub fn all() -> Self {
+        ResultColumn {
+            item: SelectItem::All(None),
+            text: "*".to_string(),
+            alias: None,
+        }
+    }
+
+    /// What the result column is called: its alias, else the item as
+    /// written, or without its quotes if it's a name.
+    pub fn name(&self) -> Cow<'_, str> {
+        match (&self.alias, &self.item) {
+            (Some(alias), _) => Cow::Borrowed(alias),
+            (None, SelectItem::Expr(Expr::Column(name))) => Cow::Borrowed(name),
+            (None, _) => Cow::Borrowed(&self.text),
+        }
+    }
+}
+
+/// The table named in FROM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    /// The table's name; empty for `(VALUES ...)` until it's named after
+    /// its alias.
+    pub name: String,
+    /// The arguments when the table is a table-valued function, like
+    /// `json_each(tags)`.
+    pub args: Option<Vec<Expr>>,
+    /// The name given after the table (`FROM orders o`).
+    pub alias: Option<String>,
+    /// The rows of `(VALUES ...)` in place of a table. The table is taken
+    /// out into a WITH clause before the query runs.
+    pub values: Option<Vec<Vec<Expr>>>,
+}
+
+impl From<Statement> for QueryType {
+    fn from(statement: Statement) -> Self {
+        match statement {
+            Statement::Select(select) => (*select).into(),
+            Statement::With { tables, select } => QueryType::With {
+                tables,
+                query: Box::new((*select).into()),
+            },
+            Statement::CreateTempTable {
+                table,
+                if_not_exists,
+                select,
+                sql,
+            } => QueryType::CreateTempTable {
+                table,
+                if_not_exists,
+                source: match select {
+                    Some(select) => TempTableSource::Select(Box::new((*select).into())),
+                    None => TempTableSource::Columns(sql),
+                },
+            },
+            Statement::DropTable { table, if_exists } => QueryType::DropTable { table, if_exists },
+            Statement::Values(rows) => QueryType::Values { rows },
+            Statement::Pragma { name, value } => QueryType::Pragma { name, value },
+            Statement::Explain(statement) => QueryType::Explain(Box::new((*statement).into())),
+            Statement::ExplainQueryPlan(statement) => {
+                QueryType::ExplainQueryPlan(Box::new((*statement).into()))
             }
-            let table = parts[table_index].to_string();
-            return Ok(QueryType::SelectCount { table });
         }
+    }
+}
 
-        let select_keyword_len = "select".len();
-        let from_keyword = " from ";
-        let where_keyword = " where ";
+impl From<SelectStmt> for QueryType {
+    fn from(select: SelectStmt) -> Self {
+        let SelectStmt {
+            distinct,
+            columns,
+            from,
+            where_clause,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+        } = select;
+        if from.iter().any(|table| table.values.is_some()) {
+            // Each `(VALUES ...)` becomes a table of a WITH clause, named
+            // after its alias or, like SQLite's, its place in FROM.
+            let mut tables = Vec::new();
+            let mut from = from;
+            for (i, table) in from.iter_mut().enumerate() {
+                if let Some(rows) = table.values.take() {
+                    table.name = table
+                        .alias
+                        .take()
+                        .unwrap_or_else(|| format!("(subquery-{})", i + 1));
+                    tables.push(CommonTable {
+                        name: table.name.clone(),
+                        columns: Vec::new(),
+                        query: Box::new(QueryType::Values { rows }),
+                    });
+                }
+            }
+            let select = SelectStmt {
+                distinct,
+                columns,
+                from,
+                where_clause,
+                group_by,
+                having,
+                order_by,
+                limit,
+                offset,
+            };
+            return QueryType::With {
+                tables,
+                query: Box::new(select.into()),
+            };
+        }
+        if from.is_empty() {
+            return QueryType::SelectExpressions {
+                columns,
+                where_clause,
+                order_by,
+                limit,
+                offset: offset.map(Box::new),
+            };
+        }
 
-        if let Some(from_pos_lower) = query_lower.find(from_keyword) {
-            let from_pos_original = original_query_trimmed
-                .to_lowercase()
-                .find(from_keyword)
-                .unwrap_or(from_pos_lower);
+        let is_count = limit.is_none()
+            && group_by.is_empty()
+            && having.is_none()
+            && matches!(&from[..], [table] if table.args.is_none())
+            && columns.len() == 1
+            && columns[0].alias.is_none()
+            && matches!(
+                &columns[0].item,
+                SelectItem::Expr(Expr::Function { name, args })
+                    if name.eq_ignore_ascii_case("count") && args.is_empty()
+            );
+        // Sorting the one row of a count changes nothing.
+        if let (true, Some(table)) = (is_count, from.first()) {
+            return QueryType::SelectCount {
+                table: table.name.clone(),
+                alias: table.alias.clone(),
+                where_clause,
+            };
+        }
+        QueryType::Select {
+            distinct,
+            columns,
+            from,
+            where_clause,
+            group_by,
+            having: having.map(Box::new),
+            order_by,
+            limit,
+            offset: offset.map(Box::new),
+        }
+    }
+}
 
-            let columns_part_str =
-                original_query_trimmed[select_keyword_len..from_pos_original].trim();
+pub fn parse_query(query: &str) -> Result<QueryType> {
+    parse_statement(query).map(QueryType::from)
+}
+
+/// Parses one SQL statement.
+pub fn parse_statement(sql: &str) -> Result<Statement> {
+    let mut parser = Parser::new(sql)?;
+    let statement = parser.parse_statement()?;
+    parser.expect_end()?;
+    Ok(statement)
+}
+
+/// `name` as it can be written in SQL: in double quotes unless it's made of
+/// letters, digits and underscores only and isn't a keyword, each part of a
+/// qualified name on its own.
+pub fn quote_name(name: &str) -> String {
+    name.split('.')
+        .map(|part| {
+            let bare = part.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                && part.chars().all(|c| c.is_alphanumeric() || c == '_')
+                && !is_operator_word(part)
+                && !is_clause_keyword(part)
+                && !part.eq_ignore_ascii_case("select");
+            if bare {
+                part.to_string()
+            } else {
+                format!("\"{}\"", part.replace('"', "\"\""))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+pub fn parse_where_expression(input: &str) -> Result<Expr> {
+    let mut parser = Parser::new(input)?;
+    if parser.at_end() {
+        bail!("Empty expression");
+    }
+    let expr = parser.parse_or()?;
+    parser.expect_end()?;
+    Ok(expr)
+}
+
+/// Splits `sql` into its statements at the `;`s between them, leaving alone
+/// any inside string literals, quoted names and comments. Statements with
+/// nothing but comments in them are dropped.
+pub fn split_statements(sql: &str) -> Result<Vec<&str>> {
+    let mut statements = Vec::new();
+    // The span from the first token of the current statement to its last.
+    let mut current: Option<Range<usize>> = None;
+    for (token, span) in tokenize(sql)? {
+        if token == Token::Other(';') {
+            statements.extend(current.take().map(|range| &sql[range]));
+        } else {
+            current = Some(current.map_or(span.start, |range| range.start)..span.end);
+        }
+    }
+    statements.extend(current.map(|range| &sql[range]));
+    Ok(statements)
+}
+
+/// Whether `sql` ends with a `;` that ends a statement, and not one inside a
+/// string literal, quoted name or comment that's still open. Comments after
+/// the `;` don't count.
+pub fn is_complete(sql: &str) -> bool {
+    let mut chars = sql.chars().peekable();
+    let mut complete = false;
+    while let Some(c) = chars.next() {
+        let close = match c {
+            '\'' | '"' | '`' => c,
+            '[' => ']',
+            '-' if chars.peek() == Some(&'-') => '\n',
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut star = false;
+                loop {
+                    match chars.next() {
+                        None => return false,
+                        Some('/') if star => break,
+                        Some(c) => star = c == '*',
+                    }
+                }
+                continue;
+            }
+            ';' => {
+                complete = true;
+                continue;
+            }
+            c => {
+                complete &= c.is_whitespace();
+                continue;
+            }
+        };
+        // A doubled quote closes the literal and opens it again at once.
+        if !chars.any(|c| c == close) && close != '\n' {
+            return false;
+        }
+    }
+    complete
+}
 
-            let remaining_part_str_original =
-                original_query_trimmed[from_pos_original + from_keyword.len()..].trim();
-            let remaining_part_str_lower = remaining_part_str_original.to_lowercase();
+/// SQLite's default SQLITE_MAX_VARIABLE_NUMBER.
+const MAX_PARAMETER: usize = 32766;
 
-            let columns: Vec<String> = columns_part_str
-                .split(',')
-                .map(|s| s.trim().to_string())
-                .filter(|s| !s.is_empty())
-                .collect();
+/// The parameters of `sql` by number, with the name each has: `?3` is the
+/// third, a bare `?` (which has no name) comes after the highest number so
+/// far, and `:name` takes the next number the first time it appears and
+/// keeps it after that.
+pub fn parameters(sql: &str) -> Result<Vec<Option<String>>> {
+    number_parameters(&mut tokenize(sql)?)
+}
 
-            if columns.is_empty() {
-                bail!("No columns specified in SELECT query");
+/// Numbers the parameters among `tokens` as `parameters` describes, writing
+/// each as `?N`, and returns their names.
+fn number_parameters(tokens: &mut [(Token, Range<usize>)]) -> Result<Vec<Option<String>>> {
+    let mut names: Vec<Option<String>> = Vec::new();
+    for (token, _) in tokens {
+        let Token::Parameter(name) = token else {
+            continue;
+        };
+        let number = match name.strip_prefix('?') {
+            Some("") => names.len() + 1,
+            Some(digits) => match digits.parse::<usize>() {
+                Ok(number @ 1..=MAX_PARAMETER) => number,
+                _ => bail!(
+                    "Parameter numbers go from ?1 to ?{}: {}",
+                    MAX_PARAMETER,
+                    name
+                ),
+            },
+            None => {
+                names
+                    .iter()
+                    .position(|other| other.as_deref() == Some(name.as_str()))
+                    .unwrap_or(names.len())
+                    + 1
             }
+        };
+        if names.len() < number {
+            names.resize(number, None);
+        }
+        if name != "?" {
+            names[number - 1].get_or_insert_with(|| name.clone());
+        }
+        *name = format!("?{}", number);
+    }
+    Ok(names)
+}
 
-            let table_name_str: String;
-            let mut where_clause: Option<WhereCondition> = None;
+/// Words that carry on the expression in front of them, so a select list
+/// item ending in one has no alias, and one before a name doesn't end it.
+const OPERATOR_WORDS: &[&str] = &[
+    "and", "or", "not", "is", "isnull", "notnull", "null", "in", "like", "glob", "escape",
+    "collate", "filter", "over", "as", "between",
+];
 
-            if let Some(where_pos_lower) = remaining_part_str_lower.find(where_keyword) {
-                let where_pos_original = remaining_part_str_original
-                    .to_lowercase()
-                    .find(where_keyword)
-                    .unwrap_or(where_pos_lower);
+fn is_operator_word(word: &str) -> bool {
+    OPERATOR_WORDS
+        .iter()
+        .any(|keyword| keyword.eq_ignore_ascii_case(word))
+}
 
-                table_name_str = remaining_part_str_original[..where_pos_original]
-                    .trim()
-                    .to_string();
-                let condition_str =
-                    remaining_part_str_original[where_pos_original + where_keyword.len()..].trim();
+/// Whether `word`, at the end of a select list item, can be its alias.
+fn is_bare_alias(word: &str) -> bool {
+    !word.contains('.') && !is_operator_word(word)
+}
 
-                let condition_parts: Vec<&str> =
-                    condition_str.splitn(2, '=').map(|s| s.trim()).collect();
-                if condition_parts.len() == 2 {
-                    let column = condition_parts[0].to_string();
-                    let mut value_str = condition_parts[1].to_string();
+/// Whether an expression can end with `token`.
+fn ends_operand(token: &Token) -> bool {
+    match token {
+        Token::Identifier(word) => !is_operator_word(word) || word.eq_ignore_ascii_case("null"),
+        Token::QuotedName(_)
+        | Token::String(_)
+        | Token::Blob(_)
+        | Token::Parameter(_)
+        | Token::Number(_)
+        | Token::RightParen => true,
+        _ => false,
+    }
+}
+
+/// Words that end a select list item or the table in FROM.
+const CLAUSE_KEYWORDS: &[&str] = &[
+    "from", "where", "group", "having", "order", "limit", "cross", "join",
+];
+
+fn is_clause_keyword(word: &str) -> bool {
+    CLAUSE_KEYWORDS
+        .iter()
+        .any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A name, possibly qualified with a table name: `name` or `t.name`.
+    /// Keywords are identifiers too; the parser tells them apart.
+    Identifier(String),
+    /// A name with at least one part in double quotes, brackets or
+    /// backticks, without them: `"unit price"` or `o.[group]`. It's never
+    /// a keyword.
+    QuotedName(String),
+    String(String),
+    /// The bytes of a blob literal, `X'DEADBEEF'`.
+    Blob(Vec<u8>),
+    /// A placeholder for a bound value as written: `?`, `?3`, `:name`,
+    /// `@name` or `$name`.
+    Parameter(String),
+    Number(String),
+    Equals,
+    NotEquals,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Comma,
+    LeftParen,
+    RightParen,
+    /// A character no token starts with. Only an error if the parser has to
+    /// look at it: the column list of a CREATE TABLE is left to `schema`.
+    Other(char),
+}
+
+/// Splits `input` into tokens, each with the byte range it covers.
+fn tokenize(input: &str) -> Result<Vec<(Token, Range<usize>)>> {
+    let chars: Vec<(usize, char)> = input.char_indices().collect();
+    let offset = |pos: usize| chars.get(pos).map_or(input.len(), |&(offset, _)| offset);
+    let char_at = |pos: usize| chars.get(pos).map(|&(_, c)| c);
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_';
+    // The text from `start` to the end of the name run at `end`, for a
+    // number that runs into one.
+    let run_on = |start: usize, mut end: usize| {
+        while char_at(end).is_some_and(is_name_char) {
+            end += 1;
+        }
+        input[offset(start)..offset(end)].to_string()
+    };
+    let mut tokens = Vec::new();
+    let mut pos = 0;
 
-                    if value_str.starts_with('\'')
-                        && value_str.ends_with('\'')
-                        && value_str.len() >= 2
+    while let Some(c) = char_at(pos) {
+        let start = pos;
+        let token = match c {
+            c if c.is_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            '-' if char_at(pos + 1) == Some('-') => {
+                while char_at(pos).is_some_and(|c| c != '\n') {
+                    pos += 1;
+                }
+                continue;
+            }
+            // Like SQLite's, a block comment left open runs to the end.
+            '/' if char_at(pos + 1) == Some('*') => {
+                pos += 2;
+                while char_at(pos).is_some()
+                    && !(char_at(pos) == Some('*') && char_at(pos + 1) == Some('/'))
+                {
+                    pos += 1;
+                }
+                pos += 2;
+                continue;
+            }
+            '(' => {
+                pos += 1;
+                Token::LeftParen
+            }
+            ')' => {
+                pos += 1;
+                Token::RightParen
+            }
+            '=' => {
+                pos += if char_at(pos + 1) == Some('=') { 2 } else { 1 };
+                Token::Equals
+            }
+            '!' if char_at(pos + 1) == Some('=') => {
+                pos += 2;
+                Token::NotEquals
+            }
+            '<' => match char_at(pos + 1) {
+                Some('>') => {
+                    pos += 2;
+                    Token::NotEquals
+                }
+                Some('=') => {
+                    pos += 2;
+                    Token::LessEquals
+                }
+                _ => {
+                    pos += 1;
+                    Token::Less
+                }
+            },
+            '>' => {
+                if char_at(pos + 1) == Some('=') {
+                    pos += 2;
+                    Token::GreaterEquals
+                } else {
+                    pos += 1;
+                    Token::Greater
+                }
+            }
+            '+' | '-' | '*' | '/' | '%' | ',' => {
+                pos += 1;
+                match c {
+                    '+' => Token::Plus,
+                    '-' => Token::Minus,
+                    '*' => Token::Star,
+                    '/' => Token::Slash,
+                    '%' => Token::Percent,
+                    _ => Token::Comma,
+                }
+            }
+            '0' if matches!(char_at(pos + 1), Some('x' | 'X')) => {
+                pos += 2;
+                while char_at(pos).is_some_and(|c| c.is_ascii_hexdigit()) {
+                    pos += 1;
+                }
+                // `0x` needs at least one hex digit.
+                if pos == start + 2 || char_at(pos).is_some_and(is_name_char) {
+                    bail!("Unrecognized token: \"{}\"", run_on(start, pos));
+                }
+                Token::Number(input[offset(start)..offset(pos)].to_string())
+            }
+            c if c.is_ascii_digit()
+                || (c == '.' && char_at(pos + 1).is_some_and(|c| c.is_ascii_digit())) =>
+            {
+                while char_at(pos).is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    pos += 1;
+                }
+                if matches!(char_at(pos), Some('e' | 'E')) {
+                    pos += 1;
+                    if matches!(char_at(pos), Some('+' | '-')) {
+                        pos += 1;
+                    }
+                    while char_at(pos).is_some_and(|c| c.is_ascii_digit()) {
+                        pos += 1;
+                    }
+                }
+                // A number can't run straight into a name, as in `12abc`.
+                if char_at(pos).is_some_and(is_name_char) {
+                    bail!("Unrecognized token: \"{}\"", run_on(start, pos));
+                }
+                Token::Number(input[offset(start)..offset(pos)].to_string())
+            }
+            '\'' => {
+                // A doubled quote inside the literal stands for one quote.
+                let mut text = String::new();
+                pos += 1;
+                loop {
+                    match char_at(pos) {
+                        None => bail!("Unterminated string literal"),
+                        Some('\'') if char_at(pos + 1) == Some('\'') => {
+                            text.push('\'');
+                            pos += 2;
+                        }
+                        Some('\'') => break,
+                        Some(c) => {
+                            text.push(c);
+                            pos += 1;
+                        }
+                    }
+                }
+                pos += 1;
+                Token::String(text)
+            }
+            'x' | 'X' if char_at(pos + 1) == Some('\'') => {
+                let end = chars[pos + 2..]
+                    .iter()
+                    .position(|&(_, c)| c == '\'')
+                    .context("Unterminated blob literal")?;
+                pos += end + 3;
+                let hex = &input[offset(start + 2)..offset(pos - 1)];
+                match unhex(hex, "") {
+                    Some(bytes) => Token::Blob(bytes),
+                    None => bail!(
+                        "Malformed blob literal: {}",
+                        &input[offset(start)..offset(pos)]
+                    ),
+                }
+            }
+            c if c.is_alphabetic() || c == '_' || is_open_quote(c) => {
+                // Parts joined by dots, each bare or quoted.
+                let mut name = String::new();
+                let mut quoted = false;
+                loop {
+                    match char_at(pos) {
+                        Some(open) if is_open_quote(open) => {
+                            let close = if open == '[' { ']' } else { open };
+                            pos += 1;
+                            loop {
+                                match char_at(pos) {
+                                    None => bail!("Unterminated quoted name"),
+                                    // Doubled, the quote stands for itself;
+                                    // brackets have no escape.
+                                    Some(c)
+                                        if c == close
+                                            && open != '['
+                                            && char_at(pos + 1) == Some(close) =>
+                                    {
+                                        name.push(c);
+                                        pos += 2;
+                                    }
+                                    Some(c) if c == close => break,
+                                    Some(c) => {
+                                        name.push(c);
+                                        pos += 1;
+                                    }
+                                }
+                            }
+                            pos += 1;
+                            quoted = true;
+                        }
+                        _ => {
+                            while let Some(c) = char_at(pos).filter(|&c| is_name_char(c)) {
+                                name.push(c);
+                                pos += 1;
+                            }
+                        }
+                    }
+                    if char_at(pos) != Some('.')
+                        || !char_at(pos + 1).is_some_and(|c| is_name_char(c) || is_open_quote(c))
                     {
-                        value_str = value_str[1..value_str.len() - 1].to_string();
-                    } else {
-                        // For now, only string literals are supported as per the challenge
-                        bail!("WHERE clause value must be a string literal enclosed in single quotes, e.g., 'Yellow'");
+                        break;
                     }
+                    name.push('.');
+                    pos += 1;
+                }
+                if quoted {
+                    Token::QuotedName(name)
+                } else {
+                    Token::Identifier(name)
+                }
+            }
+            '?' => {
+                pos += 1;
+                while char_at(pos).is_some_and(|c| c.is_ascii_digit()) {
+                    pos += 1;
+                }
+                Token::Parameter(input[offset(start)..offset(pos)].to_string())
+            }
+            ':' | '@' | '$'
+                if char_at(pos + 1).is_some_and(|c| c.is_alphanumeric() || c == '_') =>
+            {
+                pos += 1;
+                while char_at(pos).is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                    pos += 1;
+                }
+                Token::Parameter(input[offset(start)..offset(pos)].to_string())
+            }
+            c => {
+                pos += 1;
+                Token::Other(c)
+            }
+        };
+        tokens.push((token, offset(start)..offset(pos)));
+    }
 
-                    where_clause = Some(WhereCondition {
-                        column,
-                        operator: "=".to_string(),
-                        value: value_str,
-                    });
+    Ok(tokens)
+}
+
+/// Whether `c` starts a quoted name, as in `"unit price"`, `[unit price]`
+/// or `` `unit price` ``.
+fn is_open_quote(c: char) -> bool {
+    matches!(c, '"' | '[' | '`')
+}
+
+/// A recursive-descent parser over the tokens of one statement or
+/// expression.
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token>,
+    /// The byte range of each token in `input`.
+    spans: Vec<Range<usize>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Result<Self> {
+        let mut tokens = tokenize(input)?;
+        number_parameters(&mut tokens)?;
+        let (tokens, spans) = tokens.into_iter().unzip();
+        Ok(Parser {
+            input,
+            tokens,
+            spans,
+            pos: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Identifier(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    /// Moves past `keyword` if it comes next.
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// Moves past the run of `keywords` if all of them come next.
+    fn eat_keywords(&mut self, keywords: &[&str]) -> bool {
+        let found = keywords.iter().enumerate().all(|(i, keyword)| {
+            matches!(self.tokens.get(self.pos + i), Some(Token::Identifier(word)) if word.eq_ignore_ascii_case(keyword))
+        });
+        if found {
+            self.pos += keywords.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, token: Token, message: &str) -> Result<()> {
+        if self.next() != Some(token) {
+            bail!("{}", message);
+        }
+        Ok(())
+    }
+
+    /// The input from the next token on, for error messages.
+    fn rest(&self) -> &str {
+        self.spans
+            .get(self.pos)
+            .map_or("", |span| self.input[span.start..].trim_end())
+    }
+
+    fn expect_end(&self) -> Result<()> {
+        match self.peek() {
+            None => Ok(()),
+            Some(Token::Other(c)) => bail!("Unexpected character '{}'", c),
+            Some(_) => bail!("Unexpected '{}'", self.rest()),
+        }
+    }
+
+    // statement := EXPLAIN [QUERY PLAN] statement | [WITH ...] select | CREATE ... | DROP ...
+    //            | PRAGMA ...
+    fn parse_statement(&mut self) -> Result<Statement> {
+        if self.eat_keyword("create") {
+            return self.parse_create_table();
+        }
+        if self.eat_keyword("drop") {
+            return self.parse_drop_table();
+        }
+        if self.eat_keyword("pragma") {
+            return self.parse_pragma();
+        }
+        if self.eat_keyword("explain") {
+            if self.eat_keywords(&["query", "plan"]) {
+                return Ok(Statement::ExplainQueryPlan(Box::new(
+                    self.parse_statement()?,
+                )));
+            }
+            return Ok(Statement::Explain(Box::new(self.parse_statement()?)));
+        }
+        if self.eat_keyword("with") {
+            return self.parse_with();
+        }
+        if self.eat_keyword("values") {
+            return Ok(Statement::Values(self.parse_values()?));
+        }
+        if self.peek_keyword("select") {
+            return Ok(Statement::Select(Box::new(self.parse_select()?)));
+        }
+        bail!("Unsupported SQL query: {}", self.input.trim())
+    }
+
+    // with := WITH name [(column (, column)*)] AS (select) (, ...)* select
+    fn parse_with(&mut self) -> Result<Statement> {
+        if self.peek_keyword("recursive") {
+            bail!("WITH RECURSIVE is not supported");
+        }
+        let mut tables = Vec::new();
+        loop {
+            let name = match self.next() {
+                Some(Token::Identifier(name)) if !is_clause_keyword(&name) => name,
+                Some(Token::QuotedName(name)) => name,
+                _ => bail!("Expected a table name after WITH"),
+            };
+            if tables
+                .iter()
+                .any(|table: &CommonTable| table.name.eq_ignore_ascii_case(&name))
+            {
+                bail!("Duplicate WITH table name: {}", name);
+            }
+            let mut columns = Vec::new();
+            if self.peek() == Some(&Token::LeftParen) {
+                self.pos += 1;
+                loop {
+                    match self.next() {
+                        Some(Token::Identifier(column) | Token::QuotedName(column)) => {
+                            columns.push(column)
+                        }
+                        _ => bail!("Expected a column name in the column list of {}", name),
+                    }
+                    match self.next() {
+                        Some(Token::Comma) => {}
+                        Some(Token::RightParen) => break,
+                        _ => bail!("Expected ',' or ')' in the column list of {}", name),
+                    }
+                }
+            }
+            if !self.eat_keyword("as") {
+                bail!("Expected AS after WITH {}", name);
+            }
+            self.expect(Token::LeftParen, &format!("Expected '(' after {} AS", name))?;
+            let query = self.parse_subquery()?;
+            self.expect(
+                Token::RightParen,
+                &format!("Expected ')' after the SELECT of {}", name),
+            )?;
+            tables.push(CommonTable {
+                name,
+                columns,
+                query: Box::new(query),
+            });
+            if self.peek() != Some(&Token::Comma) {
+                break;
+            }
+            self.pos += 1;
+        }
+        if !self.peek_keyword("select") {
+            bail!("Expected SELECT after the WITH clause");
+        }
+        Ok(Statement::With {
+            tables,
+            select: Box::new(self.parse_select()?),
+        })
+    }
+
+    // select := SELECT [DISTINCT | ALL] item (, item)* [FROM table] [WHERE expr]
+    //           [GROUP BY expr (, expr)*] [ORDER BY term (, term)*] [LIMIT expr [(OFFSET | ,) expr]]
+    fn parse_select(&mut self) -> Result<SelectStmt> {
+        if !self.eat_keyword("select") {
+            bail!("Expected SELECT");
+        }
+        let distinct = self.eat_keyword("distinct");
+        if !distinct {
+            self.eat_keyword("all");
+        }
+        let mut columns = vec![self.parse_result_column()?];
+        while self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+            columns.push(self.parse_result_column()?);
+        }
+
+        let mut from = Vec::new();
+        if self.eat_keyword("from") {
+            from.push(self.parse_table_ref()?);
+            // `a, b` and `a CROSS JOIN b` are the same: every pair of rows.
+            loop {
+                if self.peek() == Some(&Token::Comma) {
+                    self.pos += 1;
+                } else if !self.eat_keywords(&["cross", "join"]) {
+                    break;
+                }
+                from.push(self.parse_table_ref()?);
+            }
+        }
+        let where_clause = if self.eat_keyword("where") {
+            Some(self.parse_or()?)
+        } else {
+            None
+        };
+        let mut group_by = Vec::new();
+        if self.eat_keywords(&["group", "by"]) {
+            group_by.push(self.parse_or()?);
+            while self.peek() == Some(&Token::Comma) {
+                self.pos += 1;
+                group_by.push(self.parse_or()?);
+            }
+        }
+        let having = if self.eat_keyword("having") {
+            Some(self.parse_or()?)
+        } else {
+            None
+        };
+        let order_by = if self.eat_keywords(&["order", "by"]) {
+            self.parse_order_by()?
+        } else {
+            Vec::new()
+        };
+        let (limit, offset) = if self.eat_keyword("limit") {
+            let limit = self.parse_or()?;
+            if self.eat_keyword("offset") {
+                (Some(limit), Some(self.parse_or()?))
+            } else if self.peek() == Some(&Token::Comma) {
+                // `LIMIT skip, count`.
+                self.pos += 1;
+                (Some(self.parse_or()?), Some(limit))
+            } else {
+                (Some(limit), None)
+            }
+        } else {
+            (None, None)
+        };
+        if from.is_empty() && (!group_by.is_empty() || having.is_some()) {
+            bail!("GROUP BY and HAVING need a FROM clause");
+        }
+
+        Ok(SelectStmt {
+            distinct,
+            columns,
+            from,
+            where_clause,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+        })
+    }
+
+    /// One select list item, as written: everything up to the next comma,
+    /// clause keyword or closing parenthesis outside any parentheses. A
+    /// name at its end, after `AS` or right after the expression, is its
+    /// alias.
+    fn parse_result_column(&mut self) -> Result<ResultColumn> {
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(token) = self.peek() {
+            match token {
+                Token::LeftParen => depth += 1,
+                Token::RightParen if depth == 0 => break,
+                Token::RightParen => depth -= 1,
+                Token::Comma if depth == 0 => break,
+                Token::Identifier(word) if depth == 0 && is_clause_keyword(word) => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        let (end, alias) = match &self.tokens[start..self.pos] {
+            [_, .., Token::Identifier(keyword), Token::Identifier(alias) | Token::QuotedName(alias)]
+                if keyword.eq_ignore_ascii_case("as") =>
+            {
+                if alias.contains('.') {
+                    bail!("Expected a name after AS, not '{}'", alias);
+                }
+                (self.pos - 2, Some(alias.clone()))
+            }
+            [.., before, Token::Identifier(alias)]
+                if is_bare_alias(alias) && ends_operand(before) =>
+            {
+                (self.pos - 1, Some(alias.clone()))
+            }
+            [.., before, Token::QuotedName(alias)]
+                if !alias.contains('.') && ends_operand(before) =>
+            {
+                (self.pos - 1, Some(alias.clone()))
+            }
+            _ => (self.pos, None),
+        };
+        if end == start {
+            bail!("No columns specified in SELECT query");
+        }
+        let span = self.spans[start].start..self.spans[end - 1].end;
+        let mut item = Parser {
+            input: self.input,
+            tokens: self.tokens[start..end].to_vec(),
+            spans: self.spans[start..end].to_vec(),
+            pos: 0,
+        };
+        Ok(ResultColumn {
+            item: item.parse_select_item()?,
+            text: self.input[span].to_string(),
+            alias,
+        })
+    }
+
+    // select_item := '*' | name '.' '*' | '(' select ')'
+    //              | name '(' args ')' FILTER '(' WHERE expr ')' | expr
+    fn parse_select_item(&mut self) -> Result<SelectItem> {
+        match &self.tokens[..] {
+            [Token::Star] => return Ok(SelectItem::All(None)),
+            [Token::Identifier(name) | Token::QuotedName(name), Token::Other('.'), Token::Star] => {
+                return Ok(SelectItem::All(Some(column_name(name.clone()))));
+            }
+            [Token::LeftParen, .., Token::RightParen]
+                if self.tokens.get(1).is_some_and(|token| {
+                    matches!(token, Token::Identifier(word) if word.eq_ignore_ascii_case("select"))
+                }) =>
+            {
+                self.pos = 1;
+                let query = self.parse_subquery()?;
+                self.expect(Token::RightParen, "Expected ')' after subquery")?;
+                self.expect_end()?;
+                return Ok(SelectItem::Subquery(Box::new(query)));
+            }
+            _ => {}
+        }
+        let expr = self.parse_or()?;
+        if !self.eat_keyword("filter") {
+            self.expect_end()?;
+            return Ok(SelectItem::Expr(expr));
+        }
+        let Expr::Function { name, args } = expr else {
+            bail!("FILTER needs an aggregate call in front of it");
+        };
+        let context = || format!("Expected FILTER (WHERE ...) after {}(...)", name);
+        self.expect(Token::LeftParen, &context())?;
+        if !self.eat_keyword("where") {
+            bail!(context());
+        }
+        let condition = self.parse_or()?;
+        self.expect(Token::RightParen, &context())?;
+        if !self.at_end() {
+            bail!(context());
+        }
+        Ok(SelectItem::Filtered(AggregateCall {
+            function: name.to_lowercase(),
+            args,
+            filter: Some(condition),
+        }))
+    }
+
+    // table := name ['(' args ')'] [[AS] alias]
+    fn parse_table_ref(&mut self) -> Result<TableRef> {
+        let mut values = None;
+        let name = match self.next() {
+            Some(Token::LeftParen) => {
+                if !self.eat_keyword("values") {
+                    bail!("Subqueries in FROM are not supported, other than (VALUES ...)");
+                }
+                values = Some(self.parse_values()?);
+                self.expect(Token::RightParen, "Expected ')' after VALUES")?;
+                String::new()
+            }
+            Some(Token::Identifier(name)) if !is_clause_keyword(&name) => table_name(name)?,
+            Some(Token::QuotedName(name)) => table_name(name)?,
+            _ => bail!("Missing table name in SELECT query"),
+        };
+        let args = if values.is_none() && self.peek() == Some(&Token::LeftParen) {
+            self.pos += 1;
+            Some(self.parse_arguments(&name)?)
+        } else {
+            None
+        };
+        let alias = if self.eat_keyword("as") {
+            match self.next() {
+                Some(Token::Identifier(alias) | Token::QuotedName(alias)) => Some(alias),
+                _ => bail!("Expected an alias after AS"),
+            }
+        } else {
+            match self.peek() {
+                Some(Token::Identifier(alias)) if !is_clause_keyword(alias) => {
+                    let alias = alias.clone();
+                    self.pos += 1;
+                    Some(alias)
+                }
+                Some(Token::QuotedName(alias)) => {
+                    let alias = alias.clone();
+                    self.pos += 1;
+                    Some(alias)
+                }
+                _ => None,
+            }
+        };
+        Ok(TableRef {
+            name,
+            args,
+            alias,
+            values,
+        })
+    }
+
+    /// A SELECT, or VALUES, where a subquery goes.
+    fn parse_subquery(&mut self) -> Result<QueryType> {
+        if self.eat_keyword("values") {
+            return Ok(QueryType::Values {
+                rows: self.parse_values()?,
+            });
+        }
+        Ok(self.parse_select()?.into())
+    }
+
+    // values := VALUES (expr (, expr)*) (, (expr (, expr)*))*, after VALUES
+    fn parse_values(&mut self) -> Result<Vec<Vec<Expr>>> {
+        let mut rows: Vec<Vec<Expr>> = Vec::new();
+        loop {
+            self.expect(Token::LeftParen, "Expected '(' before a row of VALUES")?;
+            let mut row = vec![self.parse_or()?];
+            loop {
+                match self.next() {
+                    Some(Token::Comma) => row.push(self.parse_or()?),
+                    Some(Token::RightParen) => break,
+                    _ => bail!("Expected ',' or ')' in a row of VALUES"),
+                }
+            }
+            if rows.first().is_some_and(|first| first.len() != row.len()) {
+                bail!("All VALUES must have the same number of terms");
+            }
+            rows.push(row);
+            if self.peek() != Some(&Token::Comma) {
+                return Ok(rows);
+            }
+            self.pos += 1;
+        }
+    }
+
+    // term := expr [ASC | DESC] [NULLS (FIRST | LAST)]
+    fn parse_order_by(&mut self) -> Result<Vec<OrderingTerm>> {
+        let mut terms = Vec::new();
+        loop {
+            if self.at_end() {
+                bail!("Expected an expression after ORDER BY");
+            }
+            let expr = self.parse_or()?;
+            let descending = if self.eat_keyword("desc") {
+                true
+            } else {
+                self.eat_keyword("asc");
+                false
+            };
+            let nulls_first = if self.eat_keyword("nulls") {
+                if self.eat_keyword("first") {
+                    Some(true)
+                } else if self.eat_keyword("last") {
+                    Some(false)
                 } else {
-                    bail!("Invalid WHERE clause format. Expected 'column = \\'value\\''");
+                    bail!("Expected FIRST or LAST after NULLS in ORDER BY");
                 }
             } else {
-                table_name_str = remaining_part_str_original.to_string();
+                None
+            };
+            terms.push(OrderingTerm {
+                expr,
+                descending,
+                nulls_first,
+            });
+            if self.peek() != Some(&Token::Comma) {
+                return Ok(terms);
             }
+            self.pos += 1;
+        }
+    }
 
-            if table_name_str.is_empty() {
-                bail!("Missing table name in SELECT query");
+    /// `CREATE TEMP[ORARY] TABLE [IF NOT EXISTS] name (AS SELECT ... |
+    /// (columns))`, after CREATE. Tables in the database file itself can't
+    /// be created: it is only read.
+    fn parse_create_table(&mut self) -> Result<Statement> {
+        if !self.eat_keyword("temp") && !self.eat_keyword("temporary") {
+            bail!("Only CREATE TEMP TABLE is supported: the database file is read-only");
+        }
+        if !self.eat_keyword("table") {
+            bail!("Expected TABLE after CREATE TEMP");
+        }
+        let if_not_exists = self.eat_keywords(&["if", "not", "exists"]);
+        let table = match self.next() {
+            Some(Token::Identifier(table) | Token::QuotedName(table)) => table_name(table)?,
+            _ => bail!("Missing table name in CREATE TEMP TABLE"),
+        };
+
+        let select = if self.eat_keyword("as") {
+            Some(Box::new(self.parse_select()?))
+        } else if self.peek() == Some(&Token::LeftParen) {
+            // The column definitions are read by `schema`, like those of
+            // any other CREATE TABLE.
+            self.pos = self.tokens.len();
+            None
+        } else {
+            bail!(
+                "Expected AS SELECT or a column list after CREATE TEMP TABLE {}",
+                table
+            );
+        };
+
+        Ok(Statement::CreateTempTable {
+            table,
+            if_not_exists,
+            select,
+            sql: self.input.trim().to_string(),
+        })
+    }
+
+    /// `DROP TABLE [IF EXISTS] name`, after DROP.
+    fn parse_drop_table(&mut self) -> Result<Statement> {
+        if !self.eat_keyword("table") {
+            bail!("Only DROP TABLE is supported");
+        }
+        let if_exists = self.eat_keywords(&["if", "exists"]);
+        let table = match (self.next(), self.peek()) {
+            (Some(Token::Identifier(table) | Token::QuotedName(table)), None) => table_name(table)?,
+            _ => bail!("Expected a single table name after DROP TABLE"),
+        };
+        Ok(Statement::DropTable { table, if_exists })
+    }
+
+    fn parse_pragma(&mut self) -> Result<Statement> {
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => table_name(name)?.to_lowercase(),
+            _ => bail!("Expected a pragma name after PRAGMA"),
+        };
+        let value = match self.peek() {
+            Some(Token::Equals) => {
+                self.pos += 1;
+                Some(self.parse_pragma_value(&name)?)
+            }
+            Some(Token::LeftParen) => {
+                self.pos += 1;
+                let value = self.parse_pragma_value(&name)?;
+                self.expect(
+                    Token::RightParen,
+                    &format!("Expected ')' after PRAGMA {}({}", name, value),
+                )?;
+                Some(value)
             }
+            _ => None,
+        };
+        Ok(Statement::Pragma { name, value })
+    }
 
-            return Ok(QueryType::Select {
-                columns,
-                table: table_name_str,
-                where_clause,
+    /// A pragma's value: a name, a string or a number, maybe negative.
+    fn parse_pragma_value(&mut self, name: &str) -> Result<String> {
+        let negative = self.peek() == Some(&Token::Minus);
+        if negative {
+            self.pos += 1;
+        }
+        Ok(match self.next() {
+            Some(Token::Number(number)) if negative => format!("-{}", number),
+            Some(Token::Number(value)) => value,
+            Some(Token::Identifier(value) | Token::QuotedName(value) | Token::String(value))
+                if !negative =>
+            {
+                value
+            }
+            _ => bail!("Expected a value for PRAGMA {}", name),
+        })
+    }
+
+    /// The arguments of a call, after its `(`, up to and including the `)`.
+    fn parse_arguments(&mut self, name: &str) -> Result<Vec<Expr>> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RightParen) {
+            self.pos += 1;
+            return Ok(args);
+        }
+        // `count(*)` is `count()`, counting rows rather than values.
+        if name.eq_ignore_ascii_case("count") && self.peek() == Some(&Token::Star) {
+            self.pos += 1;
+            self.expect(Token::RightParen, "Expected ')' after count(*")?;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.parse_or()?);
+            match self.next() {
+                Some(Token::Comma) => {}
+                Some(Token::RightParen) => return Ok(args),
+                _ => bail!("Expected ',' or ')' in arguments to {}()", name),
+            }
+        }
+    }
+
+    // or_expr := and_expr (OR and_expr)*
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut left = self.parse_and()?;
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            let right = self.parse_and()?;
+            left = Expr::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    // and_expr := not_expr (AND not_expr)*
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut left = self.parse_not()?;
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            let right = self.parse_not()?;
+            left = Expr::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    // not_expr := NOT not_expr | comparison
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_comparison()
+    }
+
+    // comparison := operand ((= | != | <> | < | <= | > | >=) operand
+    //                        | [NOT] LIKE operand [ESCAPE string]
+    //                        | [NOT] IN '(' [operand (, operand)*] ')'
+    //                        | IS [NOT] NULL | ISNULL | NOTNULL)?
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let left = self.parse_sum()?;
+        if self.eat_keyword("isnull") {
+            return Ok(Expr::IsNull(Box::new(left)));
+        }
+        if self.eat_keyword("notnull") {
+            return Ok(Expr::Not(Box::new(Expr::IsNull(Box::new(left)))));
+        }
+        if self.eat_keyword("is") {
+            let negated = self.eat_keyword("not");
+            if !self.eat_keyword("null") {
+                bail!("Only IS NULL and IS NOT NULL are supported");
+            }
+            let is_null = Expr::IsNull(Box::new(left));
+            return Ok(if negated {
+                Expr::Not(Box::new(is_null))
+            } else {
+                is_null
+            });
+        }
+        let negated = self.peek_keyword("not")
+            && matches!(self.tokens.get(self.pos + 1), Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("like") || word.eq_ignore_ascii_case("in") || word.eq_ignore_ascii_case("match") || word.eq_ignore_ascii_case("between"));
+        if negated {
+            self.pos += 1;
+        }
+        if self.peek_keyword("in") {
+            self.pos += 1;
+            if self.next() != Some(Token::LeftParen) {
+                bail!("Expected '(' after IN");
+            }
+            if self.peek_keyword("select") || self.peek_keyword("values") {
+                let query = self.parse_subquery()?;
+                self.expect(Token::RightParen, "Expected ')' after subquery")?;
+                let in_subquery = Expr::InSubquery {
+                    left: Box::new(left),
+                    query: Box::new(query),
+                };
+                return Ok(if negated {
+                    Expr::Not(Box::new(in_subquery))
+                } else {
+                    in_subquery
+                });
+            }
+            let mut list = Vec::new();
+            if self.peek() == Some(&Token::RightParen) {
+                self.pos += 1;
+            } else {
+                loop {
+                    list.push(self.parse_sum()?);
+                    match self.next() {
+                        Some(Token::Comma) => {}
+                        Some(Token::RightParen) => break,
+                        _ => bail!("Expected ',' or ')' in IN list"),
+                    }
+                }
+            }
+            let in_list = Expr::In {
+                left: Box::new(left),
+                list,
+            };
+            return Ok(if negated {
+                Expr::Not(Box::new(in_list))
+            } else {
+                in_list
+            });
+        }
+        if self.peek_keyword("between") {
+            self.pos += 1;
+            let low = self.parse_sum()?;
+            if !self.eat_keyword("and") {
+                bail!("Expected AND in BETWEEN");
+            }
+            let high = self.parse_sum()?;
+            // `x BETWEEN a AND b` is `x >= a AND x <= b`.
+            let compare = |op, bound| Expr::Compare {
+                left: Box::new(left.clone()),
+                op,
+                right: Box::new(bound),
+            };
+            let between = Expr::And(
+                Box::new(compare(CompareOp::GtEq, low)),
+                Box::new(compare(CompareOp::LtEq, high)),
+            );
+            return Ok(if negated {
+                Expr::Not(Box::new(between))
+            } else {
+                between
             });
         }
-        bail!("Invalid SELECT query format. Missing FROM clause.");
+        if self.peek_keyword("match") {
+            self.pos += 1;
+            // As in SQLite, `x MATCH y` is the call `match(y, x)`.
+            let call = Expr::Function {
+                name: "match".to_string(),
+                args: vec![self.parse_sum()?, left],
+            };
+            return Ok(if negated {
+                Expr::Not(Box::new(call))
+            } else {
+                call
+            });
+        }
+        if self.peek_keyword("like") {
+            self.pos += 1;
+            let pattern = self.parse_sum()?;
+            let escape = if self.peek_keyword("escape") {
+                self.pos += 1;
+                match self.next() {
+                    Some(Token::String(text)) if text.chars().count() == 1 => text.chars().next(),
+                    _ => bail!("ESCAPE expression must be a single character"),
+                }
+            } else {
+                None
+            };
+            let like = Expr::Like {
+                left: Box::new(left),
+                pattern: Box::new(pattern),
+                escape,
+            };
+            return Ok(if negated {
+                Expr::Not(Box::new(like))
+            } else {
+                like
+            });
+        }
+        let op = match self.peek() {
+            Some(Token::Equals) => CompareOp::Eq,
+            Some(Token::NotEquals) => CompareOp::NotEq,
+            Some(Token::Less) => CompareOp::Lt,
+            Some(Token::LessEquals) => CompareOp::LtEq,
+            Some(Token::Greater) => CompareOp::Gt,
+            Some(Token::GreaterEquals) => CompareOp::GtEq,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.parse_sum()?;
+        Ok(Expr::Compare {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        })
+    }
+
+    // sum := product ((+ | -) product)*
+    fn parse_sum(&mut self) -> Result<Expr> {
+        let mut left = self.parse_product()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Plus) => ArithmeticOp::Add,
+                Some(Token::Minus) => ArithmeticOp::Subtract,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_product()?;
+            left = Expr::Arithmetic {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            };
+        }
+    }
+
+    // product := collated ((* | / | %) collated)*
+    fn parse_product(&mut self) -> Result<Expr> {
+        let mut left = self.parse_collated()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Star) => ArithmeticOp::Multiply,
+                Some(Token::Slash) => ArithmeticOp::Divide,
+                Some(Token::Percent) => ArithmeticOp::Remainder,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_collated()?;
+            left = Expr::Arithmetic {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            };
+        }
+    }
+
+    // collated := operand (COLLATE name)*
+    fn parse_collated(&mut self) -> Result<Expr> {
+        let mut operand = self.parse_operand()?;
+        while self.eat_keyword("collate") {
+            let collation = match self.next() {
+                Some(Token::Identifier(name)) if !is_operator_word(&name) => name,
+                Some(Token::QuotedName(name) | Token::String(name)) => name,
+                _ => bail!("Expected a collation name after COLLATE"),
+            };
+            operand = Expr::Collate {
+                operand: Box::new(operand),
+                collation,
+            };
+        }
+        Ok(operand)
+    }
+
+    // window := OVER '(' [PARTITION BY expr (, expr)*] [ORDER BY term (, term)*] ')'
+    fn parse_window(&mut self, name: String, args: Vec<Expr>) -> Result<Expr> {
+        if self.peek() != Some(&Token::LeftParen) {
+            bail!("Expected '(' after OVER: named windows are not supported");
+        }
+        self.pos += 1;
+        let mut partition_by = Vec::new();
+        if self.eat_keywords(&["partition", "by"]) {
+            partition_by.push(self.parse_or()?);
+            while self.peek() == Some(&Token::Comma) {
+                self.pos += 1;
+                partition_by.push(self.parse_or()?);
+            }
+        }
+        let order_by = if self.eat_keywords(&["order", "by"]) {
+            self.parse_order_by()?
+        } else {
+            Vec::new()
+        };
+        if ["rows", "range", "groups"]
+            .iter()
+            .any(|keyword| self.peek_keyword(keyword))
+        {
+            bail!("Window frames are not supported: only the default one");
+        }
+        self.expect(Token::RightParen, "Expected ')' after the window of OVER")?;
+        Ok(Expr::Window {
+            name,
+            args,
+            partition_by,
+            order_by,
+        })
+    }
+
+    fn parse_operand(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::LeftParen) => {
+                if self.peek_keyword("select") {
+                    bail!("Subqueries are only supported in EXISTS, IN and the select list");
+                }
+                let expr = self.parse_or()?;
+                self.expect(Token::RightParen, "Expected ')'")?;
+                Ok(expr)
+            }
+            Some(Token::String(value)) => Ok(Expr::Literal(Value::Text(value.into()))),
+            Some(Token::Blob(bytes)) => Ok(Expr::Literal(Value::Blob(bytes))),
+            Some(Token::Parameter(name)) => match name[1..].parse() {
+                Ok(number) if name.starts_with('?') => Ok(Expr::Parameter(number)),
+                _ => bail!("Parameter {} has no number", name),
+            },
+            Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("null") => {
+                Ok(Expr::Literal(Value::Null))
+            }
+            Some(Token::Identifier(word))
+                if word.eq_ignore_ascii_case("exists")
+                    && self.peek() == Some(&Token::LeftParen) =>
+            {
+                self.pos += 1;
+                if !self.peek_keyword("select") && !self.peek_keyword("values") {
+                    bail!("Expected SELECT after EXISTS (");
+                }
+                let query = self.parse_subquery()?;
+                self.expect(Token::RightParen, "Expected ')' after subquery")?;
+                Ok(Expr::Exists(Box::new(query)))
+            }
+            Some(Token::Identifier(name)) if self.peek() == Some(&Token::LeftParen) => {
+                self.pos += 1;
+                let args = self.parse_arguments(&name)?;
+                if self.eat_keyword("over") {
+                    return self.parse_window(name, args);
+                }
+                Ok(Expr::Function { name, args })
+            }
+            Some(Token::Identifier(name) | Token::QuotedName(name)) => {
+                Ok(Expr::Column(column_name(name)))
+            }
+            Some(Token::Number(text)) => parse_number(&text),
+            Some(Token::Minus) => match self.peek() {
+                Some(Token::Number(text)) => {
+                    let text = text.clone();
+                    self.pos += 1;
+                    // -9223372036854775808 is an integer even though its
+                    // digits alone are too big for one.
+                    if let Ok(i) = format!("-{}", text).parse::<i64>() {
+                        return Ok(Expr::Literal(Value::Int(i)));
+                    }
+                    match parse_number(&text)? {
+                        Expr::Literal(Value::Int(i)) => {
+                            Ok(Expr::Literal(Value::Int(i.wrapping_neg())))
+                        }
+                        Expr::Literal(Value::Float(f)) => Ok(Expr::Literal(Value::Float(-f))),
+                        other => Ok(other),
+                    }
+                }
+                _ => Ok(Expr::Arithmetic {
+                    left: Box::new(Expr::Literal(Value::Int(0))),
+                    op: ArithmeticOp::Subtract,
+                    right: Box::new(self.parse_operand()?),
+                }),
+            },
+            Some(Token::Plus) => self.parse_operand(),
+            Some(Token::Other(c)) => bail!("Unexpected character '{}'", c),
+            Some(_) => {
+                self.pos -= 1;
+                bail!("Unexpected '{}'", self.rest())
+            }
+            None => bail!("Unexpected end of expression"),
+        }
+    }
+}
+
+/// The table in a `schema.table` name. Until ATTACH exists the only schemas
+/// are `main`, the database file, and `temp`, for temporary tables; either
+/// way the table is then looked up as if it had no schema.
+fn table_name(name: String) -> Result<String> {
+    match name.split_once('.') {
+        Some((schema, table))
+            if schema.eq_ignore_ascii_case("main") || schema.eq_ignore_ascii_case("temp") =>
+        {
+            Ok(table.to_string())
+        }
+        Some((schema, _)) => bail!("Unknown database '{}'", schema),
+        None => Ok(name),
     }
+}
+
+/// A column name without the schema in front of its table's name:
+/// `main.users.name` is `users.name`.
+fn column_name(name: String) -> String {
+    match name.matches('.').count() {
+        2 => table_name(name.clone()).unwrap_or(name),
+        _ => name,
+    }
+}
 
-    bail!("Unsupported SQL query: {}", query)
+fn parse_number(text: &str) -> Result<Expr> {
+    // Hex literals are the 64 bits of an integer, so 0xFFFFFFFFFFFFFFFF is -1.
+    if let Some(digits) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return match u64::from_str_radix(digits, 16) {
+            Ok(bits) => Ok(Expr::Literal(Value::Int(bits as i64))),
+            Err(_) => bail!("Hex literal too big: {}", text),
+        };
+    }
+    if let Ok(i) = text.parse::<i64>() {
+        return Ok(Expr::Literal(Value::Int(i)));
+    }
+    let f = text
+        .parse::<f64>()
+        .context(format!("Invalid numeric literal '{}'", text))?;
+    Ok(Expr::Literal(Value::Float(f)))
 }
@@ -0,0 +1,101 @@
+use crate::database::Database;
+use crate::json::{parse_json, Json};
+use crate::query::prepare;
+use crate::server::request_sql;
+use anyhow::{Context, Result};
+use std::io::{BufRead, BufWriter, Write};
+use std::time::Instant;
+
+/// Runs the `--pipe` protocol: one JSON request per stdin line,
+/// `{"sql": "...", "params": [...], "id": ...}`, answered on stdout by
+///
+/// - `{"id": ..., "columns": [...]}` once the statement is prepared,
+/// - `{"id": ..., "row": [...]}` for every result row,
+/// - `{"id": ..., "done": true, "rows": N, "elapsed_ms": T}` at the end,
+///
+/// or by a single `{"id": ..., "error": "..."}` line in place of whatever
+/// would have followed. `id` is echoed back unchanged and omitted if absent.
+/// Blank lines are ignored; the loop ends at EOF.
+pub fn run_pipe(db: &mut Database) -> Result<()> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    for line in stdin.lock().lines() {
+        let line = line.context("Failed to read request from stdin")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request = parse_json(&line).context("Request is not valid JSON");
+        let id = request
+            .as_ref()
+            .ok()
+            .and_then(|request| request.get("id"))
+            .cloned();
+        let result = request.and_then(|request| run_request(db, &request, &id, &mut out));
+        if let Err(e) = result {
+            write_message(
+                &mut out,
+                &id,
+                vec![("error", Json::String(format!("{:#}", e)))],
+            )?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn run_request(
+    db: &mut Database,
+    request: &Json,
+    id: &Option<Json>,
+    out: &mut impl Write,
+) -> Result<()> {
+    let started = Instant::now();
+    let (sql, values) = request_sql(request)?;
+    db.begin_statement()?;
+    db.set_parameters(values);
+    let prepared = prepare(db, &sql)?;
+
+    let columns = prepared
+        .columns()
+        .iter()
+        .map(|name| Json::String(name.clone()))
+        .collect();
+    write_message(out, id, vec![("columns", Json::Array(columns))])?;
+
+    let mut row_count = 0;
+    prepared.run(db, |row| {
+        row_count += 1;
+        let row = Json::Array(row.iter().map(Json::from).collect());
+        write_message(out, id, vec![("row", row)])
+    })?;
+
+    write_message(
+        out,
+        id,
+        vec![
+            ("done", Json::Bool(true)),
+            ("rows", Json::Int(row_count)),
+            (
+                "elapsed_ms",
+                Json::Real(started.elapsed().as_micros() as f64 / 1000.0),
+            ),
+        ],
+    )
+}
+
+fn write_message(out: &mut impl Write, id: &Option<Json>, fields: Vec<(&str, Json)>) -> Result<()> {
+    let members = id
+        .iter()
+        .map(|id| ("id".to_string(), id.clone()))
+        .chain(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value)),
+        )
+        .collect();
+    writeln!(out, "{}", Json::Object(members))?;
+    Ok(())
+}
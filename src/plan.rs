@@ -0,0 +1,231 @@
+use crate::error::Error;
+use crate::json::Json;
+use crate::record::Value;
+use anyhow::{bail, Result};
+
+/// How the shell prints the result of `EXPLAIN` and `EXPLAIN QUERY PLAN`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PlanFormat {
+    /// One row per operator, like any other query; a query plan is drawn
+    /// as a tree, the way the sqlite3 shell does.
+    #[default]
+    Rows,
+    /// A Graphviz digraph, for `dot -Tsvg`.
+    Dot,
+    /// The operator tree as nested JSON objects.
+    Json,
+}
+
+impl PlanFormat {
+    pub fn parse(name: &str) -> Result<Self> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "rows" => PlanFormat::Rows,
+            "dot" => PlanFormat::Dot,
+            "json" => PlanFormat::Json,
+            _ => bail!(Error::Usage(format!(
+                "Unknown plan format '{}': expected rows, dot or json",
+                name
+            ))),
+        })
+    }
+}
+
+/// One row of `EXPLAIN`'s result: id, parent, operator, detail, estimated
+/// and actual rows; or of `EXPLAIN QUERY PLAN`'s, whose detail starts with
+/// the operator and which has no row counts.
+struct Step {
+    id: i64,
+    parent: i64,
+    operator: String,
+    detail: String,
+    /// The estimated and actual rows, for `EXPLAIN`.
+    rows: Option<(Option<i64>, i64)>,
+}
+
+fn steps(rows: &[Vec<Value>]) -> Vec<Step> {
+    let int = |value: Option<&Value>| match value {
+        Some(Value::Int(i)) => Some(*i),
+        _ => None,
+    };
+    let text = |value: Option<&Value>| match value {
+        Some(Value::Text(text)) => text.to_string(),
+        _ => String::new(),
+    };
+    rows.iter()
+        .map(|row| {
+            let (id, parent) = (int(row.first()).unwrap_or(0), int(row.get(1)).unwrap_or(0));
+            if row.len() == QUERY_PLAN_COLUMNS {
+                let detail = text(row.get(3));
+                let (operator, detail) = detail.split_once(' ').unwrap_or((&detail, ""));
+                return Step {
+                    id,
+                    parent,
+                    operator: operator.to_string(),
+                    detail: detail.to_string(),
+                    rows: None,
+                };
+            }
+            Step {
+                id,
+                parent,
+                operator: text(row.get(2)),
+                detail: text(row.get(3)),
+                rows: Some((int(row.get(4)), int(row.get(5)).unwrap_or(0))),
+            }
+        })
+        .collect()
+}
+
+/// The number of columns of `EXPLAIN QUERY PLAN`'s rows.
+const QUERY_PLAN_COLUMNS: usize = 4;
+
+/// The rows of `EXPLAIN` or `EXPLAIN QUERY PLAN` as a Graphviz digraph,
+/// each operator a box with arrows to the operators it reads from.
+pub fn to_dot(rows: &[Vec<Value>]) -> String {
+    let mut dot = "digraph plan {\n  node [shape=box, fontname=\"monospace\"];\n".to_string();
+    let steps = steps(rows);
+    for step in &steps {
+        let mut label = format!("{} {}", step.operator, step.detail)
+            .trim_end()
+            .to_string();
+        if let Some((estimate, actual)) = step.rows {
+            let estimate = estimate.map_or("?".to_string(), |estimate| estimate.to_string());
+            label.push_str(&format!("\nestimated {}, actual {}", estimate, actual));
+        }
+        dot.push_str(&format!(
+            "  n{} [label=\"{}\"];\n",
+            step.id,
+            dot_escape(&label)
+        ));
+    }
+    for step in steps.iter().filter(|step| step.parent != 0) {
+        dot.push_str(&format!("  n{} -> n{};\n", step.parent, step.id));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Escapes `text` for a double-quoted DOT string; line breaks become `\n`.
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The rows of `EXPLAIN` or `EXPLAIN QUERY PLAN` as a tree of JSON objects,
+/// each with the operators it reads from under `children`; only `EXPLAIN`
+/// has row counts. A plan with a single root is that object; otherwise an
+/// array of them.
+pub fn to_json(rows: &[Vec<Value>]) -> Json {
+    let steps = steps(rows);
+    let mut roots = tree(&steps, 0);
+    match roots.len() {
+        1 => roots.remove(0),
+        _ => Json::Array(roots),
+    }
+}
+
+fn tree(steps: &[Step], parent: i64) -> Vec<Json> {
+    steps
+        .iter()
+        .filter(|step| step.parent == parent && step.id != parent)
+        .map(|step| {
+            let mut fields = vec![
+                ("id".to_string(), Json::Int(step.id)),
+                ("operator".to_string(), Json::String(step.operator.clone())),
+                ("detail".to_string(), Json::String(step.detail.clone())),
+            ];
+            if let Some((estimate, actual)) = step.rows {
+                fields.push((
+                    "estimated_rows".to_string(),
+                    estimate.map_or(Json::Null, Json::Int),
+                ));
+                fields.push(("actual_rows".to_string(), Json::Int(actual)));
+            }
+            fields.push(("children".to_string(), Json::Array(tree(steps, step.id))));
+            Json::Object(fields)
+        })
+        .collect()
+}
+
+/// The rows of `EXPLAIN QUERY PLAN` drawn as a tree, the way the sqlite3
+/// shell does:
+///
+/// ```text
+/// QUERY PLAN
+/// `--FILTER status = 'late'
+///    `--SEARCH orders USING INDEX orders_status (key = 'late')
+/// ```
+pub fn to_tree(rows: &[Vec<Value>]) -> String {
+    let steps: Vec<(i64, i64, String)> = rows
+        .iter()
+        .map(|row| match row.as_slice() {
+            [Value::Int(id), Value::Int(parent), _, Value::Text(detail)] => {
+                (*id, *parent, detail.to_string())
+            }
+            _ => (0, 0, String::new()),
+        })
+        .collect();
+    let mut tree = "QUERY PLAN\n".to_string();
+    draw(&steps, 0, "", &mut tree);
+    tree
+}
+
+fn draw(steps: &[(i64, i64, String)], parent: i64, indent: &str, tree: &mut String) {
+    let children: Vec<_> = steps
+        .iter()
+        .filter(|(id, step_parent, _)| *step_parent == parent && *id != parent)
+        .collect();
+    for (i, (id, _, detail)) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        tree.push_str(&format!(
+            "{}{}{}\n",
+            indent,
+            if last { "`--" } else { "|--" },
+            detail
+        ));
+        let indent = format!("{}{}", indent, if last { "   " } else { "|  " });
+        draw(steps, *id, &indent, tree);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_dot, to_json};
+    use crate::testing::{int, query, TestDatabase};
+
+    #[test]
+    fn query_plans_take_every_format() {
+        let image = TestDatabase::new()
+            .table(
+                "CREATE TABLE t (a, b)",
+                (1..=10).map(|i| vec![int(i), int(i)]),
+            )
+            .index("CREATE INDEX t_a ON t (a)")
+            .build()
+            .unwrap();
+        let sql = "SELECT b FROM t WHERE a = 3 ORDER BY b";
+        let plan = query(&image, &format!("EXPLAIN QUERY PLAN {}", sql)).unwrap();
+        let explain = query(&image, &format!("EXPLAIN {}", sql)).unwrap();
+
+        assert_eq!(
+            to_dot(&plan),
+            "digraph plan {\n  node [shape=box, fontname=\"monospace\"];\n  \
+             n1 [label=\"SORT b\"];\n  n2 [label=\"FILTER a = 3\"];\n  \
+             n3 [label=\"SEARCH t USING INDEX t_a (key = 3)\"];\n  \
+             n1 -> n2;\n  n2 -> n3;\n}\n"
+        );
+        assert!(to_dot(&explain).contains(
+            "n3 [label=\"SEARCH t USING INDEX t_a (key = 3)\\nestimated 1, actual 1\"];"
+        ));
+
+        assert_eq!(
+            to_json(&plan).to_string(),
+            r#"{"id":1,"operator":"SORT","detail":"b","children":[{"id":2,"operator":"FILTER","detail":"a = 3","children":[{"id":3,"operator":"SEARCH","detail":"t USING INDEX t_a (key = 3)","children":[]}]}]}"#
+        );
+        assert_eq!(
+            to_json(&explain).to_string(),
+            r#"{"id":1,"operator":"SORT","detail":"b","estimated_rows":1,"actual_rows":1,"children":[{"id":2,"operator":"FILTER","detail":"a = 3","estimated_rows":1,"actual_rows":1,"children":[{"id":3,"operator":"SEARCH","detail":"t USING INDEX t_a (key = 3)","estimated_rows":1,"actual_rows":1,"children":[]}]}]}"#
+        );
+    }
+}
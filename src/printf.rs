@@ -0,0 +1,631 @@
+use crate::eval::numeric_value;
+use crate::functions::{text_of, MAX_LENGTH};
+use crate::record::Value;
+use anyhow::{bail, Result};
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Significant digits a REAL is printed with at most, as in SQLite; past
+/// them `%f` and `%e` pad with zeros. The `!` flag raises it to 26.
+const MAX_DIGITS: i32 = 16;
+
+/// Formats `args` by the printf-style `format` the way SQLite's `printf()`
+/// does: `%d %i %u %x %X %o`, `%f %e %E %g %G`, `%s %z %c`, the quoting
+/// `%q %Q %w` and `%%`, with the flags `-+ 0#,!`, a width and a precision
+/// (either may be `*`). Missing arguments count as NULL, and an unknown
+/// conversion ends the output. Output longer than `MAX_LENGTH` is an error,
+/// caught before it's allocated.
+pub fn format(format: &str, args: &[Value]) -> Result<String> {
+    let mut args = args.iter();
+    let mut next_arg = || args.next().cloned().unwrap_or(Value::Null);
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        if chars.peek().is_none() {
+            out.push('%');
+            break;
+        }
+
+        let mut spec = Spec::default();
+        while let Some(&flag) = chars.peek() {
+            match flag {
+                '-' => spec.left = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                '#' => spec.alternate = true,
+                '!' => spec.alternate2 = true,
+                '0' => spec.zero = true,
+                ',' => spec.comma = true,
+                _ => break,
+            }
+            chars.next();
+        }
+        if chars.peek() == Some(&'*') {
+            chars.next();
+            let width = int_arg(next_arg());
+            spec.left |= width < 0;
+            spec.width = usize::try_from(width.unsigned_abs()).unwrap_or(usize::MAX);
+        } else {
+            spec.width = read_number(&mut chars);
+        }
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            spec.precision = Some(if chars.peek() == Some(&'*') {
+                chars.next();
+                usize::try_from(int_arg(next_arg()).unsigned_abs()).unwrap_or(usize::MAX)
+            } else {
+                read_number(&mut chars)
+            });
+        }
+        while chars.peek() == Some(&'l') {
+            chars.next();
+        }
+
+        let Some(conversion) = chars.next() else {
+            break;
+        };
+        // The width pads, and the precision of a number is the least digits
+        // it has.
+        let precision = match conversion {
+            'd' | 'i' | 'u' | 'x' | 'X' | 'o' | 'p' | 'f' | 'e' | 'E' | 'g' | 'G' => spec.precision,
+            _ => None,
+        };
+        check_length(
+            out.len()
+                .saturating_add(spec.width.max(precision.unwrap_or(0))),
+        )?;
+        let text = match conversion {
+            'd' | 'i' => spec.integer(int_arg(next_arg())),
+            'u' | 'x' | 'X' | 'o' | 'p' => spec.unsigned(int_arg(next_arg()) as u64, conversion),
+            'f' | 'e' | 'E' | 'g' | 'G' => spec.real(real_arg(next_arg()), conversion),
+            's' | 'z' => match next_arg() {
+                Value::Null => String::new(),
+                value => spec.truncate(text_of(value)),
+            },
+            'c' => {
+                // The precision repeats the character.
+                let first: String = text_of(next_arg()).chars().take(1).collect();
+                let count = spec.precision.unwrap_or(1);
+                check_length(out.len().saturating_add(first.len().saturating_mul(count)))?;
+                first.repeat(count)
+            }
+            'q' | 'Q' | 'w' => {
+                let quote = if conversion == 'w' { "\"" } else { "'" };
+                match next_arg() {
+                    Value::Null if conversion == 'Q' => "NULL".to_string(),
+                    Value::Null => "(NULL)".to_string(),
+                    value => {
+                        let escaped = spec
+                            .truncate(text_of(value))
+                            .replace(quote, &quote.repeat(2));
+                        if conversion == 'Q' {
+                            format!("'{}'", escaped)
+                        } else {
+                            escaped
+                        }
+                    }
+                }
+            }
+            '%' => "%".to_string(),
+            'n' => String::new(),
+            _ => break,
+        };
+        check_length(out.len().saturating_add(text.len()))?;
+        let numeric = "feEgG".contains(conversion);
+        spec.pad(&mut out, &text, numeric);
+    }
+    Ok(out)
+}
+
+/// Fails if `length` bytes are more than a string can have.
+fn check_length(length: usize) -> Result<()> {
+    if length > MAX_LENGTH as usize {
+        bail!(
+            "printf() output is too big: strings are capped at {} bytes",
+            MAX_LENGTH
+        );
+    }
+    Ok(())
+}
+
+/// The flags, width and precision of one conversion.
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    /// `!`: text is measured in characters rather than bytes, and REALs get
+    /// more digits.
+    alternate2: bool,
+    zero: bool,
+    comma: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /// Appends `text` padded out to the width, which counts bytes unless `!`
+    /// says characters. Zeros for a `numeric` (REAL) conversion go after its
+    /// sign.
+    fn pad(&self, out: &mut String, text: &str, numeric: bool) {
+        let length = if self.alternate2 {
+            text.chars().count()
+        } else {
+            text.len()
+        };
+        let fill = self.width.saturating_sub(length);
+        if self.left {
+            out.push_str(text);
+            out.extend(std::iter::repeat(' ').take(fill));
+        } else if self.zero && numeric {
+            let prefix = text.find(|c: char| c.is_ascii_digit()).unwrap_or(0);
+            out.push_str(&text[..prefix]);
+            out.extend(std::iter::repeat('0').take(fill));
+            out.push_str(&text[prefix..]);
+        } else {
+            out.extend(std::iter::repeat(' ').take(fill));
+            out.push_str(text);
+        }
+    }
+
+    /// Cuts `text` to the precision: bytes, or characters with `!`.
+    fn truncate(&self, text: String) -> String {
+        match self.precision {
+            Some(precision) if self.alternate2 => text.chars().take(precision).collect(),
+            Some(precision) if precision < text.len() => {
+                String::from_utf8_lossy(&text.as_bytes()[..precision]).into_owned()
+            }
+            _ => text,
+        }
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    fn integer(&self, value: i64) -> String {
+        let sign = self.sign(value < 0);
+        let mut digits = self.min_digits(value.unsigned_abs().to_string(), sign.len());
+        if self.comma {
+            digits = group_thousands(&digits);
+        }
+        format!("{}{}", sign, digits)
+    }
+
+    /// Zero-extends integer `digits` to the precision. Like SQLite, the `0`
+    /// flag is a minimum digit count of the width less the sign, so it
+    /// applies even when left-justified and doesn't count a `0x` prefix.
+    fn min_digits(&self, digits: String, sign_len: usize) -> String {
+        let mut precision = self.precision.unwrap_or(0);
+        if self.zero {
+            precision = precision.max(self.width.saturating_sub(sign_len));
+        }
+        format!("{:0>width$}", digits, width = precision)
+    }
+
+    fn unsigned(&self, value: u64, conversion: char) -> String {
+        let (mut digits, prefix) = match conversion {
+            'x' | 'p' => (format!("{:x}", value), "0x"),
+            'X' => (format!("{:X}", value), "0X"),
+            'o' => (format!("{:o}", value), "0"),
+            _ => (value.to_string(), ""),
+        };
+        digits = self.min_digits(digits, 0);
+        if self.comma && conversion == 'u' {
+            digits = group_thousands(&digits);
+        }
+        if self.alternate && value != 0 {
+            digits.insert_str(0, prefix);
+        }
+        digits
+    }
+
+    fn real(&self, value: f64, conversion: char) -> String {
+        let sign = self.sign(value < 0.0);
+        if value.is_nan() {
+            return "NaN".to_string();
+        }
+        if value.is_infinite() {
+            return format!("{}Inf", sign);
+        }
+        let max_digits = if self.alternate2 { 26 } else { MAX_DIGITS };
+        // `format` has checked it against MAX_LENGTH, which fits.
+        let precision = self.precision.unwrap_or(6) as i32;
+        let upper = conversion.is_ascii_uppercase();
+        let (all, exponent) = decimal_digits(value);
+        let body = match conversion {
+            'f' => {
+                let (digits, exponent) =
+                    round(&all, exponent, exponent + 1 + precision, max_digits);
+                let text = self.fixed(&digits, exponent, precision);
+                if self.alternate2 {
+                    strip_trailing_zeros(&text, true)
+                } else {
+                    text
+                }
+            }
+            'e' | 'E' => {
+                let (digits, exponent) = round(&all, exponent, precision + 1, max_digits);
+                let text = self.scientific(&digits, exponent, precision, upper);
+                if self.alternate2 {
+                    strip_trailing_zeros(&text, true)
+                } else {
+                    text
+                }
+            }
+            _ => {
+                let significant = precision.max(1);
+                let (digits, exponent) = round(&all, exponent, significant, max_digits);
+                let text = if exponent < -4 || exponent >= significant {
+                    self.scientific(&digits, exponent, significant - 1, upper)
+                } else {
+                    self.fixed(&digits, exponent, significant - 1 - exponent)
+                };
+                if self.alternate {
+                    text
+                } else {
+                    strip_trailing_zeros(&text, self.alternate2)
+                }
+            }
+        };
+        format!("{}{}", sign, body)
+    }
+
+    /// `digits`, the first of which is at the power of ten `exponent`,
+    /// written out with `precision` places after the decimal point.
+    fn fixed(&self, digits: &[u8], exponent: i32, precision: i32) -> String {
+        let digit = |power: i32| {
+            usize::try_from(exponent - power)
+                .ok()
+                .and_then(|i| digits.get(i))
+                .map_or('0', |&d| (b'0' + d) as char)
+        };
+        let mut whole: String = (0..=exponent.max(0)).rev().map(digit).collect();
+        if self.comma {
+            whole = group_thousands(&whole);
+        }
+        if precision > 0 || self.alternate {
+            whole.push('.');
+            whole.extend((1..=precision).map(|place| digit(-place)));
+        }
+        whole
+    }
+
+    /// `d.ddde+XX`, with `precision` digits after the point.
+    fn scientific(&self, digits: &[u8], exponent: i32, precision: i32, upper: bool) -> String {
+        let digit = |i: usize| (b'0' + digits.get(i).copied().unwrap_or(0)) as char;
+        let mut text = digit(0).to_string();
+        if precision > 0 || self.alternate {
+            text.push('.');
+            text.extend((1..=precision as usize).map(digit));
+        }
+        format!(
+            "{}{}{}{:02}",
+            text,
+            if upper { 'E' } else { 'e' },
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    }
+}
+
+/// The significant decimal digits of `|value|` as SQLite takes them, padded
+/// with zeros to 30, and the power of ten of the first one. SQLite scales
+/// the value by powers of ten in double-double arithmetic until it lies
+/// between about 9.2e17 and 9.2e18, and takes the digits of that as a 64-bit
+/// integer: 18 or 19 of them, which may differ in the last place from the
+/// exact decimal expansion. This does the same, for the same digits.
+fn decimal_digits(value: f64) -> (Vec<u8>, i32) {
+    if value == 0.0 {
+        return (vec![0; 30], 0);
+    }
+    let mut rr = [value.abs(), 0.0];
+    let mut exponent = 0;
+    if rr[0] > 9.223372036854775e18 {
+        while rr[0] > 9.223372036854774e118 {
+            exponent += 100;
+            dekker_mul2(&mut rr, 1e-100, -1.9991899802602883e-117);
+        }
+        while rr[0] > 9.223372036854774e28 {
+            exponent += 10;
+            dekker_mul2(&mut rr, 1e-10, -3.643219731549774e-27);
+        }
+        while rr[0] > 9.223372036854775e18 {
+            exponent += 1;
+            dekker_mul2(&mut rr, 1e-1, -5.551115123125783e-18);
+        }
+    } else {
+        while rr[0] < 9.223372036854775e-83 {
+            exponent -= 100;
+            dekker_mul2(&mut rr, 1e100, -1.5902891109759918e83);
+        }
+        while rr[0] < 9.223372036854775e7 {
+            exponent -= 10;
+            dekker_mul2(&mut rr, 1e10, 0.0);
+        }
+        while rr[0] < 9.223372036854775e17 {
+            exponent -= 1;
+            dekker_mul2(&mut rr, 1e1, 0.0);
+        }
+    }
+    let v = if rr[1] < 0.0 {
+        rr[0] as u64 - (-rr[1]) as u64
+    } else {
+        rr[0] as u64 + rr[1] as u64
+    };
+    let mut digits: Vec<u8> = v.to_string().bytes().map(|b| b - b'0').collect();
+    exponent += digits.len() as i32 - 1;
+    digits.resize(30, 0);
+    (digits, exponent)
+}
+
+/// Multiplies the double-double `x` by `y + yy`, as SQLite's `dekkerMul2`.
+fn dekker_mul2(x: &mut [f64; 2], y: f64, yy: f64) {
+    let high = |f: f64| f64::from_bits(f.to_bits() & 0xffff_ffff_fc00_0000);
+    let (hx, hy) = (high(x[0]), high(y));
+    let (tx, ty) = (x[0] - hx, y - hy);
+    let p = hx * hy;
+    let q = hx * ty + tx * hy;
+    let c = p + q;
+    let cc = p - c + q + tx * ty;
+    let cc = x[0] * yy + x[1] * y + cc;
+    x[0] = c + cc;
+    x[1] = c - x[0] + cc;
+}
+
+/// Rounds `all` half up to `count` significant digits, keeping at most
+/// `max_digits` (the places after them print as zeros). Returns the digits
+/// and the power of ten of the first, which a carry can raise.
+fn round(all: &[u8], mut exponent: i32, count: i32, max_digits: i32) -> (Vec<u8>, i32) {
+    if count < 0 {
+        return (Vec::new(), exponent);
+    }
+    let keep = count.min(max_digits) as usize;
+    let mut digits = all[..keep].to_vec();
+    if all[keep] >= 5 {
+        match digits.iter().rposition(|&d| d != 9) {
+            Some(i) => {
+                digits[i] += 1;
+                digits[i + 1..].fill(0);
+            }
+            None => {
+                digits.fill(0);
+                digits.insert(0, 1);
+                exponent += 1;
+            }
+        }
+    }
+    (digits, exponent)
+}
+
+/// Drops zeros at the end of the fraction, and the point if nothing is left
+/// after it, unless `keep_point` (the `!` flag) asks for at least `.0`. An
+/// exponent stays.
+fn strip_trailing_zeros(text: &str, keep_point: bool) -> String {
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+    let mantissa = if mantissa.contains('.') {
+        mantissa.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        mantissa
+    };
+    if keep_point && !mantissa.contains('.') {
+        format!("{}.0{}", mantissa, exponent)
+    } else {
+        format!("{}{}", mantissa, exponent)
+    }
+}
+
+fn group_thousands(digits: &str) -> String {
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+/// An argument as an integer. Text counts for its leading integer only, so
+/// `'1e3'` is 1, and REALs are truncated.
+pub(crate) fn int_arg(value: Value) -> i64 {
+    let text = match value {
+        Value::Null => return 0,
+        Value::Int(i) => return i,
+        Value::Float(f) => return f as i64,
+        Value::Text(text) => text.to_string(),
+        Value::Blob(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+    let text = text.trim_start();
+    let (negative, digits) = match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let magnitude = digits
+        .bytes()
+        .take_while(u8::is_ascii_digit)
+        .fold(0i128, |n, d| (n * 10 + (d - b'0') as i128).min(1 << 64));
+    let value = if negative { -magnitude } else { magnitude };
+    value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// An argument as a REAL: its numeric value, with anything that isn't a
+/// number at all counting as 0.
+pub(crate) fn real_arg(value: Value) -> f64 {
+    match numeric_value(value) {
+        Some(Value::Int(i)) => i as f64,
+        Some(Value::Float(f)) => f,
+        _ => 0.0,
+    }
+}
+
+fn read_number(chars: &mut Peekable<Chars>) -> usize {
+    let mut number: usize = 0;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        number = number.saturating_mul(10).saturating_add(digit as usize);
+        chars.next();
+    }
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::Value;
+    use crate::testing::{int, real, text};
+
+    fn format(format: &str, args: &[Value]) -> String {
+        super::format(format, args).unwrap()
+    }
+
+    #[test]
+    fn formats_integers() {
+        assert_eq!(
+            format("%d|%5d|%-5d|%05d|%+d|% d", &vec![int(42); 6]),
+            "42|   42|42   |00042|+42| 42"
+        );
+        assert_eq!(
+            format(
+                "%x|%X|%#x|%o|%#o|%u",
+                &[int(255), int(255), int(255), int(8), int(8), int(-1)]
+            ),
+            "ff|FF|0xff|10|010|18446744073709551615"
+        );
+        assert_eq!(
+            format("%,d|%,d", &[int(1234567), int(-1234)]),
+            "1,234,567|-1,234"
+        );
+        assert_eq!(
+            format(
+                "%i|%d|%lld|%.3d",
+                &[text("12abc"), real(3.99), int(5), int(7)]
+            ),
+            "12|3|5|007"
+        );
+    }
+
+    #[test]
+    fn formats_reals() {
+        assert_eq!(
+            format(
+                "%.2f|%10.3f|%-8.1f|%e|%.3E",
+                &[
+                    real(3.14259),
+                    real(2.5),
+                    real(2.25),
+                    real(12345.678),
+                    real(0.000123)
+                ]
+            ),
+            "3.14|     2.500|2.3     |1.234568e+04|1.230E-04"
+        );
+        assert_eq!(
+            format(
+                "%g|%g|%G|%#g|%!.3g|%#.3g",
+                &[
+                    real(100000.0),
+                    real(1e-5),
+                    real(1e20),
+                    real(1.5),
+                    real(2.0),
+                    real(100.0)
+                ]
+            ),
+            "100000|1e-05|1E+20|1.50000|2.0|100."
+        );
+        // Halves round away from zero.
+        assert_eq!(format("%.0f|%.0f", &[real(2.5), real(3.5)]), "3|4");
+        // `!` keeps a point, with no more zeros after it than that takes.
+        assert_eq!(
+            format(
+                "%!.0f|%!f|%!.0e|%!g",
+                &[real(2.0), real(2.5), real(3.0), real(1e20)]
+            ),
+            "2.0|2.5|3.0e+00|1.0e+20"
+        );
+        // The digits SQLite works out, not the exact expansion: 19 or 18 of
+        // them, the last as its double-double scaling leaves it.
+        assert_eq!(
+            format(
+                "%!.26g|%!.20e|%!.20e",
+                &[
+                    real(1.0 / 3.0),
+                    real(9223372036854775808.0),
+                    real(2.9138649815953417e-124)
+                ]
+            ),
+            "0.3333333333333333148|9.22337203685477581e+18|2.913864981595341694e-124"
+        );
+    }
+
+    #[test]
+    fn formats_text() {
+        assert_eq!(
+            format(
+                "%s|%.3s|%-6s|%6s|%z|%5.2s",
+                &[
+                    text("hello"),
+                    text("hello"),
+                    text("ab"),
+                    text("ab"),
+                    text("z"),
+                    text("abc")
+                ]
+            ),
+            "hello|hel|ab    |    ab|z|   ab"
+        );
+        assert_eq!(
+            format("%c|%5c|%.3c", &[text("xyz"), text("a"), text("b")]),
+            "x|    a|bbb"
+        );
+        assert_eq!(
+            format(
+                "%q|%Q|%Q|%w",
+                &[text("it's"), text("it's"), Value::Null, text("a\"b")]
+            ),
+            "it''s|'it''s'|NULL|a\"\"b"
+        );
+    }
+
+    #[test]
+    fn takes_width_from_arguments_and_defaults_missing_ones() {
+        assert_eq!(
+            format("%*d|%-*d|", &[int(4), int(7), int(4), int(7)]),
+            "   7|7   |"
+        );
+        assert_eq!(format("100%%|%d|%s", &[]), "100%|0|");
+    }
+
+    #[test]
+    fn refuses_output_past_the_length_limit() {
+        for (spec, args) in [
+            ("%*d", vec![int(2_000_000_000), int(1)]),
+            ("%-*s", vec![int(-2_000_000_000), text("a")]),
+            ("%.*f", vec![int(2_000_000_000), real(1.0)]),
+            ("%.*c", vec![int(600_000_000), text("é")]),
+            ("%3000000000d", vec![int(1)]),
+        ] {
+            assert!(super::format(spec, &args).is_err(), "{}", spec);
+        }
+        // Past 100000 digits a REAL's precision still counts.
+        assert_eq!(format("%.200000f", &[real(1.0)]).len(), 200_002);
+        // Text is only cut short by the precision.
+        assert_eq!(format("%.*s", &[int(2_000_000_000), text("abc")]), "abc");
+    }
+}
@@ -0,0 +1,5289 @@
+use crate::aggregate::{
+    replace_aggregate_calls, Accumulators, Aggregate, AggregateFunction, Group, Groups, ValueKey,
+};
+use crate::collation::Collation;
+use crate::database::{Database, SchemaEntry};
+use crate::error::Error;
+use crate::eval::{
+    apply_numeric_affinity, apply_text_affinity, check_columns, evaluate, explicit_collation,
+    matches, total_order, total_order_collated, Row,
+};
+use crate::fts5::{full_text_table, matching_rowids, MatchQuery};
+use crate::functions::UserFunctions;
+use crate::parser::{
+    parse_query, parse_where_expression, AggregateCall, CommonTable, CompareOp, Expr, OrderingTerm,
+    QueryType, ResultColumn, SelectItem, TableRef, TempTableSource,
+};
+use crate::record::{encode_record, TextEncoding, Value};
+use crate::schema::{parse_create_index, parse_create_table, Affinity, ColumnDef, TableDef};
+use crate::table_function::TableFunction;
+use crate::temp::{row_size, TempTable};
+use crate::virtual_table::{Constraint, VirtualTable};
+use crate::window::{replace_window_calls, Window, WindowFunction};
+use anyhow::{bail, Context, Result};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Range, RangeInclusive};
+use std::rc::Rc;
+
+/// A statement that has been parsed and resolved against the schema, so
+/// unknown tables and columns are reported before any row is produced.
+pub struct PreparedQuery {
+    columns: Vec<String>,
+    plan: Plan,
+    counters: Counters,
+}
+
+/// Rows counted by the last run of a statement, for EXPLAIN.
+#[derive(Default)]
+struct Counters {
+    /// Rows read from the table of a SELECT, before the WHERE clause.
+    read: Cell<u64>,
+    /// Rows of a SELECT's table the WHERE clause matched.
+    matched: Cell<u64>,
+    /// Rows handed on.
+    returned: Cell<u64>,
+}
+
+enum Plan {
+    Select {
+        /// The table as named in the statement.
+        table: String,
+        table_def: Box<TableDef>,
+        /// Empty unless the source is a stored table.
+        defaults: ColumnDefaults,
+        source: Source,
+        outputs: Vec<Output>,
+        where_clause: Option<Expr>,
+        /// Root pages of indexes and how to search them; the rows are the
+        /// union of what the scans find. Empty for a full table scan.
+        index_scans: Vec<(u32, IndexScan)>,
+        order_by: Vec<SortKey>,
+        /// Under a LIMIT, how many rows from the front of the sort order
+        /// can be returned at most; the sort drops the others as it goes.
+        sort_limit: Option<u64>,
+    },
+    /// A SELECT without FROM: one row, if the WHERE clause holds.
+    Expressions {
+        outputs: Vec<Output>,
+        where_clause: Option<Expr>,
+    },
+    /// `VALUES`: each row's expressions, worked out when it's reached.
+    Values {
+        rows: Vec<Vec<Expr>>,
+    },
+    Count {
+        table: String,
+        source: Source,
+    },
+    /// `SELECT count(*)` with a WHERE clause: counts the rows of `select`.
+    CountMatching {
+        select: Box<PreparedQuery>,
+    },
+    /// Aggregates, all computed in one pass over `rows`, which yields whole
+    /// table rows: over all of them, or for each group with the same values
+    /// of the GROUP BY `keys`.
+    Aggregate {
+        rows: Box<PreparedQuery>,
+        columns: Vec<ColumnDef>,
+        aggregates: Vec<Aggregate>,
+        keys: Vec<Expr>,
+        /// How each of `keys` compares text.
+        key_collations: Vec<Collation>,
+        /// The select list. Each aggregate call in it, then in `having`, then
+        /// in the sort keys, stands for the next of `aggregates`; anything
+        /// else is worked out on the group's row.
+        outputs: Vec<Expr>,
+        /// Which groups to keep.
+        having: Option<Expr>,
+        order_by: Vec<SortKey>,
+    },
+    /// Window functions, each worked out over all the rows of `rows`,
+    /// which yields whole table rows. Without ORDER BY, the rows come out
+    /// in the order of the first window, as in SQLite.
+    Window {
+        rows: Box<PreparedQuery>,
+        columns: Vec<ColumnDef>,
+        windows: Vec<Window>,
+        /// The select list. Each window function in it, then in the sort
+        /// keys, stands for the next of `windows`.
+        outputs: Vec<Expr>,
+        order_by: Vec<SortKey>,
+    },
+    CreateTempTable {
+        table: String,
+        if_not_exists: bool,
+        columns: Vec<ColumnDef>,
+        select: Option<Box<PreparedQuery>>,
+    },
+    DropTempTable {
+        table: String,
+    },
+    /// `SELECT DISTINCT`: the rows of `rows` not equal to one before them,
+    /// comparing text in each column by its collation.
+    Distinct {
+        rows: Box<PreparedQuery>,
+        collations: Vec<Collation>,
+    },
+    /// `LIMIT` / `OFFSET`: skips the first `offset` rows of `rows` and stops
+    /// after `limit` more.
+    Limit {
+        rows: Box<PreparedQuery>,
+        limit: Option<u64>,
+        offset: u64,
+    },
+    /// `WITH`: each of `tables` is run into a temporary table that `query`,
+    /// and the tables after it, read by name while the statement runs.
+    With {
+        tables: Vec<WithTable>,
+        query: Box<PreparedQuery>,
+    },
+    /// `EXPLAIN`: runs `query`, throwing its rows away, then lists its
+    /// operators with how many rows each produced. `EXPLAIN QUERY PLAN`
+    /// lists them without running it.
+    Explain {
+        query: Box<PreparedQuery>,
+        run: bool,
+    },
+}
+
+/// One table of a WITH clause.
+struct WithTable {
+    name: String,
+    columns: Vec<ColumnDef>,
+    select: PreparedQuery,
+}
+
+/// One ORDER BY key of a SELECT.
+pub(crate) struct SortKey {
+    pub source: SortSource,
+    pub descending: bool,
+    pub nulls_first: Option<bool>,
+    /// Text compares by the collation of the column sorted by, if it is one.
+    pub collation: Collation,
+}
+
+pub(crate) enum SortSource {
+    /// A column of the result, by position.
+    Output(usize),
+    /// An expression over the table row.
+    Expr(Expr),
+}
+
+/// Orders two buffered rows by the sort keys in front of them. Keys of
+/// different types sort NULLs first, then numbers, text and blobs, as in
+/// SQLite.
+pub(crate) fn compare_sort_keys(
+    order_by: &[SortKey],
+    a: &[Value],
+    b: &[Value],
+) -> std::cmp::Ordering {
+    order_by
+        .iter()
+        .zip(a.iter().zip(b))
+        .map(|(key, (a, b))| match (key.nulls_first, a, b) {
+            // An explicit NULLS FIRST / LAST holds in either direction.
+            (Some(nulls_first), Value::Null, b) if *b != Value::Null => {
+                if nulls_first {
+                    std::cmp::Ordering::Less
+                } else {
+                    std::cmp::Ordering::Greater
+                }
+            }
+            (Some(nulls_first), a, Value::Null) if *a != Value::Null => {
+                if nulls_first {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Less
+                }
+            }
+            _ if key.descending => total_order_collated(a, b, &key.collation).reverse(),
+            _ => total_order_collated(a, b, &key.collation),
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(std::cmp::Ordering::Equal)
+}
+
+/// One column of a SELECT's result.
+enum Output {
+    /// A column of the table, by its index in `TableDef::columns`.
+    Column(usize),
+    /// A scalar subquery, run for each row: the first column of its first
+    /// row, or NULL if it has none.
+    Subquery(Box<QueryType>),
+    /// An expression over the row.
+    Expr(Expr),
+}
+
+/// Works out the result row for `row`. Scalar subqueries are numbered from
+/// `first_subquery` on, to tell their cached results apart.
+fn output_values(
+    db: &mut Database,
+    outputs: &[Output],
+    row: &Row,
+    first_subquery: usize,
+    subquery_results: &mut SubqueryResults,
+) -> Result<Vec<Value>> {
+    let mut next_subquery = first_subquery;
+    let mut values = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        values.push(match output {
+            Output::Column(index) => row.values.get(*index).cloned().unwrap_or(Value::Null),
+            Output::Subquery(query) => {
+                next_subquery += 1;
+                run_subquery(
+                    db,
+                    query,
+                    row,
+                    next_subquery - 1,
+                    SubqueryUse::Scalar,
+                    subquery_results,
+                )?
+            }
+            Output::Expr(expr) => evaluate(expr, row)?,
+        });
+    }
+    Ok(values)
+}
+
+/// The index entries that can belong to matching rows; the WHERE clause
+/// still filters the rows they point at.
+enum IndexScan {
+    /// Entries whose leading keys equal this tuple, compared column by
+    /// column; the index's `descending` columns sort in reverse.
+    Equals {
+        key: Vec<Value>,
+        descending: Vec<bool>,
+    },
+    /// Entries whose key starts with this text, for `LIKE 'prefix%'`. A
+    /// `nocase` index compares keys lowercased, and the prefix is lowercase.
+    Prefix { prefix: String, nocase: bool },
+    /// Entries whose key lies between the bounds, for `<`, `<=`, `>` and
+    /// `>=`; each bound is inclusive if its flag is set. Keys compare in
+    /// SQLite's order, numbers before text before blobs, so `> 25` takes in
+    /// every text and blob key as the comparison would; only NULL is never
+    /// in range.
+    Range {
+        lower: Option<(Value, bool)>,
+        upper: Option<(Value, bool)>,
+    },
+    /// Documents an FTS5 query matches, from the full-text index whose
+    /// `_data` table is the root page.
+    FullText(MatchQuery),
+    /// Rows by rowid, for `rowid = ...` or `rowid IN (...)`, sought in the
+    /// table's own B-tree, whose root page it is.
+    Rowids(Vec<i64>),
+    /// Rows whose rowid lies between the bounds, as for `Range`, read from
+    /// the table's own B-tree, whose root page it is.
+    RowidRange {
+        lower: Option<(Value, bool)>,
+        upper: Option<(Value, bool)>,
+    },
+}
+
+/// Where a table's rows are read from.
+enum Source {
+    /// The B-tree with this root page in the database file.
+    Table(u32),
+    /// The temporary table of this name.
+    Temp(String),
+    /// A table-valued function, with its arguments and the WHERE clause's
+    /// comparisons it may use to skip rows.
+    Function {
+        function: TableFunction,
+        args: Vec<Expr>,
+        constraints: Vec<Constraint>,
+    },
+    /// A table registered from Rust, with the WHERE clause's comparisons it
+    /// may use to skip rows.
+    Virtual {
+        table: Rc<dyn VirtualTable>,
+        constraints: Vec<Constraint>,
+    },
+    /// The cross join of several tables: every combination of one row from
+    /// each, side by side.
+    Join(Vec<JoinedTable>),
+}
+
+/// One table of a cross join.
+struct JoinedTable {
+    /// The name the query calls it by, which its columns are qualified
+    /// with among the join's: `t.column`.
+    name: String,
+    /// Which of the join's columns `*` stands for.
+    visible: Range<usize>,
+    /// Reads the table whole.
+    rows: PreparedQuery,
+}
+
+pub fn prepare(db: &mut Database, sql: &str) -> Result<PreparedQuery> {
+    let parsed = parse_query(sql).map_err(|e| Error::Parse(format!("{:#}", e)))?;
+    prepare_parsed(db, parsed, sql)
+}
+
+fn prepare_parsed(db: &mut Database, parsed: QueryType, sql: &str) -> Result<PreparedQuery> {
+    match parsed {
+        QueryType::Select {
+            distinct,
+            columns,
+            from,
+            where_clause,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+        } => {
+            let functions = db.functions();
+            let mut is_aggregate = !group_by.is_empty();
+            for item in &columns {
+                is_aggregate = is_aggregate
+                    || calls_aggregate(&item.item, &functions)
+                        .map_err(|e| Error::Parse(format!("{:#}", e)))?;
+            }
+            let is_window = columns.iter().any(|item| calls_window(&item.item));
+            if is_window && is_aggregate {
+                bail!(Error::Parse(
+                    "Window functions in an aggregate query are not supported".to_string()
+                ));
+            }
+            let mut select = if is_aggregate {
+                prepare_aggregate(
+                    db,
+                    columns,
+                    from,
+                    where_clause,
+                    group_by,
+                    having.map(|h| *h),
+                    order_by,
+                )?
+            } else if having.is_some() {
+                bail!(Error::Parse(
+                    "HAVING needs GROUP BY or an aggregate in the select list".to_string()
+                ));
+            } else if is_window {
+                prepare_window(db, columns, from, where_clause, order_by)?
+            } else {
+                prepare_select(db, columns, from, where_clause, order_by)?
+            };
+            if distinct {
+                select = prepare_distinct(db, select)?;
+            }
+            prepare_limit(db, select, limit, offset.map(|o| *o))
+        }
+        QueryType::SelectExpressions {
+            columns,
+            where_clause,
+            order_by,
+            limit,
+            offset,
+        } => {
+            let select = prepare_expressions(db, columns, where_clause, order_by)?;
+            prepare_limit(db, select, limit, offset.map(|o| *o))
+        }
+        QueryType::SelectCount {
+            table,
+            alias,
+            where_clause: Some(where_clause),
+        } => {
+            let from = TableRef {
+                name: table,
+                args: None,
+                alias,
+                values: None,
+            };
+            let select =
+                prepare_select(db, Vec::new(), vec![from], Some(where_clause), Vec::new())?;
+            Ok(PreparedQuery {
+                counters: Counters::default(),
+                columns: vec!["count(*)".to_string()],
+                plan: Plan::CountMatching {
+                    select: Box::new(select),
+                },
+            })
+        }
+        QueryType::SelectCount { table, .. } => {
+            let schema = db.read_schema()?;
+            let (source, _) = table_source(db, &schema, &table, None)?;
+            Ok(PreparedQuery {
+                counters: Counters::default(),
+                columns: vec!["count(*)".to_string()],
+                plan: Plan::Count { table, source },
+            })
+        }
+        QueryType::CreateTempTable {
+            table,
+            if_not_exists,
+            source,
+        } => {
+            let (columns, select) = match source {
+                TempTableSource::Select(select) => {
+                    if !matches!(
+                        *select,
+                        QueryType::Select { .. }
+                            | QueryType::SelectExpressions { .. }
+                            | QueryType::SelectCount { .. }
+                    ) {
+                        bail!(Error::Parse(
+                            "CREATE TEMP TABLE ... AS must be followed by a SELECT".to_string()
+                        ));
+                    }
+                    let select = prepare_parsed(db, *select, sql)?;
+                    (select.result_columns(), Some(Box::new(select)))
+                }
+                TempTableSource::Columns(create_sql) => (
+                    parse_create_table(&create_sql)
+                        .map_err(|e| Error::Parse(format!("{:#}", e)))?
+                        .columns,
+                    None,
+                ),
+            };
+            Ok(PreparedQuery {
+                counters: Counters::default(),
+                columns: Vec::new(),
+                plan: Plan::CreateTempTable {
+                    table,
+                    if_not_exists,
+                    columns,
+                    select,
+                },
+            })
+        }
+        QueryType::DropTable { table, if_exists } => {
+            if db.temp_table(&table).is_none() {
+                let schema = db.read_schema()?;
+                if find_table(&schema, &table).is_ok() {
+                    bail!(
+                        "Cannot drop table '{}': only temporary tables can be dropped, the database file is read-only",
+                        table
+                    );
+                }
+                if !if_exists {
+                    bail!(Error::NotFound(format!("Table '{}' not found", table)));
+                }
+            }
+            Ok(PreparedQuery {
+                counters: Counters::default(),
+                columns: Vec::new(),
+                plan: Plan::DropTempTable { table },
+            })
+        }
+        QueryType::With { tables, query } => prepare_with(db, tables, *query, sql),
+        QueryType::Values { rows } => prepare_values(db, rows),
+        QueryType::Pragma { name, value } => prepare_pragma(db, name, value, sql),
+        QueryType::Explain(statement) => prepare_explain(db, *statement, sql, true),
+        QueryType::ExplainQueryPlan(statement) => prepare_explain(db, *statement, sql, false),
+        QueryType::Unknown => bail!(Error::Parse(format!(
+            "Unknown or unsupported SQL command: {}",
+            sql
+        ))),
+    }
+}
+
+/// Wraps `select` in its LIMIT and OFFSET, if it has them.
+fn prepare_limit(
+    db: &Database,
+    mut select: PreparedQuery,
+    limit: Option<Expr>,
+    offset: Option<Expr>,
+) -> Result<PreparedQuery> {
+    if limit.is_none() && offset.is_none() {
+        return Ok(select);
+    }
+    // Negative limits mean no limit, negative offsets none.
+    let limit = limit
+        .map(|limit| limit_value(&limit, "LIMIT", db))
+        .transpose()?
+        .and_then(|limit| u64::try_from(limit).ok());
+    let offset = offset
+        .map(|offset| limit_value(&offset, "OFFSET", db))
+        .transpose()?
+        .map_or(0, |offset| offset.max(0) as u64);
+    // Not under DISTINCT, where duplicates don't count toward it.
+    if let Plan::Select { sort_limit, .. } = &mut select.plan {
+        *sort_limit = limit.map(|limit| limit.saturating_add(offset));
+    }
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: select.columns.clone(),
+        plan: Plan::Limit {
+            rows: Box::new(select),
+            limit,
+            offset,
+        },
+    })
+}
+
+/// `WITH tables query`. While `query` and each table after the first are
+/// prepared, the tables before them stand in, empty, under their names.
+fn prepare_with(
+    db: &mut Database,
+    tables: Vec<CommonTable>,
+    query: QueryType,
+    sql: &str,
+) -> Result<PreparedQuery> {
+    let mut prepared: Vec<WithTable> = Vec::with_capacity(tables.len());
+    let mut result = Ok(());
+    for table in tables {
+        result = prepare_with_table(db, table, sql).map(|table| {
+            db.push_common_table(&table.name, TempTable::new(table.columns.clone()));
+            prepared.push(table);
+        });
+        if result.is_err() {
+            break;
+        }
+    }
+    let query = result.and_then(|()| prepare_parsed(db, query, sql));
+    for _ in &prepared {
+        db.pop_common_table();
+    }
+    let query = query?;
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: query.columns.clone(),
+        plan: Plan::With {
+            tables: prepared,
+            query: Box::new(query),
+        },
+    })
+}
+
+/// One table of a WITH clause, with its columns renamed as it says.
+fn prepare_with_table(db: &mut Database, table: CommonTable, sql: &str) -> Result<WithTable> {
+    let CommonTable {
+        name,
+        columns: names,
+        query,
+    } = table;
+    // Without RECURSIVE, the name can only mean a table outside the WITH
+    // clause, which SQLite doesn't allow either.
+    let reads_itself = match &*query {
+        QueryType::Select { from, .. } => from
+            .iter()
+            .any(|from| from.args.is_none() && from.name.eq_ignore_ascii_case(&name)),
+        QueryType::SelectCount { table, .. } => table.eq_ignore_ascii_case(&name),
+        _ => false,
+    };
+    if reads_itself {
+        bail!(Error::Parse(format!("Circular reference: {}", name)));
+    }
+    let select = prepare_parsed(db, *query, sql)?;
+    let mut columns = select.result_columns();
+    if !names.is_empty() {
+        if names.len() != columns.len() {
+            bail!(Error::Parse(format!(
+                "Table {} has {} values for {} columns",
+                name,
+                columns.len(),
+                names.len()
+            )));
+        }
+        for (column, name) in columns.iter_mut().zip(names) {
+            column.name = name;
+        }
+    }
+    Ok(WithTable {
+        name,
+        columns,
+        select,
+    })
+}
+
+/// `PRAGMA name [= value]`. The pragmas that list rows read their table
+/// function, so `PRAGMA table_info(t)` is `SELECT * FROM pragma_table_info('t')`;
+/// the others read the database header, once, when the statement is prepared.
+fn prepare_pragma(
+    db: &mut Database,
+    name: String,
+    value: Option<String>,
+    sql: &str,
+) -> Result<PreparedQuery> {
+    let function = format!("pragma_{}", name);
+    if let Some(table_function) = TableFunction::named(&function) {
+        // Only `table_list` lists everything without a name.
+        let args = match value {
+            Some(value) => vec![Expr::Literal(Value::Text(value.into()))],
+            None if table_function == TableFunction::PragmaTableList => Vec::new(),
+            None => bail!(Error::Parse(format!(
+                "PRAGMA {} needs a name: PRAGMA {}(name)",
+                name, name
+            ))),
+        };
+        let select = QueryType::Select {
+            distinct: false,
+            columns: vec![ResultColumn::all()],
+            from: vec![TableRef {
+                name: function,
+                args: Some(args),
+                alias: None,
+                values: None,
+            }],
+            where_clause: None,
+            group_by: Vec::new(),
+            having: None,
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+        };
+        return prepare_parsed(db, select, sql);
+    }
+    let header = db.read_header()?;
+    let result = match name.as_str() {
+        "page_count" => Value::Int(db.page_count().into()),
+        "page_size" => Value::Int(header.page_size as i64),
+        "freelist_count" => Value::Int(header.freelist_count.into()),
+        "schema_version" => Value::Int(header.schema_cookie.into()),
+        "user_version" => Value::Int(header.user_version as i32 as i64),
+        "application_id" => Value::Int(header.application_id as i32 as i64),
+        "encoding" => Value::Text(
+            match header.text_encoding {
+                2 => "UTF-16le",
+                3 => "UTF-16be",
+                _ => "UTF-8",
+            }
+            .into(),
+        ),
+        _ => bail!(Error::Parse(format!(
+            "Unknown or unsupported PRAGMA: {}",
+            name
+        ))),
+    };
+    if value.is_some() {
+        bail!(Error::Usage(format!(
+            "PRAGMA {} can't be set: the database file is read-only",
+            name
+        )));
+    }
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: vec![name],
+        plan: Plan::Expressions {
+            outputs: vec![Output::Expr(Expr::Literal(result))],
+            where_clause: None,
+        },
+    })
+}
+
+/// `EXPLAIN` of `statement`, which has to be a SELECT; without `run`,
+/// `EXPLAIN QUERY PLAN`.
+fn prepare_explain(
+    db: &mut Database,
+    statement: QueryType,
+    sql: &str,
+    run: bool,
+) -> Result<PreparedQuery> {
+    if !matches!(
+        statement,
+        QueryType::Select { .. }
+            | QueryType::SelectExpressions { .. }
+            | QueryType::SelectCount { .. }
+            | QueryType::With { .. }
+            | QueryType::Values { .. }
+    ) {
+        bail!(Error::Parse(
+            "EXPLAIN must be followed by a SELECT".to_string()
+        ));
+    }
+    let query = prepare_parsed(db, statement, sql)?;
+    let columns = if run {
+        &EXPLAIN_COLUMNS[..]
+    } else {
+        &QUERY_PLAN_COLUMNS[..]
+    };
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: columns.iter().map(|name| name.to_string()).collect(),
+        plan: Plan::Explain {
+            query: Box::new(query),
+            run,
+        },
+    })
+}
+
+impl PreparedQuery {
+    /// Names of the result columns, as written in the statement.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// Whether this is an `EXPLAIN`, whose rows describe the operators of
+    /// the statement after it.
+    pub fn is_explain(&self) -> bool {
+        matches!(self.plan, Plan::Explain { run: true, .. })
+    }
+
+    /// Whether this is an `EXPLAIN QUERY PLAN`, whose rows are the operators
+    /// of the statement after it as `id`, `parent`, `notused` and `detail`,
+    /// like SQLite's.
+    pub fn is_query_plan(&self) -> bool {
+        matches!(self.plan, Plan::Explain { run: false, .. })
+    }
+
+    /// Runs the statement, handing each result row to `on_row` in order.
+    pub fn run(
+        &self,
+        db: &mut Database,
+        mut on_row: impl FnMut(Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
+        let functions = &db.functions();
+        let parameters = &db.parameters();
+        let counters = &self.counters;
+        counters.read.set(0);
+        counters.matched.set(0);
+        counters.returned.set(0);
+        let mut on_row = |row: Vec<Value>| {
+            counters.returned.set(counters.returned.get() + 1);
+            on_row(row)
+        };
+        match &self.plan {
+            Plan::Select {
+                table: _,
+                table_def,
+                defaults,
+                source,
+                outputs,
+                where_clause,
+                index_scans,
+                order_by,
+                sort_limit,
+            } => {
+                let has_subqueries = where_clause.as_ref().is_some_and(contains_subquery);
+                let has_computed_outputs = outputs
+                    .iter()
+                    .any(|output| !matches!(output, Output::Column(_)));
+                let mut subquery_results = SubqueryResults::new();
+                // With ORDER BY, rows are collected with their sort keys in
+                // front and only handed out once the scan is done.
+                let mut sorted = (!order_by.is_empty())
+                    .then(|| (TempTable::new(Vec::new()), db.memory_budget()));
+                let mut visit = |db: &mut Database, record: &mut Vec<Value>| {
+                    counters.read.set(counters.read.get() + 1);
+                    defaults.fill(record);
+                    table_def.present(record);
+                    if let Some(condition) = where_clause {
+                        let row = Row {
+                            columns: &table_def.columns,
+                            values: record,
+                            functions,
+                            parameters,
+                        };
+                        let matched = if has_subqueries {
+                            let condition =
+                                run_subqueries(db, condition, &row, &mut 0, &mut subquery_results)?;
+                            matches(&condition, &row)?
+                        } else {
+                            matches(condition, &row)?
+                        };
+                        if !matched {
+                            return Ok(());
+                        }
+                    }
+                    counters.matched.set(counters.matched.get() + 1);
+                    if !has_computed_outputs && sorted.is_none() {
+                        return on_row(
+                            outputs
+                                .iter()
+                                .map(|output| match output {
+                                    Output::Column(index) => {
+                                        record.get(*index).cloned().unwrap_or(Value::Null)
+                                    }
+                                    Output::Subquery(_) | Output::Expr(_) => Value::Null,
+                                })
+                                .collect(),
+                        );
+                    }
+                    let row = Row {
+                        columns: &table_def.columns,
+                        values: record,
+                        functions,
+                        parameters,
+                    };
+                    // Numbered after the WHERE clause's subqueries.
+                    let first_subquery = where_clause.as_ref().map_or(0, count_subqueries);
+                    let values =
+                        output_values(db, outputs, &row, first_subquery, &mut subquery_results)?;
+                    match &mut sorted {
+                        None => on_row(values),
+                        Some((buffer, budget)) => {
+                            let mut entry = order_by
+                                .iter()
+                                .map(|key| match &key.source {
+                                    SortSource::Output(index) => Ok(values[*index].clone()),
+                                    SortSource::Expr(expr) => evaluate(expr, &row),
+                                })
+                                .collect::<Result<Vec<_>>>()?;
+                            entry.extend(values);
+                            buffer.push(entry, budget)?;
+                            // Under a LIMIT, whenever the buffer holds twice
+                            // the rows that can make the cut, the rest are
+                            // dropped: memory goes by the limit, not the table.
+                            if let Some(keep) = sort_limit.map(|keep| keep as usize) {
+                                if buffer.len() >= keep.saturating_mul(2).max(1024) {
+                                    buffer.sort_by(|a, b| compare_sort_keys(order_by, a, b));
+                                    buffer.truncate(keep, budget);
+                                }
+                            }
+                            Ok(())
+                        }
+                    }
+                };
+
+                match source {
+                    // A range of rowids is read straight off the table.
+                    Source::Table(root_page)
+                        if matches!(index_scans[..], [(_, IndexScan::RowidRange { .. })]) =>
+                    {
+                        let [(_, IndexScan::RowidRange { lower, upper })] = &index_scans[..] else {
+                            unreachable!("matched a single rowid range");
+                        };
+                        if let Some(rowids) = rowid_range(lower.as_ref(), upper.as_ref()) {
+                            db.for_each_table_record_in_rowid_range_with_db(
+                                *root_page, rowids, visit,
+                            )?;
+                        }
+                    }
+                    Source::Table(root_page) if !index_scans.is_empty() => {
+                        let mut rowids = Vec::new();
+                        for (index_root_page, scan) in index_scans {
+                            rowids.extend(scan_index(db, *index_root_page, scan)?);
+                        }
+                        // A row found by both sides of an OR is read once.
+                        rowids.sort_unstable();
+                        rowids.dedup();
+                        db.for_each_table_record_by_rowids_with_db(*root_page, &rowids, visit)?;
+                    }
+                    Source::Table(root_page) => {
+                        db.for_each_table_record_with_db(*root_page, &mut visit)?;
+                    }
+                    Source::Temp(name) => {
+                        for i in 0..temp_table(db, name)?.len() {
+                            db.check_interrupt()?;
+                            let mut row = temp_table(db, name)?.rows()[i].clone();
+                            visit(db, &mut row)?;
+                        }
+                    }
+                    Source::Virtual { table, constraints } => {
+                        let table = Rc::clone(table);
+                        let mut cursor = table.filter(constraints)?;
+                        while let Some(mut row) = cursor.next_row()? {
+                            db.check_interrupt()?;
+                            visit(db, &mut row)?;
+                        }
+                    }
+                    Source::Function {
+                        function,
+                        args,
+                        constraints,
+                    } => {
+                        let row = Row {
+                            columns: &[],
+                            values: &[],
+                            functions,
+                            parameters,
+                        };
+                        let args = args
+                            .iter()
+                            .map(|arg| evaluate(arg, &row))
+                            .collect::<Result<Vec<_>>>()?;
+                        function.for_each_row(db, args, constraints, |db, mut row| {
+                            visit(db, &mut row)
+                        })?;
+                    }
+                    Source::Join(tables) => {
+                        // Each table is read whole, once; then every
+                        // combination of their rows is visited, the last
+                        // table's changing fastest.
+                        let mut budget = db.memory_budget();
+                        let mut sides = Vec::with_capacity(tables.len());
+                        for table in tables {
+                            let mut side = TempTable::new(Vec::new());
+                            let mut push = |row: Vec<Value>| side.push(row, &mut budget);
+                            let push: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut push;
+                            table.rows.run(db, push)?;
+                            sides.push(side);
+                        }
+                        let mut positions = vec![0; sides.len()];
+                        let mut record = Vec::new();
+                        while !sides.iter().any(TempTable::is_empty) {
+                            db.check_interrupt()?;
+                            record.clear();
+                            record.extend(positions.iter().zip(&sides).flat_map(
+                                |(&position, side)| side.rows()[position].iter().cloned(),
+                            ));
+                            visit(db, &mut record)?;
+                            let Some(last) =
+                                (0..sides.len()).rfind(|&i| positions[i] + 1 < sides[i].len())
+                            else {
+                                break;
+                            };
+                            positions[last] += 1;
+                            positions[last + 1..].fill(0);
+                        }
+                    }
+                }
+
+                if let Some((mut buffer, _)) = sorted {
+                    buffer.sort_by(|a, b| compare_sort_keys(order_by, a, b));
+                    for mut entry in buffer.into_rows() {
+                        db.check_interrupt()?;
+                        on_row(entry.split_off(order_by.len()))?;
+                    }
+                }
+                Ok(())
+            }
+            Plan::Expressions {
+                outputs,
+                where_clause,
+            } => {
+                let row = Row {
+                    columns: &[],
+                    values: &[],
+                    functions,
+                    parameters,
+                };
+                let mut subquery_results = SubqueryResults::new();
+                if let Some(condition) = where_clause {
+                    let condition =
+                        run_subqueries(db, condition, &row, &mut 0, &mut subquery_results)?;
+                    if !matches(&condition, &row)? {
+                        return Ok(());
+                    }
+                }
+                let first_subquery = where_clause.as_ref().map_or(0, count_subqueries);
+                let values =
+                    output_values(db, outputs, &row, first_subquery, &mut subquery_results)?;
+                on_row(values)
+            }
+            Plan::Values { rows } => {
+                let row = Row {
+                    columns: &[],
+                    values: &[],
+                    functions,
+                    parameters,
+                };
+                for exprs in rows {
+                    db.check_interrupt()?;
+                    let values = exprs
+                        .iter()
+                        .map(|expr| evaluate(expr, &row))
+                        .collect::<Result<Vec<_>>>()?;
+                    on_row(values)?;
+                }
+                Ok(())
+            }
+            Plan::Count {
+                source: Source::Table(root_page),
+                ..
+            } => {
+                let mut count = 0;
+                db.for_each_table_record_with_db(*root_page, |_, _| {
+                    count += 1;
+                    Ok(())
+                })?;
+                counters.read.set(count as u64);
+                on_row(vec![Value::Int(count)])
+            }
+            Plan::Count {
+                source: Source::Temp(name),
+                ..
+            } => {
+                let count = temp_table(db, name)?.len();
+                counters.read.set(count as u64);
+                on_row(vec![Value::Int(count as i64)])
+            }
+            Plan::Count {
+                source: Source::Virtual { table, .. },
+                ..
+            } => {
+                let table = Rc::clone(table);
+                let mut cursor = table.filter(&[])?;
+                let mut count = 0;
+                while cursor.next_row()?.is_some() {
+                    db.check_interrupt()?;
+                    count += 1;
+                }
+                counters.read.set(count as u64);
+                on_row(vec![Value::Int(count)])
+            }
+            Plan::Count {
+                source: Source::Function { .. } | Source::Join(_),
+                ..
+            } => unreachable!(
+                "count(*) over a table-valued function or a join is planned as an aggregate"
+            ),
+            Plan::CountMatching { select } => {
+                let mut count = 0;
+                // Through `dyn`, like `CREATE TEMP TABLE ... AS SELECT` below.
+                let mut count_row = |_: Vec<Value>| {
+                    count += 1;
+                    Ok(())
+                };
+                let count_row: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut count_row;
+                select.run(db, count_row)?;
+                on_row(vec![Value::Int(count)])
+            }
+            Plan::Aggregate {
+                rows,
+                columns,
+                aggregates,
+                keys,
+                key_collations,
+                outputs,
+                having,
+                order_by,
+            } => {
+                let mut groups = Groups::new(key_collations.clone());
+                let mut budget = db.memory_budget();
+                let mut aggregate_row = |values: Vec<Value>| {
+                    let row = Row {
+                        columns,
+                        values: &values,
+                        functions,
+                        parameters,
+                    };
+                    let key = keys
+                        .iter()
+                        .map(|key| evaluate(key, &row))
+                        .collect::<Result<Vec<_>>>()?;
+                    let group = groups.group(key, aggregates, &mut budget)?;
+                    let picked = group.accumulators.step(aggregates, &row)?;
+                    if group.row.is_empty() || picked {
+                        budget.release(row_size(&group.row));
+                        budget.charge(row_size(&values))?;
+                        group.row = values;
+                    }
+                    Ok(())
+                };
+                // Through `dyn`, like `CREATE TEMP TABLE ... AS SELECT` below.
+                let aggregate_row: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut aggregate_row;
+                rows.run(db, aggregate_row)?;
+
+                let mut groups = groups.into_sorted();
+                // Without GROUP BY, even no rows at all make one group.
+                if keys.is_empty() && groups.is_empty() {
+                    groups.push(Group {
+                        key: Vec::new(),
+                        row: Vec::new(),
+                        accumulators: Accumulators::new(aggregates),
+                    });
+                }
+                let is_aggregate = |name: &str| functions.is_aggregate(name);
+                let mut entries = Vec::with_capacity(groups.len());
+                for group in groups {
+                    db.check_interrupt()?;
+                    let results = group.accumulators.finish(aggregates)?;
+                    let mut results = results.into_iter();
+                    let mut substitute = |_: String, _: Vec<Expr>| {
+                        Ok(Expr::Literal(results.next().unwrap_or(Value::Null)))
+                    };
+                    let row = Row {
+                        columns,
+                        values: &group.row,
+                        functions,
+                        parameters,
+                    };
+                    let mut substituted = |expr: &Expr| {
+                        replace_aggregate_calls(expr.clone(), &is_aggregate, &mut substitute)
+                    };
+                    let values = outputs
+                        .iter()
+                        .map(|expr| evaluate(&substituted(expr)?, &row))
+                        .collect::<Result<Vec<_>>>()?;
+                    if let Some(having) = having {
+                        if !matches(&substituted(having)?, &row)? {
+                            continue;
+                        }
+                    }
+                    let mut entry = order_by
+                        .iter()
+                        .map(|key| match &key.source {
+                            SortSource::Output(index) => Ok(values[*index].clone()),
+                            SortSource::Expr(expr) => evaluate(&substituted(expr)?, &row),
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    entry.extend(values);
+                    entries.push(entry);
+                }
+                // Stable, so groups the sort keys can't tell apart stay in
+                // key order.
+                entries.sort_by(|a, b| compare_sort_keys(order_by, a, b));
+                for mut entry in entries {
+                    on_row(entry.split_off(order_by.len()))?;
+                }
+                Ok(())
+            }
+            Plan::Window {
+                rows,
+                columns,
+                windows,
+                outputs,
+                order_by,
+            } => {
+                let mut budget = db.memory_budget();
+                let mut buffer = TempTable::new(Vec::new());
+                let mut keep = |row: Vec<Value>| buffer.push(row, &mut budget);
+                // Through `dyn`, like `CREATE TEMP TABLE ... AS SELECT` below.
+                let keep: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut keep;
+                rows.run(db, keep)?;
+                let rows = buffer.rows();
+
+                let mut results = vec![Vec::with_capacity(windows.len()); rows.len()];
+                let mut order = Vec::new();
+                for window in windows {
+                    db.check_interrupt()?;
+                    let values = window.values(rows, columns, functions, parameters)?;
+                    if order.is_empty() {
+                        order = values.iter().map(|(index, _)| *index).collect();
+                    }
+                    for (index, value) in values {
+                        results[index].push(value);
+                    }
+                }
+                let mut entries = Vec::with_capacity(rows.len());
+                for index in order {
+                    db.check_interrupt()?;
+                    let mut results = std::mem::take(&mut results[index]).into_iter();
+                    let mut substitute =
+                        |_: Expr| Ok(Expr::Literal(results.next().unwrap_or(Value::Null)));
+                    let mut substituted =
+                        |expr: &Expr| replace_window_calls(expr.clone(), &mut substitute);
+                    let row = Row {
+                        columns,
+                        values: &rows[index],
+                        functions,
+                        parameters,
+                    };
+                    let values = outputs
+                        .iter()
+                        .map(|expr| evaluate(&substituted(expr)?, &row))
+                        .collect::<Result<Vec<_>>>()?;
+                    let mut entry = order_by
+                        .iter()
+                        .map(|key| match &key.source {
+                            SortSource::Output(index) => Ok(values[*index].clone()),
+                            SortSource::Expr(expr) => evaluate(&substituted(expr)?, &row),
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    entry.extend(values);
+                    entries.push(entry);
+                }
+                // Stable, so rows the sort keys can't tell apart stay in the
+                // first window's order.
+                entries.sort_by(|a, b| compare_sort_keys(order_by, a, b));
+                for mut entry in entries {
+                    on_row(entry.split_off(order_by.len()))?;
+                }
+                Ok(())
+            }
+            Plan::CreateTempTable {
+                table,
+                if_not_exists,
+                columns,
+                select,
+            } => {
+                if db.temp_table(table).is_some() {
+                    if *if_not_exists {
+                        return Ok(());
+                    }
+                    bail!("Table '{}' already exists", table);
+                }
+                let mut temp = TempTable::new(columns.clone());
+                if let Some(select) = select {
+                    let mut budget = db.memory_budget();
+                    // Through `dyn`: a closure type of its own would make `run`
+                    // instantiate itself recursively.
+                    let mut materialize = |row: Vec<Value>| temp.push(row, &mut budget);
+                    let materialize: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut materialize;
+                    select.run(db, materialize)?;
+                }
+                db.create_temp_table(table, temp)
+            }
+            Plan::DropTempTable { table } => {
+                db.drop_temp_table(table);
+                Ok(())
+            }
+            Plan::Distinct { rows, collations } => {
+                let mut seen = HashSet::new();
+                let mut budget = db.memory_budget();
+                let mut take_row = |row: Vec<Value>| {
+                    let key = row
+                        .iter()
+                        .zip(collations)
+                        .map(|(value, collation)| ValueKey::new(value, collation))
+                        .collect::<Vec<_>>();
+                    if seen.contains(&key) {
+                        return Ok(());
+                    }
+                    budget.charge(row_size(&row))?;
+                    seen.insert(key);
+                    on_row(row)
+                };
+                // Through `dyn`, like `CREATE TEMP TABLE ... AS SELECT` above.
+                let take_row: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut take_row;
+                rows.run(db, take_row)
+            }
+            Plan::Limit {
+                rows,
+                limit,
+                offset,
+            } => {
+                if *limit == Some(0) {
+                    return Ok(());
+                }
+                let mut skipped = 0;
+                let mut taken = 0;
+                let mut take_row = |row: Vec<Value>| {
+                    if skipped < *offset {
+                        skipped += 1;
+                        return Ok(());
+                    }
+                    on_row(row)?;
+                    taken += 1;
+                    if Some(taken) == *limit {
+                        return Err(LimitReached.into());
+                    }
+                    Ok(())
+                };
+                // Through `dyn`, like `CREATE TEMP TABLE ... AS SELECT` above.
+                let take_row: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut take_row;
+                match rows.run(db, take_row) {
+                    Err(e) if e.is::<LimitReached>() => Ok(()),
+                    result => result,
+                }
+            }
+            Plan::With { tables, query } => {
+                // All of them are held until the statement is done.
+                let mut budget = db.memory_budget();
+                let mut pushed = 0;
+                let mut result = Ok(());
+                for table in tables {
+                    let mut temp = TempTable::new(table.columns.clone());
+                    let mut materialize = |row: Vec<Value>| temp.push(row, &mut budget);
+                    // Through `dyn`, like `CREATE TEMP TABLE ... AS SELECT`
+                    // above.
+                    let materialize: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut materialize;
+                    result = table.select.run(db, materialize);
+                    if result.is_err() {
+                        break;
+                    }
+                    db.push_common_table(&table.name, temp);
+                    pushed += 1;
+                }
+                if result.is_ok() {
+                    let on_row: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut on_row;
+                    result = query.run(db, on_row);
+                }
+                for _ in 0..pushed {
+                    db.pop_common_table();
+                }
+                result
+            }
+            Plan::Explain { query, run } => {
+                let mut rows = Vec::new();
+                if *run {
+                    // Through `dyn`, like `CREATE TEMP TABLE ... AS SELECT`
+                    // above.
+                    let discard: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut |_| Ok(());
+                    query.run(db, discard)?;
+                    query.operator(db)?.flatten(0, &mut rows);
+                } else {
+                    query.operator(db)?.flatten_plan(0, &mut rows);
+                }
+                for row in rows {
+                    on_row(row)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Definitions of the result columns, as `CREATE TEMP TABLE ... AS`
+    /// declares them: a selected column keeps the type of the column it comes
+    /// from, under the name it was selected by.
+    fn result_columns(&self) -> Vec<ColumnDef> {
+        match &self.plan {
+            Plan::Select {
+                table_def, outputs, ..
+            } => self
+                .columns
+                .iter()
+                .zip(outputs)
+                .map(|(name, output)| match output {
+                    Output::Column(index) => ColumnDef {
+                        name: name.clone(),
+                        ..table_def.columns[*index].clone()
+                    },
+                    Output::Expr(expr) => ColumnDef {
+                        collation: explicit_collation(expr).map(str::to_string),
+                        ..ColumnDef::new(name, "")
+                    },
+                    Output::Subquery(_) => ColumnDef::new(name, ""),
+                })
+                .collect(),
+            Plan::Expressions { .. } | Plan::Values { .. } => self
+                .columns
+                .iter()
+                .map(|name| ColumnDef::new(name, ""))
+                .collect(),
+            Plan::Count { .. } | Plan::CountMatching { .. } => {
+                vec![ColumnDef::new("count(*)", "")]
+            }
+            Plan::Aggregate { .. } | Plan::Window { .. } => self
+                .columns
+                .iter()
+                .map(|name| ColumnDef::new(name, ""))
+                .collect(),
+            Plan::CreateTempTable { .. } | Plan::DropTempTable { .. } => Vec::new(),
+            Plan::Distinct { rows, .. } | Plan::Limit { rows, .. } => rows.result_columns(),
+            Plan::With { query, .. } => query.result_columns(),
+            Plan::Explain { .. } => self
+                .columns
+                .iter()
+                .map(|name| ColumnDef::new(name, ""))
+                .collect(),
+        }
+    }
+}
+
+/// The columns of EXPLAIN's result.
+const EXPLAIN_COLUMNS: [&str; 6] = [
+    "id",
+    "parent",
+    "operator",
+    "detail",
+    "estimated_rows",
+    "actual_rows",
+];
+
+/// The columns of EXPLAIN QUERY PLAN's result, SQLite's.
+const QUERY_PLAN_COLUMNS: [&str; 4] = ["id", "parent", "notused", "detail"];
+
+/// One step of running a statement, as EXPLAIN lists it.
+struct Operator {
+    name: &'static str,
+    detail: String,
+    /// The rows it was expected to produce, where there is anything to base
+    /// a guess on.
+    estimate: Option<u64>,
+    /// The rows it produced.
+    actual: u64,
+    /// The operators whose rows it takes.
+    children: Vec<Operator>,
+}
+
+impl Operator {
+    fn new(name: &'static str, detail: String, estimate: Option<u64>, actual: u64) -> Self {
+        Operator {
+            name,
+            detail,
+            estimate,
+            actual,
+            children: Vec::new(),
+        }
+    }
+
+    /// This operator on top of `child`.
+    fn over(self, child: Operator) -> Self {
+        Operator {
+            children: vec![child],
+            ..self
+        }
+    }
+
+    /// Appends a row for this operator to `rows`, followed by its children
+    /// depth first. Operators are numbered from 1 in that order; the root's
+    /// parent is 0.
+    fn flatten(self, parent: i64, rows: &mut Vec<Vec<Value>>) {
+        let id = rows.len() as i64 + 1;
+        rows.push(vec![
+            Value::Int(id),
+            Value::Int(parent),
+            Value::Text(self.name.into()),
+            Value::Text(self.detail.into()),
+            self.estimate
+                .map_or(Value::Null, |rows| Value::Int(rows as i64)),
+            Value::Int(self.actual as i64),
+        ]);
+        for child in self.children {
+            child.flatten(id, rows);
+        }
+    }
+
+    /// `flatten` for EXPLAIN QUERY PLAN: no row counts, and the operator's
+    /// name leads its detail, as in `SCAN users`.
+    fn flatten_plan(self, parent: i64, rows: &mut Vec<Vec<Value>>) {
+        let id = rows.len() as i64 + 1;
+        let detail = format!("{} {}", self.name, self.detail);
+        rows.push(vec![
+            Value::Int(id),
+            Value::Int(parent),
+            Value::Int(0),
+            Value::Text(detail.trim_end().into()),
+        ]);
+        for child in self.children {
+            child.flatten_plan(id, rows);
+        }
+    }
+}
+
+impl PreparedQuery {
+    /// The operator tree of the statement, with the row counts of its last
+    /// run.
+    fn operator(&self, db: &mut Database) -> Result<Operator> {
+        let read = self.counters.read.get();
+        let matched = self.counters.matched.get();
+        let returned = self.counters.returned.get();
+        Ok(match &self.plan {
+            Plan::Select {
+                table,
+                table_def,
+                source,
+                where_clause,
+                index_scans,
+                order_by,
+                sort_limit,
+                ..
+            } => {
+                let mut operator =
+                    source_operator(db, table, &table_def.columns, source, index_scans, read)?;
+                if let Some(condition) = where_clause {
+                    // Rows an index search left are guessed from the whole
+                    // table, as the search's terms are in the condition too.
+                    let rows = match source {
+                        Source::Table(root_page) => table_rows(db, *root_page)?,
+                        _ => operator.estimate,
+                    };
+                    let estimate = scaled(rows, selectivity(condition))
+                        .map(|estimate| operator.estimate.map_or(estimate, |e| estimate.min(e)));
+                    operator = Operator::new("FILTER", condition.to_string(), estimate, matched)
+                        .over(operator);
+                }
+                if !order_by.is_empty() {
+                    let keys = sort_detail(order_by, &self.columns);
+                    let mut detail = keys;
+                    if let Some(keep) = sort_limit {
+                        detail.push_str(&format!(" (top {})", keep));
+                    }
+                    let estimate = operator
+                        .estimate
+                        .map(|estimate| sort_limit.map_or(estimate, |keep| estimate.min(keep)));
+                    operator = Operator::new("SORT", detail, estimate, returned).over(operator);
+                }
+                operator
+            }
+            Plan::Expressions { where_clause, .. } => {
+                let detail = match where_clause {
+                    Some(condition) => format!("{} WHERE {}", self.columns.join(", "), condition),
+                    None => self.columns.join(", "),
+                };
+                Operator::new("ROW", detail, Some(1), returned)
+            }
+            Plan::Values { rows } => {
+                let detail = format!("{}-ROW VALUES CLAUSE", rows.len());
+                Operator::new("SCAN", detail, Some(rows.len() as u64), returned)
+            }
+            Plan::Count { table, source } => {
+                Operator::new("COUNT", "count(*)".to_string(), Some(1), returned)
+                    .over(source_operator(db, table, &[], source, &[], read)?)
+            }
+            Plan::CountMatching { select } => {
+                Operator::new("COUNT", "count(*)".to_string(), Some(1), returned)
+                    .over(select.operator(db)?)
+            }
+            Plan::Aggregate {
+                rows,
+                keys,
+                having,
+                order_by,
+                ..
+            } => {
+                let mut detail = self.columns.join(", ");
+                if !keys.is_empty() {
+                    let keys = keys.iter().map(Expr::to_string).collect::<Vec<_>>();
+                    detail = format!("{} GROUP BY {}", detail, keys.join(", "));
+                }
+                if let Some(having) = having {
+                    detail = format!("{} HAVING {}", detail, having);
+                }
+                let rows = rows.operator(db)?;
+                // Groups of rows sharing a key are guessed like equalities.
+                let estimate = if keys.is_empty() {
+                    Some(1)
+                } else {
+                    let groups = scaled(rows.estimate, EQUALITY_SELECTIVITY);
+                    match having {
+                        Some(having) => scaled(groups, selectivity(having)),
+                        None => groups,
+                    }
+                };
+                let mut operator =
+                    Operator::new("AGGREGATE", detail, estimate, returned).over(rows);
+                if !order_by.is_empty() {
+                    let keys = sort_detail(order_by, &self.columns);
+                    operator = Operator::new("SORT", keys, estimate, returned).over(operator);
+                }
+                operator
+            }
+            Plan::Window { rows, order_by, .. } => {
+                let rows = rows.operator(db)?;
+                let estimate = rows.estimate;
+                let mut operator =
+                    Operator::new("WINDOW", self.columns.join(", "), estimate, returned).over(rows);
+                if !order_by.is_empty() {
+                    let keys = sort_detail(order_by, &self.columns);
+                    operator = Operator::new("SORT", keys, estimate, returned).over(operator);
+                }
+                operator
+            }
+            Plan::Limit {
+                rows,
+                limit,
+                offset,
+            } => {
+                let rows = rows.operator(db)?;
+                let mut detail = limit.map_or(String::new(), |limit| limit.to_string());
+                if *offset > 0 {
+                    detail = format!("{} OFFSET {}", detail, offset)
+                        .trim_start()
+                        .to_string();
+                }
+                let estimate = rows
+                    .estimate
+                    .map(|estimate| estimate.saturating_sub(*offset))
+                    .map(|estimate| limit.map_or(estimate, |limit| estimate.min(limit)));
+                Operator::new("LIMIT", detail, estimate, returned).over(rows)
+            }
+            Plan::Distinct { rows, .. } => {
+                let rows = rows.operator(db)?;
+                let estimate = rows.estimate;
+                Operator::new("DISTINCT", String::new(), estimate, returned).over(rows)
+            }
+            Plan::With { tables, query } => {
+                let mut children = Vec::with_capacity(tables.len() + 1);
+                for table in tables {
+                    let select = table.select.operator(db)?;
+                    let (estimate, actual) = (select.estimate, select.actual);
+                    children.push(
+                        Operator::new("MATERIALIZE", table.name.clone(), estimate, actual)
+                            .over(select),
+                    );
+                }
+                let query = query.operator(db)?;
+                let names = tables.iter().map(|table| table.name.as_str());
+                let mut operator = Operator::new(
+                    "WITH",
+                    names.collect::<Vec<_>>().join(", "),
+                    query.estimate,
+                    returned,
+                );
+                children.push(query);
+                operator.children = children;
+                operator
+            }
+            Plan::CreateTempTable { .. } | Plan::DropTempTable { .. } | Plan::Explain { .. } => {
+                unreachable!("only a SELECT is explained")
+            }
+        })
+    }
+}
+
+/// Wraps `select` to leave out duplicate rows. Text compares by the
+/// collation of the column a result comes from, which has to be a built-in
+/// one to be hashed.
+fn prepare_distinct(db: &Database, select: PreparedQuery) -> Result<PreparedQuery> {
+    let functions = db.functions();
+    let collations = select
+        .result_columns()
+        .iter()
+        .map(|column| {
+            let collation = functions.collation(column.collation.as_deref())?;
+            if let Collation::User(_) = collation {
+                bail!(Error::Parse(format!(
+                    "DISTINCT can't compare '{}' by its collation {}",
+                    column.name,
+                    column.collation.as_deref().unwrap_or_default()
+                )));
+            }
+            Ok(collation)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: select.columns.clone(),
+        plan: Plan::Distinct {
+            rows: Box::new(select),
+            collations,
+        },
+    })
+}
+
+/// The sort keys of `order_by` as EXPLAIN shows them, with the result
+/// columns in `columns` by name.
+fn sort_detail(order_by: &[SortKey], columns: &[String]) -> String {
+    order_by
+        .iter()
+        .map(|key| {
+            let mut term = match &key.source {
+                SortSource::Output(index) => columns[*index].clone(),
+                SortSource::Expr(expr) => expr.to_string(),
+            };
+            if key.descending {
+                term.push_str(" DESC");
+            }
+            match key.nulls_first {
+                Some(true) => term.push_str(" NULLS FIRST"),
+                Some(false) => term.push_str(" NULLS LAST"),
+                None => {}
+            }
+            term
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The operator reading the rows of `table` from `source`, which produced
+/// `read` of them. Only tables in the file and temporary tables have an
+/// estimate: the others can't be sized without running them.
+fn source_operator(
+    db: &mut Database,
+    table: &str,
+    columns: &[ColumnDef],
+    source: &Source,
+    index_scans: &[(u32, IndexScan)],
+    read: u64,
+) -> Result<Operator> {
+    let constraints_detail = |constraints: &[Constraint]| {
+        let terms = constraints
+            .iter()
+            .map(|constraint| {
+                Expr::Compare {
+                    left: Box::new(Expr::Column(columns[constraint.column].name.clone())),
+                    op: constraint.op,
+                    right: Box::new(Expr::Literal(constraint.value.clone())),
+                }
+                .to_string()
+            })
+            .collect::<Vec<_>>();
+        if terms.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", terms.join(" AND "))
+        }
+    };
+    Ok(match source {
+        Source::Table(root_page) if !index_scans.is_empty() => {
+            let rows = table_rows(db, *root_page)?;
+            let estimate = rows.map(|rows| {
+                index_scans
+                    .iter()
+                    .map(|(_, scan)| scan_estimate(scan, rows))
+                    .sum::<u64>()
+                    .min(rows)
+            });
+            let schema = db.read_schema()?;
+            let scans = index_scans
+                .iter()
+                .map(|(index_root_page, scan)| {
+                    let index = schema
+                        .iter()
+                        .find(|entry| entry.rootpage == *index_root_page)
+                        .map_or("?", |entry| entry.name.as_str());
+                    match scan {
+                        IndexScan::Equals { key, .. } => {
+                            let key = key
+                                .iter()
+                                .map(|value| Expr::Literal(value.clone()).to_string())
+                                .collect::<Vec<_>>();
+                            match key.as_slice() {
+                                [value] => format!("USING INDEX {} (key = {})", index, value),
+                                _ => format!("USING INDEX {} (key = ({}))", index, key.join(", ")),
+                            }
+                        }
+                        IndexScan::Prefix { prefix, .. } => format!(
+                            "USING INDEX {} (key LIKE {})",
+                            index,
+                            Expr::Literal(Value::Text(format!("{}%", prefix).into()))
+                        ),
+                        IndexScan::Range { lower, upper } => {
+                            let bound = |op: &str, key: &Value| {
+                                format!("key {} {}", op, Expr::Literal(key.clone()))
+                            };
+                            let bounds = [
+                                lower.as_ref().map(|(key, inclusive)| {
+                                    bound(if *inclusive { ">=" } else { ">" }, key)
+                                }),
+                                upper.as_ref().map(|(key, inclusive)| {
+                                    bound(if *inclusive { "<=" } else { "<" }, key)
+                                }),
+                            ];
+                            format!(
+                                "USING INDEX {} ({})",
+                                index,
+                                bounds
+                                    .into_iter()
+                                    .flatten()
+                                    .collect::<Vec<_>>()
+                                    .join(" AND ")
+                            )
+                        }
+                        IndexScan::FullText(_) => "USING FULL-TEXT INDEX".to_string(),
+                        IndexScan::Rowids(_) => "USING INTEGER PRIMARY KEY (rowid=?)".to_string(),
+                        IndexScan::RowidRange { lower, upper } => {
+                            let bounds =
+                                [
+                                    lower.as_ref().map(|(_, inclusive)| {
+                                        if *inclusive {
+                                            "rowid>=?"
+                                        } else {
+                                            "rowid>?"
+                                        }
+                                    }),
+                                    upper.as_ref().map(|(_, inclusive)| {
+                                        if *inclusive {
+                                            "rowid<=?"
+                                        } else {
+                                            "rowid<?"
+                                        }
+                                    }),
+                                ];
+                            format!(
+                                "USING INTEGER PRIMARY KEY ({})",
+                                bounds
+                                    .into_iter()
+                                    .flatten()
+                                    .collect::<Vec<_>>()
+                                    .join(" AND ")
+                            )
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+            let detail = format!("{} {}", table, scans.join(" OR "));
+            Operator::new("SEARCH", detail, estimate, read)
+        }
+        Source::Table(root_page) => {
+            let estimate = table_rows(db, *root_page)?;
+            Operator::new("SCAN", table.to_string(), estimate, read)
+        }
+        Source::Temp(name) => {
+            // A table of a WITH clause is gone once its statement has run.
+            let rows = db.temp_table(name).map(|temp| temp.len() as u64);
+            Operator::new("SCAN", format!("{} (temporary)", table), rows, read)
+        }
+        Source::Virtual { constraints, .. } => {
+            let detail = format!("{} VIRTUAL TABLE{}", table, constraints_detail(constraints));
+            Operator::new("SCAN", detail, None, read)
+        }
+        Source::Function {
+            args, constraints, ..
+        } => {
+            let args = args.iter().map(Expr::to_string).collect::<Vec<_>>();
+            let detail = format!(
+                "{}({}){}",
+                table,
+                args.join(", "),
+                constraints_detail(constraints)
+            );
+            Operator::new("SCAN", detail, None, read)
+        }
+        Source::Join(tables) => {
+            let children = tables
+                .iter()
+                .map(|table| table.rows.operator(db))
+                .collect::<Result<Vec<_>>>()?;
+            let estimate = children.iter().map(|child| child.estimate).product();
+            Operator {
+                children,
+                ..Operator::new("JOIN", table.to_string(), estimate, read)
+            }
+        }
+    })
+}
+
+/// The estimated rows of the table in the file whose B-tree has this root
+/// page; `None` for one that isn't in the schema, such as a full-text
+/// index's content.
+fn table_rows(db: &mut Database, root_page: u32) -> Result<Option<u64>> {
+    let name = db
+        .read_schema()?
+        .iter()
+        .find(|entry| entry.typ == "table" && entry.rootpage == root_page)
+        .map(|entry| entry.name.clone());
+    Ok(match name {
+        Some(name) => Some(db.estimate_rows(&name)?.rows),
+        None => None,
+    })
+}
+
+/// The share of rows an equality is guessed to keep, with no statistics on
+/// the column's values to go on.
+const EQUALITY_SELECTIVITY: f64 = 0.1;
+/// And a range, a `LIKE` or a prefix.
+const RANGE_SELECTIVITY: f64 = 0.25;
+
+/// The share of rows `condition` is guessed to keep: a tenth for each
+/// equality, a quarter for each range or pattern and half for anything
+/// else, combined as if the terms were independent.
+fn selectivity(condition: &Expr) -> f64 {
+    match condition {
+        Expr::And(left, right) => selectivity(left) * selectivity(right),
+        Expr::Or(left, right) => {
+            let (left, right) = (selectivity(left), selectivity(right));
+            left + right - left * right
+        }
+        Expr::Not(operand) => 1.0 - selectivity(operand),
+        Expr::Compare {
+            op: CompareOp::Eq, ..
+        }
+        | Expr::IsNull(_) => EQUALITY_SELECTIVITY,
+        Expr::Compare {
+            op: CompareOp::NotEq,
+            ..
+        } => 1.0 - EQUALITY_SELECTIVITY,
+        Expr::Compare { .. } | Expr::Like { .. } => RANGE_SELECTIVITY,
+        Expr::In { list, .. } => (list.len() as f64 * EQUALITY_SELECTIVITY).min(1.0),
+        _ => 0.5,
+    }
+}
+
+/// `rows` scaled by `share`, rounded up.
+fn scaled(rows: Option<u64>, share: f64) -> Option<u64> {
+    rows.map(|rows| (rows as f64 * share).ceil() as u64)
+}
+
+/// The entries `scan` is guessed to find in a table of `rows` rows. Rowids
+/// are counted exactly where the search gives them.
+fn scan_estimate(scan: &IndexScan, rows: u64) -> u64 {
+    let guess = |share: f64| (rows as f64 * share).ceil() as u64;
+    let range = |lower: &Option<(Value, bool)>, upper: &Option<(Value, bool)>| {
+        guess(RANGE_SELECTIVITY.powi(lower.is_some() as i32 + upper.is_some() as i32))
+    };
+    match scan {
+        IndexScan::Equals { key, .. } => guess(EQUALITY_SELECTIVITY.powi(key.len() as i32)),
+        IndexScan::Prefix { .. } => guess(RANGE_SELECTIVITY),
+        IndexScan::Range { lower, upper } => range(lower, upper),
+        IndexScan::FullText(_) => guess(EQUALITY_SELECTIVITY),
+        IndexScan::Rowids(rowids) => rowids.len() as u64,
+        IndexScan::RowidRange { lower, upper } => {
+            match rowid_range(lower.as_ref(), upper.as_ref()) {
+                None => 0,
+                Some(rowids) if lower.is_some() && upper.is_some() => rowids
+                    .end()
+                    .abs_diff(*rowids.start())
+                    .saturating_add(1)
+                    .min(rows),
+                Some(_) => range(lower, upper),
+            }
+        }
+    }
+}
+
+/// The names SQLite gives a table's rowid, unless a column takes one.
+pub(crate) const ROWID_NAMES: [&str; 3] = ["rowid", "_rowid_", "oid"];
+
+fn temp_table<'a>(db: &'a Database, name: &str) -> Result<&'a TempTable> {
+    db.temp_table(name)
+        .ok_or_else(|| Error::NotFound(format!("Table '{}' not found", name)).into())
+}
+
+pub(crate) fn find_table<'a>(
+    schema: &'a [SchemaEntry],
+    table_name: &str,
+) -> Result<&'a SchemaEntry> {
+    schema
+        .iter()
+        .find(|e| e.typ == "table" && e.tbl_name == table_name)
+        .ok_or_else(|| Error::NotFound(format!("Table '{}' not found", table_name)).into())
+}
+
+/// The columns of a stored table, with its rowid in front. The rowid is
+/// named after the table's INTEGER PRIMARY KEY, which stands for it, if it
+/// has one, and answers to whichever of SQLite's names for it are free.
+fn get_table_def(sql_create_table: &str) -> Result<TableDef> {
+    let mut table_def = parse_create_table(sql_create_table)?;
+    let mut names = ROWID_NAMES
+        .iter()
+        .filter(|name| !table_def.columns.iter().any(|c| c.is_named(name)))
+        .map(|name| name.to_string());
+    let rowid = match &table_def.rowid_alias {
+        Some(alias) => ColumnDef::new(alias, "INTEGER"),
+        // With all three taken by columns, the rowid can't be selected.
+        None => ColumnDef::new(&names.next().unwrap_or_default(), "INTEGER"),
+    };
+    let rowid = ColumnDef {
+        aliases: names.collect(),
+        ..rowid
+    };
+    table_def.columns.insert(0, rowid);
+    Ok(table_def)
+}
+
+/// Where the rows of the table `table_name` come from, and its columns. With
+/// `args`, `table_name` is a table-valued function.
+fn table_source(
+    db: &Database,
+    schema_entries: &[SchemaEntry],
+    table_name: &str,
+    args: Option<Vec<Expr>>,
+) -> Result<(Source, TableDef)> {
+    if let Some(args) = args {
+        let function = TableFunction::named(table_name).ok_or_else(|| {
+            Error::NotFound(format!("No such table-valued function: {}", table_name))
+        })?;
+        let table_def = TableDef::with_columns(function.columns());
+        let constraints = Vec::new();
+        return Ok((
+            Source::Function {
+                function,
+                args,
+                constraints,
+            },
+            table_def,
+        ));
+    }
+    // First, so the tables of a WITH clause shadow virtual tables too.
+    if let Some(temp) = db.temp_table(table_name) {
+        return Ok((Source::Temp(table_name.to_string()), temp.def.clone()));
+    }
+    match db.virtual_table(table_name) {
+        Some((table_def, table)) => {
+            let constraints = Vec::new();
+            Ok((Source::Virtual { table, constraints }, table_def))
+        }
+        None => {
+            // A table-valued function can go without its parentheses, as
+            // long as no table has its name.
+            if TableFunction::named(table_name).is_some()
+                && find_table(schema_entries, table_name).is_err()
+            {
+                return table_source(db, schema_entries, table_name, Some(Vec::new()));
+            }
+            if let Some(table) = full_text_table(schema_entries, table_name)? {
+                return Ok((Source::Table(table.content_root), table.table_def));
+            }
+            let table_entry = find_table(schema_entries, table_name)?;
+            let table_sql = table_entry.sql.as_ref().context(format!(
+                "No SQL definition found for table '{}'",
+                table_name
+            ))?;
+            let table_def = get_table_def(table_sql)?;
+            // Its rows live in an index B-tree, keyed by the PRIMARY KEY.
+            if table_def.without_rowid {
+                bail!(Error::Parse(format!(
+                    "Can't read '{}': WITHOUT ROWID tables are not supported",
+                    table_name
+                )));
+            }
+            Ok((Source::Table(table_entry.rootpage), table_def))
+        }
+    }
+}
+
+fn prepare_select(
+    db: &mut Database,
+    requested_columns: Vec<ResultColumn>,
+    from: Vec<TableRef>,
+    where_clause: Option<Expr>,
+    order_by: Vec<OrderingTerm>,
+) -> Result<PreparedQuery> {
+    let functions = db.functions();
+    let schema_entries = db.read_schema()?;
+    let (table_name, alias, mut source, table_def) = match <[TableRef; 1]>::try_from(from) {
+        Ok([table]) => {
+            let table_args = table
+                .args
+                .map(|args| resolve_table_args(db, args, &table.name, table.alias.as_deref(), None))
+                .transpose()?;
+            let (source, table_def) = table_source(db, &schema_entries, &table.name, table_args)?;
+            (table.name, table.alias, source, table_def)
+        }
+        Err(from) => {
+            let name = from
+                .iter()
+                .map(|table| table.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let (source, table_def) = join_source(db, from)?;
+            (name, None, source, table_def)
+        }
+    };
+    let (table_name, alias) = (table_name.as_str(), alias.as_deref());
+    let all_table_columns = &table_def.columns;
+    let aliases = aliases(&requested_columns);
+    let joined = joined_names(&source);
+    let scope = Scope {
+        table: table_name,
+        alias,
+        columns: all_table_columns,
+        aliases: &aliases,
+        joined: &joined,
+    };
+
+    let expanded_columns = expand_stars(requested_columns, &scope, &schema_entries, &source)?;
+    // Plain column names lose their table's name, except in a join, whose
+    // columns are all qualified with theirs.
+    let requested_columns = expanded_columns
+        .into_iter()
+        .map(|column| {
+            let SelectItem::Expr(Expr::Column(name)) = &column.item else {
+                return Ok(column);
+            };
+            let text = match name.split_once('.') {
+                Some(_) if !scope.joined.is_empty() => name,
+                Some((qualifier, name)) if scope.is_named(qualifier) => name,
+                Some(_) => return Err(unknown_table_error(&column.text)),
+                None => scope.joined_column(name)?.unwrap_or(name),
+            };
+            if let Some((qualifier, _)) = text.split_once('.') {
+                if !scope.is_named(qualifier) {
+                    return Err(unknown_table_error(&column.text));
+                }
+            }
+            Ok(ResultColumn {
+                alias: column.alias.clone(),
+                ..ResultColumn::column(text)
+            })
+        })
+        .collect::<Result<Vec<ResultColumn>>>()?;
+    let mut match_queries = Vec::new();
+    let where_clause = where_clause
+        .and_then(|condition| take_match_terms(condition, &scope, &mut match_queries))
+        .map(|condition| resolve_columns(db, condition, &scope, None))
+        .transpose()?;
+
+    let outputs = requested_columns
+        .iter()
+        .map(|column| {
+            let req_col_name = &column.text;
+            // The select list can't use its own aliases.
+            let scope = scope.without_aliases();
+            let not_found = || {
+                Error::NotFound(format!(
+                    "Column '{}' not found in table '{}'",
+                    req_col_name, table_name
+                ))
+            };
+            // Anything but a plain name is an expression over the row.
+            let expr = match &column.item {
+                SelectItem::Subquery(query) => {
+                    return Ok(Output::Subquery(Box::new(resolve_scalar_subquery(
+                        db,
+                        (**query).clone(),
+                        &scope,
+                    )?)));
+                }
+                SelectItem::Expr(Expr::Column(name)) => {
+                    match all_table_columns
+                        .iter()
+                        .position(|column| column.is_named(name))
+                    {
+                        Some(index) => return Ok(Output::Column(index)),
+                        None => bail!(not_found()),
+                    }
+                }
+                SelectItem::Expr(expr) => expr.clone(),
+                SelectItem::All(_) | SelectItem::Filtered(_) => bail!(not_found()),
+            };
+            let expr = resolve_columns(db, expr, &scope, None)?;
+            check_columns(&expr, all_table_columns).map_err(|e| {
+                Error::NotFound(format!(
+                    "'{}' references an unknown column in table '{}': {:#}",
+                    req_col_name, table_name, e
+                ))
+            })?;
+            if contains_subquery(&expr) {
+                bail!(Error::Parse(format!(
+                    "Subqueries inside '{}' are not supported",
+                    req_col_name
+                )));
+            }
+            Ok(Output::Expr(expr))
+        })
+        .collect::<Result<Vec<Output>>>()?;
+
+    if let Some(condition) = &where_clause {
+        check_columns(condition, all_table_columns).map_err(|e| {
+            Error::NotFound(format!(
+                "WHERE clause references an unknown column in table '{}': {:#}",
+                table_name, e
+            ))
+        })?;
+    }
+
+    let order_by = order_by
+        .into_iter()
+        .map(|term| {
+            let (expr, collate) = strip_collate(term.expr);
+            // Unlike elsewhere, an alias comes before a column here: it
+            // means that item of the select list.
+            let expr = match alias_position(&expr, &requested_columns) {
+                Some(position) => Expr::Literal(Value::Int(position as i64 + 1)),
+                None => expr,
+            };
+            let source = match expr {
+                Expr::Literal(Value::Int(position)) => {
+                    if position < 1 || position as usize > outputs.len() {
+                        bail!(Error::Parse(format!(
+                            "ORDER BY term {} is out of range: the select list has {} column(s)",
+                            position,
+                            outputs.len()
+                        )));
+                    }
+                    SortSource::Output(position as usize - 1)
+                }
+                expr => {
+                    let expr = resolve_columns(db, expr, &scope, None)?;
+                    check_columns(&expr, all_table_columns).map_err(|e| {
+                        Error::NotFound(format!(
+                            "ORDER BY references an unknown column in table '{}': {:#}",
+                            table_name, e
+                        ))
+                    })?;
+                    if contains_subquery(&expr) {
+                        bail!(Error::Parse(
+                            "Subqueries in ORDER BY are not supported".to_string()
+                        ));
+                    }
+                    SortSource::Expr(expr)
+                }
+            };
+            let collation = collate.as_deref().or_else(|| match &source {
+                SortSource::Output(position) => match &outputs[*position] {
+                    Output::Column(index) => all_table_columns
+                        .get(*index)
+                        .and_then(|c| c.collation.as_deref()),
+                    Output::Expr(expr) => expr_collation(expr, all_table_columns),
+                    Output::Subquery(_) => None,
+                },
+                SortSource::Expr(expr) => expr_collation(expr, all_table_columns),
+            });
+            let collation = functions.collation(collation)?;
+            Ok(SortKey {
+                source,
+                descending: term.descending,
+                nulls_first: term.nulls_first,
+                collation,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // The planner looks for comparisons with constants, which for this run
+    // of the statement its parameters are.
+    let planned_condition = where_clause
+        .as_ref()
+        .map(|condition| bind_parameters(condition, &db.parameters()));
+    if let (
+        Source::Virtual { constraints, .. } | Source::Function { constraints, .. },
+        Some(condition),
+    ) = (&mut source, &planned_condition)
+    {
+        *constraints = virtual_constraints(condition, &table_def);
+    }
+    let is_table = matches!(source, Source::Table(_));
+    let defaults = if is_table {
+        ColumnDefaults::new(&table_def.columns)
+    } else {
+        ColumnDefaults::default()
+    };
+    let index_scans = if !match_queries.is_empty() {
+        full_text_scan(
+            &schema_entries,
+            table_name,
+            is_table,
+            match_queries,
+            &db.functions(),
+            &db.parameters(),
+        )?
+    } else {
+        // A UTF-16 database keeps index keys in the order of their UTF-16
+        // bytes, which isn't the order text compares in here; only the rowid
+        // can be sought in one.
+        let indexes = match db.text_encoding() {
+            TextEncoding::Utf8 => &schema_entries[..],
+            _ => &[],
+        };
+        match (&source, &planned_condition) {
+            (Source::Table(root_page), Some(condition)) => {
+                plan_index_scans(condition, indexes, table_name, &table_def, *root_page)
+                    .unwrap_or_default()
+            }
+            _ => Vec::new(),
+        }
+    };
+
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: requested_columns
+            .iter()
+            .map(|column| match (&column.alias, &column.item) {
+                (None, SelectItem::Expr(Expr::Column(name))) => match name.split_once('.') {
+                    Some((_, name)) => name.to_string(),
+                    None => name.clone(),
+                },
+                _ => column.name().to_string(),
+            })
+            .collect(),
+        plan: Plan::Select {
+            table: table_name.to_string(),
+            source,
+            table_def: Box::new(table_def),
+            defaults,
+            outputs,
+            where_clause,
+            index_scans,
+            order_by,
+            sort_limit: None,
+        },
+    })
+}
+
+/// The positions of the columns `*` stands for, or `qualifier.*`: every
+/// declared column, not the rowid in front of a stored table's (nor the `id`
+/// of an FTS5 table's content after it), nor a table-valued function's
+/// hidden ones. A join has one range per table.
+fn star_columns(
+    schema_entries: &[SchemaEntry],
+    table_name: &str,
+    source: &Source,
+    column_count: usize,
+    qualifier: Option<&str>,
+) -> Result<Vec<Range<usize>>> {
+    Ok(vec![match source {
+        Source::Table(_) if full_text_table(schema_entries, table_name)?.is_some() => {
+            2..column_count
+        }
+        Source::Table(_) => 1..column_count,
+        Source::Function { function, .. } => 0..function.visible_columns(),
+        Source::Temp(_) | Source::Virtual { .. } => 0..column_count,
+        Source::Join(tables) => {
+            return Ok(tables
+                .iter()
+                .filter(|table| {
+                    qualifier.map_or(true, |name| table.name.eq_ignore_ascii_case(name))
+                })
+                .map(|table| table.visible.clone())
+                .collect())
+        }
+    }])
+}
+
+/// `columns` with each `*` or `t.*` replaced by the columns it stands for.
+fn expand_stars(
+    columns: Vec<ResultColumn>,
+    scope: &Scope,
+    schema_entries: &[SchemaEntry],
+    source: &Source,
+) -> Result<Vec<ResultColumn>> {
+    let mut expanded_columns = Vec::with_capacity(columns.len());
+    for column in columns {
+        let qualifier = match &column.item {
+            SelectItem::All(Some(qualifier)) if !scope.is_named(qualifier) => {
+                bail!(unknown_table_error(&column.text));
+            }
+            SelectItem::All(qualifier) => qualifier.clone(),
+            _ => {
+                expanded_columns.push(column);
+                continue;
+            }
+        };
+        let count = scope.columns.len();
+        let qualifier = qualifier.as_deref();
+        for range in star_columns(schema_entries, scope.table, source, count, qualifier)? {
+            expanded_columns.extend(
+                scope.columns[range]
+                    .iter()
+                    .map(|column| ResultColumn::column(&column.name)),
+            );
+        }
+    }
+    Ok(expanded_columns)
+}
+
+/// Where the rows of the cross join of `from` come from, and its columns:
+/// those of each table in turn, qualified with the name the query calls it.
+fn join_source(db: &mut Database, from: Vec<TableRef>) -> Result<(Source, TableDef)> {
+    let schema_entries = db.read_schema()?;
+    let mut tables: Vec<JoinedTable> = Vec::with_capacity(from.len());
+    let mut columns = Vec::new();
+    for table in from {
+        let name = table.alias.clone().unwrap_or_else(|| table.name.clone());
+        let table_name = table.name.clone();
+        if tables
+            .iter()
+            .any(|other| other.name.eq_ignore_ascii_case(&name))
+        {
+            bail!(Error::Parse(format!(
+                "'{}' is in FROM twice: give one of them an alias",
+                name
+            )));
+        }
+        let mut rows = prepare_select(db, Vec::new(), vec![table], None, Vec::new())?;
+        let Plan::Select {
+            table_def,
+            outputs,
+            source,
+            ..
+        } = &mut rows.plan
+        else {
+            unreachable!("prepare_select plans a Select");
+        };
+        *outputs = (0..table_def.columns.len()).map(Output::Column).collect();
+        let start = columns.len();
+        let visible = star_columns(
+            &schema_entries,
+            &table_name,
+            source,
+            table_def.columns.len(),
+            None,
+        )?
+        .remove(0);
+        let visible = start + visible.start..start + visible.end;
+        columns.extend(table_def.columns.iter().map(|column| {
+            ColumnDef {
+                name: format!("{}.{}", name, column.name),
+                aliases: column
+                    .aliases
+                    .iter()
+                    .map(|alias| format!("{}.{}", name, alias))
+                    .collect(),
+                ..column.clone()
+            }
+        }));
+        rows.columns = table_def.columns.iter().map(|c| c.name.clone()).collect();
+        tables.push(JoinedTable {
+            name,
+            visible,
+            rows,
+        });
+    }
+    Ok((Source::Join(tables), TableDef::with_columns(columns)))
+}
+
+/// Moves the `table MATCH query` terms the rest of `condition` is ANDed with
+/// into `queries`, returning what is left of it. Those terms are answered by
+/// the full-text index rather than row by row.
+fn take_match_terms(condition: Expr, scope: &Scope, queries: &mut Vec<Expr>) -> Option<Expr> {
+    match condition {
+        Expr::And(left, right) => {
+            let left = take_match_terms(*left, scope, queries);
+            let right = take_match_terms(*right, scope, queries);
+            match (left, right) {
+                (Some(left), Some(right)) => Some(Expr::And(Box::new(left), Box::new(right))),
+                (left, right) => left.or(right),
+            }
+        }
+        Expr::Function { name, mut args } if is_table_match(&name, &args, scope) => {
+            queries.push(args.swap_remove(0));
+            None
+        }
+        condition => Some(condition),
+    }
+}
+
+/// Whether `name(args)` is `match(query, table)`, i.e. `table MATCH query`
+/// with `table` naming the table of `scope`.
+fn is_table_match(name: &str, args: &[Expr], scope: &Scope) -> bool {
+    name.eq_ignore_ascii_case("match")
+        && matches!(args, [_, Expr::Column(table)] if scope.is_named(table))
+}
+
+/// The index scan for the MATCH terms of a query on `table_name`, all of
+/// which a row has to match. Each query has to be a constant string.
+fn full_text_scan(
+    schema_entries: &[SchemaEntry],
+    table_name: &str,
+    is_table: bool,
+    queries: Vec<Expr>,
+    functions: &UserFunctions,
+    parameters: &[Value],
+) -> Result<Vec<(u32, IndexScan)>> {
+    let table = if is_table {
+        full_text_table(schema_entries, table_name)?
+    } else {
+        None
+    }
+    .ok_or_else(|| {
+        Error::Parse(format!(
+            "MATCH needs an FTS5 table, and '{}' isn't one",
+            table_name
+        ))
+    })?;
+    let row = Row {
+        columns: &[],
+        values: &[],
+        functions,
+        parameters,
+    };
+    let mut combined: Option<MatchQuery> = None;
+    for query in queries {
+        check_columns(&query, &[]).map_err(|_| {
+            Error::Parse("The right-hand side of MATCH must be a constant".to_string())
+        })?;
+        let Value::Text(text) = evaluate(&query, &row)? else {
+            bail!(Error::Parse(
+                "The right-hand side of MATCH must be a string".to_string()
+            ));
+        };
+        let query = table
+            .parse_query(&text)
+            .map_err(|e| Error::Parse(format!("{:#}", e)))?;
+        combined = Some(match combined {
+            Some(previous) => MatchQuery::And(Box::new(previous), Box::new(query)),
+            None => query,
+        });
+    }
+    Ok(combined
+        .map(|query| (table.data_root, IndexScan::FullText(query)))
+        .into_iter()
+        .collect())
+}
+
+/// Prepares a SELECT without FROM. Its select list and WHERE clause can't
+/// refer to columns, as there is no table.
+fn prepare_expressions(
+    db: &mut Database,
+    columns: Vec<ResultColumn>,
+    where_clause: Option<Expr>,
+    order_by: Vec<OrderingTerm>,
+) -> Result<PreparedQuery> {
+    let aliases = aliases(&columns);
+    let scope = Scope {
+        table: "",
+        alias: None,
+        columns: &[],
+        aliases: &aliases,
+        joined: &[],
+    };
+    let outputs = columns
+        .iter()
+        .map(|column| {
+            let item = &column.text;
+            let scope = scope.without_aliases();
+            let expr = match &column.item {
+                SelectItem::Subquery(query) => {
+                    return Ok(Output::Subquery(Box::new(resolve_scalar_subquery(
+                        db,
+                        (**query).clone(),
+                        &scope,
+                    )?)));
+                }
+                SelectItem::All(_) => {
+                    bail!(Error::Parse("SELECT * needs a FROM clause".to_string()))
+                }
+                SelectItem::Filtered(_) => {
+                    bail!(Error::Parse(format!("'{}' needs a FROM clause", item)))
+                }
+                SelectItem::Expr(expr) => expr.clone(),
+            };
+            let expr = resolve_columns(db, expr, &scope, None)?;
+            check_columns(&expr, &[]).map_err(|e| Error::NotFound(format!("{:#}", e)))?;
+            if contains_subquery(&expr) {
+                bail!(Error::Parse(format!(
+                    "Subqueries inside '{}' are not supported",
+                    item
+                )));
+            }
+            Ok(Output::Expr(expr))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let where_clause = where_clause
+        .map(|condition| resolve_columns(db, condition, &scope, None))
+        .transpose()?;
+    if let Some(condition) = &where_clause {
+        check_columns(condition, &[]).map_err(|e| Error::NotFound(format!("{:#}", e)))?;
+    }
+    // Sorting the one row changes nothing, but the terms still have to make
+    // sense.
+    for term in order_by {
+        let (expr, collate) = strip_collate(term.expr);
+        db.functions().collation(collate.as_deref())?;
+        match expr {
+            Expr::Literal(Value::Int(position))
+                if position < 1 || position as usize > outputs.len() =>
+            {
+                bail!(Error::Parse(format!(
+                    "ORDER BY term {} is out of range: the select list has {} column(s)",
+                    position,
+                    outputs.len()
+                )));
+            }
+            expr => {
+                let expr = resolve_columns(db, expr, &scope, None)?;
+                check_columns(&expr, &[]).map_err(|e| Error::NotFound(format!("{:#}", e)))?;
+            }
+        }
+    }
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: columns
+            .iter()
+            .map(|column| column.name().to_string())
+            .collect(),
+        plan: Plan::Expressions {
+            outputs,
+            where_clause,
+        },
+    })
+}
+
+/// `VALUES`, whose expressions can't use any column.
+fn prepare_values(db: &mut Database, rows: Vec<Vec<Expr>>) -> Result<PreparedQuery> {
+    let scope = Scope {
+        table: "",
+        alias: None,
+        columns: &[],
+        aliases: &[],
+        joined: &[],
+    };
+    let rows = rows
+        .into_iter()
+        .map(|exprs| {
+            exprs
+                .into_iter()
+                .map(|expr| {
+                    let expr = resolve_columns(db, expr, &scope, None)?;
+                    check_columns(&expr, &[]).map_err(|e| Error::NotFound(format!("{:#}", e)))?;
+                    if contains_subquery(&expr) {
+                        bail!(Error::Parse(
+                            "Subqueries in VALUES are not supported".to_string()
+                        ));
+                    }
+                    Ok(expr)
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let width = rows.first().map_or(0, Vec::len);
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: (1..=width).map(|i| format!("column{}", i)).collect(),
+        plan: Plan::Values { rows },
+    })
+}
+
+/// Prepares a SELECT that aggregates: one with GROUP BY, or with aggregate
+/// calls in its select list. The rows its WHERE clause matches are read
+/// once, whole, and every aggregate of their group is updated from each row.
+fn prepare_aggregate(
+    db: &mut Database,
+    items: Vec<ResultColumn>,
+    from: Vec<TableRef>,
+    where_clause: Option<Expr>,
+    group_by: Vec<Expr>,
+    having: Option<Expr>,
+    order_by: Vec<OrderingTerm>,
+) -> Result<PreparedQuery> {
+    let alias = match &from[..] {
+        [table] => table.alias.clone(),
+        _ => None,
+    };
+    let mut rows = prepare_select(db, Vec::new(), from, where_clause, Vec::new())?;
+    let Plan::Select {
+        table,
+        table_def,
+        source,
+        outputs,
+        ..
+    } = &mut rows.plan
+    else {
+        unreachable!("prepare_select plans a Select");
+    };
+    *outputs = (0..table_def.columns.len()).map(Output::Column).collect();
+    let table_name = table.clone();
+    let columns = table_def.columns.clone();
+    let joined = joined_names(source);
+    let aliases = aliases(&items);
+    let scope = Scope {
+        table: &table_name,
+        alias: alias.as_deref(),
+        columns: &columns,
+        aliases: &aliases,
+        joined: &joined,
+    };
+    let functions = db.functions();
+    let is_aggregate = |name: &str| functions.is_aggregate(name);
+
+    // Every aggregate call, in the order `outputs`, `having` and then
+    // `order_by` have them.
+    let mut calls = Vec::new();
+    let take_calls = |expr: Expr, calls: &mut Vec<AggregateCall>| {
+        replace_aggregate_calls(expr, &is_aggregate, &mut |function, args| {
+            calls.push(AggregateCall {
+                function: function.to_lowercase(),
+                args: args.clone(),
+                filter: None,
+            });
+            Ok(Expr::Function {
+                name: function,
+                args,
+            })
+        })
+    };
+    let mut outputs = Vec::with_capacity(items.len());
+    for item in &items {
+        let name = &item.text;
+        let expr = match &item.item {
+            SelectItem::Subquery(_) => bail!(Error::Parse(
+                "Subqueries in the select list of an aggregate query are not supported".to_string()
+            )),
+            SelectItem::All(_) => bail!(Error::Parse(
+                "SELECT * in an aggregate query is not supported".to_string()
+            )),
+            // A whole item may have a FILTER clause, which an expression can't.
+            SelectItem::Filtered(call) => {
+                if call.function != "count" && !is_aggregate(&call.function) {
+                    bail!(Error::Parse(format!(
+                        "FILTER may not be used with non-aggregate {}()",
+                        call.function
+                    )));
+                }
+                calls.push(call.clone());
+                Expr::Function {
+                    name: call.function.clone(),
+                    args: call.args.clone(),
+                }
+            }
+            SelectItem::Expr(expr) => take_calls(expr.clone(), &mut calls)?,
+        };
+        let expr = resolve_columns(db, expr, &scope.without_aliases(), None)?;
+        check_columns(&expr, &columns).map_err(|e| {
+            Error::NotFound(format!(
+                "'{}' references an unknown column in table '{}': {:#}",
+                name, table_name, e
+            ))
+        })?;
+        if contains_subquery(&expr) {
+            bail!(Error::Parse(format!(
+                "Subqueries inside '{}' are not supported",
+                name
+            )));
+        }
+        outputs.push(expr);
+    }
+
+    let having = match having {
+        Some(having) => {
+            let having = resolve_columns(db, having, &scope, None)?;
+            let having = take_calls(having, &mut calls)?;
+            check_columns(&having, &columns).map_err(|e| {
+                Error::NotFound(format!(
+                    "HAVING references an unknown column in table '{}': {:#}",
+                    table_name, e
+                ))
+            })?;
+            if contains_subquery(&having) {
+                bail!(Error::Parse(
+                    "Subqueries in HAVING are not supported".to_string()
+                ));
+            }
+            Some(having)
+        }
+        None => None,
+    };
+
+    let mut key_collations = Vec::with_capacity(group_by.len());
+    let keys = group_by
+        .into_iter()
+        .map(|key| {
+            let key = match key {
+                Expr::Literal(Value::Int(position)) => {
+                    if position < 1 || position as usize > outputs.len() {
+                        bail!(Error::Parse(format!(
+                            "GROUP BY term {} is out of range: the select list has {} column(s)",
+                            position,
+                            outputs.len()
+                        )));
+                    }
+                    outputs[position as usize - 1].clone()
+                }
+                key => resolve_columns(db, key, &scope, None)?,
+            };
+            check_columns(&key, &columns).map_err(|e| {
+                Error::NotFound(format!(
+                    "GROUP BY references an unknown column in table '{}': {:#}",
+                    table_name, e
+                ))
+            })?;
+            let mut has_aggregate = false;
+            replace_aggregate_calls(key.clone(), &is_aggregate, &mut |_, _| {
+                has_aggregate = true;
+                Ok(Expr::Literal(Value::Null))
+            })?;
+            if has_aggregate {
+                bail!(Error::Parse(
+                    "Aggregate functions are not allowed in GROUP BY".to_string()
+                ));
+            }
+            if contains_subquery(&key) {
+                bail!(Error::Parse(
+                    "Subqueries in GROUP BY are not supported".to_string()
+                ));
+            }
+            key_collations.push(hashable_collation(db, &key, &columns)?);
+            Ok(key)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let order_by = order_by
+        .into_iter()
+        .map(|term| {
+            let (expr, collate) = strip_collate(term.expr);
+            let expr = match alias_position(&expr, &items) {
+                Some(position) => Expr::Literal(Value::Int(position as i64 + 1)),
+                None => expr,
+            };
+            let source = match expr {
+                Expr::Literal(Value::Int(position)) => {
+                    if position < 1 || position as usize > outputs.len() {
+                        bail!(Error::Parse(format!(
+                            "ORDER BY term {} is out of range: the select list has {} column(s)",
+                            position,
+                            outputs.len()
+                        )));
+                    }
+                    SortSource::Output(position as usize - 1)
+                }
+                expr => {
+                    let expr = resolve_columns(db, expr, &scope, None)?;
+                    let expr = take_calls(expr, &mut calls)?;
+                    check_columns(&expr, &columns).map_err(|e| {
+                        Error::NotFound(format!(
+                            "ORDER BY references an unknown column in table '{}': {:#}",
+                            table_name, e
+                        ))
+                    })?;
+                    if contains_subquery(&expr) {
+                        bail!(Error::Parse(
+                            "Subqueries in ORDER BY are not supported".to_string()
+                        ));
+                    }
+                    SortSource::Expr(expr)
+                }
+            };
+            let collation = collate.as_deref().or_else(|| match &source {
+                SortSource::Output(position) => expr_collation(&outputs[*position], &columns),
+                SortSource::Expr(expr) => expr_collation(expr, &columns),
+            });
+            let collation = functions.collation(collation)?;
+            Ok(SortKey {
+                source,
+                descending: term.descending,
+                nulls_first: term.nulls_first,
+                collation,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let aggregates = calls
+        .into_iter()
+        .map(|call| prepare_aggregate_call(db, call, &scope, &is_aggregate))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: items.iter().map(|item| item.name().to_string()).collect(),
+        plan: Plan::Aggregate {
+            rows: Box::new(rows),
+            columns,
+            aggregates,
+            keys,
+            key_collations,
+            outputs,
+            having,
+            order_by,
+        },
+    })
+}
+
+/// A SELECT with window functions in its select list. The rows matching the
+/// WHERE clause are gathered first, then each window is worked out over them
+/// all, and only then are they sorted by the ORDER BY.
+fn prepare_window(
+    db: &mut Database,
+    items: Vec<ResultColumn>,
+    from: Vec<TableRef>,
+    where_clause: Option<Expr>,
+    order_by: Vec<OrderingTerm>,
+) -> Result<PreparedQuery> {
+    let alias = match &from[..] {
+        [table] => table.alias.clone(),
+        _ => None,
+    };
+    let schema_entries = db.read_schema()?;
+    let mut rows = prepare_select(db, Vec::new(), from, where_clause, Vec::new())?;
+    let Plan::Select {
+        table,
+        table_def,
+        source,
+        outputs,
+        ..
+    } = &mut rows.plan
+    else {
+        unreachable!("prepare_select plans a Select");
+    };
+    *outputs = (0..table_def.columns.len()).map(Output::Column).collect();
+    let table_name = table.clone();
+    let columns = table_def.columns.clone();
+    let joined = joined_names(source);
+    let aliases = aliases(&items);
+    let scope = Scope {
+        table: &table_name,
+        alias: alias.as_deref(),
+        columns: &columns,
+        aliases: &aliases,
+        joined: &joined,
+    };
+    let items = expand_stars(items, &scope, &schema_entries, source)?;
+    let functions = db.functions();
+    let is_aggregate = |name: &str| functions.is_aggregate(name);
+
+    let mut outputs = Vec::with_capacity(items.len());
+    for item in &items {
+        let name = &item.text;
+        let expr = match &item.item {
+            SelectItem::Expr(expr) => expr.clone(),
+            SelectItem::Subquery(_) => bail!(Error::Parse(
+                "Subqueries in the select list of a query with window functions are not supported"
+                    .to_string()
+            )),
+            SelectItem::All(_) | SelectItem::Filtered(_) => bail!(Error::Parse(format!(
+                "'{}' is not supported in a query with window functions",
+                name
+            ))),
+        };
+        let expr = resolve_columns(db, expr, &scope.without_aliases(), None)?;
+        check_columns(&expr, &columns).map_err(|e| {
+            Error::NotFound(format!(
+                "'{}' references an unknown column in table '{}': {:#}",
+                name, table_name, e
+            ))
+        })?;
+        if contains_subquery(&expr) {
+            bail!(Error::Parse(format!(
+                "Subqueries inside '{}' are not supported",
+                name
+            )));
+        }
+        outputs.push(expr);
+    }
+
+    let order_by = order_by
+        .into_iter()
+        .map(|term| {
+            let (expr, collate) = strip_collate(term.expr);
+            let expr = match alias_position(&expr, &items) {
+                Some(position) => Expr::Literal(Value::Int(position as i64 + 1)),
+                None => expr,
+            };
+            let source = match expr {
+                Expr::Literal(Value::Int(position)) => {
+                    if position < 1 || position as usize > outputs.len() {
+                        bail!(Error::Parse(format!(
+                            "ORDER BY term {} is out of range: the select list has {} column(s)",
+                            position,
+                            outputs.len()
+                        )));
+                    }
+                    SortSource::Output(position as usize - 1)
+                }
+                expr => {
+                    let expr = resolve_columns(db, expr, &scope, None)?;
+                    check_columns(&expr, &columns).map_err(|e| {
+                        Error::NotFound(format!(
+                            "ORDER BY references an unknown column in table '{}': {:#}",
+                            table_name, e
+                        ))
+                    })?;
+                    if contains_subquery(&expr) {
+                        bail!(Error::Parse(
+                            "Subqueries in ORDER BY are not supported".to_string()
+                        ));
+                    }
+                    SortSource::Expr(expr)
+                }
+            };
+            let collation = collate.as_deref().or_else(|| match &source {
+                SortSource::Output(position) => expr_collation(&outputs[*position], &columns),
+                SortSource::Expr(expr) => expr_collation(expr, &columns),
+            });
+            let collation = functions.collation(collation)?;
+            Ok(SortKey {
+                source,
+                descending: term.descending,
+                nulls_first: term.nulls_first,
+                collation,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Every window, in the order `outputs` and then `order_by` have them.
+    let mut calls = Vec::new();
+    let sort_exprs = order_by.iter().filter_map(|key| match &key.source {
+        SortSource::Expr(expr) => Some(expr),
+        SortSource::Output(_) => None,
+    });
+    for expr in outputs.iter().chain(sort_exprs) {
+        replace_window_calls(expr.clone(), &mut |window| {
+            calls.push(window.clone());
+            Ok(window)
+        })?;
+    }
+    let windows = calls
+        .into_iter()
+        .map(|call| prepare_window_call(db, call, &scope, &is_aggregate))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(PreparedQuery {
+        counters: Counters::default(),
+        columns: items.iter().map(|item| item.name().to_string()).collect(),
+        plan: Plan::Window {
+            rows: Box::new(rows),
+            columns,
+            windows,
+            outputs,
+            order_by,
+        },
+    })
+}
+
+/// Resolves one window function, already resolved as an expression, against
+/// the table in `scope`.
+fn prepare_window_call(
+    db: &mut Database,
+    call: Expr,
+    scope: &Scope,
+    is_aggregate: &dyn Fn(&str) -> bool,
+) -> Result<Window> {
+    let Expr::Window {
+        name,
+        args,
+        partition_by,
+        order_by,
+    } = call
+    else {
+        unreachable!("only windows are prepared as windows");
+    };
+    let function_name = name.to_lowercase();
+    let wrong_arguments = || {
+        Error::Parse(format!(
+            "Wrong number of arguments to {}(): got {}",
+            name,
+            args.len()
+        ))
+    };
+    let function = match function_name.as_str() {
+        "row_number" | "rank" | "dense_rank" | "percent_rank" | "cume_dist" => {
+            if !args.is_empty() {
+                bail!(wrong_arguments());
+            }
+            match function_name.as_str() {
+                "row_number" => WindowFunction::RowNumber,
+                "rank" => WindowFunction::Rank,
+                "dense_rank" => WindowFunction::DenseRank,
+                "percent_rank" => WindowFunction::PercentRank,
+                _ => WindowFunction::CumeDist,
+            }
+        }
+        "ntile" | "first_value" | "last_value" => {
+            let Ok([arg]) = <[Expr; 1]>::try_from(args.clone()) else {
+                bail!(wrong_arguments());
+            };
+            match function_name.as_str() {
+                "ntile" => WindowFunction::Ntile(arg),
+                "first_value" => WindowFunction::FirstValue(arg),
+                _ => WindowFunction::LastValue(arg),
+            }
+        }
+        "lag" | "lead" => {
+            let mut rest = args.clone().into_iter();
+            let (Some(arg), offset, default, None) =
+                (rest.next(), rest.next(), rest.next(), rest.next())
+            else {
+                bail!(wrong_arguments());
+            };
+            WindowFunction::Shift {
+                arg,
+                offset: offset.unwrap_or(Expr::Literal(Value::Int(1))),
+                default: default.unwrap_or(Expr::Literal(Value::Null)),
+                ahead: function_name == "lead",
+            }
+        }
+        function if function == "count" || is_aggregate(function) => {
+            let call = AggregateCall {
+                function: function.to_string(),
+                args: args.clone(),
+                filter: None,
+            };
+            WindowFunction::Aggregate(prepare_aggregate_call(db, call, scope, is_aggregate)?)
+        }
+        _ => bail!(Error::Parse(format!(
+            "{}() may not be used as a window function",
+            name
+        ))),
+    };
+
+    let functions = db.functions();
+    let mut keys = Vec::with_capacity(partition_by.len() + order_by.len());
+    for expr in &partition_by {
+        keys.push(SortKey {
+            collation: functions.collation(expr_collation(expr, scope.columns))?,
+            source: SortSource::Expr(expr.clone()),
+            descending: false,
+            nulls_first: None,
+        });
+    }
+    for term in order_by {
+        keys.push(SortKey {
+            collation: functions.collation(expr_collation(&term.expr, scope.columns))?,
+            source: SortSource::Expr(term.expr),
+            descending: term.descending,
+            nulls_first: term.nulls_first,
+        });
+    }
+    Ok(Window {
+        function,
+        keys,
+        partitions: partition_by.len(),
+    })
+}
+
+/// Resolves one aggregate call against the table in `scope`.
+fn prepare_aggregate_call(
+    db: &mut Database,
+    call: AggregateCall,
+    scope: &Scope,
+    is_aggregate: &dyn Fn(&str) -> bool,
+) -> Result<Aggregate> {
+    let (table_name, columns) = (scope.table, scope.columns);
+    let function = match (call.function.as_str(), call.args.as_slice()) {
+        ("count", []) => AggregateFunction::CountRows,
+        ("count", [argument]) => {
+            let Expr::Column(column) = resolve_columns(db, argument.clone(), scope, None)? else {
+                bail!(Error::Parse("count() takes a column name or *".to_string()));
+            };
+            let index = columns
+                .iter()
+                .position(|c| c.is_named(&column))
+                .ok_or_else(|| {
+                    Error::NotFound(format!(
+                        "Column '{}' not found in table '{}'",
+                        column, table_name
+                    ))
+                })?;
+            AggregateFunction::Count(index)
+        }
+        ("count", args) => bail!(Error::Parse(format!(
+            "Wrong number of arguments to count(): expected 1, got {}",
+            args.len()
+        ))),
+        (function, args) => {
+            let args = args
+                .iter()
+                .map(|arg| {
+                    let arg = resolve_columns(db, arg.clone(), scope, None)?;
+                    check_columns(&arg, columns).map_err(|e| {
+                        Error::NotFound(format!(
+                            "{}() references an unknown column in table '{}': {:#}",
+                            function, table_name, e
+                        ))
+                    })?;
+                    if contains_subquery(&arg) {
+                        bail!(Error::Parse(format!(
+                            "Subqueries in the arguments of {}() are not supported",
+                            function
+                        )));
+                    }
+                    Ok(arg)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // An aggregate registered under a built-in's name replaces it.
+            let registered = db.functions().aggregate(function, args.len()).cloned();
+            match (function, registered) {
+                (_, Ok(new)) => AggregateFunction::User { new, args },
+                ("sum" | "avg" | "min" | "max", Err(_)) => {
+                    let Ok([arg]) = <[Expr; 1]>::try_from(args) else {
+                        bail!(Error::Parse(format!(
+                            "Wrong number of arguments to {}(): expected 1, got {}",
+                            function,
+                            call.args.len()
+                        )));
+                    };
+                    let collation = || db.functions().collation(expr_collation(&arg, columns));
+                    match function {
+                        "sum" => AggregateFunction::Sum(arg),
+                        "avg" => AggregateFunction::Avg(arg),
+                        "min" => AggregateFunction::Min {
+                            collation: collation()?,
+                            arg,
+                        },
+                        _ => AggregateFunction::Max {
+                            collation: collation()?,
+                            arg,
+                        },
+                    }
+                }
+                (_, Err(e)) => bail!(Error::Parse(format!("{:#}", e))),
+            }
+        }
+    };
+    let mut nested = false;
+    for arg in &call.args {
+        replace_aggregate_calls(arg.clone(), is_aggregate, &mut |_, _| {
+            nested = true;
+            Ok(Expr::Literal(Value::Null))
+        })?;
+    }
+    if nested {
+        bail!(Error::Parse(format!(
+            "Aggregate calls can't be nested in the arguments of {}()",
+            call.function
+        )));
+    }
+    let filter = match call.filter {
+        Some(filter) => {
+            let filter = resolve_columns(db, filter, scope, None)?;
+            check_columns(&filter, columns).map_err(|e| {
+                Error::NotFound(format!(
+                    "FILTER clause references an unknown column in table '{}': {:#}",
+                    table_name, e
+                ))
+            })?;
+            if contains_subquery(&filter) {
+                bail!(Error::Parse(
+                    "Subqueries in a FILTER clause are not supported".to_string()
+                ));
+            }
+            Some(filter)
+        }
+        None => None,
+    };
+    Ok(Aggregate { function, filter })
+}
+
+/// Whether select list item `item` has a window function in it.
+fn calls_window(item: &SelectItem) -> bool {
+    let SelectItem::Expr(expr) = item else {
+        return false;
+    };
+    let mut found = false;
+    let _ = replace_window_calls(expr.clone(), &mut |window| {
+        found = true;
+        Ok(window)
+    });
+    found
+}
+
+/// Whether select list item `item` calls an aggregate function, as a whole
+/// or anywhere inside it.
+fn calls_aggregate(item: &SelectItem, functions: &UserFunctions) -> Result<bool> {
+    let is_aggregate = |name: &str| functions.is_aggregate(name);
+    let expr = match item {
+        SelectItem::Filtered(call) if call.function != "count" && !is_aggregate(&call.function) => {
+            bail!(
+                "FILTER may not be used with non-aggregate {}()",
+                call.function
+            )
+        }
+        SelectItem::Filtered(_) => return Ok(true),
+        SelectItem::Expr(expr) => expr.clone(),
+        SelectItem::All(_) | SelectItem::Subquery(_) => return Ok(false),
+    };
+    let mut found = false;
+    replace_aggregate_calls(expr, &is_aggregate, &mut |_, _| {
+        found = true;
+        Ok(Expr::Literal(Value::Null))
+    })?;
+    Ok(found)
+}
+
+/// The column `expr` is, if it's a plain reference to one of `columns`.
+fn column_of<'a>(expr: &Expr, columns: &'a [ColumnDef]) -> Option<&'a ColumnDef> {
+    match expr {
+        Expr::Column(name) => columns.iter().find(|c| c.is_named(name)),
+        _ => None,
+    }
+}
+
+/// The name of the collation `expr` compares by: the one its COLLATE names,
+/// else the one declared for the column it is, if any.
+fn expr_collation<'a>(expr: &'a Expr, columns: &'a [ColumnDef]) -> Option<&'a str> {
+    explicit_collation(expr)
+        .or_else(|| column_of(expr, columns).and_then(|c| c.collation.as_deref()))
+}
+
+/// An ORDER BY term without the COLLATE around it, if it has one, and the
+/// collation that names: `1 COLLATE NOCASE` is still a select-list position.
+fn strip_collate(expr: Expr) -> (Expr, Option<String>) {
+    match expr {
+        Expr::Collate { operand, collation } => (*operand, Some(collation)),
+        expr => (expr, None),
+    }
+}
+
+/// The collation of `expr`, for comparing values by a hash of them: only
+/// the built-in collations can be.
+fn hashable_collation(db: &Database, expr: &Expr, columns: &[ColumnDef]) -> Result<Collation> {
+    let name = expr_collation(expr, columns);
+    let collation = db.functions().collation(name)?;
+    if let Collation::User(_) = collation {
+        bail!(Error::Parse(format!(
+            "Can't compare '{}' by its collation {} here: only BINARY, NOCASE and RTRIM can be hashed",
+            expr,
+            name.unwrap_or_default()
+        )));
+    }
+    Ok(collation)
+}
+
+/// The names of the tables `source` joins, if it's a join.
+fn joined_names(source: &Source) -> Vec<String> {
+    match source {
+        Source::Join(tables) => tables.iter().map(|table| table.name.clone()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// A table as a query refers to it: by name, or by alias if it has one.
+struct Scope<'a> {
+    table: &'a str,
+    alias: Option<&'a str>,
+    columns: &'a [ColumnDef],
+    /// The aliases of the select list, and what they stand for, which names
+    /// that aren't columns can refer to.
+    aliases: &'a [(String, Expr)],
+    /// The names of the tables of a cross join, which its `columns` are
+    /// qualified with; empty for a single table.
+    joined: &'a [String],
+}
+
+impl<'a> Scope<'a> {
+    /// Whether `qualifier`, the `t` of `t.column`, means this table, or in
+    /// a join, one of its tables.
+    fn is_named(&self, qualifier: &str) -> bool {
+        if !self.joined.is_empty() {
+            return self
+                .joined
+                .iter()
+                .any(|table| table.eq_ignore_ascii_case(qualifier));
+        }
+        self.alias
+            .unwrap_or(self.table)
+            .eq_ignore_ascii_case(qualifier)
+    }
+
+    fn has_column(&self, name: &str) -> bool {
+        self.columns.iter().any(|column| column.is_named(name))
+            || self.joined_columns(name).next().is_some()
+    }
+
+    /// In a join, the columns called `t.name`, whatever their table.
+    fn joined_columns<'b>(&'b self, name: &'b str) -> impl Iterator<Item = &'a str> + 'b {
+        let columns = if self.joined.is_empty() {
+            &[]
+        } else {
+            self.columns
+        };
+        columns
+            .iter()
+            .map(|column| column.name.as_str())
+            .filter(move |column| {
+                column
+                    .split_once('.')
+                    .is_some_and(|(_, column)| column.eq_ignore_ascii_case(name))
+            })
+    }
+
+    /// The one column of a join that `name`, without a table, refers to. It's
+    /// an error if more than one of the tables has it.
+    fn joined_column(&self, name: &str) -> Result<Option<&'a str>> {
+        let mut columns = self.joined_columns(name);
+        let Some(first) = columns.next() else {
+            return Ok(None);
+        };
+        // A stored table's rowid is in front of its columns, under the name
+        // its INTEGER PRIMARY KEY may have too.
+        if columns.any(|other| !other.eq_ignore_ascii_case(first)) {
+            bail!(Error::NotFound(format!("Ambiguous column name: {}", name)));
+        }
+        Ok(Some(first))
+    }
+
+    /// What `name` stands for if it's an alias in the select list and not a
+    /// column, which would come first.
+    fn aliased(&self, name: &str) -> Option<&Expr> {
+        if self.has_column(name) {
+            return None;
+        }
+        self.aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+            .map(|(_, expr)| expr)
+    }
+
+    /// This scope, without the select list's aliases.
+    fn without_aliases(&self) -> Scope<'_> {
+        Scope {
+            aliases: &[],
+            ..*self
+        }
+    }
+}
+
+/// The aliases in `columns` that stand for expressions, to resolve the
+/// names in the rest of the query with.
+fn aliases(columns: &[ResultColumn]) -> Vec<(String, Expr)> {
+    columns
+        .iter()
+        .filter_map(|column| {
+            let SelectItem::Expr(expr) = &column.item else {
+                return None;
+            };
+            Some((column.alias.clone()?, expr.clone()))
+        })
+        .collect()
+}
+
+/// The position in the select list `columns` of the item `expr` names by
+/// its alias, if it does.
+fn alias_position(expr: &Expr, columns: &[ResultColumn]) -> Option<usize> {
+    let Expr::Column(name) = expr else {
+        return None;
+    };
+    columns.iter().position(|column| {
+        column
+            .alias
+            .as_deref()
+            .is_some_and(|alias| alias.eq_ignore_ascii_case(name))
+    })
+}
+
+fn unknown_table_error(column: &str) -> anyhow::Error {
+    Error::NotFound(format!("'{}' refers to a table not in the query", column)).into()
+}
+
+/// Resolves the column references in a WHERE clause. References to the
+/// query's own table lose their qualifier. In a subquery, references to the
+/// `outer` query's table are left as `t.column` with `t` as that query names
+/// its table, for `bind_outer_columns` to fill in row by row.
+fn resolve_columns(
+    db: &mut Database,
+    expr: Expr,
+    scope: &Scope,
+    outer: Option<&Scope>,
+) -> Result<Expr> {
+    let mut resolve = |expr: Box<Expr>| resolve_columns(db, *expr, scope, outer).map(Box::new);
+    Ok(match expr {
+        Expr::Column(name) => match name.split_once('.') {
+            // A join's columns keep their table's name.
+            Some(_) if !scope.joined.is_empty() && scope.has_column(&name) => Expr::Column(name),
+            Some((qualifier, column)) if scope.is_named(qualifier) => {
+                Expr::Column(column.to_string())
+            }
+            Some((qualifier, column)) => match outer {
+                Some(outer) if outer.is_named(qualifier) => {
+                    if !outer.has_column(column) {
+                        bail!(Error::NotFound(format!("Column '{}' not found", name)));
+                    }
+                    Expr::Column(name)
+                }
+                _ => return Err(unknown_table_error(&name)),
+            },
+            None if scope.joined_column(&name)?.is_some() => {
+                Expr::Column(scope.joined_column(&name)?.unwrap_or_default().to_string())
+            }
+            // An alias stands for an expression written in terms of the
+            // columns, which has no aliases of its own.
+            None if scope.aliased(&name).is_some() => {
+                let expr = scope.aliased(&name).cloned().unwrap_or(Expr::Column(name));
+                resolve_columns(db, expr, &scope.without_aliases(), outer)?
+            }
+            None => match outer {
+                Some(outer) if !scope.has_column(&name) && outer.has_column(&name) => {
+                    Expr::Column(format!("{}.{}", outer.alias.unwrap_or(outer.table), name))
+                }
+                Some(_) if !scope.has_column(&name) => {
+                    bail!(Error::NotFound(format!("Column '{}' not found", name)));
+                }
+                _ => Expr::Column(name),
+            },
+        },
+        expr @ (Expr::Literal(_) | Expr::OuterColumn { .. } | Expr::Parameter(_)) => expr,
+        Expr::Compare { left, op, right } => Expr::Compare {
+            left: resolve(left)?,
+            op,
+            right: resolve(right)?,
+        },
+        Expr::Arithmetic { left, op, right } => Expr::Arithmetic {
+            left: resolve(left)?,
+            op,
+            right: resolve(right)?,
+        },
+        Expr::Like {
+            left,
+            pattern,
+            escape,
+        } => Expr::Like {
+            left: resolve(left)?,
+            pattern: resolve(pattern)?,
+            escape,
+        },
+        Expr::In { left, list } => Expr::In {
+            left: resolve(left)?,
+            list: list
+                .into_iter()
+                .map(|item| resolve(Box::new(item)).map(|item| *item))
+                .collect::<Result<_>>()?,
+        },
+        Expr::And(left, right) => Expr::And(resolve(left)?, resolve(right)?),
+        Expr::Or(left, right) => Expr::Or(resolve(left)?, resolve(right)?),
+        Expr::Not(inner) => Expr::Not(resolve(inner)?),
+        Expr::IsNull(operand) => Expr::IsNull(resolve(operand)?),
+        Expr::Function { name, mut args } if is_table_match(&name, &args, scope) => {
+            // The table operand stays as it is, for `take_match_terms`.
+            let query = std::mem::replace(&mut args[0], Expr::Literal(Value::Null));
+            args[0] = *resolve(Box::new(query))?;
+            Expr::Function { name, args }
+        }
+        Expr::Function { name, args } => Expr::Function {
+            name,
+            args: args
+                .into_iter()
+                .map(|arg| resolve(Box::new(arg)).map(|arg| *arg))
+                .collect::<Result<_>>()?,
+        },
+        Expr::Exists(query) => {
+            let mut query = resolve_subquery(db, *query, scope)?;
+            if let QueryType::Select { columns, .. } = &mut query {
+                // Only whether a row comes back matters.
+                columns.clear();
+            }
+            Expr::Exists(Box::new(query))
+        }
+        Expr::Window {
+            name,
+            args,
+            partition_by,
+            order_by,
+        } => {
+            let mut resolve_all = |exprs: Vec<Expr>| {
+                exprs
+                    .into_iter()
+                    .map(|expr| resolve(Box::new(expr)).map(|expr| *expr))
+                    .collect::<Result<Vec<_>>>()
+            };
+            let args = resolve_all(args)?;
+            let partition_by = resolve_all(partition_by)?;
+            let order_by = order_by
+                .into_iter()
+                .map(|term| {
+                    Ok(OrderingTerm {
+                        expr: *resolve(Box::new(term.expr))?,
+                        ..term
+                    })
+                })
+                .collect::<Result<_>>()?;
+            Expr::Window {
+                name,
+                args,
+                partition_by,
+                order_by,
+            }
+        }
+        Expr::Collate { operand, collation } => {
+            let operand = resolve(operand)?;
+            // An unknown collation is an error even if nothing compares.
+            db.functions().collation(Some(&collation))?;
+            Expr::Collate { operand, collation }
+        }
+        Expr::InSubquery { left, query } => Expr::InSubquery {
+            left: resolve(left)?,
+            query: Box::new(resolve_one_column_subquery(db, *query, scope, "An IN")?),
+        },
+    })
+}
+
+/// The table, alias and WHERE clause of a `SELECT` from one table or
+/// `SELECT count(*)`.
+fn select_parts_mut(query: &mut QueryType) -> Option<(&str, Option<&str>, &mut Option<Expr>)> {
+    match query {
+        QueryType::Select {
+            from, where_clause, ..
+        } => match &from[..] {
+            [table] => Some((&table.name, table.alias.as_deref(), where_clause)),
+            _ => None,
+        },
+        QueryType::SelectCount {
+            table,
+            alias,
+            where_clause,
+        } => Some((table, alias.as_deref(), where_clause)),
+        _ => None,
+    }
+}
+
+/// The expressions of a `SELECT` without FROM, or of `VALUES`, that can
+/// refer to an outer query: its select list and WHERE, or its rows. `None`
+/// for a query that reads a table.
+fn table_free_exprs_mut(query: &mut QueryType) -> Option<Vec<&mut Expr>> {
+    match query {
+        QueryType::SelectExpressions {
+            columns,
+            where_clause,
+            ..
+        } => Some(
+            columns
+                .iter_mut()
+                .filter_map(|column| match &mut column.item {
+                    SelectItem::Expr(expr) => Some(expr),
+                    _ => None,
+                })
+                .chain(where_clause.as_mut())
+                .collect(),
+        ),
+        QueryType::Values { rows } => Some(rows.iter_mut().flatten().collect()),
+        _ => None,
+    }
+}
+
+/// The arguments of the table-valued function in a `SELECT`'s FROM, if it
+/// has one.
+fn table_args_mut(query: &mut QueryType) -> Option<&mut Vec<Expr>> {
+    match query {
+        QueryType::Select { from, .. } => from.first_mut()?.args.as_mut(),
+        _ => None,
+    }
+}
+
+/// Resolves the arguments of the table-valued function `function`. They
+/// can't use the function's own columns, only those of an `outer` query.
+fn resolve_table_args(
+    db: &mut Database,
+    args: Vec<Expr>,
+    function: &str,
+    alias: Option<&str>,
+    outer: Option<&Scope>,
+) -> Result<Vec<Expr>> {
+    let scope = Scope {
+        table: function,
+        alias,
+        columns: &[],
+        aliases: &[],
+        joined: &[],
+    };
+    args.into_iter()
+        .map(|arg| {
+            let arg = resolve_columns(db, arg, &scope, outer)?;
+            if outer.is_none() {
+                check_columns(&arg, &[]).map_err(|e| {
+                    Error::NotFound(format!("Arguments of {}(): {:#}", function, e))
+                })?;
+            }
+            if contains_subquery(&arg) {
+                bail!(Error::Parse(format!(
+                    "Subqueries in the arguments of {}() are not supported",
+                    function
+                )));
+            }
+            Ok(arg)
+        })
+        .collect()
+}
+
+/// Resolves the columns of a subquery of a query whose table is `outer`.
+fn resolve_subquery(db: &mut Database, mut query: QueryType, outer: &Scope) -> Result<QueryType> {
+    if let Some(exprs) = table_free_exprs_mut(&mut query) {
+        // Without a table every column is the outer query's.
+        let scope = Scope {
+            table: "",
+            alias: None,
+            columns: &[],
+            aliases: &[],
+            joined: &[],
+        };
+        for expr in exprs {
+            *expr = resolve_columns(
+                db,
+                std::mem::replace(expr, Expr::Literal(Value::Null)),
+                &scope,
+                Some(outer),
+            )?;
+        }
+        return Ok(query);
+    }
+    if matches!(&query, QueryType::Select { from, .. } if from.len() > 1) {
+        bail!(Error::Parse(
+            "Joins in subqueries are not supported".to_string()
+        ));
+    }
+    let Some((table, alias, where_clause)) = select_parts_mut(&mut query) else {
+        bail!(Error::Parse("A subquery must be a SELECT".to_string()));
+    };
+    let (table, alias) = (table.to_string(), alias.map(str::to_string));
+    let condition = where_clause.take();
+    let schema_entries = db.read_schema()?;
+    let args = table_args_mut(&mut query).map(std::mem::take);
+    let (_, table_def) = table_source(db, &schema_entries, &table, args.clone())?;
+    let scope = Scope {
+        table: &table,
+        alias: alias.as_deref(),
+        columns: &table_def.columns,
+        aliases: &[],
+        joined: &[],
+    };
+    let condition = condition
+        .map(|condition| resolve_columns(db, condition, &scope, Some(outer)))
+        .transpose()?;
+    if let Some((_, _, where_clause)) = select_parts_mut(&mut query) {
+        *where_clause = condition;
+    }
+    if let Some(args) = args {
+        let args = resolve_table_args(db, args, &table, alias.as_deref(), Some(outer))?;
+        if let Some(table_args) = table_args_mut(&mut query) {
+            *table_args = args;
+        }
+    }
+    Ok(query)
+}
+
+/// Resolves a scalar subquery, which has to produce one column.
+fn resolve_scalar_subquery(
+    db: &mut Database,
+    query: QueryType,
+    outer: &Scope,
+) -> Result<QueryType> {
+    resolve_one_column_subquery(db, query, outer, "A scalar")
+}
+
+/// Resolves a subquery that has to produce one column; `kind` starts the
+/// error if it doesn't.
+fn resolve_one_column_subquery(
+    db: &mut Database,
+    query: QueryType,
+    outer: &Scope,
+    kind: &str,
+) -> Result<QueryType> {
+    let width = match &query {
+        QueryType::Select { columns, .. } | QueryType::SelectExpressions { columns, .. } => {
+            columns.len()
+        }
+        QueryType::Values { rows } => rows.first().map_or(0, Vec::len),
+        _ => 1,
+    };
+    if width != 1 {
+        bail!(Error::Parse(format!(
+            "{} subquery must return one column, not {}",
+            kind, width
+        )));
+    }
+    resolve_subquery(db, query, outer)
+}
+
+/// Cached subquery results, by subquery and the outer values it was run with.
+type SubqueryResults = HashMap<(usize, Vec<u8>), Vec<Value>>;
+
+/// What a subquery's rows are turned into.
+#[derive(Clone, Copy)]
+enum SubqueryUse {
+    /// 1 if there is a row, 0 if not.
+    Exists,
+    /// The first column of the first row, or NULL.
+    Scalar,
+    /// The first column of every row: the list of an IN.
+    List,
+}
+
+/// How many results `SubqueryResults` keeps before it stops caching.
+const MAX_CACHED_SUBQUERY_RESULTS: usize = 10_000;
+
+/// Returned from a row callback to stop a subquery at its first row.
+#[derive(Debug)]
+struct FoundRow(Vec<Value>);
+
+impl std::fmt::Display for FoundRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "subquery returned a row")
+    }
+}
+
+impl std::error::Error for FoundRow {}
+
+/// Returned from a row callback to stop a query once its LIMIT is reached.
+#[derive(Debug)]
+struct LimitReached;
+
+impl std::fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "LIMIT reached")
+    }
+}
+
+impl std::error::Error for LimitReached {}
+
+/// Evaluates a LIMIT or OFFSET expression, which can't refer to columns, to
+/// an integer.
+fn limit_value(expr: &Expr, clause: &str, db: &Database) -> Result<i64> {
+    check_columns(expr, &[])
+        .map_err(|e| Error::NotFound(format!("{} can't refer to columns: {:#}", clause, e)))?;
+    if contains_subquery(expr) {
+        bail!(Error::Parse(format!(
+            "Subqueries in {} are not supported",
+            clause
+        )));
+    }
+    let functions = db.functions();
+    let parameters = db.parameters();
+    let row = Row {
+        columns: &[],
+        values: &[],
+        functions: &functions,
+        parameters: &parameters,
+    };
+    match apply_numeric_affinity(evaluate(expr, &row)?) {
+        Value::Int(i) => Ok(i),
+        Value::Float(f) if f.fract() == 0.0 && f.abs() < 9.2e18 => Ok(f as i64),
+        _ => bail!(Error::Parse(format!("{} must be an integer", clause))),
+    }
+}
+
+fn contains_subquery(expr: &Expr) -> bool {
+    match expr {
+        Expr::Column(_) | Expr::Literal(_) | Expr::OuterColumn { .. } | Expr::Parameter(_) => false,
+        Expr::Compare { left, right, .. }
+        | Expr::Arithmetic { left, right, .. }
+        | Expr::And(left, right)
+        | Expr::Or(left, right) => contains_subquery(left) || contains_subquery(right),
+        Expr::Like { left, pattern, .. } => contains_subquery(left) || contains_subquery(pattern),
+        Expr::In { left, list } => contains_subquery(left) || list.iter().any(contains_subquery),
+        Expr::Not(inner) | Expr::IsNull(inner) | Expr::Collate { operand: inner, .. } => {
+            contains_subquery(inner)
+        }
+        Expr::Exists(_) | Expr::InSubquery { .. } => true,
+        Expr::Function { args, .. } => args.iter().any(contains_subquery),
+        Expr::Window {
+            args,
+            partition_by,
+            order_by,
+            ..
+        } => args
+            .iter()
+            .chain(partition_by)
+            .chain(order_by.iter().map(|term| &term.expr))
+            .any(contains_subquery),
+    }
+}
+
+fn count_subqueries(expr: &Expr) -> usize {
+    match expr {
+        Expr::Column(_) | Expr::Literal(_) | Expr::OuterColumn { .. } | Expr::Parameter(_) => 0,
+        Expr::Compare { left, right, .. }
+        | Expr::Arithmetic { left, right, .. }
+        | Expr::And(left, right)
+        | Expr::Or(left, right) => count_subqueries(left) + count_subqueries(right),
+        Expr::Like { left, pattern, .. } => count_subqueries(left) + count_subqueries(pattern),
+        Expr::In { left, list } => {
+            count_subqueries(left) + list.iter().map(count_subqueries).sum::<usize>()
+        }
+        Expr::Not(inner) | Expr::IsNull(inner) | Expr::Collate { operand: inner, .. } => {
+            count_subqueries(inner)
+        }
+        Expr::Exists(_) => 1,
+        Expr::InSubquery { left, .. } => count_subqueries(left) + 1,
+        Expr::Function { args, .. } => args.iter().map(count_subqueries).sum(),
+        Expr::Window {
+            args,
+            partition_by,
+            order_by,
+            ..
+        } => args
+            .iter()
+            .chain(partition_by)
+            .chain(order_by.iter().map(|term| &term.expr))
+            .map(count_subqueries)
+            .sum(),
+    }
+}
+
+/// Replaces every EXISTS in `condition` with whether its subquery returns a
+/// row for the outer `row`, and every `IN (SELECT ...)` with an IN of the
+/// values it returns. Subqueries are numbered from `next_subquery` in
+/// the order they appear, to tell their cached results apart.
+fn run_subqueries(
+    db: &mut Database,
+    condition: &Expr,
+    row: &Row,
+    next_subquery: &mut usize,
+    results: &mut SubqueryResults,
+) -> Result<Expr> {
+    let mut run = |expr: &Expr| run_subqueries(db, expr, row, next_subquery, results).map(Box::new);
+    Ok(match condition {
+        // A window can't have subqueries.
+        Expr::Column(_)
+        | Expr::Literal(_)
+        | Expr::OuterColumn { .. }
+        | Expr::Parameter(_)
+        | Expr::Window { .. } => condition.clone(),
+        Expr::Compare { left, op, right } => Expr::Compare {
+            left: run(left)?,
+            op: *op,
+            right: run(right)?,
+        },
+        Expr::Arithmetic { left, op, right } => Expr::Arithmetic {
+            left: run(left)?,
+            op: *op,
+            right: run(right)?,
+        },
+        Expr::Like {
+            left,
+            pattern,
+            escape,
+        } => Expr::Like {
+            left: run(left)?,
+            pattern: run(pattern)?,
+            escape: *escape,
+        },
+        Expr::In { left, list } => Expr::In {
+            left: run(left)?,
+            list: list
+                .iter()
+                .map(|item| run(item).map(|item| *item))
+                .collect::<Result<_>>()?,
+        },
+        Expr::And(left, right) => Expr::And(run(left)?, run(right)?),
+        Expr::Or(left, right) => Expr::Or(run(left)?, run(right)?),
+        Expr::Not(inner) => Expr::Not(run(inner)?),
+        Expr::IsNull(operand) => Expr::IsNull(run(operand)?),
+        Expr::Collate { operand, collation } => Expr::Collate {
+            operand: run(operand)?,
+            collation: collation.clone(),
+        },
+        Expr::Function { name, args } => Expr::Function {
+            name: name.clone(),
+            args: args
+                .iter()
+                .map(|arg| run(arg).map(|arg| *arg))
+                .collect::<Result<_>>()?,
+        },
+        Expr::Exists(query) => {
+            *next_subquery += 1;
+            Expr::Literal(run_subquery(
+                db,
+                query,
+                row,
+                *next_subquery - 1,
+                SubqueryUse::Exists,
+                results,
+            )?)
+        }
+        Expr::InSubquery { left, query } => {
+            let left = run(left)?;
+            *next_subquery += 1;
+            let values = run_subquery_values(
+                db,
+                query,
+                row,
+                *next_subquery - 1,
+                SubqueryUse::List,
+                results,
+            )?;
+            Expr::In {
+                left,
+                list: values.into_iter().map(Expr::Literal).collect(),
+            }
+        }
+    })
+}
+
+/// Runs subquery number `subquery` for the outer `row`, for its one value.
+fn run_subquery(
+    db: &mut Database,
+    query: &QueryType,
+    row: &Row,
+    subquery: usize,
+    usage: SubqueryUse,
+    results: &mut SubqueryResults,
+) -> Result<Value> {
+    let values = run_subquery_values(db, query, row, subquery, usage, results)?;
+    Ok(values.into_iter().next().unwrap_or(Value::Null))
+}
+
+/// Runs subquery number `subquery` for the outer `row`, or takes its result
+/// from `results` if it already ran with the same outer values. Only a
+/// `List` has other than one value.
+fn run_subquery_values(
+    db: &mut Database,
+    query: &QueryType,
+    row: &Row,
+    subquery: usize,
+    usage: SubqueryUse,
+    results: &mut SubqueryResults,
+) -> Result<Vec<Value>> {
+    let mut query = query.clone();
+    let mut outer_values = Vec::new();
+    if let Some((_, _, where_clause)) = select_parts_mut(&mut query) {
+        *where_clause = where_clause
+            .as_ref()
+            .map(|condition| bind_outer_columns(condition, row, &mut outer_values))
+            .transpose()?;
+    }
+    if let Some(args) = table_args_mut(&mut query) {
+        for arg in args.iter_mut() {
+            *arg = bind_outer_columns(arg, row, &mut outer_values)?;
+        }
+    }
+    if let Some(exprs) = table_free_exprs_mut(&mut query) {
+        for expr in exprs {
+            *expr = bind_outer_columns(expr, row, &mut outer_values)?;
+        }
+    }
+    let key = (subquery, encode_record(&outer_values));
+    if let Some(value) = results.get(&key) {
+        return Ok(value.clone());
+    }
+
+    let values = match usage {
+        SubqueryUse::Exists => vec![Value::Int(first_row(db, query)?.is_some() as i64)],
+        SubqueryUse::Scalar => vec![first_row(db, query)?
+            .and_then(|row| row.into_iter().next())
+            .unwrap_or(Value::Null)],
+        SubqueryUse::List => first_column(db, query)?,
+    };
+    if results.len() < MAX_CACHED_SUBQUERY_RESULTS {
+        results.insert(key, values.clone());
+    }
+    Ok(values)
+}
+
+/// Replaces the outer column references that `resolve_columns` left
+/// qualified with their values in `row`, collecting those values. Nested
+/// subqueries can't refer to `row`, so they are left alone.
+fn bind_outer_columns(expr: &Expr, row: &Row, values: &mut Vec<Value>) -> Result<Expr> {
+    let mut bind = |expr: &Expr| bind_outer_columns(expr, row, values).map(Box::new);
+    Ok(match expr {
+        Expr::Column(name) => match name.split_once('.') {
+            Some((_, column)) => {
+                let index = row.position(column)?;
+                let value = row.values.get(index).cloned().unwrap_or(Value::Null);
+                values.push(value.clone());
+                Expr::OuterColumn {
+                    value,
+                    affinity: row.columns[index].affinity,
+                    collation: row.columns[index].collation.clone(),
+                }
+            }
+            None => expr.clone(),
+        },
+        // A window here is an error when evaluated anyway.
+        Expr::Literal(_)
+        | Expr::OuterColumn { .. }
+        | Expr::Parameter(_)
+        | Expr::Exists(_)
+        | Expr::Window { .. } => expr.clone(),
+        Expr::InSubquery { left, query } => Expr::InSubquery {
+            left: bind(left)?,
+            query: query.clone(),
+        },
+        Expr::Compare { left, op, right } => Expr::Compare {
+            left: bind(left)?,
+            op: *op,
+            right: bind(right)?,
+        },
+        Expr::Arithmetic { left, op, right } => Expr::Arithmetic {
+            left: bind(left)?,
+            op: *op,
+            right: bind(right)?,
+        },
+        Expr::Like {
+            left,
+            pattern,
+            escape,
+        } => Expr::Like {
+            left: bind(left)?,
+            pattern: bind(pattern)?,
+            escape: *escape,
+        },
+        Expr::In { left, list } => Expr::In {
+            left: bind(left)?,
+            list: list
+                .iter()
+                .map(|item| bind(item).map(|item| *item))
+                .collect::<Result<_>>()?,
+        },
+        Expr::And(left, right) => Expr::And(bind(left)?, bind(right)?),
+        Expr::Or(left, right) => Expr::Or(bind(left)?, bind(right)?),
+        Expr::Not(inner) => Expr::Not(bind(inner)?),
+        Expr::IsNull(operand) => Expr::IsNull(bind(operand)?),
+        Expr::Collate { operand, collation } => Expr::Collate {
+            operand: bind(operand)?,
+            collation: collation.clone(),
+        },
+        Expr::Function { name, args } => Expr::Function {
+            name: name.clone(),
+            args: args
+                .iter()
+                .map(|arg| bind(arg).map(|arg| *arg))
+                .collect::<Result<_>>()?,
+        },
+    })
+}
+
+/// What each column reads as in a row written before `ALTER TABLE ADD
+/// COLUMN` added it, which is stored short: its `DEFAULT` with the column's
+/// affinity applied, or NULL. Only an added column can be missing, and
+/// SQLite requires its default to be a constant; the others, which may be
+/// `CURRENT_TIME` or refer to columns, are never read from here and are
+/// left NULL when they don't work out without a row.
+#[derive(Debug, Default)]
+pub(crate) struct ColumnDefaults(Vec<Value>);
+
+impl ColumnDefaults {
+    pub(crate) fn new(columns: &[ColumnDef]) -> Self {
+        let functions = UserFunctions::default();
+        let row = Row {
+            columns: &[],
+            values: &[],
+            functions: &functions,
+            parameters: &[],
+        };
+        ColumnDefaults(
+            columns
+                .iter()
+                .map(|column| {
+                    let Some(value) = column
+                        .default
+                        .as_deref()
+                        .and_then(|default| parse_where_expression(default).ok())
+                        .and_then(|default| evaluate(&default, &row).ok())
+                    else {
+                        return Value::Null;
+                    };
+                    match column.affinity {
+                        Affinity::Text => apply_text_affinity(value),
+                        affinity if affinity.is_numeric() => apply_numeric_affinity(value),
+                        _ => value,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Fills in the columns missing from the end of `record`.
+    pub(crate) fn fill(&self, record: &mut Vec<Value>) {
+        if record.len() < self.0.len() {
+            record.extend_from_slice(&self.0[record.len()..]);
+        }
+    }
+
+    /// Column `index` of `record`.
+    pub(crate) fn column<'a>(&'a self, record: &'a [Value], index: usize) -> &'a Value {
+        record.get(index).unwrap_or(&self.0[index])
+    }
+}
+
+/// `expr` with its parameters replaced by the values bound to them, `?1`
+/// by `values[0]`. Subqueries are left alone.
+fn bind_parameters(expr: &Expr, values: &[Value]) -> Expr {
+    let bind = |expr: &Expr| Box::new(bind_parameters(expr, values));
+    match expr {
+        Expr::Parameter(number) => {
+            Expr::Literal(values.get(number - 1).cloned().unwrap_or(Value::Null))
+        }
+        Expr::Column(_)
+        | Expr::Literal(_)
+        | Expr::OuterColumn { .. }
+        | Expr::Exists(_)
+        | Expr::Window { .. } => expr.clone(),
+        Expr::InSubquery { left, query } => Expr::InSubquery {
+            left: bind(left),
+            query: query.clone(),
+        },
+        Expr::Compare { left, op, right } => Expr::Compare {
+            left: bind(left),
+            op: *op,
+            right: bind(right),
+        },
+        Expr::Arithmetic { left, op, right } => Expr::Arithmetic {
+            left: bind(left),
+            op: *op,
+            right: bind(right),
+        },
+        Expr::Like {
+            left,
+            pattern,
+            escape,
+        } => Expr::Like {
+            left: bind(left),
+            pattern: bind(pattern),
+            escape: *escape,
+        },
+        Expr::In { left, list } => Expr::In {
+            left: bind(left),
+            list: list.iter().map(|item| *bind(item)).collect(),
+        },
+        Expr::And(left, right) => Expr::And(bind(left), bind(right)),
+        Expr::Or(left, right) => Expr::Or(bind(left), bind(right)),
+        Expr::Not(inner) => Expr::Not(bind(inner)),
+        Expr::IsNull(operand) => Expr::IsNull(bind(operand)),
+        Expr::Collate { operand, collation } => Expr::Collate {
+            operand: bind(operand),
+            collation: collation.clone(),
+        },
+        Expr::Function { name, args } => Expr::Function {
+            name: name.clone(),
+            args: args.iter().map(|arg| *bind(arg)).collect(),
+        },
+    }
+}
+
+/// Runs `query` for the first column of all its rows, charged to the
+/// memory budget as they are kept.
+fn first_column(db: &mut Database, query: QueryType) -> Result<Vec<Value>> {
+    let prepared = prepare_parsed(db, query, "subquery")?;
+    let mut budget = db.memory_budget();
+    let mut values = Vec::new();
+    let mut keep = |row: Vec<Value>| -> Result<()> {
+        let value = row.into_iter().next().unwrap_or(Value::Null);
+        budget.charge(row_size(std::slice::from_ref(&value)))?;
+        values.push(value);
+        Ok(())
+    };
+    // Through `dyn`, like `first_row` below.
+    let keep: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut keep;
+    prepared.run(db, keep)?;
+    Ok(values)
+}
+
+/// Runs `query` until its first row.
+fn first_row(db: &mut Database, query: QueryType) -> Result<Option<Vec<Value>>> {
+    let prepared = prepare_parsed(db, query, "subquery")?;
+    // Through `dyn`, like `CREATE TEMP TABLE ... AS SELECT`.
+    let mut stop = |row: Vec<Value>| -> Result<()> { Err(FoundRow(row).into()) };
+    let stop: &mut dyn FnMut(Vec<Value>) -> Result<()> = &mut stop;
+    match prepared.run(db, stop) {
+        Ok(()) => Ok(None),
+        Err(e) => match e.downcast::<FoundRow>() {
+            Ok(FoundRow(row)) => Ok(Some(row)),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+/// Picks index scans that together find every row `condition` can match:
+/// one for a term the rest of the clause is ANDed with, or one per side of
+/// an OR whose sides can each use an index. `None` means scanning the table,
+/// whose B-tree has the root page `table_root`.
+fn plan_index_scans(
+    condition: &Expr,
+    schema_entries: &[SchemaEntry],
+    table_name: &str,
+    table_def: &TableDef,
+    table_root: u32,
+) -> Option<Vec<(u32, IndexScan)>> {
+    let plan =
+        |condition| plan_index_scans(condition, schema_entries, table_name, table_def, table_root);
+    if let Expr::Or(left, right) = condition {
+        let mut scans = plan(left)?;
+        scans.extend(plan(right)?);
+        return Some(scans);
+    }
+    plan_index_scan(condition, schema_entries, table_name, table_def, table_root)
+        .or_else(|| or_terms(condition).into_iter().find_map(plan))
+}
+
+/// Picks index scans for one `column = 'text'`, `column IN ('text', ...)` or
+/// `column LIKE 'prefix%'` term that every matching row must satisfy, or for
+/// the `<`, `<=`, `>` and `>=` comparisons of a column with constants. An IN list
+/// probes the index once per distinct value; numbers in it are probed as
+/// text when the column has TEXT affinity. The rowid, the first column, is
+/// sought in the table itself, whose root page is `table_root`, before any
+/// index is considered.
+fn plan_index_scan(
+    condition: &Expr,
+    schema_entries: &[SchemaEntry],
+    table_name: &str,
+    table_def: &TableDef,
+    table_root: u32,
+) -> Option<Vec<(u32, IndexScan)>> {
+    let column_def = |column: &str| table_def.columns.iter().find(|c| c.is_named(column));
+    // The index compares keys as BINARY, so `=` has to as well.
+    let binary_column = |column: &str| {
+        column_def(column).is_some_and(|c| {
+            c.collation
+                .as_deref()
+                .map_or(true, |name| name.eq_ignore_ascii_case("binary"))
+        })
+    };
+    // The key a constant is found under: `=` converts it by the column's
+    // affinity, so `'7'` finds `7` in an INTEGER column and `7` finds `'7'`
+    // in a TEXT one.
+    let literal_key = |column: &str, value: &Value| match column_def(column).map(|c| c.affinity) {
+        Some(Affinity::Text) => apply_text_affinity(value.clone()),
+        Some(affinity) if affinity.is_numeric() => apply_numeric_affinity(value.clone()),
+        _ => value.clone(),
+    };
+    // An outer query's column keeps its own affinity, so only a numeric
+    // column converts it, and it has no key where it would convert the
+    // column's values instead or impose a collation other than BINARY.
+    let index_key = |column: &str, key: &Expr| match key {
+        Expr::Literal(value) => Some(literal_key(column, value)),
+        Expr::OuterColumn {
+            value,
+            affinity,
+            collation,
+        } => {
+            let column_is_numeric = column_def(column).is_some_and(|c| c.affinity.is_numeric());
+            let binary = collation
+                .as_deref()
+                .map_or(true, |name| name.eq_ignore_ascii_case("binary"));
+            if !binary || (affinity.is_numeric() && !column_is_numeric) {
+                None
+            } else if column_is_numeric && !affinity.is_numeric() {
+                Some(apply_numeric_affinity(value.clone()))
+            } else {
+                Some(value.clone())
+            }
+        }
+        _ => None,
+    };
+    let is_rowid = |column: &str| table_def.columns[0].is_named(column);
+    let rowid_keys = equality_terms(condition)
+        .into_iter()
+        .filter(|(column, _)| is_rowid(column))
+        .filter_map(|(column, key)| Some(vec![index_key(column, key)?]))
+        .chain(
+            in_list_terms(condition)
+                .into_iter()
+                .filter(|(column, _)| is_rowid(column))
+                .map(|(column, keys)| {
+                    keys.into_iter()
+                        .map(|key| literal_key(column, key))
+                        .collect()
+                }),
+        )
+        .next();
+    if let Some(keys) = rowid_keys {
+        let mut rowids = keys.iter().filter_map(rowid_of).collect::<Vec<_>>();
+        rowids.sort_unstable();
+        rowids.dedup();
+        return Some(vec![(table_root, IndexScan::Rowids(rowids))]);
+    }
+    let range_terms = range_terms(condition)
+        .into_iter()
+        .filter_map(|(column, op, key)| {
+            let key = index_key(column, key)?;
+            (!matches!(key, Value::Null)).then_some((column, op, key))
+        })
+        .collect::<Vec<_>>();
+    if let Some((column, ..)) = range_terms.iter().find(|(column, ..)| is_rowid(column)) {
+        let (lower, upper) = bounds(&range_terms, column);
+        return Some(vec![(table_root, IndexScan::RowidRange { lower, upper })]);
+    }
+
+    // The keys `column = constant` and `column IN (...)` allow, sorted. The
+    // items of an IN list have no affinity of their own, like literals. NULL
+    // equals nothing, so it has no key.
+    let column_keys = equality_terms(condition)
+        .into_iter()
+        .filter_map(|(column, key)| Some((column, vec![index_key(column, key)?])))
+        .chain(in_list_terms(condition).into_iter().map(|(column, keys)| {
+            let keys = keys.into_iter().map(|key| literal_key(column, key));
+            (column, keys.collect())
+        }))
+        .filter(|(column, keys)| !keys.is_empty() && binary_column(column))
+        .map(|(column, mut keys)| {
+            keys.retain(|key| !matches!(key, Value::Null));
+            keys.sort_by(total_order);
+            keys.dedup_by(|a, b| total_order(a, b).is_eq());
+            (column, keys)
+        })
+        .collect::<Vec<_>>();
+    let term_position = |column: &str| {
+        column_keys
+            .iter()
+            .position(|(other, _)| other.eq_ignore_ascii_case(column))
+    };
+    // The index with the most leading keys pinned down, `(a, b)` for
+    // `a = 1 AND b IN (2, 3)`, gets probed once for each combination of
+    // them; of equally good ones, the first by where its first key comes up
+    // in the WHERE clause.
+    schema_entries
+        .iter()
+        .filter(|e| e.typ == "index" && e.tbl_name == table_name)
+        .filter_map(|index_entry| {
+            let columns = index_columns(index_entry, table_def)?;
+            let prefix = columns
+                .iter()
+                .enumerate()
+                .map_while(|(position, column)| {
+                    let collation = index_key_collation(index_entry, table_def, position, column);
+                    let keys = &column_keys[term_position(column)?].1;
+                    collation.eq_ignore_ascii_case("BINARY").then_some(keys)
+                })
+                .collect::<Vec<_>>();
+            let first = term_position(columns.first()?)?;
+            (!prefix.is_empty()).then_some((index_entry, prefix, first))
+        })
+        .min_by_key(|(_, prefix, first)| (std::cmp::Reverse(prefix.len()), *first))
+        .map(|(index_entry, prefix, _)| {
+            let descending = index_descending(index_entry);
+            key_tuples(&prefix)
+                .into_iter()
+                .map(|key| {
+                    let descending = descending.clone();
+                    (index_entry.rootpage, IndexScan::Equals { key, descending })
+                })
+                .collect()
+        })
+        .or_else(|| {
+            like_prefix_terms(condition)
+                .into_iter()
+                .filter(|(column, prefix)| {
+                    !prefix.is_empty()
+                        && column_def(column).is_some_and(|c| c.affinity == Affinity::Text)
+                })
+                .find_map(|(column, prefix)| {
+                    like_prefix_scan(schema_entries, table_name, table_def, column, prefix)
+                })
+                .map(|scan| vec![scan])
+        })
+        .or_else(|| {
+            range_terms
+                .iter()
+                .map(|(column, ..)| *column)
+                .filter(|column| binary_column(column))
+                .find_map(|column| {
+                    let index_entry = find_leading_column_index(
+                        schema_entries,
+                        table_name,
+                        table_def,
+                        column,
+                        "BINARY",
+                    )?;
+                    let (lower, upper) = bounds(&range_terms, column);
+                    Some(vec![(
+                        index_entry.rootpage,
+                        IndexScan::Range { lower, upper },
+                    )])
+                })
+        })
+}
+
+/// One side of a range of keys: a key, and whether it's in the range.
+type Bound = Option<(Value, bool)>;
+
+/// The tightest bounds the range `terms` on `column` put below and above
+/// it, as `IndexScan::Range` takes them.
+fn bounds(terms: &[(&str, CompareOp, Value)], column: &str) -> (Bound, Bound) {
+    let (mut lower, mut upper) = (None, None);
+    for (_, op, key) in terms
+        .iter()
+        .filter(|(other, ..)| other.eq_ignore_ascii_case(column))
+    {
+        let bound = (key.clone(), matches!(op, CompareOp::GtEq | CompareOp::LtEq));
+        // Of two bounds on the same side, the tighter one.
+        if matches!(op, CompareOp::Gt | CompareOp::GtEq) {
+            lower = Some(match lower.take() {
+                Some(other) => tighter_bound(other, bound, std::cmp::Ordering::Greater),
+                None => bound,
+            });
+        } else {
+            upper = Some(match upper.take() {
+                Some(other) => tighter_bound(other, bound, std::cmp::Ordering::Less),
+                None => bound,
+            });
+        }
+    }
+    (lower, upper)
+}
+
+/// Every combination of one key for each column, in order.
+fn key_tuples(columns: &[&Vec<Value>]) -> Vec<Vec<Value>> {
+    columns.iter().fold(vec![Vec::new()], |tuples, keys| {
+        tuples
+            .iter()
+            .flat_map(|tuple| {
+                keys.iter().map(move |key| {
+                    let mut tuple = tuple.clone();
+                    tuple.push(key.clone());
+                    tuple
+                })
+            })
+            .collect()
+    })
+}
+
+/// The `column op constant` terms `condition` ANDs together, for a virtual
+/// table or table-valued function to narrow its scan with.
+fn virtual_constraints(condition: &Expr, table_def: &TableDef) -> Vec<Constraint> {
+    match condition {
+        Expr::And(left, right) => {
+            let mut constraints = virtual_constraints(left, table_def);
+            constraints.extend(virtual_constraints(right, table_def));
+            constraints
+        }
+        Expr::Compare { left, op, right } => {
+            let (column, op, value) = match (left.as_ref(), right.as_ref()) {
+                (Expr::Column(column), Expr::Literal(value)) => (column, *op, value),
+                (Expr::Literal(value), Expr::Column(column)) => {
+                    let flipped = match op {
+                        CompareOp::Lt => CompareOp::Gt,
+                        CompareOp::LtEq => CompareOp::GtEq,
+                        CompareOp::Gt => CompareOp::Lt,
+                        CompareOp::GtEq => CompareOp::LtEq,
+                        op => *op,
+                    };
+                    (column, flipped, value)
+                }
+                _ => return Vec::new(),
+            };
+            table_def
+                .columns
+                .iter()
+                .position(|c| c.is_named(column))
+                .map(|column| Constraint {
+                    column,
+                    op,
+                    value: value.clone(),
+                })
+                .into_iter()
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Collects the OR expressions the rest of the WHERE clause is ANDed with.
+fn or_terms(condition: &Expr) -> Vec<&Expr> {
+    match condition {
+        Expr::And(left, right) => {
+            let mut terms = or_terms(left);
+            terms.extend(or_terms(right));
+            terms
+        }
+        Expr::Or(..) => vec![condition],
+        _ => Vec::new(),
+    }
+}
+
+/// Runs one index scan, returning the rowids it finds.
+fn scan_index(db: &mut Database, index_root_page: u32, scan: &IndexScan) -> Result<Vec<i64>> {
+    match scan {
+        IndexScan::Equals { key, descending } => {
+            db.collect_index_rowids(index_root_page, key, descending)
+        }
+        IndexScan::Prefix { prefix, nocase } => {
+            // LIKE also matches blobs by their bytes, and those sort after
+            // every text key.
+            let mut rowids = db.collect_index_rowids_in_range(index_root_page, |keys| {
+                text_prefix_position(&keys[0], prefix, *nocase)
+            })?;
+            rowids.extend(db.collect_index_rowids_in_range(index_root_page, |keys| {
+                blob_prefix_position(&keys[0], prefix, *nocase)
+            })?);
+            Ok(rowids)
+        }
+        IndexScan::Range { lower, upper } => db
+            .collect_index_rowids_in_range(index_root_page, |keys| {
+                range_position(&keys[0], lower.as_ref(), upper.as_ref())
+            }),
+        IndexScan::FullText(query) => matching_rowids(db, index_root_page, query),
+        IndexScan::Rowids(rowids) => Ok(rowids.clone()),
+        IndexScan::RowidRange { lower, upper } => {
+            let mut rowids = Vec::new();
+            if let Some(range) = rowid_range(lower.as_ref(), upper.as_ref()) {
+                db.for_each_table_record_in_rowid_range_with_db(
+                    index_root_page,
+                    range,
+                    |_, record| {
+                        if let Some(Value::Int(rowid)) = record.first() {
+                            rowids.push(*rowid);
+                        }
+                        Ok(())
+                    },
+                )?;
+            }
+            Ok(rowids)
+        }
+    }
+}
+
+/// The rowid `key` stands for, if it equals one: an integer, or a real with
+/// an integer value.
+fn rowid_of(key: &Value) -> Option<i64> {
+    match key {
+        Value::Int(rowid) => Some(*rowid),
+        Value::Float(f) if f.fract() == 0.0 && *f >= i64::MIN as f64 && *f < i64::MAX as f64 => {
+            Some(*f as i64)
+        }
+        _ => None,
+    }
+}
+
+/// The rowids between `lower` and `upper`, bounds as `IndexScan::Range`
+/// takes them, or `None` if there are none. Text and blobs sort after every
+/// number, so they bound nothing from above and everything from below.
+fn rowid_range(
+    lower: Option<&(Value, bool)>,
+    upper: Option<&(Value, bool)>,
+) -> Option<RangeInclusive<i64>> {
+    // Past the last integer as a real, and the first.
+    let (past, first) = (i64::MAX as f64, i64::MIN as f64);
+    let start = match lower {
+        None => i64::MIN,
+        Some((Value::Int(key), true)) => *key,
+        Some((Value::Int(key), false)) => key.checked_add(1)?,
+        Some((Value::Float(key), _)) if *key >= past => return None,
+        Some((Value::Float(key), _)) if *key < first => i64::MIN,
+        Some((Value::Float(key), inclusive)) => {
+            let start = key.ceil() as i64;
+            if !inclusive && key.ceil() == *key {
+                start.checked_add(1)?
+            } else {
+                start
+            }
+        }
+        Some(_) => return None,
+    };
+    let end = match upper {
+        None => i64::MAX,
+        Some((Value::Int(key), true)) => *key,
+        Some((Value::Int(key), false)) => key.checked_sub(1)?,
+        Some((Value::Float(key), _)) if *key >= past => i64::MAX,
+        Some((Value::Float(key), _)) if *key < first => return None,
+        Some((Value::Float(key), inclusive)) => {
+            let end = key.floor() as i64;
+            if !inclusive && key.floor() == *key {
+                end.checked_sub(1)?
+            } else {
+                end
+            }
+        }
+        Some((Value::Null, _)) => return None,
+        Some(_) => i64::MAX,
+    };
+    (start <= end).then_some(start..=end)
+}
+
+/// Of two bounds `(key, inclusive)` on the same side of a range, the one
+/// that lets fewer keys through: the key further toward `inward` (`Greater`
+/// for lower bounds), or the exclusive one if the keys are equal.
+fn tighter_bound(a: (Value, bool), b: (Value, bool), inward: std::cmp::Ordering) -> (Value, bool) {
+    match total_order(&a.0, &b.0) {
+        std::cmp::Ordering::Equal => (a.0, a.1 && b.1),
+        ordering if ordering == inward => a,
+        _ => b,
+    }
+}
+
+/// Places an index key relative to the keys between `lower` and `upper`, as
+/// `IndexScan::Range` takes them. NULLs never compare true, and sort first.
+fn range_position(
+    key: &Value,
+    lower: Option<&(Value, bool)>,
+    upper: Option<&(Value, bool)>,
+) -> std::cmp::Ordering {
+    if matches!(key, Value::Null) {
+        return std::cmp::Ordering::Less;
+    }
+    if let Some((bound, inclusive)) = lower {
+        match total_order(key, bound) {
+            std::cmp::Ordering::Less => return std::cmp::Ordering::Less,
+            std::cmp::Ordering::Equal if !inclusive => return std::cmp::Ordering::Less,
+            _ => {}
+        }
+    }
+    if let Some((bound, inclusive)) = upper {
+        match total_order(key, bound) {
+            std::cmp::Ordering::Greater => return std::cmp::Ordering::Greater,
+            std::cmp::Ordering::Equal if !inclusive => return std::cmp::Ordering::Greater,
+            _ => {}
+        }
+    }
+    std::cmp::Ordering::Equal
+}
+
+/// Collects the `column < constant` (and `<=`, `>`, `>=`) terms joined to
+/// the rest of the WHERE clause by AND only, turned around to put the column
+/// on the left.
+fn range_terms(condition: &Expr) -> Vec<(&str, CompareOp, &Expr)> {
+    match condition {
+        Expr::And(left, right) => {
+            let mut terms = range_terms(left);
+            terms.extend(range_terms(right));
+            terms
+        }
+        Expr::Compare { left, op, right } => {
+            let flipped = match op {
+                CompareOp::Lt => CompareOp::Gt,
+                CompareOp::LtEq => CompareOp::GtEq,
+                CompareOp::Gt => CompareOp::Lt,
+                CompareOp::GtEq => CompareOp::LtEq,
+                CompareOp::Eq | CompareOp::NotEq => return Vec::new(),
+            };
+            match (left.as_ref(), right.as_ref()) {
+                (Expr::Column(column), key) if is_constant(key) => vec![(column, *op, key)],
+                (key, Expr::Column(column)) if is_constant(key) => vec![(column, flipped, key)],
+                _ => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Whether `expr` has the same value for every row: a literal, or a column
+/// of an outer query bound to its value.
+fn is_constant(expr: &Expr) -> bool {
+    matches!(expr, Expr::Literal(_) | Expr::OuterColumn { .. })
+}
+
+/// Collects the `column = constant` terms that every matching row must
+/// satisfy, i.e. those joined to the rest of the WHERE clause by AND only.
+fn equality_terms(condition: &Expr) -> Vec<(&str, &Expr)> {
+    match condition {
+        Expr::And(left, right) => {
+            let mut terms = equality_terms(left);
+            terms.extend(equality_terms(right));
+            terms
+        }
+        Expr::Compare {
+            left,
+            op: CompareOp::Eq,
+            right,
+        } => match (left.as_ref(), right.as_ref()) {
+            (Expr::Column(column), key) | (key, Expr::Column(column)) if is_constant(key) => {
+                vec![(column, key)]
+            }
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Collects the `column IN (constant, ...)` terms joined to the rest of the
+/// WHERE clause by AND only, if every value in the list is a constant.
+fn in_list_terms(condition: &Expr) -> Vec<(&str, Vec<&Value>)> {
+    match condition {
+        Expr::And(left, right) => {
+            let mut terms = in_list_terms(left);
+            terms.extend(in_list_terms(right));
+            terms
+        }
+        Expr::In { left, list } => match left.as_ref() {
+            Expr::Column(column) => list
+                .iter()
+                .map(|item| match item {
+                    Expr::Literal(key) | Expr::OuterColumn { value: key, .. } => Some(key),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|keys| vec![(column.as_str(), keys)])
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Collects the `column LIKE 'text'` terms joined to the rest of the WHERE
+/// clause by AND only, each with the literal text its pattern starts with.
+fn like_prefix_terms(condition: &Expr) -> Vec<(&str, String)> {
+    match condition {
+        Expr::And(left, right) => {
+            let mut terms = like_prefix_terms(left);
+            terms.extend(like_prefix_terms(right));
+            terms
+        }
+        Expr::Like {
+            left,
+            pattern,
+            escape,
+        } => match (left.as_ref(), pattern.as_ref()) {
+            (Expr::Column(column), Expr::Literal(Value::Text(pattern))) => {
+                vec![(column, like_prefix(pattern, *escape))]
+            }
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// The literal characters a LIKE pattern starts with, up to its first
+/// wildcard.
+fn like_prefix(pattern: &str, escape: Option<char>) -> String {
+    let mut prefix = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if Some(c) == escape => match chars.next() {
+                Some(escaped) => prefix.push(escaped),
+                None => break,
+            },
+            '%' | '_' => break,
+            c => prefix.push(c),
+        }
+    }
+    prefix
+}
+
+/// Picks an index to find the keys starting with `prefix` in. LIKE ignores
+/// ASCII case, so those keys are only next to each other in a NOCASE index,
+/// or in a BINARY one when the prefix has no letters.
+fn like_prefix_scan(
+    schema_entries: &[SchemaEntry],
+    table_name: &str,
+    table_def: &TableDef,
+    column: &str,
+    prefix: String,
+) -> Option<(u32, IndexScan)> {
+    if let Some(index_entry) =
+        find_leading_column_index(schema_entries, table_name, table_def, column, "NOCASE")
+    {
+        return Some((
+            index_entry.rootpage,
+            IndexScan::Prefix {
+                prefix: prefix.to_ascii_lowercase(),
+                nocase: true,
+            },
+        ));
+    }
+    if prefix.chars().any(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    find_leading_column_index(schema_entries, table_name, table_def, column, "BINARY").map(
+        |index_entry| {
+            (
+                index_entry.rootpage,
+                IndexScan::Prefix {
+                    prefix,
+                    nocase: false,
+                },
+            )
+        },
+    )
+}
+
+/// Places an index key relative to the text keys starting with `prefix`.
+/// NULLs and numbers sort before any text key, blobs after.
+fn text_prefix_position(key: &Value, prefix: &str, nocase: bool) -> std::cmp::Ordering {
+    let Value::Text(text) = key else {
+        return match key {
+            Value::Blob(_) => std::cmp::Ordering::Greater,
+            _ => std::cmp::Ordering::Less,
+        };
+    };
+    let folded;
+    let text = if nocase {
+        folded = text.to_ascii_lowercase();
+        folded.as_str()
+    } else {
+        text
+    };
+    if text.starts_with(prefix) {
+        std::cmp::Ordering::Equal
+    } else {
+        text.cmp(prefix)
+    }
+}
+
+/// Places an index key relative to the blob keys that can start with `prefix`.
+/// Collations don't apply to blobs, so with `nocase` the range runs from the
+/// all-uppercase prefix to the all-lowercase one and takes in the other mixes
+/// of case too, plus some blobs the WHERE clause then drops.
+fn blob_prefix_position(key: &Value, prefix: &str, nocase: bool) -> std::cmp::Ordering {
+    let Value::Blob(bytes) = key else {
+        return std::cmp::Ordering::Less;
+    };
+    let (low, high) = if nocase {
+        (prefix.to_ascii_uppercase(), prefix.to_ascii_lowercase())
+    } else {
+        (prefix.to_string(), prefix.to_string())
+    };
+    let head = &bytes[..bytes.len().min(high.len())];
+    if bytes.as_slice() < low.as_bytes() {
+        std::cmp::Ordering::Less
+    } else if head > high.as_bytes() {
+        std::cmp::Ordering::Greater
+    } else {
+        std::cmp::Ordering::Equal
+    }
+}
+
+/// Returns the key columns of an index, deriving them from the table's
+/// constraints for the `sqlite_autoindex_*` indexes that have no SQL.
+pub(crate) fn index_columns(
+    index_entry: &SchemaEntry,
+    table_def: &TableDef,
+) -> Option<Vec<String>> {
+    match &index_entry.sql {
+        Some(sql) => {
+            let index_def = parse_create_index(sql).ok()?;
+            (!index_def.partial).then_some(index_def.columns)
+        }
+        None => {
+            let number = index_entry
+                .name
+                .strip_prefix("sqlite_autoindex_")?
+                .rsplit('_')
+                .next()?
+                .parse()
+                .ok()?;
+            table_def.autoindex_columns(number)
+        }
+    }
+}
+
+/// Finds an index whose first key is `column`, sorted ascending by
+/// `collation`; any keys after it don't change where its values lie.
+fn find_leading_column_index<'a>(
+    schema_entries: &'a [SchemaEntry],
+    table_name: &str,
+    table_def: &TableDef,
+    column: &str,
+    collation: &str,
+) -> Option<&'a SchemaEntry> {
+    schema_entries
+        .iter()
+        .filter(|e| e.typ == "index" && e.tbl_name == table_name)
+        .find(|e| {
+            index_columns(e, table_def).is_some_and(|columns| {
+                columns
+                    .first()
+                    .is_some_and(|c| c.eq_ignore_ascii_case(column))
+            }) && index_key_collation(e, table_def, 0, column).eq_ignore_ascii_case(collation)
+                && !index_descending(e)
+                    .first()
+                    .is_some_and(|descending| *descending)
+        })
+}
+
+/// The collation of an index's key at `position`, which is `column`: the
+/// one named in the index, or else the one its table column was declared
+/// with.
+pub(crate) fn index_key_collation(
+    index_entry: &SchemaEntry,
+    table_def: &TableDef,
+    position: usize,
+    column: &str,
+) -> String {
+    index_entry
+        .sql
+        .as_deref()
+        .and_then(|sql| parse_create_index(sql).ok())
+        .and_then(|index_def| index_def.collations.into_iter().nth(position).flatten())
+        .or_else(|| {
+            table_def
+                .columns
+                .iter()
+                .find(|c| c.is_named(column))
+                .and_then(|c| c.collation.clone())
+        })
+        .unwrap_or_else(|| "BINARY".to_string())
+}
+
+/// Whether each of an index's keys is sorted `DESC`. The indexes SQLite
+/// makes for PRIMARY KEY and UNIQUE constraints are taken as ascending.
+pub(crate) fn index_descending(index_entry: &SchemaEntry) -> Vec<bool> {
+    index_entry
+        .sql
+        .as_deref()
+        .and_then(|sql| parse_create_index(sql).ok())
+        .map(|index_def| index_def.descending)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse_query, QueryType};
+    use crate::record::Value;
+    use crate::testing::{int, one_table, query, real, text, TestDatabase};
+
+    /// The single count `sql` returns.
+    fn count(image: &[u8], sql: &str) -> i64 {
+        match query(image, sql).unwrap().as_slice() {
+            [row] => match row.as_slice() {
+                [Value::Int(count)] => *count,
+                other => panic!("{} returned {:?}", sql, other),
+            },
+            other => panic!("{} returned {:?}", sql, other),
+        }
+    }
+
+    /// `x(a)` holding 1, NULL and 3.
+    fn with_null() -> Vec<u8> {
+        one_table(
+            "CREATE TABLE x (a INTEGER)",
+            [vec![int(1)], vec![Value::Null], vec![int(3)]],
+        )
+    }
+
+    #[test]
+    fn count_is_recognised_however_it_is_written() {
+        let image = with_null();
+        for sql in [
+            "SELECT count(*) FROM x",
+            "SELECT COUNT( * ) FROM x",
+            "SELECT count (\n*\n) FROM x",
+            "SELECT count() FROM x",
+        ] {
+            assert!(
+                matches!(parse_query(sql).unwrap(), QueryType::SelectCount { .. }),
+                "{}",
+                sql
+            );
+            assert_eq!(count(&image, sql), 3);
+        }
+        assert_eq!(
+            count(&image, "SELECT Count( * ) FILTER (WHERE a > 1) FROM x"),
+            1
+        );
+    }
+
+    #[test]
+    fn rowid_terms_seek_the_table() {
+        let image = TestDatabase::new()
+            .page_size(512)
+            .table(
+                "CREATE TABLE t (a, b)",
+                (1..=300).map(|i| vec![int(i), text(&"x".repeat(20))]),
+            )
+            .build()
+            .unwrap();
+        let rowids = |condition: &str| -> Vec<i64> {
+            query(&image, &format!("SELECT rowid FROM t WHERE {}", condition))
+                .unwrap()
+                .into_iter()
+                .map(|row| match row[..] {
+                    [Value::Int(rowid)] => rowid,
+                    _ => panic!("{} returned {:?}", condition, row),
+                })
+                .collect()
+        };
+        let plan = |condition: &str| {
+            let rows = query(
+                &image,
+                &format!("EXPLAIN QUERY PLAN SELECT * FROM t WHERE {}", condition),
+            )
+            .unwrap();
+            rows.last().unwrap()[3].clone()
+        };
+        assert_eq!(rowids("rowid = 150"), [150]);
+        assert_eq!(rowids("oid = '7'"), [7]);
+        assert_eq!(rowids("rowid = 7.5"), Vec::<i64>::new());
+        assert_eq!(rowids("_rowid_ IN (300, 2.0, '1', 301, NULL)"), [1, 2, 300]);
+        assert_eq!(rowids("rowid > 297"), [298, 299, 300]);
+        assert_eq!(rowids("rowid >= 4.5 AND rowid < 7"), [5, 6]);
+        assert_eq!(rowids("rowid BETWEEN 99 AND 101"), [99, 100, 101]);
+        assert_eq!(rowids("rowid < 3 OR rowid = 200"), [1, 2, 200]);
+        assert_eq!(rowids("rowid > 'a'"), Vec::<i64>::new());
+        assert_eq!(rowids("rowid > 9223372036854775807"), Vec::<i64>::new());
+        assert_eq!(
+            plan("rowid = 150"),
+            text("SEARCH t USING INTEGER PRIMARY KEY (rowid=?)")
+        );
+        assert_eq!(
+            plan("rowid BETWEEN 99 AND 101"),
+            text("SEARCH t USING INTEGER PRIMARY KEY (rowid>=? AND rowid<=?)")
+        );
+        assert_eq!(
+            plan("rowid > 297"),
+            text("SEARCH t USING INTEGER PRIMARY KEY (rowid>?)")
+        );
+    }
+
+    #[test]
+    fn estimates_carry_through_every_operator() {
+        let image = TestDatabase::new()
+            .table(
+                "CREATE TABLE t (a, b)",
+                (1..=100).map(|i| vec![int(i), int(i % 10)]),
+            )
+            .build()
+            .unwrap();
+        let estimates = |sql: &str| -> Vec<(Value, Value)> {
+            query(&image, &format!("EXPLAIN {}", sql))
+                .unwrap()
+                .into_iter()
+                .map(|row| (row[2].clone(), row[4].clone()))
+                .collect()
+        };
+        assert_eq!(
+            estimates("SELECT b, count(*) FROM t WHERE a > 10 GROUP BY b LIMIT 2"),
+            [
+                (text("LIMIT"), int(2)),
+                (text("AGGREGATE"), int(3)),
+                (text("FILTER"), int(25)),
+                (text("SCAN"), int(100)),
+            ]
+        );
+        assert_eq!(
+            estimates("SELECT * FROM t WHERE rowid BETWEEN 5 AND 14 AND b = 3"),
+            [(text("FILTER"), int(1)), (text("SEARCH"), int(10))]
+        );
+        assert_eq!(
+            estimates("SELECT * FROM t x, t y WHERE x.a = y.b")[..2],
+            [(text("FILTER"), int(1000)), (text("JOIN"), int(10000))]
+        );
+    }
+
+    #[test]
+    fn integer_primary_key_is_sought_as_the_rowid() {
+        let image = TestDatabase::new()
+            .page_size(512)
+            .table(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT)",
+                (1..=300).map(|i| vec![int(i * 2), text(&format!("name {}", i))]),
+            )
+            .build()
+            .unwrap();
+        let plan = |sql: &str| query(&image, &format!("EXPLAIN QUERY PLAN {}", sql)).unwrap();
+        for sql in [
+            "SELECT * FROM t WHERE id = ?",
+            "SELECT * FROM t WHERE id = 42",
+            "SELECT * FROM t WHERE 42 = t.id AND name LIKE 'n%'",
+            "SELECT * FROM t WHERE id IN (42, 44)",
+        ] {
+            assert_eq!(
+                plan(sql).last().unwrap()[3],
+                text("SEARCH t USING INTEGER PRIMARY KEY (rowid=?)"),
+                "{}",
+                sql
+            );
+        }
+        assert_eq!(
+            plan("SELECT * FROM t WHERE id > 10 AND id < 20")
+                .last()
+                .unwrap()[3],
+            text("SEARCH t USING INTEGER PRIMARY KEY (rowid>? AND rowid<?)")
+        );
+        assert_eq!(
+            query(&image, "SELECT name FROM t WHERE id = 42").unwrap(),
+            vec![vec![text("name 21")]]
+        );
+        assert_eq!(
+            query(&image, "SELECT id FROM t WHERE id BETWEEN 595 AND 1000").unwrap(),
+            vec![vec![int(596)], vec![int(598)], vec![int(600)]]
+        );
+    }
+
+    #[test]
+    fn parameters_name_their_columns_as_written() {
+        let mut conn = crate::Connection::from_bytes(with_null()).unwrap();
+        let sql = "SELECT :a, ?, ?5, @b AS named, :a + 1 FROM x LIMIT 1";
+        assert_eq!(
+            conn.column_names(sql).unwrap(),
+            [":a", "?", "?5", "named", ":a + 1"]
+        );
+        let mut statement = conn.prepare(sql).unwrap();
+        statement.bind_named(":a", int(2)).unwrap();
+        statement.bind(2, text("b")).unwrap();
+        let mut rows = Vec::new();
+        statement
+            .query_each(|row| {
+                rows.push(row);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![vec![int(2), text("b"), Value::Null, Value::Null, int(3)]]
+        );
+    }
+
+    #[test]
+    fn short_rows_read_added_columns_as_their_default() {
+        let image = TestDatabase::new()
+            .table(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, a, b DEFAULT 7, c REAL DEFAULT 2, \
+                 d TEXT DEFAULT -1, e)",
+                [
+                    vec![int(1), int(10)],
+                    vec![int(2), int(20), int(8), real(3.5), text("z"), int(9)],
+                ],
+            )
+            .index("CREATE INDEX t_b ON t (b)")
+            .build()
+            .unwrap();
+        assert_eq!(
+            query(&image, "SELECT * FROM t").unwrap(),
+            vec![
+                vec![int(1), int(10), int(7), real(2.0), text("-1"), Value::Null],
+                vec![int(2), int(20), int(8), real(3.5), text("z"), int(9)],
+            ]
+        );
+        assert_eq!(count(&image, "SELECT count(*) FROM t WHERE e IS NULL"), 1);
+        assert_eq!(count(&image, "SELECT sum(b) FROM t"), 15);
+        // Found through the index, whose entry holds the default too.
+        assert_eq!(
+            query(&image, "SELECT id FROM t WHERE b = 7").unwrap(),
+            vec![vec![int(1)]]
+        );
+    }
+
+    #[test]
+    fn null_compares_as_unknown() {
+        let image = with_null();
+        let matching = |condition: &str| {
+            count(
+                &image,
+                &format!("SELECT count(*) FROM x WHERE {}", condition),
+            )
+        };
+        assert_eq!(matching("a != 1"), 1);
+        assert_eq!(matching("a = NULL"), 0);
+        assert_eq!(matching("a IS NULL"), 1);
+        assert_eq!(matching("NOT (a > 1)"), 1);
+        assert_eq!(matching("a IN (1, NULL)"), 1);
+        assert_eq!(matching("a NOT IN (1, NULL)"), 0);
+        assert_eq!(matching("a NOT IN (1)"), 1);
+    }
+
+    #[test]
+    fn aggregates_skip_nulls() {
+        let image = with_null();
+        assert_eq!(
+            query(
+                &image,
+                "SELECT count(*), count(a), sum(a), avg(a), min(a), max(a) FROM x"
+            )
+            .unwrap(),
+            vec![vec![int(3), int(2), int(4), real(2.0), int(1), int(3)]]
+        );
+        assert_eq!(
+            query(
+                &image,
+                "SELECT count(a), sum(a), avg(a), min(a), max(a) FROM x WHERE a IS NULL"
+            )
+            .unwrap(),
+            vec![vec![
+                int(0),
+                Value::Null,
+                Value::Null,
+                Value::Null,
+                Value::Null
+            ]]
+        );
+        // NULLs group together, ahead of the values.
+        assert_eq!(
+            query(
+                &image,
+                "SELECT a IS NULL, count(*) FROM x GROUP BY a IS NULL"
+            )
+            .unwrap(),
+            vec![vec![int(0), int(2)], vec![int(1), int(1)]]
+        );
+    }
+
+    #[test]
+    fn index_holds_null_keys() {
+        // Every other key is NULL; a small page size gives interior pages.
+        let image = TestDatabase::new()
+            .page_size(512)
+            .table(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, a INTEGER)",
+                (1..=300).map(|i| {
+                    let a = if i % 2 == 0 { Value::Null } else { int(i) };
+                    vec![int(i), a]
+                }),
+            )
+            .index("CREATE INDEX t_a ON t (a)")
+            .build()
+            .unwrap();
+        assert_eq!(count(&image, "SELECT count(*) FROM t WHERE a IS NULL"), 150);
+        assert_eq!(count(&image, "SELECT count(*) FROM t WHERE a = 7"), 1);
+        assert_eq!(count(&image, "SELECT count(*) FROM t WHERE a = NULL"), 0);
+        assert_eq!(count(&image, "SELECT count(*) FROM t WHERE a < 10"), 5);
+        assert_eq!(count(&image, "SELECT count(*) FROM t WHERE a >= 295"), 3);
+        assert_eq!(
+            count(&image, "SELECT count(*) FROM t WHERE a IN (2, 3, NULL)"),
+            1
+        );
+        assert_eq!(
+            query(&image, "SELECT a FROM t ORDER BY a LIMIT 2 OFFSET 149").unwrap(),
+            vec![vec![Value::Null], vec![int(1)]]
+        );
+    }
+
+    #[test]
+    fn outer_columns_compare_with_their_affinity_and_collation() {
+        let image = TestDatabase::new()
+            .table(
+                "CREATE TABLE o (id INTEGER PRIMARY KEY, b, n INTEGER, nc TEXT COLLATE NOCASE)",
+                [
+                    vec![int(1), int(1), int(1), text("ABC")],
+                    vec![int(2), text("1"), int(2), text("zzz")],
+                ],
+            )
+            .table(
+                "CREATE TABLE i (t TEXT)",
+                [vec![text("1")], vec![text("abc")], vec![text("2.0")]],
+            )
+            .index("CREATE INDEX i_t ON i (t)")
+            .build()
+            .unwrap();
+        let ids = |condition: &str| {
+            query(
+                &image,
+                &format!(
+                    "SELECT id FROM o WHERE EXISTS (SELECT 1 FROM i WHERE {})",
+                    condition
+                ),
+            )
+            .unwrap()
+        };
+        // Neither side is numeric, so the integer 1 stays one.
+        assert_eq!(ids("o.b = i.t"), vec![vec![int(2)]]);
+        // The outer column's NOCASE wins, being on the left.
+        assert_eq!(ids("o.nc = i.t"), vec![vec![int(1)]]);
+        // The INTEGER column turns '2.0' into a number.
+        assert_eq!(ids("i.t = o.n"), vec![vec![int(1)], vec![int(2)]]);
+        // Only one side can probe the index, so neither does.
+        assert_eq!(
+            ids("i.t = o.n OR i.t = o.b"),
+            vec![vec![int(1)], vec![int(2)]]
+        );
+    }
+
+    #[test]
+    fn subqueries_without_from_see_the_outer_columns() {
+        let image = with_null();
+        assert_eq!(
+            query(&image, "SELECT (SELECT x.a + 1), (SELECT a * 2) FROM x").unwrap(),
+            vec![
+                vec![int(2), int(2)],
+                vec![Value::Null, Value::Null],
+                vec![int(4), int(6)],
+            ]
+        );
+        assert_eq!(
+            query(
+                &image,
+                "SELECT a FROM x WHERE EXISTS (SELECT 1 WHERE x.a > 1)"
+            )
+            .unwrap(),
+            vec![vec![int(3)]]
+        );
+        assert_eq!(
+            query(&image, "SELECT a FROM x WHERE a IN (VALUES (1), (x.a - 1))").unwrap(),
+            vec![vec![int(1)]]
+        );
+        assert!(query(&image, "SELECT (SELECT y.a) FROM x").is_err());
+    }
+
+    #[test]
+    fn in_list_items_have_no_affinity() {
+        let image = one_table(
+            "CREATE TABLE x (t TEXT, n INTEGER)",
+            [vec![text("1.0"), int(1)], vec![text("2"), int(2)]],
+        );
+        // `t IN (n)` is `t = +n`: n turns to text rather than t to a number.
+        assert_eq!(
+            query(&image, "SELECT t FROM x WHERE t IN (n)").unwrap(),
+            vec![vec![text("2")]]
+        );
+        assert_eq!(
+            query(&image, "SELECT t FROM x WHERE t = n").unwrap(),
+            vec![vec![text("1.0")], vec![text("2")]]
+        );
+        assert_eq!(
+            query(&image, "SELECT t FROM x WHERE n IN (t)").unwrap(),
+            vec![vec![text("1.0")], vec![text("2")]]
+        );
+    }
+}
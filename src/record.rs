@@ -1,4 +1,7 @@
-use anyhow::{bail, Context, Result};
+use crate::error::corrupt;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::sync::Arc;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, PartialEq)]
@@ -6,22 +9,69 @@ pub enum Value {
     Null,
     Int(i64),
     Float(f64),
-    Text(String),
+    Text(Arc<str>),
     Blob(Vec<u8>),
 }
 
-pub fn read_varint(bytes: &[u8]) -> Result<(u64, &[u8], usize)> {
+/// Formats a REAL the way SQLite prints it (`%!.15g`): integral values keep a
+/// trailing `.0` and very large or small magnitudes use exponent notation.
+pub fn format_real(value: f64) -> String {
+    if value.is_nan() {
+        return "NaN".to_string();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "Inf" } else { "-Inf" }.to_string();
+    }
+    if value == 0.0 {
+        return "0.0".to_string();
+    }
+
+    let scientific = format!("{:.14e}", value);
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("scientific formatting always contains an exponent");
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+
+    if !(-4..15).contains(&exponent) {
+        let mantissa = mantissa.trim_end_matches('0');
+        let mantissa = if mantissa.ends_with('.') {
+            format!("{}0", mantissa)
+        } else {
+            mantissa.to_string()
+        };
+        return format!(
+            "{}e{}{:02}",
+            mantissa,
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        );
+    }
+
+    let decimals = (14 - exponent).max(0) as usize;
+    let fixed = format!("{:.*}", decimals, value);
+    if fixed.contains('.') {
+        let trimmed = fixed.trim_end_matches('0');
+        if trimmed.ends_with('.') {
+            format!("{}0", trimmed)
+        } else {
+            trimmed.to_string()
+        }
+    } else {
+        format!("{}.0", fixed)
+    }
+}
 
+pub fn read_varint(bytes: &[u8]) -> Result<(u64, &[u8], usize)> {
     let mut result: u64 = 0;
     let mut bytes_read: usize = 0;
 
     if bytes.is_empty() {
-        bail!("Cannot read varint from empty slice");
+        corrupt!("Cannot read varint from empty slice");
     }
 
     for i in 0..9 {
         if i >= bytes.len() {
-            bail!(
+            corrupt!(
                 "Unexpected end of bytes when reading varint (tried to read byte {}, slice len {})",
                 i + 1,
                 bytes.len()
@@ -46,7 +96,94 @@ pub fn read_varint(bytes: &[u8]) -> Result<(u64, &[u8], usize)> {
     Ok((result, &bytes[bytes_read..], bytes_read))
 }
 
-pub fn parse_record(record_payload: &[u8]) -> Result<Vec<Value>> {
+/// Controls how record bodies are decoded into `Value`s.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeOptions {
+    /// Replace invalid UTF-8 in TEXT values with U+FFFD instead of failing.
+    pub lossy_text: bool,
+    /// Share one allocation between equal short TEXT values within a scan.
+    pub intern_text: bool,
+    /// How TEXT values are stored, from the database header.
+    pub text_encoding: TextEncoding,
+}
+
+pub fn parse_record(record_payload: &[u8], options: DecodeOptions) -> Result<Vec<Value>> {
+    let mut values = Vec::new();
+    parse_record_into(record_payload, options, &mut Vec::new(), &mut values, None)?;
+    Ok(values)
+}
+
+/// Decodes the rows of a scan one after another into a buffer the scan
+/// reuses, keeping its own buffer of serial types from row to row too, so
+/// only text and blob values allocate. On narrow rows the row allocations
+/// were most of the cost of a full scan.
+pub struct RecordDecoder {
+    options: DecodeOptions,
+    serial_types: Vec<u64>,
+    interner: Option<Interner>,
+}
+
+/// Texts longer than this are never interned: they are unlikely to repeat
+/// and hashing them costs more than the allocation saved.
+const INTERN_MAX_LEN: usize = 64;
+/// Distinct texts kept per scan. Past this the column is evidently not low
+/// cardinality, and new values are allocated as usual.
+const INTERN_MAX_ENTRIES: usize = 4096;
+
+/// The texts seen so far in one scan, so repeats of a status or a country
+/// code share the first one's allocation.
+#[derive(Default)]
+struct Interner {
+    texts: HashSet<Arc<str>>,
+}
+
+impl Interner {
+    fn intern(&mut self, text: &str) -> Arc<str> {
+        if let Some(shared) = self.texts.get(text) {
+            return shared.clone();
+        }
+        let text: Arc<str> = text.into();
+        if self.texts.len() < INTERN_MAX_ENTRIES {
+            self.texts.insert(text.clone());
+        }
+        text
+    }
+}
+
+impl RecordDecoder {
+    pub fn new(options: DecodeOptions) -> Self {
+        RecordDecoder {
+            options,
+            serial_types: Vec::new(),
+            interner: options.intern_text.then(Interner::default),
+        }
+    }
+
+    /// Replaces `row` with the record in `payload` as a table row: `rowid`
+    /// followed by its values.
+    pub fn decode_row(&mut self, rowid: i64, payload: &[u8], row: &mut Vec<Value>) -> Result<()> {
+        row.clear();
+        row.push(Value::Int(rowid));
+        parse_record_into(
+            payload,
+            self.options,
+            &mut self.serial_types,
+            row,
+            self.interner.as_mut(),
+        )
+    }
+}
+
+/// Decodes a record like `parse_record`, appending its values to `values`.
+/// `column_serial_types` is scratch space, cleared first. Short texts are
+/// looked up in `interner`, if given, before allocating.
+fn parse_record_into(
+    record_payload: &[u8],
+    options: DecodeOptions,
+    column_serial_types: &mut Vec<u64>,
+    values: &mut Vec<Value>,
+    mut interner: Option<&mut Interner>,
+) -> Result<()> {
     // K: total_header_size, L: bytes_for_k_varint
     // The first varint in record_payload is K.
     // It is followed by K-L bytes which are the serial type definitions.
@@ -56,7 +193,7 @@ pub fn parse_record(record_payload: &[u8]) -> Result<Vec<Value>> {
             .context("Failed to read record's total header size (K) varint")?;
 
     if k_total_header_size < l_bytes_for_k_varint as u64 {
-        bail!(
+        corrupt!(
             "Record's total header size K ({}) is less than the size of K's varint L ({}). Invalid record.",
             k_total_header_size, l_bytes_for_k_varint
         );
@@ -66,7 +203,7 @@ pub fn parse_record(record_payload: &[u8]) -> Result<Vec<Value>> {
     let serial_types_section_len = k_total_header_size as usize - l_bytes_for_k_varint;
 
     if serial_types_section_len > cursor_after_k_varint.len() {
-        bail!(
+        corrupt!(
             "Record's declared serial types section length (K-L = {}) is greater than actual remaining data length ({}) after K-varint.",
             serial_types_section_len,
             cursor_after_k_varint.len()
@@ -77,7 +214,7 @@ pub fn parse_record(record_payload: &[u8]) -> Result<Vec<Value>> {
     let mut body_data_cursor = &cursor_after_k_varint[serial_types_section_len..];
 
     let mut serial_types_scan_pos = 0;
-    let mut column_serial_types = Vec::new();
+    column_serial_types.clear();
 
     while serial_types_scan_pos < serial_types_section_len {
         let (serial_type, _, bytes_read_for_st) =
@@ -89,15 +226,29 @@ pub fn parse_record(record_payload: &[u8]) -> Result<Vec<Value>> {
             })?;
 
         if bytes_read_for_st == 0 {
-            bail!("Read 0 bytes for a serial type varint in header (should not happen).");
+            corrupt!("Read 0 bytes for a serial type varint in header (should not happen).");
         }
         serial_types_scan_pos += bytes_read_for_st;
         column_serial_types.push(serial_type);
     }
 
-    let mut values = Vec::new();
+    values.reserve(column_serial_types.len());
     for (idx, &serial_type) in column_serial_types.iter().enumerate() {
-        let (value, bytes_consumed_by_value) = parse_value(serial_type, body_data_cursor)
+        if let Some(interner) = interner.as_deref_mut() {
+            let len = serial_type.saturating_sub(13) as usize / 2;
+            if serial_type >= 13
+                && serial_type % 2 == 1
+                && len <= INTERN_MAX_LEN
+                && options.text_encoding == TextEncoding::Utf8
+            {
+                if let Some(Ok(text)) = body_data_cursor.get(..len).map(std::str::from_utf8) {
+                    values.push(Value::Text(interner.intern(text)));
+                    body_data_cursor = &body_data_cursor[len..];
+                    continue;
+                }
+            }
+        }
+        let (value, bytes_consumed_by_value) = parse_value(serial_type, body_data_cursor, options)
             .with_context(|| {
                 format!(
                     "Failed to parse value for column {} (serial type {})",
@@ -107,7 +258,7 @@ pub fn parse_record(record_payload: &[u8]) -> Result<Vec<Value>> {
 
         values.push(value);
         if bytes_consumed_by_value > body_data_cursor.len() {
-            bail!(
+            corrupt!(
                  "Value parser for serial type {} reported consuming {} bytes, but only {} bytes remain in body.",
                  serial_type, bytes_consumed_by_value, body_data_cursor.len()
              );
@@ -115,23 +266,27 @@ pub fn parse_record(record_payload: &[u8]) -> Result<Vec<Value>> {
         body_data_cursor = &body_data_cursor[bytes_consumed_by_value..];
     }
 
-    Ok(values)
+    Ok(())
 }
 
-pub fn parse_value(serial_type: u64, bytes: &[u8]) -> Result<(Value, usize)> {
+pub fn parse_value(
+    serial_type: u64,
+    bytes: &[u8],
+    options: DecodeOptions,
+) -> Result<(Value, usize)> {
     match serial_type {
         0 => Ok((Value::Null, 0)),
         1 => {
             // Int8
             if bytes.is_empty() {
-                bail!("Not enough data for Int8 (1 byte)");
+                corrupt!("Not enough data for Int8 (1 byte)");
             }
             Ok((Value::Int(bytes[0] as i8 as i64), 1))
         }
         2 => {
             // Int16
             if bytes.len() < 2 {
-                bail!("Not enough data for Int16 (2 bytes)");
+                corrupt!("Not enough data for Int16 (2 bytes)");
             }
             Ok((
                 Value::Int(i16::from_be_bytes([bytes[0], bytes[1]]) as i64),
@@ -141,7 +296,7 @@ pub fn parse_value(serial_type: u64, bytes: &[u8]) -> Result<(Value, usize)> {
         3 => {
             // Int24
             if bytes.len() < 3 {
-                bail!("Not enough data for Int24 (3 bytes)");
+                corrupt!("Not enough data for Int24 (3 bytes)");
             }
             let val = ((bytes[0] as i64) << 16) | ((bytes[1] as i64) << 8) | (bytes[2] as i64);
             // Sign extend if the 24th bit (0x800000) is set
@@ -157,7 +312,7 @@ pub fn parse_value(serial_type: u64, bytes: &[u8]) -> Result<(Value, usize)> {
         4 => {
             // Int32
             if bytes.len() < 4 {
-                bail!("Not enough data for Int32 (4 bytes)");
+                corrupt!("Not enough data for Int32 (4 bytes)");
             }
             Ok((
                 Value::Int(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64),
@@ -167,7 +322,7 @@ pub fn parse_value(serial_type: u64, bytes: &[u8]) -> Result<(Value, usize)> {
         5 => {
             // Int48 (6 bytes)
             if bytes.len() < 6 {
-                bail!("Not enough data for Int48 (6 bytes)");
+                corrupt!("Not enough data for Int48 (6 bytes)");
             }
             let mut buf = [0u8; 8]; // Buffer for full i64
                                     // Check sign bit of the 48-bit number (MSB of the first byte)
@@ -181,7 +336,7 @@ pub fn parse_value(serial_type: u64, bytes: &[u8]) -> Result<(Value, usize)> {
         6 => {
             // Int64
             if bytes.len() < 8 {
-                bail!("Not enough data for Int64 (8 bytes)");
+                corrupt!("Not enough data for Int64 (8 bytes)");
             }
             Ok((
                 Value::Int(i64::from_be_bytes(
@@ -195,7 +350,7 @@ pub fn parse_value(serial_type: u64, bytes: &[u8]) -> Result<(Value, usize)> {
         7 => {
             // Float64
             if bytes.len() < 8 {
-                bail!("Not enough data for Float64 (8 bytes)");
+                corrupt!("Not enough data for Float64 (8 bytes)");
             }
             Ok((
                 Value::Float(f64::from_be_bytes(
@@ -209,13 +364,13 @@ pub fn parse_value(serial_type: u64, bytes: &[u8]) -> Result<(Value, usize)> {
         8 => Ok((Value::Int(0), 0)), // Constant 0
         9 => Ok((Value::Int(1), 0)), // Constant 1
         st if st == 10 || st == 11 => {
-            bail!("Reserved serial type {} encountered. These are unused.", st)
+            corrupt!("Reserved serial type {} encountered. These are unused.", st)
         }
         st if st >= 12 => {
             // Blob or Text
             let len = ((st - (if st % 2 == 0 { 12 } else { 13 })) / 2) as usize;
             if bytes.len() < len {
-                bail!(
+                corrupt!(
                     "Not enough data for {} (serial type {}): expected {} bytes, got {}",
                     if st % 2 == 0 { "Blob" } else { "Text" },
                     st,
@@ -228,16 +383,178 @@ pub fn parse_value(serial_type: u64, bytes: &[u8]) -> Result<(Value, usize)> {
                 Ok((Value::Blob(bytes[..len].to_vec()), len))
             } else {
                 // Text
-                match String::from_utf8(bytes[..len].to_vec()) {
-                    Ok(text) => Ok((Value::Text(text), len)),
-                    Err(e) => bail!(
-                        "Invalid UTF-8 sequence for Text (serial type {}): {}",
-                        st,
-                        e
-                    ),
+                let text = options
+                    .text_encoding
+                    .decode(&bytes[..len], options.lossy_text)
+                    .with_context(|| format!("Invalid Text (serial type {})", st))?;
+                Ok((Value::Text(text.into()), len))
+            }
+        }
+        _ => corrupt!("Unknown or unhandled serial type: {}", serial_type),
+    }
+}
+
+/// Appends `value` as a SQLite varint: 7 bits per byte, most significant
+/// first, with all 8 bits of the ninth byte used.
+pub fn write_varint(value: u64, out: &mut Vec<u8>) {
+    if value > 0x00FF_FFFF_FFFF_FFFF {
+        let mut bytes = [0u8; 9];
+        bytes[8] = value as u8;
+        let mut rest = value >> 8;
+        for byte in bytes[..8].iter_mut().rev() {
+            *byte = (rest & 0x7F) as u8 | 0x80;
+            rest >>= 7;
+        }
+        out.extend_from_slice(&bytes);
+        return;
+    }
+
+    let mut bytes = Vec::with_capacity(8);
+    let mut rest = value;
+    loop {
+        bytes.push((rest & 0x7F) as u8 | if bytes.is_empty() { 0 } else { 0x80 });
+        rest >>= 7;
+        if rest == 0 {
+            break;
+        }
+    }
+    out.extend(bytes.iter().rev());
+}
+
+pub fn varint_len(value: u64) -> usize {
+    let mut out = Vec::with_capacity(9);
+    write_varint(value, &mut out);
+    out.len()
+}
+
+/// How TEXT values are stored, as given by the database header.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    Utf16le,
+    Utf16be,
+}
+
+impl TextEncoding {
+    /// The value of the header's text encoding field.
+    pub fn header_value(self) -> u32 {
+        match self {
+            TextEncoding::Utf8 => 1,
+            TextEncoding::Utf16le => 2,
+            TextEncoding::Utf16be => 3,
+        }
+    }
+
+    /// The encoding a header's text encoding field stands for. A database
+    /// with no pages yet has 0 there, which is UTF-8 until it's written.
+    pub fn from_header_value(value: u32) -> Option<Self> {
+        match value {
+            0 | 1 => Some(TextEncoding::Utf8),
+            2 => Some(TextEncoding::Utf16le),
+            3 => Some(TextEncoding::Utf16be),
+            _ => None,
+        }
+    }
+
+    /// The text stored as `bytes`. Invalid text, including a UTF-16 one of
+    /// odd length, is an error, or with `lossy` has U+FFFD in its place.
+    pub fn decode(self, bytes: &[u8], lossy: bool) -> Result<String> {
+        let units = |to_unit: fn([u8; 2]) -> u16| {
+            bytes
+                .chunks_exact(2)
+                .map(|pair| to_unit([pair[0], pair[1]]))
+                .collect::<Vec<_>>()
+        };
+        let units = match self {
+            TextEncoding::Utf8 => {
+                return match std::str::from_utf8(bytes) {
+                    Ok(text) => Ok(text.to_string()),
+                    Err(_) if lossy => Ok(String::from_utf8_lossy(bytes).into_owned()),
+                    Err(e) => corrupt!("Invalid UTF-8 sequence: {}", e),
+                };
+            }
+            TextEncoding::Utf16le => units(u16::from_le_bytes),
+            TextEncoding::Utf16be => units(u16::from_be_bytes),
+        };
+        let odd = bytes.len() % 2 == 1;
+        match String::from_utf16(&units) {
+            Ok(text) if !odd => Ok(text),
+            _ if lossy => {
+                let mut text = String::from_utf16_lossy(&units);
+                if odd {
+                    text.push(char::REPLACEMENT_CHARACTER);
                 }
+                Ok(text)
             }
+            Ok(_) => corrupt!("UTF-16 text of odd length {}", bytes.len()),
+            Err(e) => corrupt!("Invalid UTF-16 sequence: {}", e),
+        }
+    }
+
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf16le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            TextEncoding::Utf16be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
         }
-        _ => bail!("Unknown or unhandled serial type: {}", serial_type),
     }
 }
+
+/// Encodes values as a UTF-8 record, the inverse of `parse_record`.
+pub fn encode_record(values: &[Value]) -> Vec<u8> {
+    encode_record_in(values, TextEncoding::Utf8)
+}
+
+/// Encodes values as a record with TEXT in `encoding`. Integers use the
+/// smallest serial type that holds them, including the 0 and 1 constants of
+/// schema format 4.
+pub fn encode_record_in(values: &[Value], encoding: TextEncoding) -> Vec<u8> {
+    let mut serial_types = Vec::new();
+    let mut body = Vec::new();
+    for value in values {
+        let serial_type = match value {
+            Value::Null => 0,
+            Value::Int(0) => 8,
+            Value::Int(1) => 9,
+            Value::Int(i) => {
+                let (serial_type, width) = match *i {
+                    -0x80..=0x7F => (1, 1),
+                    -0x8000..=0x7FFF => (2, 2),
+                    -0x80_0000..=0x7F_FFFF => (3, 3),
+                    -0x8000_0000..=0x7FFF_FFFF => (4, 4),
+                    -0x8000_0000_0000..=0x7FFF_FFFF_FFFF => (5, 6),
+                    _ => (6, 8),
+                };
+                body.extend_from_slice(&i.to_be_bytes()[8 - width..]);
+                serial_type
+            }
+            Value::Float(f) => {
+                body.extend_from_slice(&f.to_be_bytes());
+                7
+            }
+            Value::Text(text) => {
+                let text = encoding.encode(text);
+                body.extend_from_slice(&text);
+                text.len() as u64 * 2 + 13
+            }
+            Value::Blob(bytes) => {
+                body.extend_from_slice(bytes);
+                bytes.len() as u64 * 2 + 12
+            }
+        };
+        write_varint(serial_type, &mut serial_types);
+    }
+
+    // The header size counts its own varint.
+    let mut header_size = serial_types.len() + 1;
+    while varint_len(header_size as u64) + serial_types.len() != header_size {
+        header_size = serial_types.len() + varint_len(header_size as u64);
+    }
+
+    let mut record = Vec::with_capacity(header_size + body.len());
+    write_varint(header_size as u64, &mut record);
+    record.extend_from_slice(&serial_types);
+    record.extend_from_slice(&body);
+    record
+}
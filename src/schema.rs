@@ -0,0 +1,650 @@
+use crate::record::Value;
+use anyhow::{bail, Context, Result};
+
+/// SQLite column affinity, derived from the declared type of a column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Affinity {
+    Integer,
+    Real,
+    Numeric,
+    Text,
+    Blob,
+}
+
+impl Affinity {
+    /// Applies the rules from section 3.1 of https://www.sqlite.org/datatype3.html.
+    pub fn from_declared_type(declared_type: &str) -> Self {
+        let upper = declared_type.to_ascii_uppercase();
+        if upper.contains("INT") {
+            Affinity::Integer
+        } else if upper.contains("CHAR") || upper.contains("CLOB") || upper.contains("TEXT") {
+            Affinity::Text
+        } else if upper.contains("BLOB") || upper.is_empty() {
+            Affinity::Blob
+        } else if upper.contains("REAL") || upper.contains("FLOA") || upper.contains("DOUB") {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    pub fn is_numeric(self) -> bool {
+        matches!(self, Affinity::Integer | Affinity::Real | Affinity::Numeric)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnDef {
+    pub name: String,
+    pub declared_type: String,
+    pub affinity: Affinity,
+    /// The `COLLATE` name the column was declared with, if any; BINARY
+    /// otherwise.
+    pub collation: Option<String>,
+    /// Declared `NOT NULL`.
+    pub not_null: bool,
+    /// The `DEFAULT` value as written, without parentheses around an
+    /// expression.
+    pub default: Option<String>,
+    /// Other names the column goes by: for a table's rowid, those of
+    /// `rowid`, `_rowid_` and `oid` that no declared column has taken.
+    pub aliases: Vec<String>,
+}
+
+impl ColumnDef {
+    pub fn new(name: &str, declared_type: &str) -> Self {
+        ColumnDef {
+            name: name.to_string(),
+            declared_type: declared_type.to_string(),
+            affinity: Affinity::from_declared_type(declared_type),
+            collation: None,
+            not_null: false,
+            default: None,
+            aliases: Vec::new(),
+        }
+    }
+
+    /// Whether `name` (any case) is this column's name or one of its
+    /// aliases.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+
+    /// In a STRICT table `ANY` means "keep whatever was stored", so unlike the
+    /// ordinary affinity rules (which would give it NUMERIC) it gets none.
+    fn new_strict(name: &str, declared_type: &str) -> Self {
+        let mut column = ColumnDef::new(name, declared_type);
+        if declared_type.eq_ignore_ascii_case("any") {
+            column.affinity = Affinity::Blob;
+        }
+        column
+    }
+
+    /// Converts a stored value to the form SQLite hands back for this column.
+    /// Integral values in REAL columns are written to disk as integers, so they
+    /// are turned back into REALs here.
+    pub fn present(&self, value: Value) -> Value {
+        match value {
+            Value::Int(i) if self.affinity == Affinity::Real => Value::Float(i as f64),
+            other => other,
+        }
+    }
+}
+
+/// The datatypes a STRICT table may declare
+/// (https://www.sqlite.org/stricttables.html).
+pub(crate) const STRICT_TYPES: [&str; 6] = ["INT", "INTEGER", "REAL", "TEXT", "BLOB", "ANY"];
+
+/// A PRIMARY KEY or UNIQUE constraint, in the order it appears in the DDL.
+#[derive(Debug, Clone)]
+pub struct KeyConstraint {
+    pub columns: Vec<String>,
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableDef {
+    pub columns: Vec<ColumnDef>,
+    pub strict: bool,
+    pub without_rowid: bool,
+    pub key_constraints: Vec<KeyConstraint>,
+    /// The column declared `INTEGER PRIMARY KEY`, which is stored as the rowid.
+    pub rowid_alias: Option<String>,
+}
+
+impl TableDef {
+    /// An ordinary table with these columns.
+    pub fn with_columns(columns: Vec<ColumnDef>) -> Self {
+        TableDef {
+            columns,
+            strict: false,
+            without_rowid: false,
+            key_constraints: Vec::new(),
+            rowid_alias: None,
+        }
+    }
+
+    /// Returns the key columns of `sqlite_autoindex_<table>_<number>`. SQLite
+    /// stores NULL as the `sql` of these indexes; they are created, in order,
+    /// for every PRIMARY KEY (other than a rowid alias) and UNIQUE constraint,
+    /// skipping constraints that duplicate an earlier one.
+    pub fn autoindex_columns(&self, number: usize) -> Option<Vec<String>> {
+        let mut created: Vec<&Vec<String>> = Vec::new();
+        for constraint in &self.key_constraints {
+            if constraint.primary && self.rowid_alias.is_some() {
+                continue;
+            }
+            let duplicate = created.iter().any(|existing| {
+                existing.len() == constraint.columns.len()
+                    && existing
+                        .iter()
+                        .zip(&constraint.columns)
+                        .all(|(a, b)| a.eq_ignore_ascii_case(b))
+            });
+            if !duplicate {
+                created.push(&constraint.columns);
+            }
+        }
+        created
+            .get(number.checked_sub(1)?)
+            .map(|columns| columns.to_vec())
+    }
+
+    /// The columns, by position, in the order a WITHOUT ROWID table stores
+    /// them: its PRIMARY KEY columns, then the others as declared.
+    pub fn storage_order(&self) -> Vec<usize> {
+        let key: Vec<usize> = self
+            .key_constraints
+            .iter()
+            .find(|constraint| constraint.primary)
+            .map(|constraint| {
+                constraint
+                    .columns
+                    .iter()
+                    .filter_map(|name| {
+                        self.columns
+                            .iter()
+                            .position(|column| column.name.eq_ignore_ascii_case(name))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let rest = (0..self.columns.len()).filter(|i| !key.contains(i));
+        key.iter().copied().chain(rest).collect()
+    }
+
+    /// Converts the stored values of `record`, in place, to the form SQLite
+    /// hands back for their columns.
+    pub fn present(&self, record: &mut [Value]) {
+        for (value, column) in record.iter_mut().zip(&self.columns) {
+            if column.affinity == Affinity::Real {
+                *value = column.present(std::mem::replace(value, Value::Null));
+            }
+        }
+    }
+}
+
+const TABLE_CONSTRAINT_KEYWORDS: [&str; 5] =
+    ["constraint", "primary", "unique", "check", "foreign"];
+
+const COLUMN_CONSTRAINT_KEYWORDS: [&str; 11] = [
+    "constraint",
+    "primary",
+    "not",
+    "null",
+    "unique",
+    "check",
+    "default",
+    "collate",
+    "references",
+    "generated",
+    "as",
+];
+
+/// Parses the column definitions and table options (`STRICT`, `WITHOUT ROWID`)
+/// out of a `CREATE TABLE` statement.
+pub fn parse_create_table(sql_create_table: &str) -> Result<TableDef> {
+    let start_idx = sql_create_table
+        .find('(')
+        .context("Invalid CREATE TABLE syntax: missing '('")?;
+    let end_idx = sql_create_table
+        .rfind(')')
+        .context("Invalid CREATE TABLE syntax: missing ')'")?;
+
+    if start_idx >= end_idx {
+        bail!("Invalid CREATE TABLE syntax: '(' not before ')'");
+    }
+
+    let mut strict = false;
+    let mut without_rowid = false;
+    for option in split_top_level(&sql_create_table[end_idx + 1..]) {
+        let option = split_words(option.trim_end_matches(';')).join(" ");
+        if option.eq_ignore_ascii_case("strict") {
+            strict = true;
+        } else if option.eq_ignore_ascii_case("without rowid") {
+            without_rowid = true;
+        } else if !option.is_empty() {
+            bail!("Unknown table option: {}", option);
+        }
+    }
+
+    let mut columns = Vec::new();
+    let mut key_constraints = Vec::new();
+    let mut rowid_alias = None;
+    let mut column_primary_key = false;
+    for definition in split_top_level(&sql_create_table[start_idx + 1..end_idx]) {
+        let words = split_words(definition);
+        let Some(&word) = words.first() else {
+            continue;
+        };
+        if is_table_constraint(word) {
+            if let Some(constraint) = parse_table_key_constraint(definition)? {
+                key_constraints.push(constraint);
+            }
+            continue;
+        }
+
+        let first = &unquote(word);
+        let type_words = &words[1..1 + type_word_count(&words)];
+        let declared_type = type_words.join(" ");
+
+        let constraint_words = &words[1 + type_words.len()..];
+        let collation = collation_name(constraint_words);
+        let not_null = constraint_words.windows(2).any(|pair| {
+            pair[0].eq_ignore_ascii_case("not") && pair[1].eq_ignore_ascii_case("null")
+        });
+        // `DEFAULT (1 + 2)` is a single word, the group attached.
+        let default = constraint_words
+            .iter()
+            .position(|word| leading_word(word).eq_ignore_ascii_case("default"))
+            .and_then(
+                |position| match constraint_words[position]["default".len()..].trim() {
+                    "" => constraint_words.get(position + 1).copied(),
+                    attached => Some(attached),
+                },
+            )
+            .map(
+                |value| match value.strip_prefix('(').and_then(|v| v.strip_suffix(')')) {
+                    Some(expression) => expression.trim().to_string(),
+                    None => value.to_string(),
+                },
+            );
+        for (i, word) in constraint_words.iter().enumerate() {
+            let primary = word.eq_ignore_ascii_case("primary");
+            if !primary && !word.eq_ignore_ascii_case("unique") {
+                continue;
+            }
+            column_primary_key |= primary;
+            if primary
+                && declared_type.eq_ignore_ascii_case("integer")
+                && !constraint_words
+                    .get(i + 2)
+                    .is_some_and(|word| word.eq_ignore_ascii_case("desc"))
+            {
+                rowid_alias = Some(first.to_string());
+            }
+            key_constraints.push(KeyConstraint {
+                columns: vec![first.to_string()],
+                primary,
+            });
+        }
+
+        if strict {
+            if !STRICT_TYPES
+                .iter()
+                .any(|allowed| declared_type.eq_ignore_ascii_case(allowed))
+            {
+                bail!(
+                    "Unknown datatype for STRICT column '{}': \"{}\"",
+                    first,
+                    declared_type
+                );
+            }
+            columns.push(ColumnDef {
+                collation,
+                not_null,
+                default,
+                ..ColumnDef::new_strict(first, &declared_type)
+            });
+        } else {
+            columns.push(ColumnDef {
+                collation,
+                not_null,
+                default,
+                ..ColumnDef::new(first, &declared_type)
+            });
+        }
+    }
+
+    // A table-level `PRIMARY KEY (id)` also makes an INTEGER column the rowid,
+    // even with DESC, which in a column's own `INTEGER PRIMARY KEY DESC`
+    // keeps it an ordinary column.
+    if !column_primary_key {
+        if let Some(constraint) = key_constraints.iter().find(|c| c.primary) {
+            if let [column] = constraint.columns.as_slice() {
+                if columns.iter().any(|c: &ColumnDef| {
+                    c.name.eq_ignore_ascii_case(column)
+                        && c.declared_type.eq_ignore_ascii_case("integer")
+                }) {
+                    rowid_alias = Some(column.clone());
+                }
+            }
+        }
+    }
+
+    // A WITHOUT ROWID table has no rowid for its key to stand for.
+    if without_rowid {
+        rowid_alias = None;
+    }
+
+    Ok(TableDef {
+        columns,
+        strict,
+        without_rowid,
+        key_constraints,
+        rowid_alias,
+    })
+}
+
+fn is_table_constraint(first_word: &str) -> bool {
+    TABLE_CONSTRAINT_KEYWORDS
+        .iter()
+        .any(|keyword| leading_word(first_word).eq_ignore_ascii_case(keyword))
+}
+
+/// How many words of a column definition, after the name, make up its
+/// declared type; the constraints follow.
+fn type_word_count(words: &[&str]) -> usize {
+    words[1..]
+        .iter()
+        .take_while(|word| {
+            !COLUMN_CONSTRAINT_KEYWORDS
+                .iter()
+                .any(|keyword| leading_word(word).eq_ignore_ascii_case(keyword))
+        })
+        .count()
+}
+
+/// Lays out a `CREATE TABLE` statement with one column per line and the
+/// columns' types and constraints lined up. Only whitespace changes.
+pub fn format_create_table(sql_create_table: &str) -> Result<String> {
+    let start_idx = sql_create_table
+        .find('(')
+        .context("Invalid CREATE TABLE syntax: missing '('")?;
+    let end_idx = sql_create_table
+        .rfind(')')
+        .context("Invalid CREATE TABLE syntax: missing ')'")?;
+    if start_idx >= end_idx {
+        bail!("Invalid CREATE TABLE syntax: '(' not before ')'");
+    }
+
+    let mut columns = Vec::new();
+    let mut table_constraints = Vec::new();
+    for definition in split_top_level(&sql_create_table[start_idx + 1..end_idx]) {
+        let words = split_words(definition);
+        let Some(first) = words.first() else {
+            continue;
+        };
+        if is_table_constraint(first) {
+            table_constraints.push(words.join(" "));
+            continue;
+        }
+        let type_end = 1 + type_word_count(&words);
+        columns.push((
+            *first,
+            words[1..type_end].join(" "),
+            words[type_end..].join(" "),
+        ));
+    }
+
+    let name_width = columns
+        .iter()
+        .map(|(name, _, _)| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let type_width = columns
+        .iter()
+        .map(|(_, declared_type, _)| declared_type.chars().count())
+        .max()
+        .unwrap_or(0);
+    let lines: Vec<String> = columns
+        .iter()
+        .map(|(name, declared_type, constraints)| {
+            let mut line = format!("{:<name_width$}", name);
+            if type_width > 0 {
+                line += &format!(" {:<type_width$}", declared_type);
+            }
+            format!("{} {}", line, constraints).trim_end().to_string()
+        })
+        .chain(table_constraints)
+        .collect();
+
+    let head = split_words(&sql_create_table[..start_idx]).join(" ");
+    let options = split_words(&sql_create_table[end_idx + 1..]).join(" ");
+    Ok(format!(
+        "{} (\n  {}\n){}{}",
+        head,
+        lines.join(",\n  "),
+        if options.is_empty() { "" } else { " " },
+        options
+    ))
+}
+
+/// Parses a table-level `[CONSTRAINT name] PRIMARY KEY (...)` or `UNIQUE (...)`.
+/// Other table constraints (CHECK, FOREIGN KEY) yield `None`.
+fn parse_table_key_constraint(definition: &str) -> Result<Option<KeyConstraint>> {
+    let mut words = split_words(definition);
+    if words
+        .first()
+        .is_some_and(|word| word.eq_ignore_ascii_case("constraint"))
+    {
+        words.drain(..2.min(words.len()));
+    }
+    let primary = match words.first().map(|word| leading_word(word)) {
+        Some(word) if word.eq_ignore_ascii_case("primary") => true,
+        Some(word) if word.eq_ignore_ascii_case("unique") => false,
+        _ => return Ok(None),
+    };
+
+    let start = definition
+        .find('(')
+        .context("Invalid key constraint: missing '('")?;
+    let end = definition
+        .rfind(')')
+        .context("Invalid key constraint: missing ')'")?;
+    Ok(Some(KeyConstraint {
+        columns: parse_indexed_columns(&definition[start + 1..end]),
+        primary,
+    }))
+}
+
+/// Takes the column names out of an indexed-column list such as
+/// `a COLLATE NOCASE, b DESC`.
+fn parse_indexed_columns(list: &str) -> Vec<String> {
+    split_top_level(list)
+        .into_iter()
+        .filter_map(|column| split_words(column).first().map(|name| unquote(name)))
+        .collect()
+}
+
+/// The name after `COLLATE` in a run of words, without quotes.
+fn collation_name(words: &[&str]) -> Option<String> {
+    let position = words
+        .iter()
+        .position(|word| word.eq_ignore_ascii_case("collate"))?;
+    let name = words.get(position + 1)?;
+    Some(
+        name.trim_matches(|c| matches!(c, '"' | '\'' | '`' | '[' | ']'))
+            .to_string(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexDef {
+    pub columns: Vec<String>,
+    /// The `COLLATE` given for each key column in the index itself, if any;
+    /// otherwise a key compares by the collation of its table column.
+    pub collations: Vec<Option<String>>,
+    /// Whether each key column is sorted `DESC`.
+    pub descending: Vec<bool>,
+    pub unique: bool,
+    /// Partial indexes (`CREATE INDEX ... WHERE ...`) only cover some rows.
+    pub partial: bool,
+}
+
+/// Parses `CREATE [UNIQUE] INDEX [IF NOT EXISTS] name ON table (columns) [WHERE ...]`.
+pub fn parse_create_index(sql_create_index: &str) -> Result<IndexDef> {
+    let start = sql_create_index
+        .find('(')
+        .context("Invalid CREATE INDEX syntax: missing '('")?;
+
+    let mut depth = 0;
+    let mut end = None;
+    for (i, c) in sql_create_index[start..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    end = Some(start + i);
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let end = end.context("Invalid CREATE INDEX syntax: missing ')'")?;
+
+    let head = split_words(&sql_create_index[..start]);
+    let unique = head
+        .get(1)
+        .is_some_and(|word| word.eq_ignore_ascii_case("unique"));
+    let partial = split_words(&sql_create_index[end + 1..])
+        .first()
+        .is_some_and(|word| word.eq_ignore_ascii_case("where"));
+
+    let key_list = &sql_create_index[start + 1..end];
+    Ok(IndexDef {
+        columns: parse_indexed_columns(key_list),
+        collations: split_top_level(key_list)
+            .into_iter()
+            .map(|column| collation_name(&split_words(column)))
+            .collect(),
+        descending: split_top_level(key_list)
+            .into_iter()
+            .map(|column| {
+                split_words(column)
+                    .last()
+                    .is_some_and(|word| word.eq_ignore_ascii_case("desc"))
+            })
+            .collect(),
+        unique,
+        partial,
+    })
+}
+
+/// A name or string as written in SQL, without its quotes; a doubled quote
+/// inside stands for one.
+pub(crate) fn unquote(text: &str) -> String {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next_back()) {
+        (Some(open @ ('\'' | '"' | '`')), Some(close)) if open == close && text.len() >= 2 => {
+            let quote = open.to_string();
+            text[1..text.len() - 1].replace(&quote.repeat(2), &quote)
+        }
+        (Some('['), Some(']')) => text[1..text.len() - 1].to_string(),
+        _ => text.to_string(),
+    }
+}
+
+/// Splits a definition list on commas that are not nested inside parentheses,
+/// so types like `DECIMAL(10,2)` stay in one piece.
+pub(crate) fn split_top_level(input: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut quote = None;
+    for (i, c) in input.char_indices() {
+        if let Some(close) = quote {
+            if c == close {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '\'' | '"' | '`' | '[' => quote = Some(closing_quote(c)),
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(input[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(input[start..].trim());
+    parts.into_iter().filter(|part| !part.is_empty()).collect()
+}
+
+/// The part of a word from `split_words` before any parenthesized group
+/// written against it, as in `UNIQUE(a, b)` or `CHECK (a > 0)`.
+fn leading_word(word: &str) -> &str {
+    word.split('(').next().unwrap_or(word).trim_end()
+}
+
+/// Splits on whitespace, keeping a parenthesized group attached to the word
+/// before it (e.g. `VARCHAR(255)` or `DECIMAL (10, 2)`). Quoted strings and
+/// names stay in one piece.
+fn split_words(input: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut depth = 0;
+    let mut start: Option<usize> = None;
+    let mut quote = None;
+    for (i, c) in input.char_indices() {
+        if let Some(close) = quote {
+            if c == close {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '\'' | '"' | '`' | '[' => {
+                quote = Some(closing_quote(c));
+                start.get_or_insert(i);
+            }
+            '(' => {
+                depth += 1;
+                start.get_or_insert(i);
+            }
+            ')' => depth -= 1,
+            c if c.is_whitespace() && depth == 0 => {
+                let next_is_group = input[i..].trim_start().starts_with('(');
+                if let Some(s) = start {
+                    if !next_is_group {
+                        words.push(&input[s..i]);
+                        start = None;
+                    }
+                }
+            }
+            _ => {
+                start.get_or_insert(i);
+            }
+        }
+    }
+    if let Some(s) = start {
+        words.push(&input[s..]);
+    }
+    words
+}
+
+fn closing_quote(open: char) -> char {
+    if open == '[' {
+        ']'
+    } else {
+        open
+    }
+}
@@ -0,0 +1,403 @@
+use crate::database::Database;
+use crate::interrupt::Interrupt;
+use crate::json::{parse_json, Json};
+use crate::parser::parameters;
+use crate::query::prepare;
+use crate::record::Value;
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Take, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Request bodies larger than this are refused; a query is a few lines of SQL.
+const MAX_BODY_SIZE: usize = 1 << 20;
+/// Nor may the request line and headers together be longer than this.
+const MAX_HEAD_SIZE: u64 = 16 << 10;
+/// Connections served at once, at the least; more on machines with more CPUs.
+/// A slow client or a long query only holds up one of them.
+const MIN_WORKERS: usize = 4;
+/// How long the server sleeps between checks for new connections and `stop`.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(20);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Result rows are buffered into chunks of about this size before being sent.
+const CHUNK_SIZE: usize = 8192;
+
+/// Serves `POST /query` on `listen` until `stop` is interrupted. The body is
+/// a JSON object `{"sql": "...", "params": [...]}`; the response streams
+/// `{"columns": [...], "rows": [[...], ...]}` using chunked encoding, so large
+/// results are never held in memory as JSON.
+///
+/// Connections are handled by a pool of worker threads, one per CPU, each
+/// with its own database from `open`, which is only ever read; the rest
+/// wait their turn. On `stop` no more are accepted, and those being served
+/// are finished first. Unless `quiet`, the bound address is announced on
+/// stderr.
+pub fn serve(
+    open: impl Fn() -> Result<Database> + Send + Sync + 'static,
+    listen: &str,
+    stop: &Interrupt,
+    quiet: bool,
+) -> Result<()> {
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("Failed to listen on {}", listen))?;
+    let workers = std::thread::available_parallelism()
+        .map_or(MIN_WORKERS, |n| n.get())
+        .max(MIN_WORKERS);
+    let (connections, workers) = start_workers(open, workers)?;
+    if !quiet {
+        eprintln!("Listening on http://{}", listener.local_addr()?);
+    }
+
+    // Accepting without blocking leaves room to notice `stop`.
+    listener.set_nonblocking(true)?;
+    while !stop.is_interrupted() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                connections
+                    .send(stream)
+                    .context("Server workers have stopped")?;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_INTERVAL),
+            Err(e) => eprintln!("Error: Failed to accept connection: {}", e),
+        }
+    }
+
+    drop(connections);
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+/// Starts `workers` threads taking connections from the returned sender, once
+/// each has opened its database. They stop when the sender is dropped.
+fn start_workers(
+    open: impl Fn() -> Result<Database> + Send + Sync + 'static,
+    workers: usize,
+) -> Result<(Sender<TcpStream>, Vec<JoinHandle<()>>)> {
+    let open = Arc::new(open);
+    let (connections, incoming) = mpsc::channel::<TcpStream>();
+    let incoming = Arc::new(Mutex::new(incoming));
+    let (opened, results) = mpsc::channel();
+    let mut handles = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let (open, incoming, opened) = (open.clone(), incoming.clone(), opened.clone());
+        handles.push(std::thread::spawn(move || {
+            let mut db = match open() {
+                Ok(db) => db,
+                Err(e) => {
+                    let _ = opened.send(Err(e));
+                    return;
+                }
+            };
+            let _ = opened.send(Ok(()));
+            loop {
+                // The lock is only held while waiting, not while serving.
+                let Ok(stream) = incoming.lock().unwrap().recv() else {
+                    return;
+                };
+                if let Err(e) = handle_connection(&mut db, stream) {
+                    eprintln!("Error: {:#}", e);
+                }
+            }
+        }));
+    }
+    for result in results.iter().take(workers) {
+        result?;
+    }
+    Ok((connections, handles))
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+fn handle_connection(db: &mut Database, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = match read_request(&mut BufReader::new(&mut stream)) {
+        Ok(request) => request,
+        Err(e) => return respond_error(&mut stream, 400, &format!("{:#}", e)),
+    };
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/query") => handle_query(db, &request.body, stream),
+        (_, "/query") => respond_error(&mut stream, 405, "Only POST is supported on /query"),
+        _ => respond_error(&mut stream, 404, "Not found"),
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let mut head = reader.take(MAX_HEAD_SIZE);
+    let mut request_line = String::new();
+    read_head_line(&mut head, &mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("Malformed request line");
+    };
+    // Ignore any query string; everything comes in the body.
+    let path = target.split('?').next().unwrap_or(target).to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        read_head_line(&mut head, &mut header)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .context("Invalid Content-Length header")?;
+            } else if name.trim().eq_ignore_ascii_case("transfer-encoding") {
+                bail!("Chunked request bodies are not supported; send a Content-Length");
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        bail!("Request body is larger than {} bytes", MAX_BODY_SIZE);
+    }
+    let mut body = vec![0; content_length];
+    head.into_inner()
+        .read_exact(&mut body)
+        .context("Failed to read request body")?;
+
+    Ok(Request {
+        method: method.to_string(),
+        path,
+        body,
+    })
+}
+
+/// Reads one line of the request line and headers, which may not run past
+/// the `MAX_HEAD_SIZE` that `head` is limited to.
+fn read_head_line(head: &mut Take<impl BufRead>, line: &mut String) -> Result<()> {
+    head.read_line(line)?;
+    if !line.ends_with('\n') {
+        if head.limit() == 0 {
+            bail!("Request headers are larger than {} bytes", MAX_HEAD_SIZE);
+        }
+        bail!("Connection closed before the end of the headers");
+    }
+    Ok(())
+}
+
+/// Extracts the SQL text and parameter values from a `/query` request body.
+fn query_sql(body: &[u8]) -> Result<(String, Vec<Value>)> {
+    let body = std::str::from_utf8(body).context("Request body is not valid UTF-8")?;
+    let request = parse_json(body).context("Request body is not valid JSON")?;
+    request_sql(&request)
+}
+
+/// Extracts the SQL text from a `{"sql": "...", "params": [...]}` request, as
+/// sent to `POST /query` and to `--pipe` mode, with the values of its
+/// parameters, `?1` first: `params` is an array of values for `?1`, `?2`,
+/// ..., or an object of values by name, as in `{":country": "Chad"}`.
+pub fn request_sql(request: &Json) -> Result<(String, Vec<Value>)> {
+    let Some(Json::String(sql)) = request.get("sql") else {
+        bail!("Request must be a JSON object with a \"sql\" string");
+    };
+    let sql = sql.trim().trim_end_matches(';').trim();
+    let names = parameters(sql)?;
+    let mut values = vec![Value::Null; names.len()];
+    match request.get("params") {
+        None | Some(Json::Null) => {}
+        Some(Json::Array(params)) => {
+            if params.len() > names.len() {
+                bail!(
+                    "{} parameters given, but the statement has {}",
+                    params.len(),
+                    names.len()
+                );
+            }
+            for (slot, param) in values.iter_mut().zip(params) {
+                *slot = param_value(param)?;
+            }
+        }
+        Some(Json::Object(params)) => {
+            for (name, param) in params {
+                let Some(i) = names.iter().position(|n| n.as_deref() == Some(name)) else {
+                    bail!("No parameter named {}", name);
+                };
+                values[i] = param_value(param)?;
+            }
+        }
+        Some(_) => bail!("\"params\" must be an array or an object"),
+    }
+    Ok((sql.to_string(), values))
+}
+
+/// The value a JSON parameter binds; `true` and `false` are 1 and 0.
+fn param_value(param: &Json) -> Result<Value> {
+    Ok(match param {
+        Json::Null => Value::Null,
+        Json::Bool(b) => Value::Int(*b as i64),
+        Json::Int(i) => Value::Int(*i),
+        Json::Real(f) => Value::Float(*f),
+        Json::Number(_) => param.to_value(),
+        Json::String(s) => Value::Text(s.as_str().into()),
+        Json::Array(_) | Json::Object(_) => {
+            bail!("A parameter must be null, a boolean, a number or a string")
+        }
+    })
+}
+
+fn handle_query(db: &mut Database, body: &[u8], mut stream: TcpStream) -> Result<()> {
+    let prepared = query_sql(body).and_then(|(sql, values)| {
+        db.begin_statement()?;
+        db.set_parameters(values);
+        prepare(db, &sql)
+    });
+    let prepared = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => return respond_error(&mut stream, 400, &format!("{:#}", e)),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+    )?;
+    let mut out = ChunkedWriter::new(stream);
+
+    let columns = prepared
+        .columns()
+        .iter()
+        .map(|name| Json::String(name.clone()))
+        .collect();
+    write!(out, "{{\"columns\":{},\"rows\":[", Json::Array(columns))?;
+
+    let mut first = true;
+    let result = prepared.run(db, |row| {
+        if !first {
+            out.write_all(b",")?;
+        }
+        first = false;
+        let row = Json::Array(row.iter().map(Json::from).collect());
+        write!(out, "{}", row)?;
+        Ok(())
+    });
+
+    // The status line is already sent, so a failure part-way through the rows
+    // is reported in the body.
+    match result {
+        Ok(()) => write!(out, "]}}")?,
+        Err(e) => write!(out, "],\"error\":{}}}", Json::String(format!("{:#}", e)))?,
+    }
+    out.finish()
+}
+
+fn respond_error(stream: &mut TcpStream, status: u16, message: &str) -> Result<()> {
+    let reason = match status {
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Error",
+    };
+    let body = Json::Object(vec![(
+        "error".to_string(),
+        Json::String(message.to_string()),
+    )]);
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+/// Frames everything written to it as HTTP/1.1 chunks.
+struct ChunkedWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    fn new(inner: W) -> Self {
+        ChunkedWriter {
+            inner,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn send_chunk(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            write!(self.inner, "{:x}\r\n", self.buffer.len())?;
+            self.inner.write_all(&self.buffer)?;
+            self.inner.write_all(b"\r\n")?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.send_chunk()?;
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_chunk()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_request, MAX_HEAD_SIZE};
+    use std::io::Cursor;
+
+    #[test]
+    fn request_heads_are_capped() {
+        let request = |head: String| read_request(&mut Cursor::new(head.into_bytes()));
+
+        let body = r#"{"sql": "SELECT 1"}"#;
+        let parsed = request(format!(
+            "POST /query?x=1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ))
+        .unwrap();
+        assert_eq!(
+            (parsed.method.as_str(), parsed.path.as_str()),
+            ("POST", "/query")
+        );
+        assert_eq!(parsed.body, body.as_bytes());
+
+        let long = "a".repeat(MAX_HEAD_SIZE as usize);
+        let error = request(format!("POST /query HTTP/1.1\r\nX-Long: {}\r\n\r\n", long))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("larger than"), "{}", error);
+        let error = request(long).unwrap_err().to_string();
+        assert!(error.contains("larger than"), "{}", error);
+        let error = request("POST /query HTTP/1.1\r\nHost: x".to_string())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("closed"), "{}", error);
+    }
+}
@@ -0,0 +1,642 @@
+use crate::backup::backup;
+use crate::database::Database;
+use crate::error::{exit_code, Error, EXIT_SUCCESS};
+use crate::eval::like;
+use crate::hash::{content_hashes, to_hex};
+use crate::json::Json;
+use crate::parser::{is_complete, parameters, split_statements};
+use crate::plan::{self, PlanFormat};
+use crate::query;
+use crate::record::{format_real, Value};
+use crate::schema::format_create_table;
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    /// Values separated by `|`, one row per line.
+    List,
+    Csv,
+    Tabs,
+    /// One `column = value` line per value, rows separated by a blank line.
+    Line,
+    /// A JSON array of objects keyed by column name.
+    Json,
+}
+
+impl OutputMode {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "list" => OutputMode::List,
+            "csv" => OutputMode::Csv,
+            "tabs" => OutputMode::Tabs,
+            "line" => OutputMode::Line,
+            "json" => OutputMode::Json,
+            _ => bail!(Error::Usage(format!(
+                "Unknown mode '{}': expected list, csv, tabs, line or json",
+                name
+            ))),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Colors {
+    /// Color only when stdout is a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// The interactive front end: runs dot-commands and SQL against one database
+/// and formats results according to the session's output settings.
+pub struct Shell {
+    db: Database,
+    mode: OutputMode,
+    headers: bool,
+    null_value: String,
+    colors: Colors,
+    plan_format: PlanFormat,
+    bail: bool,
+    quiet: bool,
+    exit_status: i32,
+    /// Values for `?1`, `?2`, ... in every statement.
+    parameters: Vec<Value>,
+    /// Values for parameters by name, e.g. `:country`.
+    named_parameters: Vec<(String, Value)>,
+}
+
+impl Shell {
+    pub fn new(db: Database) -> Self {
+        Shell {
+            db,
+            mode: OutputMode::List,
+            headers: false,
+            null_value: "NULL".to_string(),
+            colors: Colors::Auto,
+            plan_format: PlanFormat::Rows,
+            bail: false,
+            quiet: false,
+            exit_status: EXIT_SUCCESS,
+            parameters: Vec::new(),
+            named_parameters: Vec::new(),
+        }
+    }
+
+    /// Stop a non-interactive run at the first failing statement instead of
+    /// reporting it and carrying on.
+    pub fn set_bail(&mut self, bail: bool) {
+        self.bail = bail;
+    }
+
+    /// How to print the result of `EXPLAIN`.
+    pub fn set_plan_format(&mut self, plan_format: PlanFormat) {
+        self.plan_format = plan_format;
+    }
+
+    /// Never print prompts, even when stdin is a terminal.
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Values for the parameters of every statement run: `parameters` for
+    /// `?1`, `?2`, ..., and `named` for those by name. A name wins over a
+    /// number, and any parameter left without a value is NULL.
+    pub fn set_parameters(&mut self, parameters: Vec<Value>, named: Vec<(String, Value)>) {
+        self.parameters = parameters;
+        self.named_parameters = named;
+    }
+
+    /// The exit code a batch run should end with: that of the first statement
+    /// that failed, or 0.
+    pub fn exit_status(&self) -> i32 {
+        self.exit_status
+    }
+
+    /// Prints the error of a statement that failed in the REPL and carries on,
+    /// or with `bail` set, hands it back to end the run.
+    fn report(&mut self, error: anyhow::Error, interactive: bool) -> Result<()> {
+        if self.bail && !interactive {
+            return Err(error);
+        }
+        eprintln!("Error: {:#}", error);
+        if !interactive && self.exit_status == EXIT_SUCCESS {
+            self.exit_status = exit_code(&error);
+        }
+        Ok(())
+    }
+
+    pub fn database(&mut self) -> &mut Database {
+        &mut self.db
+    }
+
+    /// Runs a dot-command, or one or more SQL statements separated by `;`
+    /// in order, stopping at the first that fails.
+    pub fn run_command(&mut self, command: &str) -> Result<()> {
+        if command.starts_with('.') {
+            // Another process may have written to the file since the last
+            // statement.
+            self.db.begin_statement()?;
+            let args = split_dot_command(command)?;
+            return self.run_dot_command(&args[0], &args[1..]);
+        }
+        let statements = split_statements(command).map_err(|e| Error::Parse(format!("{:#}", e)))?;
+        for statement in statements {
+            self.db.begin_statement()?;
+            self.handle_select(statement)?;
+        }
+        Ok(())
+    }
+
+    fn run_dot_command(&mut self, name: &str, args: &[String]) -> Result<()> {
+        match (name, args) {
+            (".dbinfo", []) => handle_dbinfo(&mut self.db),
+            (".tables", []) => handle_tables(&mut self.db, None),
+            (".tables", [pattern]) => handle_tables(&mut self.db, Some(pattern)),
+            (".tables", _) => bail!(Error::Usage("Usage: .tables ?LIKE-PATTERN?".to_string())),
+            (".schema", args) => {
+                let (indent, pattern) = match args {
+                    [] => (false, None),
+                    [flag] if flag == "--indent" => (true, None),
+                    [pattern] => (false, Some(pattern.as_str())),
+                    [flag, pattern] if flag == "--indent" => (true, Some(pattern.as_str())),
+                    _ => bail!(Error::Usage(
+                        "Usage: .schema ?--indent? ?LIKE-PATTERN?".to_string()
+                    )),
+                };
+                handle_schema(&mut self.db, indent, pattern)
+            }
+            (".sha3sum", []) => handle_sha3sum(&mut self.db, None),
+            (".sha3sum", [pattern]) => handle_sha3sum(&mut self.db, Some(pattern)),
+            (".sha3sum", _) => bail!(Error::Usage("Usage: .sha3sum ?LIKE-PATTERN?".to_string())),
+            (".mode", [mode]) => {
+                self.mode = OutputMode::parse(mode)?;
+                Ok(())
+            }
+            (".mode", _) => bail!(Error::Usage(
+                "Usage: .mode list|csv|tabs|line|json".to_string()
+            )),
+            (".headers", [switch]) => {
+                self.headers = parse_switch(switch)?;
+                Ok(())
+            }
+            (".headers", _) => bail!(Error::Usage("Usage: .headers on|off".to_string())),
+            (".nullvalue", [text]) => {
+                self.null_value = text.clone();
+                Ok(())
+            }
+            (".nullvalue", _) => bail!(Error::Usage("Usage: .nullvalue STRING".to_string())),
+            (".colors", [choice]) => {
+                self.colors = match choice.as_str() {
+                    "auto" => Colors::Auto,
+                    choice => {
+                        if parse_switch(choice)? {
+                            Colors::Always
+                        } else {
+                            Colors::Never
+                        }
+                    }
+                };
+                Ok(())
+            }
+            (".colors", _) => bail!(Error::Usage("Usage: .colors on|off|auto".to_string())),
+            (".backup" | ".clone", [path]) => {
+                backup(&mut self.db, path)?;
+                Ok(())
+            }
+            (".backup" | ".clone", _) => bail!(Error::Usage(format!("Usage: {} FILE", name))),
+            (".pagecache", [pages]) => {
+                let pages = pages.parse().map_err(|_| {
+                    Error::Usage("Usage: .pagecache PAGES (0 turns the cache off)".to_string())
+                })?;
+                self.db.set_cache_size(pages);
+                Ok(())
+            }
+            (".pagecache", _) => bail!(Error::Usage(
+                "Usage: .pagecache PAGES (0 turns the cache off)".to_string()
+            )),
+            _ => bail!(Error::Usage(format!("Unsupported command: {}", name))),
+        }
+    }
+
+    /// Runs a file of commands, one per line, as used for `~/.sequelrc`. Lines
+    /// starting with `#` or `--` are comments. A failing line is reported and
+    /// skipped so one typo doesn't lose the rest of the settings.
+    pub fn run_script(&mut self, path: &Path) -> Result<()> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        for (number, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("--") {
+                continue;
+            }
+            let command = line.strip_suffix(';').unwrap_or(line).trim();
+            if let Err(e) = self.run_command(command) {
+                eprintln!("Error: {}:{}: {:#}", path.display(), number + 1, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads commands from stdin until EOF or `.quit`. Dot-commands are one
+    /// line; SQL statements may span lines and end with `;`. Failing
+    /// statements are reported on stderr; see `exit_status` for the result of
+    /// a piped-in script.
+    pub fn run_repl(&mut self) -> Result<()> {
+        let stdin = std::io::stdin();
+        let interactive = stdin.is_terminal();
+        let mut pending = String::new();
+
+        loop {
+            if interactive && !self.quiet {
+                print!(
+                    "{}",
+                    if pending.is_empty() {
+                        "sequel> "
+                    } else {
+                        "   ...> "
+                    }
+                );
+                std::io::stdout().flush()?;
+            }
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim();
+
+            if pending.is_empty() {
+                if line.is_empty() {
+                    continue;
+                }
+                if line == ".quit" || line == ".exit" {
+                    break;
+                }
+                if line.starts_with('.') {
+                    if let Err(e) = self.run_command(line) {
+                        self.report(e, interactive)?;
+                    }
+                    continue;
+                }
+            }
+
+            // Keep the line break, which ends any `--` comment on the line.
+            if !pending.is_empty() {
+                pending.push('\n');
+            }
+            pending.push_str(line);
+            if is_complete(&pending) {
+                let result = self.run_command(pending.trim());
+                pending.clear();
+                if let Err(e) = result {
+                    self.report(e, interactive)?;
+                }
+            }
+        }
+
+        if !pending.trim().is_empty() {
+            if let Err(e) = self.run_command(pending.trim()) {
+                self.report(e, interactive)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn use_colors(&self) -> bool {
+        match self.colors {
+            Colors::Auto => std::io::stdout().is_terminal(),
+            Colors::Always => true,
+            Colors::Never => false,
+        }
+    }
+
+    /// The session's values for the parameters of `sql`, `?1` first.
+    fn parameter_values(&self, sql: &str) -> Result<Vec<Value>> {
+        let names = parameters(sql)?;
+        let mut values = self.parameters.clone();
+        values.resize(names.len(), Value::Null);
+        for (name, value) in &self.named_parameters {
+            if let Some(i) = names.iter().position(|n| n.as_deref() == Some(name)) {
+                values[i] = value.clone();
+            }
+        }
+        Ok(values)
+    }
+
+    fn handle_select(&mut self, sql: &str) -> Result<()> {
+        let values = self
+            .parameter_values(sql)
+            .map_err(|e| Error::Parse(format!("{:#}", e)))?;
+        self.db.set_parameters(values);
+        let prepared = query::prepare(&mut self.db, sql)?;
+        // A query plan is always drawn; EXPLAIN's rows print as rows unless
+        // another format is asked for.
+        if prepared.is_query_plan()
+            || (prepared.is_explain() && self.plan_format != PlanFormat::Rows)
+        {
+            let mut rows = Vec::new();
+            prepared.run(&mut self.db, |row| {
+                rows.push(row);
+                Ok(())
+            })?;
+            match self.plan_format {
+                PlanFormat::Rows => write!(std::io::stdout(), "{}", plan::to_tree(&rows)),
+                PlanFormat::Dot => write!(std::io::stdout(), "{}", plan::to_dot(&rows)),
+                PlanFormat::Json => writeln!(std::io::stdout(), "{}", plan::to_json(&rows)),
+            }?;
+            return Ok(());
+        }
+        let columns = prepared.columns().to_vec();
+        let colors = self.use_colors() && !matches!(self.mode, OutputMode::Csv | OutputMode::Json);
+        let (mode, headers, null_value) = (self.mode, self.headers, self.null_value.clone());
+        let name_width = columns.iter().map(|name| name.len()).max().unwrap_or(0);
+
+        let mut stdout = std::io::stdout().lock();
+        let mut row_count = 0;
+        prepared.run(&mut self.db, |row| {
+            let out = &mut stdout;
+            let first = row_count == 0;
+            row_count += 1;
+
+            let text = |value: &Value| -> String {
+                match value {
+                    Value::Null if colors => format!("{}{}{}", DIM, null_value, RESET),
+                    Value::Null => null_value.clone(),
+                    value => display_value(value),
+                }
+            };
+
+            match mode {
+                OutputMode::List | OutputMode::Tabs | OutputMode::Csv => {
+                    let separator = match mode {
+                        OutputMode::List => "|",
+                        OutputMode::Tabs => "\t",
+                        _ => ",",
+                    };
+                    let field = |s: String| {
+                        if mode == OutputMode::Csv {
+                            csv_quote(s)
+                        } else {
+                            s
+                        }
+                    };
+                    if first && headers {
+                        let header: Vec<String> =
+                            columns.iter().map(|name| field(name.clone())).collect();
+                        if colors {
+                            writeln!(out, "{}{}{}", BOLD, header.join(separator), RESET)?;
+                        } else {
+                            writeln!(out, "{}", header.join(separator))?;
+                        }
+                    }
+                    let values: Vec<String> = row
+                        .iter()
+                        .map(|value| match value {
+                            // Numbers never need quoting.
+                            Value::Int(_) | Value::Float(_) => text(value),
+                            _ => field(text(value)),
+                        })
+                        .collect();
+                    writeln!(out, "{}", values.join(separator))?;
+                }
+                OutputMode::Line => {
+                    if !first {
+                        writeln!(out)?;
+                    }
+                    for (name, value) in columns.iter().zip(&row) {
+                        if colors {
+                            write!(out, "{}{:>width$}{}", BOLD, name, RESET, width = name_width)?;
+                        } else {
+                            write!(out, "{:>width$}", name, width = name_width)?;
+                        }
+                        writeln!(out, " = {}", text(value))?;
+                    }
+                }
+                OutputMode::Json => {
+                    let object = Json::Object(
+                        columns
+                            .iter()
+                            .zip(&row)
+                            .map(|(name, value)| (name.clone(), Json::from(value)))
+                            .collect(),
+                    );
+                    write!(out, "{}{}", if first { "[" } else { ",\n" }, object)?;
+                }
+            }
+            Ok(())
+        })?;
+
+        if mode == OutputMode::Json && row_count > 0 {
+            writeln!(stdout, "]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the startup file is looked for: `$XDG_CONFIG_HOME/sequel/sequelrc`
+/// (or `~/.config/sequel/sequelrc`) if it exists, otherwise `~/.sequelrc`.
+pub fn config_file() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let xdg_config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+
+    xdg_config
+        .map(|dir| dir.join("sequel").join("sequelrc"))
+        .into_iter()
+        .chain(home.map(|home| home.join(".sequelrc")))
+        .find(|path| path.is_file())
+}
+
+fn parse_switch(value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "on" | "yes" | "true" | "1" => Ok(true),
+        "off" | "no" | "false" | "0" => Ok(false),
+        _ => bail!(Error::Usage(format!("Expected on or off, got '{}'", value))),
+    }
+}
+
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::Text(value) => value.to_string(),
+        Value::Int(value) => value.to_string(),
+        Value::Float(value) => format_real(*value),
+        Value::Blob(_) => "[BLOB]".to_string(),
+        Value::Null => "NULL".to_string(),
+    }
+}
+
+/// Quotes a CSV field when it holds a separator, quote, line break or
+/// surrounding space, doubling any embedded quotes.
+fn csv_quote(field: String) -> String {
+    if field.contains([',', '"', '\n', '\r']) || field.starts_with(' ') || field.ends_with(' ') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// Splits a dot-command into its words. Like the sqlite3 shell, arguments may
+/// be wrapped in single or double quotes to include spaces.
+fn split_dot_command(command: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = command.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut arg = String::new();
+        if c == '\'' || c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some(ch) if ch == c => break,
+                    Some(ch) => arg.push(ch),
+                    None => bail!(Error::Usage(format!("Unterminated {} in: {}", c, command))),
+                }
+            }
+        } else {
+            while let Some(&ch) = chars.peek() {
+                if ch.is_whitespace() {
+                    break;
+                }
+                arg.push(ch);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+/// Prints the database header fields and schema object counts, following the
+/// sqlite3 shell's `.dbinfo`.
+fn handle_dbinfo(db: &mut Database) -> Result<()> {
+    let header = db.read_header()?;
+    let schema = db.read_schema()?;
+
+    let text_encoding = match header.text_encoding {
+        1 => "utf8",
+        2 => "utf16le",
+        3 => "utf16be",
+        _ => "unknown",
+    };
+    // Formats 1 and 2 are the rollback journal and WAL file formats.
+    let journal_mode = match header.write_format {
+        1 => "rollback journal",
+        2 => "wal",
+        _ => "unknown",
+    };
+    let count = |typ: &str| schema.iter().filter(|entry| entry.typ == typ).count();
+    let schema_size: usize = schema
+        .iter()
+        .filter_map(|entry| entry.sql.as_ref())
+        .map(|sql| sql.len())
+        .sum();
+
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "database page size: {}", header.page_size)?;
+    writeln!(out, "write format: {}", header.write_format)?;
+    writeln!(out, "read format: {}", header.read_format)?;
+    writeln!(out, "journal mode: {}", journal_mode)?;
+    writeln!(out, "reserved bytes: {}", header.reserved_bytes)?;
+    writeln!(out, "file change counter: {}", header.change_counter)?;
+    writeln!(out, "database page count: {}", header.page_count)?;
+    writeln!(out, "freelist page count: {}", header.freelist_count)?;
+    writeln!(out, "schema cookie: {}", header.schema_cookie)?;
+    writeln!(out, "schema format: {}", header.schema_format)?;
+    writeln!(out, "default cache size: {}", header.default_cache_size)?;
+    writeln!(out, "autovacuum top root: {}", header.autovacuum_top_root)?;
+    writeln!(out, "incremental vacuum: {}", header.incremental_vacuum)?;
+    writeln!(
+        out,
+        "text encoding: {} ({})",
+        header.text_encoding, text_encoding
+    )?;
+    writeln!(out, "user version: {}", header.user_version)?;
+    writeln!(out, "application id: {}", header.application_id)?;
+    writeln!(out, "software version: {}", header.software_version)?;
+    writeln!(out, "number of tables: {}", count("table"))?;
+    writeln!(out, "number of indexes: {}", count("index"))?;
+    writeln!(out, "number of triggers: {}", count("trigger"))?;
+    writeln!(out, "number of views: {}", count("view"))?;
+    writeln!(out, "schema size: {}", schema_size)?;
+    Ok(())
+}
+
+/// Lists user tables, optionally only those whose name matches a LIKE pattern.
+/// Prints the stored CREATE statements of the tables matching `pattern` and
+/// of everything on them. `indent` lays out each CREATE TABLE one column per
+/// line; otherwise statements are shown as they were written.
+fn handle_schema(db: &mut Database, indent: bool, pattern: Option<&str>) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    for entry in db.read_schema()? {
+        let Some(sql) = &entry.sql else {
+            continue;
+        };
+        if pattern.is_some_and(|pattern| !like(pattern, &entry.tbl_name, None)) {
+            continue;
+        }
+        // CREATE VIRTUAL TABLE takes module arguments, not columns.
+        let plain_table = entry.typ == "table"
+            && sql
+                .split_whitespace()
+                .nth(1)
+                .is_some_and(|word| word.eq_ignore_ascii_case("table"));
+        match format_create_table(sql) {
+            Ok(formatted) if indent && plain_table => writeln!(out, "{};", formatted),
+            _ => writeln!(out, "{};", sql),
+        }?;
+    }
+    Ok(())
+}
+
+/// Prints the content hash of every table, then one over all of them; two
+/// databases holding the same rows print the same hashes.
+fn handle_sha3sum(db: &mut Database, pattern: Option<&str>) -> Result<()> {
+    let hashes = content_hashes(db, pattern)?;
+    let mut out = std::io::stdout().lock();
+    for (table, hash) in &hashes.tables {
+        writeln!(out, "{}  {}", to_hex(hash), table)?;
+    }
+    writeln!(out, "{}  (total)", to_hex(&hashes.total))?;
+    Ok(())
+}
+
+fn handle_tables(db: &mut Database, pattern: Option<&str>) -> Result<()> {
+    let schema = db.read_schema()?;
+
+    let mut table_names = Vec::new();
+    for entry in schema {
+        if entry.typ == "table"
+            && !entry.tbl_name.starts_with("sqlite_")
+            && pattern.map_or(true, |pattern| like(pattern, &entry.tbl_name, None))
+        {
+            table_names.push(entry.tbl_name);
+        }
+    }
+    for name in db.virtual_table_names() {
+        if pattern.map_or(true, |pattern| like(pattern, &name, None)) {
+            table_names.push(name);
+        }
+    }
+    for name in db.temp_table_names() {
+        if pattern.map_or(true, |pattern| like(pattern, &name, None)) {
+            table_names.push(format!("temp.{}", name));
+        }
+    }
+
+    if !table_names.is_empty() {
+        writeln!(std::io::stdout(), "{}", table_names.join(" "))?;
+    }
+    Ok(())
+}
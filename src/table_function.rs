@@ -0,0 +1,626 @@
+use crate::database::Database;
+use crate::fts5::full_text_table;
+use crate::functions::text_of;
+use crate::json::{json_argument, key_path_step, Json};
+use crate::parser::CompareOp;
+use crate::printf::int_arg;
+use crate::query::prepare;
+use crate::record::Value;
+use crate::schema::{
+    parse_create_index, parse_create_table, split_top_level, ColumnDef, TableDef, STRICT_TYPES,
+};
+use crate::virtual_table::Constraint;
+use anyhow::{bail, Result};
+
+/// A built-in table-valued function, which a query reads from like a table:
+/// `SELECT key, value FROM json_each(tags)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TableFunction {
+    /// `json_each(json [, path])`: one row per element of the array or
+    /// object at `path` (`$` if left out), or a single row if it is neither.
+    JsonEach,
+    /// `generate_series(start [, stop [, step]])`: the integers from `start`
+    /// to `stop` (4294967295 if left out), `step` (1) apart. A negative
+    /// step counts down from `start`.
+    GenerateSeries,
+    /// `pragma_table_info(table)`: one row per column of `table`, like
+    /// `PRAGMA table_info`.
+    PragmaTableInfo,
+    /// `pragma_table_xinfo(table)`: `pragma_table_info` plus whether each
+    /// column is hidden.
+    PragmaTableXinfo,
+    /// `pragma_index_list(table)`: one row per index on `table`, newest
+    /// first.
+    PragmaIndexList,
+    /// `pragma_index_info(index)`: one row per key column of `index`.
+    PragmaIndexInfo,
+    /// `pragma_table_list([table])`: one row per table, view and virtual
+    /// table, or just `table`, with whether it is WITHOUT ROWID and STRICT.
+    PragmaTableList,
+}
+
+impl TableFunction {
+    /// The function called `name`, in any case.
+    pub fn named(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "json_each" => Some(TableFunction::JsonEach),
+            "generate_series" => Some(TableFunction::GenerateSeries),
+            "pragma_table_info" => Some(TableFunction::PragmaTableInfo),
+            "pragma_table_xinfo" => Some(TableFunction::PragmaTableXinfo),
+            "pragma_index_list" => Some(TableFunction::PragmaIndexList),
+            "pragma_index_info" => Some(TableFunction::PragmaIndexInfo),
+            "pragma_table_list" => Some(TableFunction::PragmaTableList),
+            _ => None,
+        }
+    }
+
+    pub fn columns(self) -> Vec<ColumnDef> {
+        let names: &[&str] = match self {
+            TableFunction::JsonEach => &[
+                "key", "value", "type", "atom", "id", "parent", "fullkey", "path",
+            ],
+            TableFunction::GenerateSeries => &["value", "start", "stop", "step"],
+            TableFunction::PragmaTableInfo => {
+                &["cid", "name", "type", "notnull", "dflt_value", "pk"]
+            }
+            TableFunction::PragmaTableXinfo => &[
+                "cid",
+                "name",
+                "type",
+                "notnull",
+                "dflt_value",
+                "pk",
+                "hidden",
+            ],
+            TableFunction::PragmaIndexList => &["seq", "name", "unique", "origin", "partial"],
+            TableFunction::PragmaIndexInfo => &["seqno", "cid", "name"],
+            TableFunction::PragmaTableList => &["schema", "name", "type", "ncol", "wr", "strict"],
+        };
+        names.iter().map(|name| ColumnDef::new(name, "")).collect()
+    }
+
+    /// How many of `columns` a `SELECT *` lists: those after them are
+    /// hidden, like the arguments `generate_series` also shows as columns.
+    pub fn visible_columns(self) -> usize {
+        match self {
+            TableFunction::GenerateSeries => 1,
+            _ => self.columns().len(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TableFunction::JsonEach => "json_each",
+            TableFunction::GenerateSeries => "generate_series",
+            TableFunction::PragmaTableInfo => "pragma_table_info",
+            TableFunction::PragmaTableXinfo => "pragma_table_xinfo",
+            TableFunction::PragmaIndexList => "pragma_index_list",
+            TableFunction::PragmaIndexInfo => "pragma_index_info",
+            TableFunction::PragmaTableList => "pragma_table_list",
+        }
+    }
+
+    /// Produces the function's rows for `args`, one at a time. Rows
+    /// `constraints` rule out may be skipped, but needn't be.
+    pub fn for_each_row(
+        self,
+        db: &mut Database,
+        args: Vec<Value>,
+        constraints: &[Constraint],
+        mut on_row: impl FnMut(&mut Database, Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            TableFunction::GenerateSeries => generate_series(db, args, constraints, on_row),
+            TableFunction::PragmaTableList => {
+                let name = match args.as_slice() {
+                    [] => None,
+                    [Value::Null] => return Ok(()),
+                    [name] => Some(text_of(name.clone())),
+                    _ => bail!(
+                        "Wrong number of arguments to {}(): expected 0 or 1, got {}",
+                        self.name(),
+                        args.len()
+                    ),
+                };
+                for row in table_list(db, name.as_deref())? {
+                    on_row(db, row)?;
+                }
+                Ok(())
+            }
+            TableFunction::PragmaTableInfo
+            | TableFunction::PragmaTableXinfo
+            | TableFunction::PragmaIndexList
+            | TableFunction::PragmaIndexInfo => {
+                let name = match args.as_slice() {
+                    [Value::Null] | [_, Value::Null] => return Ok(()),
+                    [name] => text_of(name.clone()),
+                    // Only the main schema has tables in it.
+                    [name, Value::Text(schema)] if schema.eq_ignore_ascii_case("main") => {
+                        text_of(name.clone())
+                    }
+                    [_, _] => return Ok(()),
+                    _ => bail!(
+                        "Wrong number of arguments to {}(): expected 1 or 2, got {}",
+                        self.name(),
+                        args.len()
+                    ),
+                };
+                let rows = match self {
+                    TableFunction::PragmaIndexList => index_list(db, &name)?,
+                    TableFunction::PragmaIndexInfo => index_info(db, &name)?,
+                    _ => table_info(db, &name, self == TableFunction::PragmaTableXinfo)?,
+                };
+                for row in rows {
+                    on_row(db, row)?;
+                }
+                Ok(())
+            }
+            TableFunction::JsonEach => {
+                let (json, path) = match args.as_slice() {
+                    [json] => (json, "$".to_string()),
+                    [_, Value::Null] => return Ok(()),
+                    [json, Value::Text(path)] => (json, path.to_string()),
+                    [_, _] => bail!("json_each() path must be text"),
+                    _ => bail!(
+                        "Wrong number of arguments to json_each(): expected 1 or 2, got {}",
+                        args.len()
+                    ),
+                };
+                let Some(json) = json_argument(json)? else {
+                    return Ok(());
+                };
+                let Some(node) = json.at_path(&path)? else {
+                    return Ok(());
+                };
+                // `id` numbers the rows; SQLite's is an internal offset into
+                // the document with no meaning of its own either.
+                let row = |id: usize, key: Value, fullkey: String, value: &Json| {
+                    let atom = match value {
+                        Json::Array(_) | Json::Object(_) => Value::Null,
+                        value => value.to_value(),
+                    };
+                    vec![
+                        key,
+                        value.to_value(),
+                        Value::Text(value.type_name().into()),
+                        atom,
+                        Value::Int(id as i64),
+                        Value::Null,
+                        Value::Text(fullkey.into()),
+                        Value::Text(path.as_str().into()),
+                    ]
+                };
+                match node {
+                    Json::Array(items) => {
+                        for (i, item) in items.iter().enumerate() {
+                            db.check_interrupt()?;
+                            let fullkey = format!("{}[{}]", path, i);
+                            on_row(db, row(i + 1, Value::Int(i as i64), fullkey, item))?;
+                        }
+                    }
+                    Json::Object(members) => {
+                        for (i, (key, value)) in members.iter().enumerate() {
+                            db.check_interrupt()?;
+                            let fullkey = format!("{}{}", path, key_path_step(key));
+                            on_row(
+                                db,
+                                row(i + 1, Value::Text(key.as_str().into()), fullkey, value),
+                            )?;
+                        }
+                    }
+                    value => on_row(db, row(0, Value::Null, path.clone(), value))?,
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn generate_series(
+    db: &mut Database,
+    args: Vec<Value>,
+    constraints: &[Constraint],
+    mut on_row: impl FnMut(&mut Database, Vec<Value>) -> Result<()>,
+) -> Result<()> {
+    if args.is_empty() || args.len() > 3 {
+        bail!(
+            "Wrong number of arguments to generate_series(): expected 1 to 3, got {}",
+            args.len()
+        );
+    }
+    if args.contains(&Value::Null) {
+        return Ok(());
+    }
+    let mut args = args.into_iter().map(int_arg);
+    let start = args.next().unwrap_or(0);
+    let stop = args.next().unwrap_or(u32::MAX as i64);
+    let step = match args.next().unwrap_or(1) {
+        0 => 1,
+        step => step,
+    };
+
+    // Bounds on `value` narrow the series, so `generate_series(1) WHERE
+    // value < 10` doesn't count to four billion.
+    let (mut low, mut high) = (i64::MIN as i128, i64::MAX as i128);
+    for constraint in constraints.iter().filter(|c| c.column == 0) {
+        let Value::Int(bound) = constraint.value else {
+            continue;
+        };
+        let bound = bound as i128;
+        match constraint.op {
+            CompareOp::Eq => (low, high) = (low.max(bound), high.min(bound)),
+            CompareOp::Lt => high = high.min(bound - 1),
+            CompareOp::LtEq => high = high.min(bound),
+            CompareOp::Gt => low = low.max(bound + 1),
+            CompareOp::GtEq => low = low.max(bound),
+            CompareOp::NotEq => {}
+        }
+    }
+
+    let (start, stop, step) = (start as i128, stop as i128, step as i128);
+    let (mut value, end) = if step > 0 {
+        let skipped = (low - start).max(0);
+        (start + (skipped + step - 1) / step * step, stop.min(high))
+    } else {
+        let skipped = (start - high).max(0);
+        (start + (skipped - step - 1) / -step * step, stop.max(low))
+    };
+    while (step > 0 && value <= end) || (step < 0 && value >= end) {
+        db.check_interrupt()?;
+        on_row(
+            db,
+            vec![
+                Value::Int(value as i64),
+                Value::Int(start as i64),
+                Value::Int(stop as i64),
+                Value::Int(step as i64),
+            ],
+        )?;
+        value += step;
+    }
+    Ok(())
+}
+
+/// The columns of `table` as `PRAGMA table_info` lists them, with the hidden
+/// column on the end for `xinfo`. Temporary and virtual tables only have
+/// names and types.
+fn table_info(db: &mut Database, table: &str, xinfo: bool) -> Result<Vec<Vec<Value>>> {
+    let table_def = match (db.temp_table(table), db.virtual_table(table)) {
+        (Some(temp), _) => temp.def.clone(),
+        (None, Some((def, _))) => def,
+        (None, None) => {
+            let schema = db.read_schema()?;
+            let Some(sql) = schema
+                .iter()
+                .find(|e| e.typ == "table" && e.tbl_name.eq_ignore_ascii_case(table))
+                .and_then(|e| e.sql.as_deref())
+            else {
+                return Ok(Vec::new());
+            };
+            parse_create_table(sql)?
+        }
+    };
+    let primary_key = table_def
+        .key_constraints
+        .iter()
+        .find(|constraint| constraint.primary)
+        .map_or(&[][..], |constraint| &constraint.columns[..]);
+    Ok(table_def
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(cid, column)| {
+            let pk = primary_key
+                .iter()
+                .position(|key| key.eq_ignore_ascii_case(&column.name))
+                .map_or(0, |position| position as i64 + 1);
+            // WITHOUT ROWID tables can't have a NULL in their key.
+            let not_null = column.not_null || (table_def.without_rowid && pk > 0);
+            let mut row = vec![
+                Value::Int(cid as i64),
+                Value::Text(column.name.as_str().into()),
+                Value::Text(declared_type(&column.declared_type).into()),
+                Value::Int(not_null as i64),
+                column
+                    .default
+                    .as_deref()
+                    .map_or(Value::Null, |default| Value::Text(default.into())),
+                Value::Int(pk),
+            ];
+            if xinfo {
+                row.push(Value::Int(0));
+            }
+            row
+        })
+        .collect())
+}
+
+/// The tables as `PRAGMA table_list` lists them, or only those called
+/// `name`: the schema each is in, its type (`table`, `view`, `virtual`, or
+/// `shadow` for the tables an FTS5 table keeps its data in), its number of
+/// columns, and whether it is WITHOUT ROWID and STRICT.
+fn table_list(db: &mut Database, name: Option<&str>) -> Result<Vec<Vec<Value>>> {
+    let row = |schema: &str,
+               table: &str,
+               typ: &str,
+               ncol: Option<usize>,
+               table_def: Option<&TableDef>| {
+        vec![
+            Value::Text(schema.into()),
+            Value::Text(table.into()),
+            Value::Text(typ.into()),
+            ncol.map_or(Value::Null, |ncol| Value::Int(ncol as i64)),
+            Value::Int(table_def.is_some_and(|def| def.without_rowid) as i64),
+            Value::Int(table_def.is_some_and(|def| def.strict) as i64),
+        ]
+    };
+    let schema = db.read_schema()?;
+    let full_text_tables: Vec<_> = schema
+        .iter()
+        .filter(|e| e.typ == "table")
+        .filter_map(|e| {
+            Some((
+                e.tbl_name.as_str(),
+                full_text_table(&schema, &e.tbl_name).ok()??,
+            ))
+        })
+        .collect();
+    let mut rows = Vec::new();
+    for entry in schema
+        .iter()
+        .filter(|e| e.typ == "table" || e.typ == "view")
+    {
+        let sql = entry.sql.as_deref().unwrap_or_default();
+        if entry.typ == "view" {
+            rows.push(row(
+                "main",
+                &entry.tbl_name,
+                "view",
+                view_column_count(db, sql),
+                None,
+            ));
+        } else if let Some((_, table)) = full_text_tables
+            .iter()
+            .find(|(table, _)| *table == entry.tbl_name)
+        {
+            // Its own columns, after the rowid and `<name>_content`'s `id`,
+            // then the hidden ones named after the table and `rank`.
+            let ncol = table.table_def.columns[2..].len() + 2;
+            rows.push(row("main", &entry.tbl_name, "virtual", Some(ncol), None));
+        } else if sql
+            .split_whitespace()
+            .nth(1)
+            .is_some_and(|word| word.eq_ignore_ascii_case("virtual"))
+        {
+            rows.push(row("main", &entry.tbl_name, "virtual", None, None));
+        } else {
+            let table_def = parse_create_table(sql)?;
+            let shadow = full_text_tables.iter().any(|(table, _)| {
+                entry
+                    .tbl_name
+                    .strip_prefix(table)
+                    .and_then(|rest| rest.strip_prefix('_'))
+                    .is_some_and(|suffix| {
+                        FTS5_SHADOW_TABLES
+                            .iter()
+                            .any(|shadow| suffix.eq_ignore_ascii_case(shadow))
+                    })
+            });
+            let typ = if shadow { "shadow" } else { "table" };
+            rows.push(row(
+                "main",
+                &entry.tbl_name,
+                typ,
+                Some(table_def.columns.len()),
+                Some(&table_def),
+            ));
+        }
+    }
+    for table in db.virtual_table_names() {
+        if let Some((table_def, _)) = db.virtual_table(&table) {
+            rows.push(row(
+                "main",
+                &table,
+                "virtual",
+                Some(table_def.columns.len()),
+                None,
+            ));
+        }
+    }
+    rows.push(row("main", "sqlite_schema", "table", Some(5), None));
+    for table in db.temp_table_names() {
+        if let Some(temp) = db.temp_table(&table) {
+            rows.push(row(
+                "temp",
+                &table,
+                "table",
+                Some(temp.def.columns.len()),
+                None,
+            ));
+        }
+    }
+    rows.push(row("temp", "sqlite_temp_schema", "table", Some(5), None));
+    rows.retain(|row| match (name, &row[1]) {
+        (Some(name), Value::Text(table)) => table.eq_ignore_ascii_case(name),
+        _ => true,
+    });
+    Ok(rows)
+}
+
+/// The tables an FTS5 table `t` keeps its data in are `t_<suffix>`.
+const FTS5_SHADOW_TABLES: [&str; 5] = ["config", "content", "data", "docsize", "idx"];
+
+/// How many columns the view `CREATE VIEW ... AS SELECT ...` has: as many as
+/// it names, or else as its SELECT gives. `None` if that can't be run.
+fn view_column_count(db: &mut Database, sql: &str) -> Option<usize> {
+    let position = sql.to_ascii_lowercase().find(" as ")?;
+    let (head, select) = (&sql[..position], &sql[position + " as ".len()..]);
+    if let (Some(open), Some(close)) = (head.find('('), head.rfind(')')) {
+        return Some(split_top_level(&head[open + 1..close]).len());
+    }
+    Some(prepare(db, select).ok()?.columns().len())
+}
+
+/// The indexes on `table` as `PRAGMA index_list` lists them: newest first,
+/// with where each came from (`c` for CREATE INDEX, `u` for UNIQUE, `pk` for
+/// PRIMARY KEY).
+fn index_list(db: &mut Database, table: &str) -> Result<Vec<Vec<Value>>> {
+    let schema = db.read_schema()?;
+    let Some(table_entry) = schema
+        .iter()
+        .find(|e| e.typ == "table" && e.tbl_name.eq_ignore_ascii_case(table))
+    else {
+        return Ok(Vec::new());
+    };
+    let Some(table_def) = table_entry
+        .sql
+        .as_deref()
+        .map(parse_create_table)
+        .transpose()?
+    else {
+        return Ok(Vec::new());
+    };
+    let indexes: Vec<_> = schema
+        .iter()
+        .filter(|e| e.typ == "index" && e.tbl_name.eq_ignore_ascii_case(table))
+        .rev()
+        .collect();
+    let mut listed = Vec::new();
+    for entry in &indexes {
+        listed.push(match &entry.sql {
+            Some(sql) => {
+                let index_def = parse_create_index(sql)?;
+                (entry.name.clone(), index_def.unique, "c", index_def.partial)
+            }
+            None => {
+                let columns = autoindex_columns(&entry.name, &table_def);
+                let primary = table_def
+                    .key_constraints
+                    .iter()
+                    .any(|c| c.primary && Some(&c.columns) == columns.as_ref());
+                (
+                    entry.name.clone(),
+                    true,
+                    if primary { "pk" } else { "u" },
+                    false,
+                )
+            }
+        });
+    }
+    // The key of a WITHOUT ROWID table is the table's own b-tree, so it has
+    // no schema entry, but SQLite lists it as the newest autoindex.
+    if let Some((name, _)) = without_rowid_key(&table_entry.tbl_name, &table_def) {
+        let newer = indexes
+            .iter()
+            .take_while(|e| e.sql.is_some() || autoindex_number(&e.name) > autoindex_number(&name))
+            .count();
+        listed.insert(newer, (name, true, "pk", false));
+    }
+    Ok(listed
+        .into_iter()
+        .enumerate()
+        .map(|(seq, (name, unique, origin, partial))| {
+            vec![
+                Value::Int(seq as i64),
+                Value::Text(name.into()),
+                Value::Int(unique as i64),
+                Value::Text(origin.into()),
+                Value::Int(partial as i64),
+            ]
+        })
+        .collect())
+}
+
+/// The key columns of `index` as `PRAGMA index_info` lists them: each with
+/// its position in the table, or -2 and no name for an expression.
+fn index_info(db: &mut Database, index: &str) -> Result<Vec<Vec<Value>>> {
+    let schema = db.read_schema()?;
+    let entry = schema
+        .iter()
+        .find(|e| e.typ == "index" && e.name.eq_ignore_ascii_case(index));
+    // Without an entry, it may be the key of a WITHOUT ROWID table.
+    let table = match entry {
+        Some(entry) => &entry.tbl_name,
+        None => match index.strip_prefix("sqlite_autoindex_") {
+            Some(rest) => rest.rsplit_once('_').map_or(rest, |(table, _)| table),
+            None => return Ok(Vec::new()),
+        },
+    };
+    let Some(table_def) = schema
+        .iter()
+        .find(|e| e.typ == "table" && e.tbl_name.eq_ignore_ascii_case(table))
+        .and_then(|e| e.sql.as_deref())
+        .map(parse_create_table)
+        .transpose()?
+    else {
+        return Ok(Vec::new());
+    };
+    let columns = match entry {
+        Some(entry) => match &entry.sql {
+            Some(sql) => parse_create_index(sql)?.columns,
+            None => autoindex_columns(&entry.name, &table_def).unwrap_or_default(),
+        },
+        None => match without_rowid_key(table, &table_def) {
+            Some((name, columns)) if name.eq_ignore_ascii_case(index) => columns,
+            _ => return Ok(Vec::new()),
+        },
+    };
+    Ok(columns
+        .iter()
+        .enumerate()
+        .map(|(seqno, name)| {
+            let cid = table_def
+                .columns
+                .iter()
+                .position(|column| column.name.eq_ignore_ascii_case(name));
+            vec![
+                Value::Int(seqno as i64),
+                Value::Int(cid.map_or(-2, |cid| cid as i64)),
+                match cid {
+                    Some(cid) => Value::Text(table_def.columns[cid].name.as_str().into()),
+                    None => Value::Null,
+                },
+            ]
+        })
+        .collect())
+}
+
+/// A declared type as `PRAGMA table_info` shows it: the names STRICT allows
+/// in upper case, anything else as written.
+fn declared_type(declared: &str) -> String {
+    match STRICT_TYPES
+        .iter()
+        .find(|name| declared.eq_ignore_ascii_case(name))
+    {
+        Some(name) => name.to_string(),
+        None => declared.to_string(),
+    }
+}
+
+/// The name and columns SQLite gives the PRIMARY KEY of a WITHOUT ROWID
+/// table, numbered after its other autoindexes.
+fn without_rowid_key(table: &str, table_def: &TableDef) -> Option<(String, Vec<String>)> {
+    if !table_def.without_rowid {
+        return None;
+    }
+    let columns = table_def
+        .key_constraints
+        .iter()
+        .find(|constraint| constraint.primary)?
+        .columns
+        .clone();
+    let number = (1..)
+        .map_while(|number| table_def.autoindex_columns(number))
+        .position(|key| key == columns)?
+        + 1;
+    Some((format!("sqlite_autoindex_{table}_{number}"), columns))
+}
+
+/// The key columns of `sqlite_autoindex_<table>_<number>`.
+fn autoindex_columns(name: &str, table_def: &TableDef) -> Option<Vec<String>> {
+    table_def.autoindex_columns(autoindex_number(name)?)
+}
+
+fn autoindex_number(name: &str) -> Option<usize> {
+    name.rsplit('_').next()?.parse().ok()
+}
@@ -0,0 +1,72 @@
+use crate::budget::MemoryBudget;
+use crate::record::Value;
+use crate::schema::{ColumnDef, TableDef};
+use anyhow::Result;
+use std::cmp::Ordering;
+
+/// A table that lives in memory for the rest of the session: what `CREATE TEMP
+/// TABLE` creates, and the row store for any step that has to materialize its
+/// input before it can produce output. Rows are kept in insertion order and
+/// charged against the statement's memory budget as they are added; nothing
+/// is ever spilled to disk.
+#[derive(Debug, Clone)]
+pub struct TempTable {
+    pub def: TableDef,
+    rows: Vec<Vec<Value>>,
+}
+
+impl TempTable {
+    pub fn new(columns: Vec<ColumnDef>) -> Self {
+        TempTable {
+            def: TableDef::with_columns(columns),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Value>, budget: &mut MemoryBudget) -> Result<()> {
+        budget.charge(row_size(&row))?;
+        self.rows.push(row);
+        Ok(())
+    }
+
+    pub fn sort_by(&mut self, compare: impl FnMut(&Vec<Value>, &Vec<Value>) -> Ordering) {
+        self.rows.sort_by(compare);
+    }
+
+    /// Drops every row after the first `len`, giving their memory back.
+    pub fn truncate(&mut self, len: usize, budget: &mut MemoryBudget) {
+        for row in self.rows.drain(len.min(self.rows.len())..) {
+            budget.release(row_size(&row));
+        }
+    }
+
+    pub fn rows(&self) -> &[Vec<Value>] {
+        &self.rows
+    }
+
+    pub fn into_rows(self) -> Vec<Vec<Value>> {
+        self.rows
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+}
+
+/// Approximate bytes a row occupies once buffered.
+pub fn row_size(row: &[Value]) -> usize {
+    row.iter()
+        .map(|value| {
+            std::mem::size_of::<Value>()
+                + match value {
+                    Value::Text(text) => text.len(),
+                    Value::Blob(bytes) => bytes.len(),
+                    _ => 0,
+                }
+        })
+        .sum()
+}
@@ -0,0 +1,404 @@
+use crate::budget::MemoryBudget;
+use crate::copy::IndexBuilder;
+use crate::database::{Database, SchemaEntry};
+use crate::eval::total_order;
+use crate::record::{encode_record_in, TextEncoding, Value};
+use crate::schema::parse_create_table;
+use crate::writer::{DatabaseWriter, TableBuilder};
+use anyhow::{bail, Context, Result};
+use std::cmp::Ordering;
+use std::io::Cursor;
+
+/// A small database described in code and built in memory, so tests don't
+/// need binary fixtures. Tables and indexes are given by their `CREATE`
+/// statements, tables with their rows; the pages follow from the data the
+/// way SQLite would lay them out. A small page size and a few hundred rows
+/// give interior pages, and values longer than about a quarter of a page
+/// give overflow chains.
+///
+/// ```
+/// use sequel::record::Value;
+/// use sequel::testing::TestDatabase;
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let image = TestDatabase::new()
+///     .page_size(512)
+///     .table(
+///         "CREATE TABLE fruit (id INTEGER PRIMARY KEY, name TEXT)",
+///         (1..=300).map(|i| vec![Value::Int(i), Value::Text(format!("fruit {}", i).into())]),
+///     )
+///     .index("CREATE INDEX fruit_name ON fruit (name)")
+///     .build()?;
+///
+/// let mut conn = sequel::Connection::from_bytes(image)?;
+/// let mut rows = Vec::new();
+/// conn.query_each("SELECT id FROM fruit WHERE name = 'fruit 7'", |row| {
+///     rows.push(row);
+///     Ok(())
+/// })?;
+/// assert_eq!(rows, vec![vec![Value::Int(7)]]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TestDatabase {
+    page_size: usize,
+    text_encoding: TextEncoding,
+    tables: Vec<(String, Vec<Vec<Value>>)>,
+    indexes: Vec<String>,
+}
+
+impl Default for TestDatabase {
+    fn default() -> Self {
+        TestDatabase {
+            page_size: 4096,
+            text_encoding: TextEncoding::Utf8,
+            tables: Vec::new(),
+            indexes: Vec::new(),
+        }
+    }
+}
+
+impl TestDatabase {
+    pub fn new() -> Self {
+        TestDatabase::default()
+    }
+
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn text_encoding(mut self, text_encoding: TextEncoding) -> Self {
+        self.text_encoding = text_encoding;
+        self
+    }
+
+    /// Adds a table and its rows, one value per column in declaration order.
+    /// An integer in the INTEGER PRIMARY KEY column becomes the row's rowid;
+    /// other rows get the next free rowid, as on INSERT. Rows may be shorter
+    /// than the table, like rows written before an `ALTER TABLE ADD COLUMN`.
+    /// A WITHOUT ROWID table needs every value and is stored in PRIMARY KEY
+    /// order.
+    pub fn table(
+        mut self,
+        create_table_sql: &str,
+        rows: impl IntoIterator<Item = Vec<Value>>,
+    ) -> Self {
+        self.tables
+            .push((create_table_sql.to_string(), rows.into_iter().collect()));
+        self
+    }
+
+    /// Adds an index over one of the tables, filled from its rows. The
+    /// automatic indexes of PRIMARY KEY and UNIQUE constraints are always
+    /// created and don't need to be added. Keys may be `DESC` and collated
+    /// by any built-in collation.
+    pub fn index(mut self, create_index_sql: &str) -> Self {
+        self.indexes.push(create_index_sql.to_string());
+        self
+    }
+
+    /// Builds the database file image. Rows that break a PRIMARY KEY or
+    /// UNIQUE constraint are an error, as they would be on INSERT.
+    pub fn build(&self) -> Result<Vec<u8>> {
+        let mut writer = DatabaseWriter::new(Cursor::new(Vec::new()), self.page_size)?;
+        writer.set_text_encoding(self.text_encoding);
+        let mut budget = MemoryBudget::new(None);
+        let mut schema = Vec::new();
+
+        for index_sql in &self.indexes {
+            let table = index_table_name(index_sql)?;
+            if !self
+                .tables
+                .iter()
+                .any(|(sql, _)| table_name(sql).is_ok_and(|name| name.eq_ignore_ascii_case(&table)))
+            {
+                bail!("Index '{}' is on unknown table '{}'", index_sql, table);
+            }
+        }
+
+        for (table_sql, rows) in &self.tables {
+            let name = table_name(table_sql)?;
+            let table_def = parse_create_table(table_sql)?;
+            if table_def.without_rowid {
+                schema.push(self.without_rowid_table(&mut writer, table_sql, rows)?);
+                continue;
+            }
+            let alias = table_def.rowid_alias.as_ref().and_then(|alias| {
+                table_def
+                    .columns
+                    .iter()
+                    .position(|column| column.name.eq_ignore_ascii_case(alias))
+            });
+
+            let mut rows_by_rowid = Vec::with_capacity(rows.len());
+            let mut next_rowid = 1;
+            for row in rows {
+                if row.len() > table_def.columns.len() {
+                    bail!(
+                        "Row {:?} has more values than table '{}' has columns",
+                        row,
+                        name
+                    );
+                }
+                let mut row = row.clone();
+                // SQLite never writes a record without columns; one NULL reads
+                // back the same.
+                if row.is_empty() {
+                    row.push(Value::Null);
+                }
+                let rowid = match alias.filter(|&i| i < row.len()) {
+                    // The rowid alias column itself is stored as NULL.
+                    Some(i) => match std::mem::replace(&mut row[i], Value::Null) {
+                        Value::Int(rowid) => rowid,
+                        Value::Null => next_rowid,
+                        value => bail!(
+                            "INTEGER PRIMARY KEY values must be integers, got {:?}",
+                            value
+                        ),
+                    },
+                    None => next_rowid,
+                };
+                next_rowid = next_rowid.max(rowid.saturating_add(1));
+                rows_by_rowid.push((rowid, row));
+            }
+            rows_by_rowid.sort_by_key(|(rowid, _)| *rowid);
+            if let Some(pair) = rows_by_rowid.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+                bail!("Duplicate rowid {} in table '{}'", pair[0].0, name);
+            }
+
+            let table_entry = SchemaEntry {
+                typ: "table".to_string(),
+                name: name.clone(),
+                tbl_name: name.clone(),
+                rootpage: 0,
+                sql: Some(table_sql.clone()),
+            };
+            let mut index_entries: Vec<SchemaEntry> = (1..)
+                .map_while(|number| {
+                    table_def.autoindex_columns(number).map(|_| SchemaEntry {
+                        typ: "index".to_string(),
+                        name: format!("sqlite_autoindex_{}_{}", name, number),
+                        tbl_name: name.clone(),
+                        rootpage: 0,
+                        sql: None,
+                    })
+                })
+                .collect();
+            for index_sql in &self.indexes {
+                if index_table_name(index_sql)?.eq_ignore_ascii_case(&name) {
+                    index_entries.push(SchemaEntry {
+                        typ: "index".to_string(),
+                        name: index_name(index_sql)?,
+                        tbl_name: name.clone(),
+                        rootpage: 0,
+                        sql: Some(index_sql.clone()),
+                    });
+                }
+            }
+            let mut indexes = Vec::new();
+            for entry in &index_entries {
+                match IndexBuilder::new(entry, &table_def)? {
+                    Some(index) => indexes.push(index),
+                    None => bail!(
+                        "Index '{}' is not supported: only indexes on plain columns covering every row can be built",
+                        entry.name
+                    ),
+                }
+            }
+
+            let mut table = TableBuilder::new();
+            for (rowid, row) in &rows_by_rowid {
+                table.insert(
+                    &mut writer,
+                    *rowid,
+                    &encode_record_in(row, self.text_encoding),
+                )?;
+                for index in &mut indexes {
+                    index.add(*rowid, row, &mut budget)?;
+                }
+            }
+            schema.push(SchemaEntry {
+                rootpage: table.finish(&mut writer)?,
+                ..table_entry
+            });
+            for index in indexes {
+                schema.push(index.finish(&mut writer, self.text_encoding)?);
+            }
+        }
+
+        Ok(writer.finish(&schema)?.into_inner())
+    }
+
+    /// Writes a WITHOUT ROWID table: an index B-tree of its rows, PRIMARY KEY
+    /// columns first, in key order. Its keys sort as BINARY and other indexes
+    /// on it can't be built.
+    fn without_rowid_table(
+        &self,
+        writer: &mut DatabaseWriter<Cursor<Vec<u8>>>,
+        table_sql: &str,
+        rows: &[Vec<Value>],
+    ) -> Result<SchemaEntry> {
+        let name = table_name(table_sql)?;
+        let table_def = parse_create_table(table_sql)?;
+        let key_columns = table_def
+            .key_constraints
+            .iter()
+            .find(|constraint| constraint.primary)
+            .map_or(0, |constraint| constraint.columns.len());
+        let extra_indexes = (1..)
+            .map_while(|number| table_def.autoindex_columns(number))
+            .count()
+            > 1;
+        if extra_indexes
+            || self.indexes.iter().any(|sql| {
+                index_table_name(sql).is_ok_and(|table| table.eq_ignore_ascii_case(&name))
+            })
+        {
+            bail!(
+                "Indexes on WITHOUT ROWID table '{}' are not supported",
+                name
+            );
+        }
+
+        let order = table_def.storage_order();
+        let mut records = Vec::with_capacity(rows.len());
+        for row in rows {
+            if row.len() != table_def.columns.len() {
+                bail!(
+                    "Row {:?} doesn't have a value for each column of table '{}'",
+                    row,
+                    name
+                );
+            }
+            records.push(order.iter().map(|&i| row[i].clone()).collect::<Vec<_>>());
+        }
+        let compare_keys = |a: &[Value], b: &[Value]| {
+            a[..key_columns]
+                .iter()
+                .zip(&b[..key_columns])
+                .map(|(a, b)| total_order(a, b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        };
+        records.sort_by(|a, b| compare_keys(a, b));
+        if let Some(pair) = records
+            .windows(2)
+            .find(|pair| compare_keys(&pair[0], &pair[1]).is_eq())
+        {
+            bail!(
+                "Duplicate PRIMARY KEY {:?} in table '{}'",
+                &pair[0][..key_columns],
+                name
+            );
+        }
+        let records: Vec<Vec<u8>> = records
+            .iter()
+            .map(|record| encode_record_in(record, self.text_encoding))
+            .collect();
+        Ok(SchemaEntry {
+            typ: "table".to_string(),
+            name: name.clone(),
+            tbl_name: name,
+            rootpage: writer.write_index(&records)?,
+            sql: Some(table_sql.to_string()),
+        })
+    }
+
+    /// Builds the database and opens it.
+    pub fn open(&self) -> Result<Database> {
+        Database::from_bytes(self.build()?)
+    }
+}
+
+/// The words of a `CREATE` statement before its column list.
+fn head_words(sql: &str) -> Result<Vec<String>> {
+    let head = &sql[..sql.find('(').context("Expected '(' in CREATE statement")?];
+    Ok(head
+        .split_whitespace()
+        .map(|word| {
+            word.trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'))
+                .to_string()
+        })
+        .collect())
+}
+
+fn table_name(create_table_sql: &str) -> Result<String> {
+    head_words(create_table_sql)?
+        .pop()
+        .context("Missing table name in CREATE TABLE")
+}
+
+fn index_on(create_index_sql: &str) -> Result<(Vec<String>, usize)> {
+    let words = head_words(create_index_sql)?;
+    let on = words
+        .iter()
+        .position(|word| word.eq_ignore_ascii_case("on"))
+        .filter(|&on| on > 0 && on + 1 < words.len())
+        .context("Expected CREATE INDEX <name> ON <table> (...)")?;
+    Ok((words, on))
+}
+
+fn index_name(create_index_sql: &str) -> Result<String> {
+    let (words, on) = index_on(create_index_sql)?;
+    Ok(words[on - 1].clone())
+}
+
+fn index_table_name(create_index_sql: &str) -> Result<String> {
+    let (words, on) = index_on(create_index_sql)?;
+    Ok(words[on + 1].clone())
+}
+
+/// The rows `sql` returns from the database `image`.
+#[cfg(test)]
+pub(crate) fn query(image: &[u8], sql: &str) -> Result<Vec<Vec<Value>>> {
+    let mut conn = crate::Connection::from_bytes(image.to_vec())?;
+    let mut rows = Vec::new();
+    conn.query_each(sql, |row| {
+        rows.push(row);
+        Ok(())
+    })?;
+    Ok(rows)
+}
+
+/// The one value `SELECT <expr>` returns, run against an empty database.
+#[cfg(test)]
+pub(crate) fn select(expr: &str) -> Value {
+    let rows = query(
+        &TestDatabase::new().build().unwrap(),
+        &format!("SELECT {}", expr),
+    )
+    .unwrap();
+    match <[_; 1]>::try_from(rows) {
+        Ok([row]) if row.len() == 1 => row.into_iter().next().unwrap(),
+        rows => panic!("SELECT {} returned {:?}", expr, rows),
+    }
+}
+
+/// A database holding just the one table.
+#[cfg(test)]
+pub(crate) fn one_table(
+    create_table_sql: &str,
+    rows: impl IntoIterator<Item = Vec<Value>>,
+) -> Vec<u8> {
+    TestDatabase::new()
+        .table(create_table_sql, rows)
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+pub(crate) fn int(i: i64) -> Value {
+    Value::Int(i)
+}
+
+#[cfg(test)]
+pub(crate) fn real(f: f64) -> Value {
+    Value::Float(f)
+}
+
+#[cfg(test)]
+pub(crate) fn text(s: &str) -> Value {
+    Value::Text(s.into())
+}
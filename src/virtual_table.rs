@@ -0,0 +1,35 @@
+use crate::parser::CompareOp;
+use crate::record::Value;
+use anyhow::Result;
+
+/// A table whose rows come from Rust instead of the database file: a CSV
+/// file, a `Vec` in memory, an API. Registered on a connection under a name,
+/// it's queried like any other table.
+pub trait VirtualTable {
+    /// The columns, as a `CREATE TABLE` statement, like
+    /// `sqlite3_declare_vtab`. The table name in it doesn't matter.
+    fn schema(&self) -> String;
+
+    /// Opens a cursor over the rows of one scan. `constraints` are the
+    /// comparisons with a constant that the WHERE clause ANDs together, for
+    /// a table that can use them to skip rows; it's free to ignore them, as
+    /// the WHERE clause is still checked on every row the cursor gives.
+    fn filter(&self, constraints: &[Constraint]) -> Result<Box<dyn VirtualCursor + '_>>;
+}
+
+/// The rows of one scan of a [`VirtualTable`].
+pub trait VirtualCursor {
+    /// The next row, its values in the order of the schema's columns, or
+    /// `None` once there are no more. Missing trailing values read as NULL.
+    fn next_row(&mut self) -> Result<Option<Vec<Value>>>;
+}
+
+/// `column op value` from the WHERE clause, with the column on the left
+/// whichever way round it was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constraint {
+    /// The column, by its position in the schema.
+    pub column: usize,
+    pub op: CompareOp,
+    pub value: Value,
+}
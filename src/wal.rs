@@ -0,0 +1,391 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom},
+};
+
+const WAL_HEADER_SIZE: u64 = 32;
+const WAL_FRAME_HEADER_SIZE: u64 = 24;
+/// The low bit of the magic number says whether checksums are computed over
+/// big-endian (1) or little-endian (0) 32-bit words.
+const WAL_MAGIC_LE: u32 = 0x377f_0682;
+const WAL_MAGIC_BE: u32 = 0x377f_0683;
+/// Offset of `nBackfill` in the wal-index (`-shm`) file: it follows the two
+/// 48-byte copies of the wal-index header.
+const SHM_BACKFILL_OFFSET: u64 = 96;
+
+/// A read snapshot of the write-ahead log, pinned at the last commit frame
+/// (`mxFrame`) that existed when it was loaded. Frames appended by a writer
+/// afterwards are ignored, so every page of a statement comes from the same
+/// committed state of the database.
+pub struct WalSnapshot {
+    file: File,
+    shm_path: String,
+    page_size: usize,
+    salt: [u8; 8],
+    max_frame: u32,
+    db_size: u32,
+    /// Latest frame (1-based) at or below `max_frame` holding each page.
+    frames: HashMap<u32, u32>,
+}
+
+impl WalSnapshot {
+    /// Loads the `-wal` file next to the database. Returns `None` when there is
+    /// no WAL, its header is invalid, or it holds no committed transaction —
+    /// in all of those cases the database file alone is authoritative.
+    pub fn load(db_path: &str) -> Result<Option<Self>> {
+        let wal_path = format!("{}-wal", db_path);
+        let mut file = match File::open(&wal_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to open WAL file '{}'", wal_path))
+            }
+        };
+
+        let mut reader = BufReader::new(&mut file);
+        let mut header = [0u8; WAL_HEADER_SIZE as usize];
+        if read_fully(&mut reader, &mut header)? < header.len() {
+            return Ok(None);
+        }
+
+        let big_endian = match be_u32(&header[0..4]) {
+            WAL_MAGIC_LE => false,
+            WAL_MAGIC_BE => true,
+            _ => return Ok(None),
+        };
+        let page_size = match be_u32(&header[8..12]) {
+            1 => 65536,
+            size => size as usize,
+        };
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            return Ok(None);
+        }
+        let checksum = wal_checksum(big_endian, [0, 0], &header[..24]);
+        if checksum != [be_u32(&header[24..28]), be_u32(&header[28..32])] {
+            return Ok(None);
+        }
+        let mut salt = [0u8; 8];
+        salt.copy_from_slice(&header[16..24]);
+
+        // Walk frames while their salts and cumulative checksums are valid;
+        // only frames up to the last commit frame are visible to readers.
+        let mut checksum = checksum;
+        let mut frame_header = [0u8; WAL_FRAME_HEADER_SIZE as usize];
+        let mut page = vec![0u8; page_size];
+        let mut pending = Vec::new();
+        let mut frames = HashMap::new();
+        let mut max_frame = 0;
+        let mut db_size = 0;
+        let mut frame_number = 0u32;
+
+        loop {
+            if read_fully(&mut reader, &mut frame_header)? < frame_header.len()
+                || read_fully(&mut reader, &mut page)? < page.len()
+            {
+                break;
+            }
+            frame_number += 1;
+
+            if frame_header[8..16] != salt {
+                break;
+            }
+            checksum = wal_checksum(big_endian, checksum, &frame_header[..8]);
+            checksum = wal_checksum(big_endian, checksum, &page);
+            if checksum != [be_u32(&frame_header[16..20]), be_u32(&frame_header[20..24])] {
+                break;
+            }
+
+            pending.push((be_u32(&frame_header[0..4]), frame_number));
+            let commit_size = be_u32(&frame_header[4..8]);
+            if commit_size != 0 {
+                frames.extend(pending.drain(..));
+                max_frame = frame_number;
+                db_size = commit_size;
+            }
+        }
+
+        if max_frame == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(WalSnapshot {
+            file,
+            shm_path: format!("{}-shm", db_path),
+            page_size,
+            salt,
+            max_frame,
+            db_size,
+            frames,
+        }))
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Identifies the snapshot: the WAL generation (salt) and last commit
+    /// frame. Equal marks mean the WAL holds the same committed pages.
+    pub fn mark(&self) -> ([u8; 8], u32) {
+        (self.salt, self.max_frame)
+    }
+
+    /// Size of the database in pages as of the snapshot's commit.
+    pub fn db_size(&self) -> u32 {
+        self.db_size
+    }
+
+    /// Returns the snapshot's copy of a page, or `None` if the page has not
+    /// been written since the last checkpoint and lives in the database file.
+    pub fn read_page(&mut self, page_number: u32) -> Result<Option<Vec<u8>>> {
+        let Some(&frame) = self.frames.get(&page_number) else {
+            return Ok(None);
+        };
+
+        let frame_size = WAL_FRAME_HEADER_SIZE + self.page_size as u64;
+        let offset = WAL_HEADER_SIZE + (frame as u64 - 1) * frame_size;
+        let mut frame_header = [0u8; WAL_FRAME_HEADER_SIZE as usize];
+        let mut page = vec![0u8; self.page_size];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut frame_header)?;
+        self.file.read_exact(&mut page)?;
+
+        if frame_header[8..16] != self.salt {
+            bail!(
+                "Read snapshot lost: the WAL was restarted by a checkpoint during the statement; retry it"
+            );
+        }
+        Ok(Some(page))
+    }
+
+    /// Checks that pages read from the database file still belong to the
+    /// snapshot. A checkpoint that copied frames past our `mxFrame` back into
+    /// the database file, or restarted the WAL, would mix versions.
+    pub fn verify_database_file(&mut self) -> Result<()> {
+        let mut salt = [0u8; 8];
+        self.file.seek(SeekFrom::Start(16))?;
+        self.file.read_exact(&mut salt)?;
+        if salt != self.salt {
+            bail!(
+                "Read snapshot lost: the WAL was restarted by a checkpoint during the statement; retry it"
+            );
+        }
+
+        if let Ok(mut shm) = File::open(&self.shm_path) {
+            let mut backfill = [0u8; 4];
+            if shm.seek(SeekFrom::Start(SHM_BACKFILL_OFFSET)).is_ok()
+                && shm.read_exact(&mut backfill).is_ok()
+                // The wal-index is in native byte order.
+                && u32::from_ne_bytes(backfill) > self.max_frame
+            {
+                bail!(
+                    "Read snapshot lost: a checkpoint copied newer pages into the database during the statement; retry it"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// The WAL checksum from https://www.sqlite.org/fileformat2.html#walformat,
+/// continued from `previous` over `data` (a multiple of 8 bytes).
+pub(crate) fn wal_checksum(big_endian: bool, previous: [u32; 2], data: &[u8]) -> [u32; 2] {
+    let [mut s0, mut s1] = previous;
+    for chunk in data.chunks_exact(8) {
+        let (x0, x1) = if big_endian {
+            (be_u32(&chunk[0..4]), be_u32(&chunk[4..8]))
+        } else {
+            (
+                u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+                u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+            )
+        };
+        s0 = s0.wrapping_add(x0).wrapping_add(s1);
+        s1 = s1.wrapping_add(x1).wrapping_add(s0);
+    }
+    [s0, s1]
+}
+
+/// Like `read_exact`, but a short read at end of file is reported through the
+/// returned length rather than as an error.
+fn read_fully(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wal_checksum, WAL_MAGIC_BE, WAL_MAGIC_LE};
+    use crate::database::Database;
+    use crate::query::prepare;
+    use crate::record::Value;
+    use crate::testing::TestDatabase;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const PAGE_SIZE: usize = 512;
+    const SALT: [u8; 8] = *b"saltsalt";
+
+    /// A one-row database whose row reads `name`.
+    fn image(name: &str) -> Vec<u8> {
+        TestDatabase::new()
+            .page_size(PAGE_SIZE)
+            .table(
+                "CREATE TABLE t (name TEXT)",
+                [vec![Value::Text(name.into())]],
+            )
+            .build()
+            .unwrap()
+    }
+
+    /// The frames that turn `from` into `to`: each page that differs, the
+    /// last one marked as a commit unless `commit` is false.
+    fn frames(from: &[u8], to: &[u8], commit: bool) -> Vec<(u32, u32, Vec<u8>)> {
+        let mut frames: Vec<_> = from
+            .chunks(PAGE_SIZE)
+            .zip(to.chunks(PAGE_SIZE))
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, (_, new))| (i as u32 + 1, 0, new.to_vec()))
+            .collect();
+        if let Some(last) = frames.last_mut().filter(|_| commit) {
+            last.1 = (to.len() / PAGE_SIZE) as u32;
+        }
+        frames
+    }
+
+    /// A `-wal` file holding `frames` of (page number, commit size, page).
+    fn wal(big_endian: bool, frames: &[(u32, u32, Vec<u8>)]) -> Vec<u8> {
+        let magic = if big_endian {
+            WAL_MAGIC_BE
+        } else {
+            WAL_MAGIC_LE
+        };
+        let mut wal = Vec::new();
+        wal.extend(magic.to_be_bytes());
+        wal.extend(3_007_000u32.to_be_bytes());
+        wal.extend((PAGE_SIZE as u32).to_be_bytes());
+        wal.extend(0u32.to_be_bytes());
+        wal.extend(SALT);
+        let mut checksum = wal_checksum(big_endian, [0, 0], &wal);
+        wal.extend(checksum.iter().flat_map(|word| word.to_be_bytes()));
+        for (page_number, commit_size, page) in frames {
+            let mut header = Vec::new();
+            header.extend(page_number.to_be_bytes());
+            header.extend(commit_size.to_be_bytes());
+            checksum = wal_checksum(big_endian, checksum, &header);
+            checksum = wal_checksum(big_endian, checksum, page);
+            header.extend(SALT);
+            header.extend(checksum.iter().flat_map(|word| word.to_be_bytes()));
+            wal.extend(header);
+            wal.extend(page);
+        }
+        wal
+    }
+
+    /// A database file, removed with its `-wal` on drop.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, image: &[u8]) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("sequel-wal-{}-{}.db", std::process::id(), name));
+            fs::write(&path, image).unwrap();
+            TempFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        fn write_wal(&self, wal: &[u8]) {
+            fs::write(format!("{}-wal", self.path()), wal).unwrap();
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(format!("{}-wal", self.path()));
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn names(db: &mut Database) -> Vec<Vec<Value>> {
+        let mut rows = Vec::new();
+        prepare(db, "SELECT name FROM t")
+            .unwrap()
+            .run(db, |row| {
+                rows.push(row);
+                Ok(())
+            })
+            .unwrap();
+        rows
+    }
+
+    fn name(name: &str) -> Vec<Vec<Value>> {
+        vec![vec![Value::Text(name.into())]]
+    }
+
+    #[test]
+    fn reads_committed_frames() {
+        let (old, new) = (image("old"), image("new"));
+        for big_endian in [false, true] {
+            let file = TempFile::new(&format!("committed-{}", big_endian), &old);
+            file.write_wal(&wal(big_endian, &frames(&old, &new, true)));
+            assert_eq!(
+                names(&mut Database::open(file.path()).unwrap()),
+                name("new")
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_frames_after_the_last_commit() {
+        let (old, new, newer) = (image("old"), image("new"), image("newer"));
+        let file = TempFile::new("uncommitted", &old);
+        let mut log = frames(&old, &new, true);
+        log.extend(frames(&new, &newer, false));
+        file.write_wal(&wal(false, &log));
+        assert_eq!(
+            names(&mut Database::open(file.path()).unwrap()),
+            name("new")
+        );
+
+        // Nor does a committed frame count past one whose checksum is wrong.
+        let mut log = frames(&old, &new, false);
+        log.extend(frames(&new, &newer, true));
+        let mut bytes = wal(false, &log);
+        bytes[32 + 24] ^= 1;
+        file.write_wal(&bytes);
+        assert_eq!(
+            names(&mut Database::open(file.path()).unwrap()),
+            name("old")
+        );
+    }
+
+    #[test]
+    fn keeps_its_snapshot_until_the_next_statement() {
+        let (old, new) = (image("old"), image("new"));
+        let file = TempFile::new("snapshot", &old);
+        let mut db = Database::open(file.path()).unwrap();
+        assert_eq!(names(&mut db), name("old"));
+
+        file.write_wal(&wal(false, &frames(&old, &new, true)));
+        assert_eq!(names(&mut db), name("old"));
+        db.begin_statement().unwrap();
+        assert_eq!(names(&mut db), name("new"));
+    }
+}
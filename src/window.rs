@@ -0,0 +1,407 @@
+use crate::aggregate::{Accumulators, Aggregate};
+use crate::eval::{evaluate, Row};
+use crate::functions::UserFunctions;
+use crate::parser::Expr;
+use crate::query::{compare_sort_keys, SortKey, SortSource};
+use crate::record::Value;
+use crate::schema::ColumnDef;
+use anyhow::{bail, Result};
+
+/// A window function of the select list, worked out over all the rows of
+/// the query at once.
+pub struct Window {
+    pub function: WindowFunction,
+    /// The PARTITION BY keys, then the ORDER BY ones, as expressions over
+    /// the table row.
+    pub(crate) keys: Vec<SortKey>,
+    /// How many of `keys` are PARTITION BY keys.
+    pub partitions: usize,
+}
+
+pub enum WindowFunction {
+    /// `row_number()`: the row's position in its partition, from 1.
+    RowNumber,
+    /// `rank()`: the position of the first row sorting equal to it.
+    Rank,
+    /// `dense_rank()`: how many different ORDER BY values come up to it.
+    DenseRank,
+    /// `percent_rank()`: `(rank - 1) / (rows - 1)`, or 0 for one row.
+    PercentRank,
+    /// `cume_dist()`: the share of the partition's rows up to its last peer.
+    CumeDist,
+    /// `ntile(n)`: which of `n` nearly equal groups the row falls in.
+    Ntile(Expr),
+    /// `lag(arg, offset, default)`, or with `ahead` `lead(...)`: `arg` for
+    /// the row `offset` rows back (or ahead) in the partition, `default`
+    /// when there is none.
+    Shift {
+        arg: Expr,
+        offset: Expr,
+        default: Expr,
+        ahead: bool,
+    },
+    /// `first_value(arg)`
+    FirstValue(Expr),
+    /// `last_value(arg)`: `arg` for the row's last peer, which the default
+    /// frame ends with.
+    LastValue(Expr),
+    /// An aggregate over the partition's rows up to the row's last peer.
+    Aggregate(Aggregate),
+}
+
+impl Window {
+    /// The window's value for each of `rows`, which are whole table rows,
+    /// with the row's index: in the order of the window's keys.
+    pub fn values(
+        &self,
+        rows: &[Vec<Value>],
+        columns: &[ColumnDef],
+        functions: &UserFunctions,
+        parameters: &[Value],
+    ) -> Result<Vec<(usize, Value)>> {
+        let mut sorted = rows
+            .iter()
+            .enumerate()
+            .map(|(index, values)| {
+                let row = Row {
+                    columns,
+                    values,
+                    functions,
+                    parameters,
+                };
+                let keys = self
+                    .keys
+                    .iter()
+                    .map(|key| match &key.source {
+                        SortSource::Expr(expr) => evaluate(expr, &row),
+                        SortSource::Output(_) => unreachable!("window keys are expressions"),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok((keys, index))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Stable, so rows that are peers stay in scan order.
+        sorted.sort_by(|(a, _), (b, _)| compare_sort_keys(&self.keys, a, b));
+
+        let mut values = Vec::with_capacity(rows.len());
+        let mut start = 0;
+        while start < sorted.len() {
+            let end = start
+                + sorted[start..]
+                    .iter()
+                    .take_while(|(keys, _)| {
+                        compare_sort_keys(&self.keys[..self.partitions], keys, &sorted[start].0)
+                            .is_eq()
+                    })
+                    .count();
+            let partition = &sorted[start..end];
+            let partition_values =
+                self.partition_values(partition, rows, columns, functions, parameters)?;
+            values.extend(
+                partition
+                    .iter()
+                    .map(|(_, index)| *index)
+                    .zip(partition_values),
+            );
+            start = end;
+        }
+        Ok(values)
+    }
+
+    /// The window's value for each row of one partition, sorted.
+    fn partition_values(
+        &self,
+        partition: &[(Vec<Value>, usize)],
+        rows: &[Vec<Value>],
+        columns: &[ColumnDef],
+        functions: &UserFunctions,
+        parameters: &[Value],
+    ) -> Result<Vec<Value>> {
+        let row = |position: usize| Row {
+            columns,
+            values: &rows[partition[position].1],
+            functions,
+            parameters,
+        };
+        let count = partition.len();
+        // Where each row's peers, the rows sorting equal to it, start and
+        // end. Without ORDER BY, the whole partition is one set of peers.
+        let mut peers = Vec::with_capacity(count);
+        let mut start = 0;
+        while start < count {
+            let end = start
+                + partition[start..]
+                    .iter()
+                    .take_while(|(keys, _)| {
+                        compare_sort_keys(&self.keys, keys, &partition[start].0).is_eq()
+                    })
+                    .count();
+            peers.extend(std::iter::repeat(start..end).take(end - start));
+            start = end;
+        }
+
+        let mut values = Vec::with_capacity(count);
+        match &self.function {
+            WindowFunction::RowNumber => {
+                values.extend((1..=count as i64).map(Value::Int));
+            }
+            WindowFunction::Rank => {
+                values.extend(peers.iter().map(|peers| Value::Int(peers.start as i64 + 1)));
+            }
+            WindowFunction::DenseRank => {
+                let mut rank = 0;
+                for (position, peers) in peers.iter().enumerate() {
+                    if peers.start == position {
+                        rank += 1;
+                    }
+                    values.push(Value::Int(rank));
+                }
+            }
+            WindowFunction::PercentRank => {
+                values.extend(peers.iter().map(|peers| match count {
+                    1 => Value::Float(0.0),
+                    _ => Value::Float(peers.start as f64 / (count - 1) as f64),
+                }));
+            }
+            WindowFunction::CumeDist => {
+                values.extend(
+                    peers
+                        .iter()
+                        .map(|peers| Value::Float(peers.end as f64 / count as f64)),
+                );
+            }
+            WindowFunction::Ntile(groups) => {
+                let groups = match evaluate(groups, &row(0))? {
+                    Value::Int(groups) if groups > 0 => groups as usize,
+                    _ => bail!("Argument of ntile must be a positive integer"),
+                };
+                // The first `count % groups` groups get one row more.
+                let size = count / groups;
+                let larger = count % groups;
+                let in_larger = larger * (size + 1);
+                values.extend((0..count).map(|position| {
+                    let group = if position < in_larger {
+                        position / (size + 1)
+                    } else {
+                        larger + (position - in_larger) / size.max(1)
+                    };
+                    Value::Int(group as i64 + 1)
+                }));
+            }
+            WindowFunction::Shift {
+                arg,
+                offset,
+                default,
+                ahead,
+            } => {
+                for position in 0..count {
+                    let offset = match evaluate(offset, &row(position))? {
+                        Value::Int(offset) => offset,
+                        _ => bail!("Second argument of lag and lead must be an integer"),
+                    };
+                    let offset = if *ahead {
+                        offset
+                    } else {
+                        offset.wrapping_neg()
+                    };
+                    let target = (position as i64).checked_add(offset);
+                    values.push(match target {
+                        Some(target) if (0..count as i64).contains(&target) => {
+                            evaluate(arg, &row(target as usize))?
+                        }
+                        _ => evaluate(default, &row(position))?,
+                    });
+                }
+            }
+            WindowFunction::FirstValue(arg) => {
+                let first = evaluate(arg, &row(0))?;
+                values.resize(count, first);
+            }
+            WindowFunction::LastValue(arg) => {
+                for peers in &peers {
+                    values.push(evaluate(arg, &row(peers.end - 1))?);
+                }
+            }
+            WindowFunction::Aggregate(aggregate) => {
+                let aggregates = std::slice::from_ref(aggregate);
+                let mut accumulators = Accumulators::new(aggregates);
+                let mut position = 0;
+                while position < count {
+                    let end = peers[position].end;
+                    for position in position..end {
+                        accumulators.step(aggregates, &row(position))?;
+                    }
+                    // An aggregate registered from Rust can only be
+                    // finished once, so it starts over for each set of peers.
+                    let value = match accumulators.current(aggregates) {
+                        Some(current) => current?,
+                        None => {
+                            let mut again = Accumulators::new(aggregates);
+                            for position in 0..end {
+                                again.step(aggregates, &row(position))?;
+                            }
+                            again.finish(aggregates)?
+                        }
+                    };
+                    let value = value.into_iter().next().unwrap_or(Value::Null);
+                    values.resize(end, value);
+                    position = end;
+                }
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// Rebuilds `expr` with every window function in it replaced by what
+/// `replace` makes of it, in the order they are written. Subqueries have
+/// windows of their own, and are left alone.
+pub fn replace_window_calls(
+    expr: Expr,
+    replace: &mut dyn FnMut(Expr) -> Result<Expr>,
+) -> Result<Expr> {
+    let mut map = |expr: Box<Expr>| replace_window_calls(*expr, &mut *replace).map(Box::new);
+    Ok(match expr {
+        expr @ Expr::Window { .. } => replace(expr)?,
+        Expr::Function { name, args } => Expr::Function {
+            name,
+            args: args
+                .into_iter()
+                .map(|arg| map(Box::new(arg)).map(|arg| *arg))
+                .collect::<Result<_>>()?,
+        },
+        Expr::Compare { left, op, right } => Expr::Compare {
+            left: map(left)?,
+            op,
+            right: map(right)?,
+        },
+        Expr::Like {
+            left,
+            pattern,
+            escape,
+        } => Expr::Like {
+            left: map(left)?,
+            pattern: map(pattern)?,
+            escape,
+        },
+        Expr::In { left, list } => Expr::In {
+            left: map(left)?,
+            list: list
+                .into_iter()
+                .map(|item| map(Box::new(item)).map(|item| *item))
+                .collect::<Result<_>>()?,
+        },
+        Expr::IsNull(operand) => Expr::IsNull(map(operand)?),
+        Expr::Collate { operand, collation } => Expr::Collate {
+            operand: map(operand)?,
+            collation,
+        },
+        Expr::Arithmetic { left, op, right } => Expr::Arithmetic {
+            left: map(left)?,
+            op,
+            right: map(right)?,
+        },
+        Expr::And(left, right) => Expr::And(map(left)?, map(right)?),
+        Expr::Or(left, right) => Expr::Or(map(left)?, map(right)?),
+        Expr::Not(inner) => Expr::Not(map(inner)?),
+        Expr::InSubquery { left, query } => Expr::InSubquery {
+            left: map(left)?,
+            query,
+        },
+        expr @ (Expr::Column(_)
+        | Expr::Literal(_)
+        | Expr::OuterColumn { .. }
+        | Expr::Parameter(_)
+        | Expr::Exists(_)) => expr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::Value;
+    use crate::testing::{int, one_table, query, real, text};
+
+    const N: Value = Value::Null;
+
+    /// Two groups of values, one of them NULL and two equal.
+    fn run(sql: &str) -> Vec<Vec<Value>> {
+        let image = one_table(
+            "CREATE TABLE s (id INTEGER PRIMARY KEY, g TEXT, v INTEGER)",
+            [
+                ("a", int(1)),
+                ("a", int(2)),
+                ("a", int(2)),
+                ("b", int(5)),
+                ("b", N),
+            ]
+            .into_iter()
+            .map(|(g, v)| vec![N, text(g), v]),
+        );
+        query(&image, sql).unwrap()
+    }
+
+    #[test]
+    fn numbers_rows() {
+        assert_eq!(
+            run(
+                "SELECT id, row_number() OVER (ORDER BY id DESC), rank() OVER (ORDER BY v), \
+                 dense_rank() OVER (ORDER BY v) FROM s ORDER BY id"
+            ),
+            vec![
+                vec![int(1), int(5), int(2), int(2)],
+                vec![int(2), int(4), int(3), int(3)],
+                vec![int(3), int(3), int(3), int(3)],
+                vec![int(4), int(2), int(5), int(4)],
+                vec![int(5), int(1), int(1), int(1)],
+            ]
+        );
+        assert_eq!(
+            run(
+                "SELECT percent_rank() OVER (ORDER BY v), cume_dist() OVER (ORDER BY v), \
+                 ntile(2) OVER (ORDER BY id) FROM s ORDER BY id"
+            ),
+            vec![
+                vec![real(0.25), real(0.4), int(1)],
+                vec![real(0.5), real(0.8), int(1)],
+                vec![real(0.5), real(0.8), int(1)],
+                vec![real(1.0), real(1.0), int(2)],
+                vec![real(0.0), real(0.2), int(2)],
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_neighbouring_rows() {
+        assert_eq!(
+            run(&format!(
+                "SELECT lag(v) OVER {w}, lead(v, 1, 0) OVER {w}, first_value(v) OVER {w}, \
+                 last_value(v) OVER {w} FROM s ORDER BY id",
+                w = "(PARTITION BY g ORDER BY id)"
+            )),
+            vec![
+                vec![N, int(2), int(1), int(1)],
+                vec![int(1), int(2), int(1), int(2)],
+                vec![int(2), int(0), int(1), int(2)],
+                vec![N, N, int(5), int(5)],
+                vec![int(5), int(0), int(5), N],
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregates_up_to_the_last_peer() {
+        assert_eq!(
+            run(
+                "SELECT sum(v) OVER (PARTITION BY g), sum(v) OVER (PARTITION BY g ORDER BY v), \
+                 count(v) OVER () FROM s ORDER BY id"
+            ),
+            vec![
+                vec![int(5), int(1), int(4)],
+                vec![int(5), int(5), int(4)],
+                vec![int(5), int(5), int(4)],
+                vec![int(5), int(5), int(4)],
+                vec![int(5), N, int(4)],
+            ]
+        );
+    }
+}
@@ -0,0 +1,425 @@
+use crate::database::{SchemaEntry, DATABASE_HEADER_SIZE, SQLITE_MAGIC};
+use crate::record::{encode_record_in, write_varint, TextEncoding, Value};
+use anyhow::{bail, Result};
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Range;
+
+const INTERIOR_INDEX_PAGE: u8 = 0x02;
+const INTERIOR_TABLE_PAGE: u8 = 0x05;
+const LEAF_INDEX_PAGE: u8 = 0x0a;
+const LEAF_TABLE_PAGE: u8 = 0x0d;
+
+/// Written to the "SQLite version number" header field of new files.
+const SOFTWARE_VERSION: u32 = 3_045_000;
+
+/// Writes a new database file from scratch, one B-tree at a time. Trees are
+/// built bottom-up and every page is written as soon as it is complete, so a
+/// table of any size passes through with only one leaf page (and one key per
+/// finished leaf) in memory. Page 1, which holds the header and
+/// `sqlite_schema`, is written last by `finish`, once every root page is known.
+pub struct DatabaseWriter<W: Write + Seek> {
+    out: W,
+    page_size: usize,
+    page_count: u32,
+    text_encoding: TextEncoding,
+}
+
+impl<W: Write + Seek> DatabaseWriter<W> {
+    pub fn new(out: W, page_size: usize) -> Result<Self> {
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            bail!(
+                "Invalid page size {} (must be a power of two between 512 and 65536)",
+                page_size
+            );
+        }
+        Ok(DatabaseWriter {
+            out,
+            page_size,
+            // Page 1 is reserved for `finish`.
+            page_count: 1,
+            text_encoding: TextEncoding::Utf8,
+        })
+    }
+
+    /// Sets the encoding recorded in the header, which every TEXT value in
+    /// the records handed to the writer must use. Defaults to UTF-8.
+    pub fn set_text_encoding(&mut self, text_encoding: TextEncoding) {
+        self.text_encoding = text_encoding;
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn allocate_page(&mut self) -> u32 {
+        self.page_count += 1;
+        self.page_count
+    }
+
+    fn write_page(&mut self, page_number: u32, page: &[u8]) -> Result<()> {
+        let offset = (page_number as u64 - 1) * self.page_size as u64;
+        self.out.seek(SeekFrom::Start(offset))?;
+        self.out.write_all(page)?;
+        Ok(())
+    }
+
+    /// Appends `payload` to `cell`, keeping at most `max_local` bytes on the
+    /// B-tree page and moving the rest to a chain of overflow pages, split the
+    /// same way SQLite splits it (https://www.sqlite.org/fileformat2.html#cellformat).
+    fn payload_cell(
+        &mut self,
+        mut cell: Vec<u8>,
+        payload: &[u8],
+        max_local: usize,
+    ) -> Result<Vec<u8>> {
+        let usable = self.page_size;
+        let min_local = (usable - 12) * 32 / 255 - 23;
+        let local = if payload.len() <= max_local {
+            payload.len()
+        } else {
+            let surplus = min_local + (payload.len() - min_local) % (usable - 4);
+            if surplus <= max_local {
+                surplus
+            } else {
+                min_local
+            }
+        };
+        cell.extend_from_slice(&payload[..local]);
+
+        if local < payload.len() {
+            let chunks: Vec<&[u8]> = payload[local..].chunks(usable - 4).collect();
+            let pages: Vec<u32> = chunks.iter().map(|_| self.allocate_page()).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let mut page = vec![0; usable];
+                let next = pages.get(i + 1).copied().unwrap_or(0);
+                page[..4].copy_from_slice(&next.to_be_bytes());
+                page[4..4 + chunk.len()].copy_from_slice(chunk);
+                self.write_page(pages[i], &page)?;
+            }
+            cell.extend_from_slice(&pages[0].to_be_bytes());
+        }
+        // SQLite counts every cell as at least 4 bytes when it checks a
+        // page's free space, so tiny cells are padded to that size.
+        cell.resize(cell.len().max(4), 0);
+        Ok(cell)
+    }
+
+    fn table_leaf_cell(&mut self, rowid: i64, record: &[u8]) -> Result<Vec<u8>> {
+        let mut cell = Vec::new();
+        write_varint(record.len() as u64, &mut cell);
+        write_varint(rowid as u64, &mut cell);
+        let max_local = self.page_size - 35;
+        self.payload_cell(cell, record, max_local)
+    }
+
+    fn index_cell(&mut self, record: &[u8]) -> Result<Vec<u8>> {
+        let mut cell = Vec::new();
+        write_varint(record.len() as u64, &mut cell);
+        let max_local = (self.page_size - 12) * 64 / 255 - 23;
+        self.payload_cell(cell, record, max_local)
+    }
+
+    /// Writes an index B-tree holding `records`, which must already be in
+    /// index order, and returns its root page.
+    pub fn write_index(&mut self, records: &[Vec<u8>]) -> Result<u32> {
+        let cells = records
+            .iter()
+            .map(|record| self.index_cell(record))
+            .collect::<Result<Vec<_>>>()?;
+        let sizes: Vec<usize> = cells.iter().map(Vec::len).collect();
+        // Unlike in a table, an index entry moved up to an interior page as a
+        // divider is not repeated in a leaf.
+        let (groups, dividers) = split_into_pages(&sizes, self.page_size - 8);
+
+        let mut children = Vec::new();
+        let mut right = 0;
+        for (i, group) in groups.into_iter().enumerate() {
+            let page_number = self.allocate_page();
+            let page = build_page(LEAF_INDEX_PAGE, &cells[group], None, 0, self.page_size);
+            self.write_page(page_number, &page)?;
+            match dividers.get(i) {
+                Some(&divider) => children.push((page_number, cells[divider].clone())),
+                None => right = page_number,
+            }
+        }
+        self.write_interior_levels(INTERIOR_INDEX_PAGE, children, right)
+    }
+
+    /// Stacks interior pages on top of one level of a B-tree until a single
+    /// root remains. `children` are the pages of the level with the body of
+    /// the cell that points to each (its key, or for an index the divider
+    /// entry that follows it); `right` is the last page, which has no cell.
+    fn write_interior_levels(
+        &mut self,
+        page_type: u8,
+        mut children: Vec<(u32, Vec<u8>)>,
+        mut right: u32,
+    ) -> Result<u32> {
+        while !children.is_empty() {
+            let cells: Vec<Vec<u8>> = children
+                .iter()
+                .map(|(child, body)| [&child.to_be_bytes()[..], body].concat())
+                .collect();
+            let sizes: Vec<usize> = cells.iter().map(Vec::len).collect();
+            let (groups, dividers) = split_into_pages(&sizes, self.page_size - 12);
+
+            let mut parents = Vec::new();
+            let mut last_page = 0;
+            for (i, group) in groups.into_iter().enumerate() {
+                let right_child = match dividers.get(i) {
+                    Some(&divider) => children[divider].0,
+                    None => right,
+                };
+                let page_number = self.allocate_page();
+                let page = build_page(
+                    page_type,
+                    &cells[group],
+                    Some(right_child),
+                    0,
+                    self.page_size,
+                );
+                self.write_page(page_number, &page)?;
+                match dividers.get(i) {
+                    Some(&divider) => parents.push((page_number, children[divider].1.clone())),
+                    None => last_page = page_number,
+                }
+            }
+            children = parents;
+            right = last_page;
+        }
+        Ok(right)
+    }
+
+    /// Writes page 1, with the database header and a `sqlite_schema` made of
+    /// `schema`, and hands back the finished output.
+    pub fn finish(mut self, schema: &[SchemaEntry]) -> Result<W> {
+        let mut cells = Vec::new();
+        for (i, entry) in schema.iter().enumerate() {
+            let record = encode_record_in(
+                &[
+                    Value::Text(entry.typ.as_str().into()),
+                    Value::Text(entry.name.as_str().into()),
+                    Value::Text(entry.tbl_name.as_str().into()),
+                    Value::Int(entry.rootpage as i64),
+                    entry
+                        .sql
+                        .as_deref()
+                        .map_or(Value::Null, |sql| Value::Text(sql.into())),
+                ],
+                self.text_encoding,
+            );
+            cells.push(self.table_leaf_cell(i as i64 + 1, &record)?);
+        }
+        let page_size = self.page_size;
+        let fits_on_page_1 = |cells: &[Vec<u8>], page_header_size: usize| {
+            let used: usize = cells.iter().map(|cell| cell.len() + 2).sum();
+            DATABASE_HEADER_SIZE + page_header_size + used <= page_size
+        };
+        let mut page = if fits_on_page_1(&cells, 8) {
+            build_page(
+                LEAF_TABLE_PAGE,
+                &cells,
+                None,
+                DATABASE_HEADER_SIZE,
+                self.page_size,
+            )
+        } else {
+            // Like any table, sqlite_schema grows into a tree; its root stays
+            // on page 1, above leaves written elsewhere.
+            let mut leaves = TableBuilder::new();
+            for (i, cell) in cells.into_iter().enumerate() {
+                leaves.push_cell(&mut self, i as i64 + 1, cell)?;
+            }
+            let mut children = leaves.finish_leaves(&mut self)?;
+            let (right, _) = children.pop().unwrap_or_default();
+            let cells: Vec<Vec<u8>> = children
+                .iter()
+                .map(|(child, key)| [&child.to_be_bytes()[..], key].concat())
+                .collect();
+            if !fits_on_page_1(&cells, 12) {
+                bail!(
+                    "The schema does not fit under page 1 of a {}-byte page database",
+                    self.page_size
+                );
+            }
+            build_page(
+                INTERIOR_TABLE_PAGE,
+                &cells,
+                Some(right),
+                DATABASE_HEADER_SIZE,
+                self.page_size,
+            )
+        };
+        let schema_cookie = u32::from(!schema.is_empty());
+        let header = &mut page[..DATABASE_HEADER_SIZE];
+        header[..16].copy_from_slice(SQLITE_MAGIC);
+        // 65536 doesn't fit in two bytes and is stored as 1.
+        let stored_page_size = if self.page_size == 65536 {
+            1
+        } else {
+            self.page_size as u16
+        };
+        header[16..18].copy_from_slice(&stored_page_size.to_be_bytes());
+        header[18] = 1; // write format: rollback journal
+        header[19] = 1; // read format
+        header[21] = 64; // maximum embedded payload fraction
+        header[22] = 32; // minimum embedded payload fraction
+        header[23] = 32; // leaf payload fraction
+        header[24..28].copy_from_slice(&1u32.to_be_bytes()); // change counter
+        header[28..32].copy_from_slice(&self.page_count.to_be_bytes());
+        header[40..44].copy_from_slice(&schema_cookie.to_be_bytes());
+        header[44..48].copy_from_slice(&4u32.to_be_bytes()); // schema format
+        header[56..60].copy_from_slice(&self.text_encoding.header_value().to_be_bytes());
+        // The page count above is valid for this change counter.
+        header[92..96].copy_from_slice(&1u32.to_be_bytes());
+        header[96..100].copy_from_slice(&SOFTWARE_VERSION.to_be_bytes());
+
+        self.write_page(1, &page)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Streams rows, in ascending rowid order, into a new table B-tree.
+#[derive(Default)]
+pub struct TableBuilder {
+    leaf: Vec<Vec<u8>>,
+    leaf_size: usize,
+    last_rowid: Option<i64>,
+    /// Finished leaves, each with its largest rowid as a key varint.
+    children: Vec<(u32, Vec<u8>)>,
+}
+
+impl TableBuilder {
+    pub fn new() -> Self {
+        TableBuilder::default()
+    }
+
+    /// Adds a row whose encoded record is `record`.
+    pub fn insert<W: Write + Seek>(
+        &mut self,
+        writer: &mut DatabaseWriter<W>,
+        rowid: i64,
+        record: &[u8],
+    ) -> Result<()> {
+        if let Some(last_rowid) = self.last_rowid {
+            if rowid <= last_rowid {
+                bail!(
+                    "Rows must be added in ascending rowid order: {} after {}",
+                    rowid,
+                    last_rowid
+                );
+            }
+        }
+        let cell = writer.table_leaf_cell(rowid, record)?;
+        self.push_cell(writer, rowid, cell)
+    }
+
+    fn push_cell<W: Write + Seek>(
+        &mut self,
+        writer: &mut DatabaseWriter<W>,
+        rowid: i64,
+        cell: Vec<u8>,
+    ) -> Result<()> {
+        if 8 + self.leaf_size + cell.len() + 2 > writer.page_size() {
+            self.flush_leaf(writer)?;
+        }
+        self.leaf_size += cell.len() + 2;
+        self.leaf.push(cell);
+        self.last_rowid = Some(rowid);
+        Ok(())
+    }
+
+    fn flush_leaf<W: Write + Seek>(&mut self, writer: &mut DatabaseWriter<W>) -> Result<()> {
+        let page_number = writer.allocate_page();
+        let page = build_page(LEAF_TABLE_PAGE, &self.leaf, None, 0, writer.page_size());
+        writer.write_page(page_number, &page)?;
+        let mut key = Vec::new();
+        write_varint(self.last_rowid.unwrap_or(0) as u64, &mut key);
+        self.children.push((page_number, key));
+        self.leaf.clear();
+        self.leaf_size = 0;
+        Ok(())
+    }
+
+    /// Writes the rest of the tree and returns its root page.
+    pub fn finish<W: Write + Seek>(self, writer: &mut DatabaseWriter<W>) -> Result<u32> {
+        let mut children = self.finish_leaves(writer)?;
+        let (right, _) = children.pop().unwrap_or_default();
+        writer.write_interior_levels(INTERIOR_TABLE_PAGE, children, right)
+    }
+
+    /// Writes the last leaf and returns every leaf with its key.
+    fn finish_leaves<W: Write + Seek>(
+        mut self,
+        writer: &mut DatabaseWriter<W>,
+    ) -> Result<Vec<(u32, Vec<u8>)>> {
+        if !self.leaf.is_empty() || self.children.is_empty() {
+            self.flush_leaf(writer)?;
+        }
+        Ok(self.children)
+    }
+}
+
+/// Splits a run of cells into pages of `capacity` bytes (each cell also
+/// takes a 2-byte pointer), leaving one cell between every two pages to go
+/// up to the parent. Returns the cell ranges of the pages and the indexes of
+/// the cells between them. No page is left empty.
+fn split_into_pages(sizes: &[usize], capacity: usize) -> (Vec<Range<usize>>, Vec<usize>) {
+    let mut pages = Vec::new();
+    let mut dividers = Vec::new();
+    let mut start = 0;
+    let mut used = 0;
+    let mut i = 0;
+    while i < sizes.len() {
+        if i == start || used + sizes[i] + 2 <= capacity {
+            used += sizes[i] + 2;
+            i += 1;
+        } else if i + 1 == sizes.len() && i - start >= 2 {
+            // Moving the last cell up would leave nothing for the final page,
+            // so the one before it goes instead.
+            pages.push(start..i - 1);
+            dividers.push(i - 1);
+            start = i;
+            used = 0;
+        } else {
+            pages.push(start..i);
+            dividers.push(i);
+            start = i + 1;
+            used = 0;
+            i += 1;
+        }
+    }
+    pages.push(start..sizes.len());
+    (pages, dividers)
+}
+
+/// Lays out a B-tree page: the header at `header_offset` (100 on page 1), the
+/// cell pointer array after it, and the cells packed against the end.
+fn build_page(
+    page_type: u8,
+    cells: &[Vec<u8>],
+    right_most_pointer: Option<u32>,
+    header_offset: usize,
+    page_size: usize,
+) -> Vec<u8> {
+    let mut page = vec![0; page_size];
+    let header_size = if right_most_pointer.is_some() { 12 } else { 8 };
+    let mut content_start = page_size;
+    for (i, cell) in cells.iter().enumerate() {
+        content_start -= cell.len();
+        page[content_start..content_start + cell.len()].copy_from_slice(cell);
+        let pointer = header_offset + header_size + 2 * i;
+        page[pointer..pointer + 2].copy_from_slice(&(content_start as u16).to_be_bytes());
+    }
+
+    let header = &mut page[header_offset..];
+    header[0] = page_type;
+    header[3..5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    // An empty 65536-byte page starts its content at 65536, stored as 0.
+    header[5..7].copy_from_slice(&(content_start as u16).to_be_bytes());
+    if let Some(right_most_pointer) = right_most_pointer {
+        header[8..12].copy_from_slice(&right_most_pointer.to_be_bytes());
+    }
+    page
+}